@@ -0,0 +1,227 @@
+use serde::{Deserialize, Serialize};
+
+use crate::blockchain::{Block, BlockHeader, Hash};
+use crate::consensus::Validator;
+use crate::contracts::{Contract, ContractSet, ContractUndo, Receipt};
+use crate::genesis::Allocation;
+use crate::ledger::{BlockUndo, LedgerError, LedgerKind, LedgerModel, LedgerSnapshot};
+use crate::staking::StakeSet;
+use crate::transaction::{Address, OutPoint, Transaction};
+use crate::trie::{self, InvalidProof, ProofNode, StateTrie};
+
+/// Balance of an address and the number of transactions it sent
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Account {
+    pub balance: u64,
+    pub nonce: u64,
+}
+
+/// Evidence of the account of an address at a block, checked against the header alone
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AccountProof {
+    pub address: Address,
+    /// Hash of the header whose state root the proof leads to
+    pub block: Hash,
+    /// Roots of the parts of the state, the accounts first
+    pub roots: Vec<Hash>,
+    pub nodes: Vec<ProofNode>,
+}
+
+impl AccountProof {
+    /// The account the proof shows, the default account when the address has none
+    pub fn verify(&self, header: &BlockHeader) -> Result<Account, InvalidProof> {
+        if header.hash() != self.block || trie::combine(&self.roots) != header.state_root {
+            return Err(InvalidProof);
+        }
+        let root = self.roots.first().ok_or(InvalidProof)?;
+        trie::verify_proof(root, &self.address, &self.nodes).map(Option::unwrap_or_default)
+    }
+}
+
+/// Balances of all addresses that ever held coins
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct AccountState {
+    accounts: StateTrie<Address, Account>,
+    stakes: StakeSet,
+    #[serde(default)]
+    contracts: ContractSet,
+}
+
+impl AccountState {
+    /// Every allocation of the genesis block credited to its address
+    pub fn from_genesis(
+        allocations: &[Allocation],
+        stakes: StakeSet,
+        contracts: ContractSet,
+    ) -> AccountState {
+        let mut state = AccountState {
+            accounts: StateTrie::new(),
+            stakes,
+            contracts,
+        };
+        for allocation in allocations {
+            let mut account = state.account(&allocation.address);
+            account.balance = account.balance.saturating_add(allocation.amount);
+            state.accounts.insert(allocation.address, account);
+        }
+        state
+    }
+
+    fn account(&self, address: &Address) -> Account {
+        self.accounts.get(address).copied().unwrap_or_default()
+    }
+
+    /// Apply a transaction, returns what it changed in the contracts and receipts
+    fn apply(&mut self, tx: &Transaction) -> Result<ContractUndo, LedgerError> {
+        // 区块校验已经限制了 coinbase 的金额，只需要记入矿工的账户
+        if !tx.is_coinbase() {
+            self.check(tx, &[])?;
+            let mut from = self.account(&tx.from);
+            from.balance -= tx.debit();
+            from.nonce += 1;
+            self.accounts.insert(tx.from, from);
+            self.stakes.apply(tx);
+        }
+        let contract = self.contracts.apply(tx);
+        for output in tx.outputs() {
+            let mut to = self.account(&output.address);
+            to.balance = to.balance.saturating_add(output.amount);
+            self.accounts.insert(output.address, to);
+        }
+        Ok(contract)
+    }
+
+    fn revert_stakes(&mut self, txs: &[Transaction]) {
+        for tx in txs.iter().rev().filter(|tx| !tx.is_coinbase()) {
+            self.stakes.revert(tx);
+        }
+    }
+
+    fn restore(&mut self, undo: Vec<(Address, Option<Account>)>) {
+        // 倒序恢复，同一地址可能被记录多次，最早的状态最后写回
+        for (address, account) in undo.into_iter().rev() {
+            match account {
+                Some(account) => self.accounts.insert(address, account),
+                None => self.accounts.remove(&address),
+            };
+        }
+    }
+}
+
+impl LedgerModel for AccountState {
+    fn kind(&self) -> LedgerKind {
+        LedgerKind::Account
+    }
+
+    /// The transaction must follow the pending ones of the sender, which must be able to pay for
+    /// all of them and their fees
+    fn check(&self, tx: &Transaction, pending: &[&Transaction]) -> Result<(), LedgerError> {
+        if !tx.inputs.is_empty() || tx.change != 0 {
+            return Err(LedgerError::UnexpectedInputs);
+        }
+        let account = self.account(&tx.from);
+        let expected = account.nonce + pending.len() as u64;
+        if tx.nonce != expected {
+            return Err(LedgerError::NonceMismatch {
+                expected,
+                actual: tx.nonce,
+            });
+        }
+        self.stakes.check(tx, pending)?;
+        self.contracts.check(tx, pending)?;
+        let required = pending
+            .iter()
+            .fold(tx.debit(), |sum, tx| sum.saturating_add(tx.debit()));
+        if required > account.balance {
+            return Err(LedgerError::InsufficientFunds {
+                available: account.balance,
+                required,
+            });
+        }
+        Ok(())
+    }
+
+    // 账户模型的交易只需要金额和 nonce
+    fn fund(
+        &self,
+        _tx: &mut Transaction,
+        _reserved: &dyn Fn(&OutPoint) -> bool,
+    ) -> Result<(), LedgerError> {
+        Ok(())
+    }
+
+    fn connect_block(&mut self, block: &Block) -> Result<BlockUndo, LedgerError> {
+        let mut undo = Vec::new();
+        let mut contracts = Vec::new();
+        for (applied, tx) in block.transactions.iter().enumerate() {
+            undo.push((tx.from, self.accounts.get(&tx.from).copied()));
+            undo.push((tx.to, self.accounts.get(&tx.to).copied()));
+            match self.apply(tx) {
+                Ok(contract) => contracts.push(contract),
+                Err(e) => {
+                    self.revert_stakes(&block.transactions[..applied]);
+                    self.contracts.revert(contracts);
+                    self.restore(undo);
+                    return Err(e);
+                }
+            }
+        }
+        Ok(BlockUndo::Account(undo, contracts))
+    }
+
+    fn disconnect_block(&mut self, block: &Block, undo: BlockUndo) {
+        if let BlockUndo::Account(undo, contracts) = undo {
+            self.revert_stakes(&block.transactions);
+            self.contracts.revert(contracts);
+            self.restore(undo);
+        }
+    }
+
+    fn balance(&self, address: &Address) -> u64 {
+        self.account(address).balance
+    }
+
+    fn balances(&self) -> Vec<(Address, u64)> {
+        self.accounts
+            .iter()
+            .filter(|(_, account)| account.balance > 0)
+            .map(|(address, account)| (*address, account.balance))
+            .collect()
+    }
+
+    fn nonce(&self, address: &Address) -> u64 {
+        self.account(address).nonce
+    }
+
+    fn validators(&self) -> Vec<Validator> {
+        self.stakes.validators()
+    }
+
+    fn slashed(&self, address: &Address) -> u64 {
+        self.stakes.slashed(address)
+    }
+
+    fn contract(&self, address: &Address) -> Option<&Contract> {
+        self.contracts.contract(address)
+    }
+
+    fn receipt(&self, txid: &Hash) -> Option<&Receipt> {
+        self.contracts.receipt(txid)
+    }
+
+    fn state_roots(&self) -> Vec<Hash> {
+        vec![
+            self.accounts.root(),
+            self.stakes.root(),
+            self.contracts.root(),
+        ]
+    }
+
+    fn prove_account(&self, address: &Address) -> Option<Vec<ProofNode>> {
+        Some(self.accounts.prove(address))
+    }
+
+    fn snapshot(&self) -> LedgerSnapshot {
+        LedgerSnapshot::Account(self.clone())
+    }
+}
@@ -0,0 +1,108 @@
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::fs;
+use std::future::Future;
+use std::io::ErrorKind;
+
+use anyhow::{Context, Result};
+use libp2p::multiaddr::Protocol;
+use libp2p::{Multiaddr, PeerId};
+use serde::{Deserialize, Serialize};
+
+use crate::clock::now;
+use crate::consts::{
+    ADDRESS_BOOK_FILE_PATH, ADDRESS_BOOK_MAX_ADDRS_PER_PEER, ADDRESS_BOOK_MAX_AGE,
+    ADDRESS_BOOK_RECONNECT_PEERS,
+};
+use crate::storage;
+
+/// Addresses we reached a peer at
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AddressEntry {
+    /// Most recently used first
+    pub addrs: Vec<Multiaddr>,
+    /// Unix time of the last successful connection
+    pub last_seen: u64,
+}
+
+/// Peers we successfully dialed, persisted so the node can reconnect after a restart
+#[derive(Debug, Default)]
+pub struct AddressBook {
+    peers: HashMap<PeerId, AddressEntry>,
+}
+
+impl AddressBook {
+    /// Load the persisted address book, dropping peers not seen for too long
+    pub fn load() -> Result<AddressBook> {
+        let content = match fs::read(ADDRESS_BOOK_FILE_PATH) {
+            Ok(content) => content,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(AddressBook::default()),
+            Err(e) => {
+                return Err(e).with_context(|| format!("can not read {}", ADDRESS_BOOK_FILE_PATH))
+            }
+        };
+        let mut peers: HashMap<PeerId, AddressEntry> = serde_json::from_slice(&content)
+            .with_context(|| format!("invalid address book {}", ADDRESS_BOOK_FILE_PATH))?;
+        let cutoff = now().saturating_sub(ADDRESS_BOOK_MAX_AGE.as_secs());
+        peers.retain(|_, entry| entry.last_seen >= cutoff);
+        Ok(AddressBook { peers })
+    }
+
+    /// Remember the address a connection to the peer was dialed on
+    pub fn record(&mut self, peer_id: PeerId, addr: Multiaddr) {
+        let entry = self.peers.entry(peer_id).or_insert_with(|| AddressEntry {
+            addrs: Vec::new(),
+            last_seen: 0,
+        });
+        entry.addrs.retain(|a| a != &addr);
+        entry.addrs.insert(0, addr);
+        entry.addrs.truncate(ADDRESS_BOOK_MAX_ADDRS_PER_PEER);
+        entry.last_seen = now();
+    }
+
+    /// Addresses the peer was reached at, most recently used first
+    pub fn addrs(&self, peer_id: &PeerId) -> Vec<Multiaddr> {
+        self.peers
+            .get(peer_id)
+            .map(|entry| entry.addrs.clone())
+            .unwrap_or_default()
+    }
+
+    pub fn forget(&mut self, peer_id: &PeerId) {
+        self.peers.remove(peer_id);
+    }
+
+    /// Latest address of the most recently seen peers, each ending with `/p2p/<peer id>`
+    pub fn recent(&self) -> Vec<Multiaddr> {
+        let mut entries: Vec<(&PeerId, &AddressEntry)> = self.peers.iter().collect();
+        entries.sort_by_key(|(_, entry)| Reverse(entry.last_seen));
+        entries
+            .into_iter()
+            .take(ADDRESS_BOOK_RECONNECT_PEERS)
+            .filter_map(|(peer_id, entry)| {
+                let addr = entry.addrs.first()?.clone();
+                match addr.iter().last() {
+                    Some(Protocol::P2p(_)) => Some(addr),
+                    _ => Some(addr.with(Protocol::P2p(*peer_id))),
+                }
+            })
+            .collect()
+    }
+
+    /// Peer ids and addresses of the most recently seen peers
+    pub fn sample(&self, count: usize) -> Vec<(PeerId, Vec<Multiaddr>)> {
+        let mut entries: Vec<(&PeerId, &AddressEntry)> = self.peers.iter().collect();
+        entries.sort_by_key(|(_, entry)| Reverse(entry.last_seen));
+        entries
+            .into_iter()
+            .take(count)
+            .map(|(peer_id, entry)| (*peer_id, entry.addrs.clone()))
+            .collect()
+    }
+
+    /// Persist the peers, copied when it is called so the write can be spawned off the event loop
+    pub fn save(&self) -> impl Future<Output = Result<()>> + Send + 'static {
+        let json = serde_json::to_vec(&self.peers);
+        async move { storage::replace_file(ADDRESS_BOOK_FILE_PATH, json?).await }
+    }
+}
@@ -0,0 +1,54 @@
+use std::path::Path;
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::blockchain::{Chain, Hash};
+use crate::checksummed;
+use crate::clock::now;
+use crate::storage::{self, Record};
+use crate::wallet::Wallet;
+
+/// Records of the databases of a node taken between two commands, so the blocks and the recipes
+/// are of the same moment, with the wallet when the operator asks for it
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Backup {
+    /// Unix time the backup was taken
+    pub created: u64,
+    pub chain_id: String,
+    pub genesis: Hash,
+    /// Records of the block database, none for light nodes
+    pub blocks: Vec<Record>,
+    pub recipes: Vec<Record>,
+    /// The encrypted keystore, left out unless asked for
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub wallet: Option<Wallet>,
+}
+
+impl Backup {
+    /// Records of the chain, unless it is of a light node, and of the recipes
+    pub fn take(chain: &Chain, light: bool, wallet: Option<Wallet>) -> Result<Backup> {
+        Ok(Backup {
+            created: now(),
+            chain_id: chain.chain_id().to_owned(),
+            genesis: chain.genesis_hash(),
+            blocks: if light {
+                Vec::new()
+            } else {
+                chain.storage_snapshot()?
+            },
+            recipes: storage::recipes().snapshot()?,
+            wallet,
+        })
+    }
+}
+
+/// Write the backup to the file, returns its size in bytes
+pub async fn write(path: &Path, backup: &Backup) -> Result<usize> {
+    checksummed::write(path, backup).await
+}
+
+/// Read a backup file, which must match its checksum
+pub async fn read(path: &Path) -> Result<Backup> {
+    checksummed::read(path, "backup").await
+}
@@ -0,0 +1,56 @@
+use std::collections::HashSet;
+use std::fs;
+use std::future::Future;
+use std::io::ErrorKind;
+
+use anyhow::{Context, Result};
+use libp2p::PeerId;
+
+use crate::consts::BANNED_PEERS_FILE_PATH;
+use crate::storage;
+
+/// Peers the operator banned, persisted so bans survive restarts
+#[derive(Debug, Default)]
+pub struct BanList {
+    peers: HashSet<PeerId>,
+}
+
+impl BanList {
+    /// Load the persisted bans, a missing file means nobody is banned yet
+    pub fn load() -> Result<BanList> {
+        let content = match fs::read(BANNED_PEERS_FILE_PATH) {
+            Ok(content) => content,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(BanList::default()),
+            Err(e) => {
+                return Err(e).with_context(|| format!("can not read {}", BANNED_PEERS_FILE_PATH))
+            }
+        };
+        let peers = serde_json::from_slice(&content)
+            .with_context(|| format!("invalid ban list {}", BANNED_PEERS_FILE_PATH))?;
+        Ok(BanList { peers })
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &PeerId> {
+        self.peers.iter()
+    }
+
+    pub fn contains(&self, peer_id: &PeerId) -> bool {
+        self.peers.contains(peer_id)
+    }
+
+    /// Returns false if the peer was already banned
+    pub fn insert(&mut self, peer_id: PeerId) -> bool {
+        self.peers.insert(peer_id)
+    }
+
+    /// Returns false if the peer was not banned
+    pub fn remove(&mut self, peer_id: &PeerId) -> bool {
+        self.peers.remove(peer_id)
+    }
+
+    /// Replace the file with the bans as they are now, the returned future only writes them
+    pub fn save(&self) -> impl Future<Output = Result<()>> + Send + 'static {
+        let json = serde_json::to_vec(&self.peers);
+        async move { storage::replace_file(BANNED_PEERS_FILE_PATH, json?).await }
+    }
+}
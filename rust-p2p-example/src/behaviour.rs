@@ -1,23 +1,249 @@
-use libp2p::floodsub::{Floodsub, FloodsubEvent};
-use libp2p::mdns;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use libp2p::connection_limits::{self, ConnectionLimits};
+use libp2p::identity::Keypair;
+use libp2p::kad::store::MemoryStore;
+use libp2p::rendezvous::{self, Cookie};
+use libp2p::request_response::{self, ProtocolSupport};
+use libp2p::swarm::behaviour::toggle::Toggle;
 use libp2p::swarm::NetworkBehaviour;
+use libp2p::{
+    allow_block_list, autonat, dcutr, gossipsub, identify, kad, mdns, ping, relay, upnp, PeerId,
+};
+use log::debug;
+use void::Void;
+
+use crate::bootstrap::peer_id_of;
+use crate::config::CONFIG;
+use crate::consts::{
+    GOSSIPSUB_FANOUT_TTL, GOSSIPSUB_HEARTBEAT_INTERVAL, GOSSIPSUB_PRUNE_BACKOFF,
+    IDENTIFY_PROTOCOL_VERSION, KAD_PROTOCOL_NAME, RECIPE_PROTOCOL_NAME, RECIPE_REQUEST_TIMEOUT,
+    RENDEZVOUS_NAMESPACE, SYNC_PROTOCOL_NAME, SYNC_REQUEST_TIMEOUT,
+};
+use crate::models::{ListRequest, ListResponse};
+use crate::sync::{SyncEvent, SyncRequest, SyncResponse};
+
+pub type RecipeExchangeEvent = request_response::Event<ListRequest, ListResponse>;
 
 #[derive(NetworkBehaviour)]
 #[behaviour(to_swarm = "RecipeBehaviourEvent")]
 pub struct RecipeBehaviour {
-    pub(crate) flood_sub: Floodsub,
-    pub(crate) mdns: mdns::tokio::Behaviour,
+    pub(crate) gossipsub: gossipsub::Behaviour,
+    pub(crate) mdns: Toggle<mdns::tokio::Behaviour>,
+    pub(crate) kad: kad::Behaviour<MemoryStore>,
+    pub(crate) request_response: request_response::cbor::Behaviour<ListRequest, ListResponse>,
+    pub(crate) sync: request_response::cbor::Behaviour<SyncRequest, SyncResponse>,
+    pub(crate) relay_client: relay::client::Behaviour,
+    pub(crate) relay_server: Toggle<relay::Behaviour>,
+    pub(crate) dcutr: dcutr::Behaviour,
+    pub(crate) autonat: autonat::Behaviour,
+    pub(crate) identify: identify::Behaviour,
+    pub(crate) ping: ping::Behaviour,
+    pub(crate) blocked_peers: allow_block_list::Behaviour<allow_block_list::BlockedPeers>,
+    pub(crate) allowed_peers: Toggle<allow_block_list::Behaviour<allow_block_list::AllowedPeers>>,
+    pub(crate) connection_limits: connection_limits::Behaviour,
+    pub(crate) rendezvous: rendezvous::client::Behaviour,
+    pub(crate) rendezvous_server: Toggle<rendezvous::server::Behaviour>,
+    pub(crate) upnp: Toggle<upnp::tokio::Behaviour>,
+}
+
+impl RecipeBehaviour {
+    pub fn new(key: &Keypair, relay_client: relay::client::Behaviour) -> Result<Self> {
+        RecipeBehaviour::build(key, relay_client, true)
+    }
+
+    /// Same as `new` but without mDNS and UPnP, which talk to the local network even when the
+    /// node itself does not use sockets
+    #[cfg(feature = "memory-transport")]
+    pub fn without_local_network(
+        key: &Keypair,
+        relay_client: relay::client::Behaviour,
+    ) -> Result<Self> {
+        RecipeBehaviour::build(key, relay_client, false)
+    }
+
+    fn build(
+        key: &Keypair,
+        relay_client: relay::client::Behaviour,
+        local_network: bool,
+    ) -> Result<Self> {
+        // Identical payloads share an id, so gossipsub drops re-broadcasts of the same content
+        let message_id_fn = |message: &gossipsub::Message| {
+            let mut hasher = DefaultHasher::new();
+            message.data.hash(&mut hasher);
+            gossipsub::MessageId::from(hasher.finish().to_string())
+        };
+        let gossipsub_config = gossipsub::ConfigBuilder::default()
+            .heartbeat_interval(GOSSIPSUB_HEARTBEAT_INTERVAL)
+            .prune_backoff(GOSSIPSUB_PRUNE_BACKOFF)
+            .fanout_ttl(GOSSIPSUB_FANOUT_TTL)
+            .validation_mode(gossipsub::ValidationMode::Strict)
+            .message_id_fn(message_id_fn)
+            .build()
+            .map_err(|e| anyhow!("invalid gossipsub config: {}", e))?;
+        let gossipsub = gossipsub::Behaviour::new(
+            gossipsub::MessageAuthenticity::Signed(key.clone()),
+            gossipsub_config,
+        )
+        .map_err(|e| anyhow!("can not create gossipsub: {}", e))?;
+
+        let peer_id = key.public().to_peer_id();
+        let mut kad_config = kad::Config::default();
+        kad_config.set_protocol_names(vec![KAD_PROTOCOL_NAME]);
+        let mut kad = kad::Behaviour::with_config(peer_id, MemoryStore::new(peer_id), kad_config);
+        // Answer DHT queries even before an external address has been confirmed
+        kad.set_mode(Some(kad::Mode::Server));
+
+        let mut request_response_config = request_response::Config::default();
+        request_response_config.set_request_timeout(RECIPE_REQUEST_TIMEOUT);
+        let request_response = request_response::cbor::Behaviour::new(
+            [(RECIPE_PROTOCOL_NAME, ProtocolSupport::Full)],
+            request_response_config,
+        );
+
+        let mut sync_config = request_response::Config::default();
+        sync_config.set_request_timeout(SYNC_REQUEST_TIMEOUT);
+        let sync = request_response::cbor::Behaviour::new(
+            [(SYNC_PROTOCOL_NAME, ProtocolSupport::Full)],
+            sync_config,
+        );
+
+        let relay_server = CONFIG.relay_server.then(|| {
+            let relay_config = relay::Config {
+                max_reservations: CONFIG.relay_max_reservations,
+                max_reservations_per_peer: CONFIG.relay_max_reservations_per_peer,
+                max_circuits_per_peer: CONFIG.relay_max_circuits_per_peer,
+                ..Default::default()
+            };
+            relay::Behaviour::new(peer_id, relay_config)
+        });
+
+        // 配置了白名单时只与名单内的节点通信
+        let allowed_peers = (!CONFIG.allowed_peers.is_empty()).then(|| {
+            let mut allowed =
+                allow_block_list::Behaviour::<allow_block_list::AllowedPeers>::default();
+            CONFIG
+                .allowed_peers
+                .iter()
+                .for_each(|peer| allowed.allow_peer(*peer));
+            allowed
+        });
+
+        let mdns = if CONFIG.mdns && local_network {
+            let mdns_config = mdns::Config {
+                ttl: Duration::from_secs(CONFIG.mdns_ttl),
+                query_interval: Duration::from_secs(CONFIG.mdns_query_interval),
+                ..Default::default()
+            };
+            Some(mdns::tokio::Behaviour::new(mdns_config, peer_id)?)
+        } else {
+            None
+        };
+
+        let limits = &CONFIG.connection_limits;
+        let connection_limits = ConnectionLimits::default()
+            .with_max_pending_incoming(limits.max_pending_incoming)
+            .with_max_pending_outgoing(limits.max_pending_outgoing)
+            .with_max_established_incoming(limits.max_established_incoming)
+            .with_max_established_outgoing(limits.max_established_outgoing)
+            .with_max_established_per_peer(limits.max_established_per_peer)
+            .with_max_established(limits.max_established_total);
+
+        let mut behaviour = RecipeBehaviour {
+            gossipsub,
+            mdns: mdns.into(),
+            kad,
+            request_response,
+            sync,
+            relay_client,
+            relay_server: relay_server.into(),
+            dcutr: dcutr::Behaviour::new(peer_id),
+            autonat: autonat::Behaviour::new(peer_id, autonat::Config::default()),
+            identify: identify::Behaviour::new(
+                identify::Config::new(IDENTIFY_PROTOCOL_VERSION.to_owned(), key.public())
+                    .with_agent_version(format!("ant-chain/{}", env!("CARGO_PKG_VERSION"))),
+            ),
+            ping: ping::Behaviour::new(ping::Config::new()),
+            blocked_peers: Default::default(),
+            allowed_peers: allowed_peers.into(),
+            connection_limits: connection_limits::Behaviour::new(connection_limits),
+            rendezvous: rendezvous::client::Behaviour::new(key.clone()),
+            rendezvous_server: CONFIG
+                .rendezvous_server
+                .then(|| rendezvous::server::Behaviour::new(rendezvous::server::Config::default()))
+                .into(),
+            upnp: (CONFIG.upnp && local_network)
+                .then(upnp::tokio::Behaviour::default)
+                .into(),
+        };
+        behaviour.add_bootstrap_nodes();
+        Ok(behaviour)
+    }
+
+    /// Seed the routing table with the configured bootstrap nodes
+    fn add_bootstrap_nodes(&mut self) {
+        for addr in CONFIG.bootstrap.iter() {
+            if let Some(peer_id) = peer_id_of(addr) {
+                self.kad.add_address(&peer_id, addr.clone());
+            }
+        }
+    }
+
+    /// Register under the recipe namespace at a rendezvous point
+    ///
+    /// Registration needs at least one external address, until then only discovery works
+    pub fn rendezvous_register(&mut self, rendezvous_node: PeerId) {
+        if let Err(e) =
+            self.rendezvous
+                .register(RENDEZVOUS_NAMESPACE.clone(), rendezvous_node, None)
+        {
+            debug!("skip rendezvous registration at {}: {}", rendezvous_node, e);
+        }
+    }
+
+    /// Ask a rendezvous point for recipe nodes, the cookie limits the answer to new registrations
+    pub fn rendezvous_discover(&mut self, rendezvous_node: PeerId, cookie: Option<Cookie>) {
+        self.rendezvous.discover(
+            Some(RENDEZVOUS_NAMESPACE.clone()),
+            cookie,
+            None,
+            rendezvous_node,
+        );
+    }
+
+    /// Refresh the Kademlia routing table, a no-op until at least one peer is known
+    pub fn bootstrap(&mut self) {
+        if let Err(e) = self.kad.bootstrap() {
+            debug!("skip kademlia bootstrap: {}", e);
+        }
+    }
 }
 
 #[derive(Debug)]
+#[allow(clippy::large_enum_variant)]
 pub enum RecipeBehaviourEvent {
-    Floodsub(FloodsubEvent),
+    Gossipsub(gossipsub::Event),
     Mdns(mdns::Event),
+    Kad(kad::Event),
+    RequestResponse(RecipeExchangeEvent),
+    Sync(SyncEvent),
+    RelayClient(relay::client::Event),
+    RelayServer(relay::Event),
+    Dcutr(dcutr::Event),
+    Autonat(autonat::Event),
+    Identify(identify::Event),
+    Ping(ping::Event),
+    Rendezvous(rendezvous::client::Event),
+    RendezvousServer(rendezvous::server::Event),
+    Upnp(upnp::Event),
 }
 
-impl From<FloodsubEvent> for RecipeBehaviourEvent {
-    fn from(event: FloodsubEvent) -> RecipeBehaviourEvent {
-        RecipeBehaviourEvent::Floodsub(event)
+impl From<gossipsub::Event> for RecipeBehaviourEvent {
+    fn from(event: gossipsub::Event) -> RecipeBehaviourEvent {
+        RecipeBehaviourEvent::Gossipsub(event)
     }
 }
 
@@ -25,4 +251,83 @@ impl From<mdns::Event> for RecipeBehaviourEvent {
     fn from(event: mdns::Event) -> RecipeBehaviourEvent {
         RecipeBehaviourEvent::Mdns(event)
     }
-}
\ No newline at end of file
+}
+
+impl From<kad::Event> for RecipeBehaviourEvent {
+    fn from(event: kad::Event) -> RecipeBehaviourEvent {
+        RecipeBehaviourEvent::Kad(event)
+    }
+}
+
+impl From<RecipeExchangeEvent> for RecipeBehaviourEvent {
+    fn from(event: RecipeExchangeEvent) -> RecipeBehaviourEvent {
+        RecipeBehaviourEvent::RequestResponse(event)
+    }
+}
+
+impl From<SyncEvent> for RecipeBehaviourEvent {
+    fn from(event: SyncEvent) -> RecipeBehaviourEvent {
+        RecipeBehaviourEvent::Sync(event)
+    }
+}
+
+impl From<relay::client::Event> for RecipeBehaviourEvent {
+    fn from(event: relay::client::Event) -> RecipeBehaviourEvent {
+        RecipeBehaviourEvent::RelayClient(event)
+    }
+}
+
+impl From<relay::Event> for RecipeBehaviourEvent {
+    fn from(event: relay::Event) -> RecipeBehaviourEvent {
+        RecipeBehaviourEvent::RelayServer(event)
+    }
+}
+
+impl From<dcutr::Event> for RecipeBehaviourEvent {
+    fn from(event: dcutr::Event) -> RecipeBehaviourEvent {
+        RecipeBehaviourEvent::Dcutr(event)
+    }
+}
+
+impl From<autonat::Event> for RecipeBehaviourEvent {
+    fn from(event: autonat::Event) -> RecipeBehaviourEvent {
+        RecipeBehaviourEvent::Autonat(event)
+    }
+}
+
+impl From<identify::Event> for RecipeBehaviourEvent {
+    fn from(event: identify::Event) -> RecipeBehaviourEvent {
+        RecipeBehaviourEvent::Identify(event)
+    }
+}
+
+impl From<ping::Event> for RecipeBehaviourEvent {
+    fn from(event: ping::Event) -> RecipeBehaviourEvent {
+        RecipeBehaviourEvent::Ping(event)
+    }
+}
+
+impl From<rendezvous::client::Event> for RecipeBehaviourEvent {
+    fn from(event: rendezvous::client::Event) -> RecipeBehaviourEvent {
+        RecipeBehaviourEvent::Rendezvous(event)
+    }
+}
+
+impl From<rendezvous::server::Event> for RecipeBehaviourEvent {
+    fn from(event: rendezvous::server::Event) -> RecipeBehaviourEvent {
+        RecipeBehaviourEvent::RendezvousServer(event)
+    }
+}
+
+impl From<upnp::Event> for RecipeBehaviourEvent {
+    fn from(event: upnp::Event) -> RecipeBehaviourEvent {
+        RecipeBehaviourEvent::Upnp(event)
+    }
+}
+
+/// Behaviours without events of their own, such as the allow and block lists or connection limits
+impl From<Void> for RecipeBehaviourEvent {
+    fn from(event: Void) -> RecipeBehaviourEvent {
+        void::unreachable(event)
+    }
+}
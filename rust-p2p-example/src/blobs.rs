@@ -0,0 +1,293 @@
+use std::convert::TryInto;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard};
+
+use anyhow::{anyhow, bail, Context, Result};
+use once_cell::sync::OnceCell;
+use serde::{Deserialize, Serialize};
+
+use crate::blockchain::Hash;
+use crate::consts::{BLOBS_DB_PATH, BLOBS_DIR, BLOB_MAX_SIZE};
+use crate::storage::{self, Op, Pending, Storage, StorageBackend};
+
+/// Blobs of the node, opened at startup
+static BLOBS: OnceCell<BlobStore> = OnceCell::new();
+
+/// Key prefix of the entries, followed by the hash of the content
+const ENTRY_PREFIX: &[u8] = b"blob/";
+
+/// Key prefix of the contents kept in the database itself, by the memory backend
+const DATA_PREFIX: &[u8] = b"data/";
+
+/// What is recorded of a blob, its content is kept apart
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct BlobEntry {
+    pub size: u64,
+    /// Recipes and other records pointing at the blob, removed by `gc` once none do
+    pub refs: u64,
+}
+
+/// Large binary data, e.g. recipe images, stored once per content and kept out of the databases
+/// of the records
+///
+/// Contents are files named by their SHA-256 in a directory, the database only counts the
+/// references to each. A content is written before its entry, a file without an entry is left
+/// over from an interrupted write and removed by `gc`
+#[derive(Debug)]
+pub struct BlobStore {
+    db: Mutex<Box<dyn Storage>>,
+    /// Directory of the contents, none when they are kept in the database
+    dir: Option<PathBuf>,
+}
+
+/// What a garbage collection removed
+#[derive(Debug, Default)]
+pub struct Collected {
+    pub blobs: usize,
+    pub bytes: u64,
+}
+
+/// Open the blobs in the backend, contents of the memory backend are kept in memory as well
+pub fn open_blobs(backend: StorageBackend) -> Result<()> {
+    let db = storage::open(backend, BLOBS_DB_PATH)?;
+    let dir = match backend {
+        StorageBackend::Memory => None,
+        _ => {
+            fs::create_dir_all(BLOBS_DIR)
+                .with_context(|| format!("can not create {}", BLOBS_DIR))?;
+            Some(PathBuf::from(BLOBS_DIR))
+        }
+    };
+    BLOBS
+        .set(BlobStore {
+            db: Mutex::new(db),
+            dir,
+        })
+        .map_err(|_| anyhow!("blobs are already open"))
+}
+
+/// The blobs opened by `open_blobs`
+pub fn blobs() -> &'static BlobStore {
+    BLOBS.get().expect("blobs opened at startup")
+}
+
+fn entry_key(hash: &Hash) -> Vec<u8> {
+    [ENTRY_PREFIX, &hash.0[..]].concat()
+}
+
+fn data_key(hash: &Hash) -> Vec<u8> {
+    [DATA_PREFIX, &hash.0[..]].concat()
+}
+
+fn entry_put(hash: &Hash, entry: &BlobEntry) -> Result<Op> {
+    let mut value = Vec::new();
+    ciborium::into_writer(entry, &mut value)?;
+    Ok(Op::Put {
+        key: entry_key(hash),
+        value,
+    })
+}
+
+fn decode_entry(value: &[u8]) -> Result<BlobEntry> {
+    ciborium::from_reader(value).map_err(|e| anyhow!("invalid blob entry: {}", e))
+}
+
+impl BlobStore {
+    fn db(&self) -> MutexGuard<'_, Box<dyn Storage>> {
+        storage::lock(&self.db)
+    }
+
+    fn path(dir: &Path, hash: &Hash) -> PathBuf {
+        dir.join(hash.to_string())
+    }
+
+    fn entry(db: &dyn Storage, hash: &Hash) -> Result<Option<BlobEntry>> {
+        db.get(&entry_key(hash))?
+            .as_deref()
+            .map(decode_entry)
+            .transpose()
+    }
+
+    /// Store the content unless it is stored already and count a reference to it, returns its
+    /// hash
+    pub fn put(&self, content: &[u8]) -> Result<Hash> {
+        if content.len() > BLOB_MAX_SIZE {
+            bail!(
+                "blob of {} bytes is larger than {} bytes",
+                content.len(),
+                BLOB_MAX_SIZE
+            );
+        }
+        let hash = Hash::digest(content);
+        let mut db = self.db();
+        let mut batch = Vec::new();
+        let entry = match BlobStore::entry(db.as_ref(), &hash)? {
+            Some(entry) => entry,
+            None => {
+                match &self.dir {
+                    Some(dir) => {
+                        // 先写临时文件再改名，中断时不会留下内容不全的文件
+                        let path = BlobStore::path(dir, &hash);
+                        let staged = path.with_extension("tmp");
+                        fs::write(&staged, content)
+                            .and_then(|()| fs::rename(&staged, &path))
+                            .with_context(|| format!("can not write {}", path.display()))?;
+                    }
+                    None => batch.push(Op::Put {
+                        key: data_key(&hash),
+                        value: content.to_vec(),
+                    }),
+                }
+                BlobEntry {
+                    size: content.len() as u64,
+                    refs: 0,
+                }
+            }
+        };
+        batch.push(entry_put(
+            &hash,
+            &BlobEntry {
+                refs: entry.refs + 1,
+                ..entry
+            },
+        )?);
+        db.batch(batch)?;
+        Ok(hash)
+    }
+
+    /// Count another reference to a stored blob, false when there is no such blob
+    pub fn retain(&self, hash: &Hash) -> Result<bool> {
+        let mut db = self.db();
+        let entry = match BlobStore::entry(db.as_ref(), hash)? {
+            Some(entry) => entry,
+            None => return Ok(false),
+        };
+        let entry = BlobEntry {
+            refs: entry.refs + 1,
+            ..entry
+        };
+        db.batch(vec![entry_put(hash, &entry)?])?;
+        Ok(true)
+    }
+
+    /// Drop a reference to the blob, it stays until the next `gc`
+    pub fn release(&self, hash: &Hash) -> Result<()> {
+        let mut db = self.db();
+        let entry = match BlobStore::entry(db.as_ref(), hash)? {
+            Some(entry) => entry,
+            None => return Ok(()),
+        };
+        let entry = BlobEntry {
+            refs: entry.refs.saturating_sub(1),
+            ..entry
+        };
+        db.batch(vec![entry_put(hash, &entry)?])
+    }
+
+    /// The content of the blob, checked against its hash
+    pub fn get(&self, hash: &Hash) -> Result<Option<Vec<u8>>> {
+        let db = self.db();
+        if BlobStore::entry(db.as_ref(), hash)?.is_none() {
+            return Ok(None);
+        }
+        let content = match &self.dir {
+            Some(dir) => {
+                let path = BlobStore::path(dir, hash);
+                fs::read(&path).with_context(|| format!("can not read {}", path.display()))?
+            }
+            None => db
+                .get(&data_key(hash))?
+                .ok_or_else(|| anyhow!("content of blob {} is missing", hash))?,
+        };
+        if Hash::digest(&content) != *hash {
+            bail!("content of blob {} does not match its hash", hash);
+        }
+        Ok(Some(content))
+    }
+
+    /// Every blob with its entry, ordered by hash
+    pub fn list(&self) -> Result<Vec<(Hash, BlobEntry)>> {
+        BlobStore::entries(self.db().as_ref())
+    }
+
+    fn entries(db: &dyn Storage) -> Result<Vec<(Hash, BlobEntry)>> {
+        db.iter(ENTRY_PREFIX)
+            .map(|entry| {
+                let (key, value) = entry?;
+                let hash = key[ENTRY_PREFIX.len()..]
+                    .try_into()
+                    .map(Hash)
+                    .map_err(|_| anyhow!("invalid blob key {}", hex::encode(&key)))?;
+                Ok((hash, decode_entry(&value)?))
+            })
+            .collect()
+    }
+
+    /// Compact the database of the entries, answers the bytes reclaimed
+    pub fn compact(&self) -> Pending<u64> {
+        self.db().compact()
+    }
+
+    pub fn flush(&self) -> Pending<()> {
+        self.db().flush()
+    }
+
+    /// Remove the blobs nothing refers to any more and the files left over from interrupted
+    /// writes
+    pub fn gc(&self) -> Result<Collected> {
+        let mut collected = Collected::default();
+        let mut db = self.db();
+        let unreferenced: Vec<(Hash, BlobEntry)> = BlobStore::entries(db.as_ref())?
+            .into_iter()
+            .filter(|(_, entry)| entry.refs == 0)
+            .collect();
+        let mut batch = Vec::new();
+        for (hash, entry) in unreferenced.iter() {
+            batch.push(Op::Delete {
+                key: entry_key(hash),
+            });
+            if self.dir.is_none() {
+                batch.push(Op::Delete {
+                    key: data_key(hash),
+                });
+            }
+            collected.blobs += 1;
+            collected.bytes += entry.size;
+        }
+        // 先删记录再删文件，中断时留下的文件下次回收
+        db.batch(batch)?;
+        let dir = match &self.dir {
+            Some(dir) => dir,
+            None => return Ok(collected),
+        };
+        for file in fs::read_dir(dir).with_context(|| format!("can not read {}", dir.display()))? {
+            let file = file?;
+            let name = file.file_name();
+            let hash = name.to_str().and_then(|name| name.parse::<Hash>().ok());
+            let stale = match hash {
+                Some(hash) => BlobStore::entry(db.as_ref(), &hash)?.is_none(),
+                None => Path::new(&name).extension().is_some_and(|ext| ext == "tmp"),
+            };
+            if !stale {
+                continue;
+            }
+            let size = file.metadata().map(|m| m.len()).unwrap_or_default();
+            match fs::remove_file(file.path()) {
+                Ok(()) => {
+                    // 有记录的 blob 已计入，这里只计中断写入留下的文件
+                    if !unreferenced.iter().any(|(h, _)| Some(*h) == hash) {
+                        collected.blobs += 1;
+                        collected.bytes += size;
+                    }
+                }
+                Err(e) if e.kind() == ErrorKind::NotFound => {}
+                Err(e) => {
+                    return Err(e).with_context(|| format!("can not remove {:?}", file.path()))
+                }
+            }
+        }
+        Ok(collected)
+    }
+}
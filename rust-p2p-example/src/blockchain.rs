@@ -0,0 +1,1691 @@
+use std::cmp;
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use anyhow::{anyhow, bail, Context, Result};
+use libp2p::identity::Keypair;
+use log::{error, warn};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use sha2::{Digest, Sha256};
+
+use crate::accounts::AccountProof;
+use crate::clock::{now, Clock, SystemClock};
+use crate::consensus::{self, Consensus, ConsensusKind, Validator};
+use crate::consts::{
+    DEFAULT_GAS_PRICE, DEFAULT_HALVING_INTERVAL, DEFAULT_INITIAL_DIFFICULTY,
+    DEFAULT_INITIAL_REWARD, DEFAULT_MAX_BLOCK_GAS, DEFAULT_MAX_BLOCK_SIZE,
+    DEFAULT_MAX_BLOCK_TRANSACTIONS, DEFAULT_MAX_DIFFICULTY, DEFAULT_MIN_DIFFICULTY,
+    DEFAULT_RETARGET_INTERVAL, DEFAULT_SLASH_PERCENT, DEFAULT_TARGET_BLOCK_TIME, MAX_REORG_DEPTH,
+    MEDIAN_TIME_SPAN, PRUNE_INTERVAL,
+};
+use crate::contracts::{Log, LogFilter, Receipt};
+use crate::genesis::{Allocation, Genesis};
+use crate::index::ChainIndex;
+use crate::ledger::{BlockUndo, LedgerKind, LedgerModel, LedgerSnapshot};
+use crate::merkle::{MerkleProof, MerkleTree};
+use crate::snapshot::Snapshot;
+use crate::storage::{self, MemoryStore, Op, Pending, Record, Schema, Storage};
+use crate::transaction::{Address, Transaction};
+use crate::validation::{self, Rules, ValidationError};
+
+/// Consensus parameters, all nodes of a network must agree on them
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ChainParams {
+    /// Seconds the difficulty schedule aims for between two blocks
+    pub target_block_time: u64,
+    /// Blocks between two difficulty adjustments
+    pub retarget_interval: u64,
+    /// Difficulty of the genesis block and the first interval, in leading zero bits
+    pub initial_difficulty: u32,
+    pub min_difficulty: u32,
+    pub max_difficulty: u32,
+    /// Largest block in bytes of its JSON encoding
+    pub max_block_size: usize,
+    /// Most transactions a block may contain, the coinbase included
+    pub max_block_transactions: usize,
+    /// Gas the calls of a block may be given together, so no block takes long to execute
+    pub max_block_gas: u64,
+    /// Coins a call pays for every 1000 gas of its limit, rounded up
+    pub gas_price: u64,
+    pub ledger: LedgerKind,
+    pub consensus: ConsensusKind,
+    /// Coins the coinbase of the first blocks may create besides the fees
+    pub initial_reward: u64,
+    /// Blocks after which the subsidy is halved, 0 keeps it at the initial reward
+    pub halving_interval: u64,
+    /// Percent of its stake burned when a validator is caught signing two blocks of a slot
+    pub slash_percent: u64,
+    /// Whether validators vote on blocks to make them final, final blocks are never reverted
+    pub finality: bool,
+}
+
+impl ChainParams {
+    /// Coins the coinbase of the block at the height may create besides the fees
+    pub fn subsidy(&self, index: u64) -> u64 {
+        let halvings = match self.halving_interval {
+            0 => 0,
+            interval => index / interval,
+        };
+        if halvings >= 64 {
+            return 0;
+        }
+        self.initial_reward >> halvings
+    }
+}
+
+impl Default for ChainParams {
+    fn default() -> Self {
+        ChainParams {
+            target_block_time: DEFAULT_TARGET_BLOCK_TIME,
+            retarget_interval: DEFAULT_RETARGET_INTERVAL,
+            initial_difficulty: DEFAULT_INITIAL_DIFFICULTY,
+            min_difficulty: DEFAULT_MIN_DIFFICULTY,
+            max_difficulty: DEFAULT_MAX_DIFFICULTY,
+            max_block_size: DEFAULT_MAX_BLOCK_SIZE,
+            max_block_transactions: DEFAULT_MAX_BLOCK_TRANSACTIONS,
+            max_block_gas: DEFAULT_MAX_BLOCK_GAS,
+            gas_price: DEFAULT_GAS_PRICE,
+            ledger: LedgerKind::default(),
+            consensus: ConsensusKind::default(),
+            initial_reward: DEFAULT_INITIAL_REWARD,
+            halving_interval: DEFAULT_HALVING_INTERVAL,
+            slash_percent: DEFAULT_SLASH_PERCENT,
+            finality: false,
+        }
+    }
+}
+
+/// SHA-256 digest, shown and serialized as hex
+#[derive(Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Hash(pub [u8; 32]);
+
+impl Hash {
+    pub fn digest(data: &[u8]) -> Hash {
+        Hash(Sha256::digest(data).into())
+    }
+
+    /// Proof of work of the hash, the more zero bits it starts with the harder it was to find
+    pub fn leading_zero_bits(&self) -> u32 {
+        let mut bits = 0;
+        for byte in self.0.iter() {
+            bits += byte.leading_zeros();
+            if *byte != 0 {
+                break;
+            }
+        }
+        bits
+    }
+}
+
+impl fmt::Display for Hash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&hex::encode(self.0))
+    }
+}
+
+impl fmt::Debug for Hash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Hash({})", self)
+    }
+}
+
+impl FromStr for Hash {
+    type Err = hex::FromHexError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut bytes = [0u8; 32];
+        hex::decode_to_slice(s, &mut bytes)?;
+        Ok(Hash(bytes))
+    }
+}
+
+impl Serialize for Hash {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+impl<'de> Deserialize<'de> for Hash {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// The part of a block the hash is computed over, it commits to the payload and the
+/// transactions through their digests so it can be checked without them
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BlockHeader {
+    /// Height of the block, the genesis block is 0
+    pub index: u64,
+    /// Unix time in seconds the block was created at
+    pub timestamp: u64,
+    pub prev_hash: Hash,
+    /// Leading zero bits the hash must have, set by the retarget schedule
+    pub difficulty: u32,
+    pub nonce: u64,
+    /// Root of the Merkle tree over the transaction ids
+    pub merkle_root: Hash,
+    pub data_hash: Hash,
+    /// Root of the ledger state after the block, zero for the genesis block
+    #[serde(default)]
+    pub state_root: Hash,
+    /// Root of the Merkle tree over the receipts of the transactions, zero for the genesis block
+    #[serde(default)]
+    pub receipts_root: Hash,
+    /// Validator that proposed the block, proof-of-stake blocks only
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub proposer: Option<Address>,
+    /// Signature of the proposer over the hash of the header, which does not cover it
+    #[serde(default, with = "hex", skip_serializing_if = "Vec::is_empty")]
+    pub signature: Vec<u8>,
+}
+
+impl BlockHeader {
+    /// SHA-256 over the fields in a fixed order
+    pub fn hash(&self) -> Hash {
+        let mut bytes = Vec::with_capacity(188);
+        bytes.extend_from_slice(&self.index.to_be_bytes());
+        bytes.extend_from_slice(&self.timestamp.to_be_bytes());
+        bytes.extend_from_slice(&self.prev_hash.0);
+        bytes.extend_from_slice(&self.difficulty.to_be_bytes());
+        bytes.extend_from_slice(&self.nonce.to_be_bytes());
+        bytes.extend_from_slice(&self.merkle_root.0);
+        bytes.extend_from_slice(&self.data_hash.0);
+        bytes.extend_from_slice(&self.state_root.0);
+        bytes.extend_from_slice(&self.receipts_root.0);
+        if let Some(proposer) = &self.proposer {
+            bytes.extend_from_slice(&proposer.0);
+        }
+        Hash::digest(&bytes)
+    }
+
+    /// Hashes it takes on average to find a block of the difficulty
+    pub fn work(&self) -> u128 {
+        1 << cmp::min(self.difficulty, 127)
+    }
+}
+
+/// A block of the chain, serializable so it can be gossiped like any other message
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Block {
+    #[serde(flatten)]
+    pub header: BlockHeader,
+    /// Hash of the header
+    pub hash: Hash,
+    /// Application payload, e.g. recipes anchored on the chain
+    pub data: String,
+    /// Transfers confirmed by the block, applied in order
+    #[serde(default)]
+    pub transactions: Vec<Transaction>,
+}
+
+impl Block {
+    /// A block on top of `prev_hash` created now
+    pub fn new(
+        index: u64,
+        prev_hash: Hash,
+        difficulty: u32,
+        data: String,
+        transactions: Vec<Transaction>,
+    ) -> Block {
+        let header = BlockHeader {
+            index,
+            timestamp: now(),
+            prev_hash,
+            difficulty,
+            nonce: 0,
+            merkle_root: merkle_root(&transactions),
+            data_hash: Hash::digest(data.as_bytes()),
+            state_root: Hash::default(),
+            receipts_root: Hash::default(),
+            proposer: None,
+            signature: Vec::new(),
+        };
+        Block {
+            hash: header.hash(),
+            header,
+            data,
+            transactions,
+        }
+    }
+
+    pub fn compute_hash(&self) -> Hash {
+        self.header.hash()
+    }
+
+    /// Encoded size in bytes, the size limit of blocks applies to it
+    pub fn size(&self) -> usize {
+        serde_json::to_vec(self).map_or(usize::MAX, |json| json.len())
+    }
+}
+
+/// Root of the Merkle tree over the ids of the transactions
+pub fn merkle_root(transactions: &[Transaction]) -> Hash {
+    MerkleTree::new(transactions.iter().map(Transaction::id).collect()).root()
+}
+
+/// Root of the receipts the ledger recorded for the transactions of the block, the last block it
+/// connected
+fn receipts_root(ledger: &dyn LedgerModel, block: &Block) -> Hash {
+    let leaves = block
+        .transactions
+        .iter()
+        .map(|tx| {
+            ledger
+                .receipt(&tx.id())
+                .map_or(Hash::default(), Receipt::hash)
+        })
+        .collect();
+    MerkleTree::new(leaves).root()
+}
+
+/// Block the operator trusts to be on the chain, e.g. taken from a block explorer, written as
+/// `<height>:<hash>`
+///
+/// Nodes joining long after genesis can not tell the real history from a rewritten one by the
+/// chain alone, a checkpoint settles it without trusting the peers
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Checkpoint {
+    pub height: u64,
+    pub hash: Hash,
+}
+
+impl FromStr for Checkpoint {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (height, hash) = match s.split_once(':') {
+            Some(parts) => parts,
+            None => bail!("expected <height>:<hash>"),
+        };
+        Ok(Checkpoint {
+            height: height.parse().context("invalid height")?,
+            hash: hash.parse().context("invalid hash")?,
+        })
+    }
+}
+
+/// Blocks a reorganization took off the chain
+#[derive(Debug)]
+pub struct Reorg {
+    /// Height of the last block both branches share
+    pub fork: u64,
+    pub old_tip: Hash,
+    /// Blocks of the old branch, oldest first
+    pub disconnected: Vec<Block>,
+}
+
+impl Reorg {
+    /// Blocks taken off the chain
+    pub fn depth(&self) -> u64 {
+        self.disconnected.len() as u64
+    }
+}
+
+/// Blocks from the genesis block up to the tip
+#[derive(Debug)]
+pub struct Chain {
+    blocks: Vec<Block>,
+    /// Work of the blocks up to each height, so fork choice does not add it up again
+    work: Vec<u128>,
+    chain_id: String,
+    params: ChainParams,
+    allocations: Vec<Allocation>,
+    /// Who owns which coins at the tip
+    ledger: Box<dyn LedgerModel>,
+    /// How to disconnect each block after the genesis block that is not pruned
+    undo: VecDeque<BlockUndo>,
+    /// Who may seal blocks and how
+    consensus: Box<dyn Consensus>,
+    /// Stake bonded in the genesis block, further stake is bonded by transactions
+    genesis_validators: Vec<Validator>,
+    /// Height of the last final block, finalized by votes, a checkpoint or pruning, blocks up to it
+    /// are never taken off
+    finalized: u64,
+    /// Trusted blocks by height, blocks at these heights must match them
+    checkpoints: BTreeMap<u64, Hash>,
+    /// Bodies kept behind the tip, none keeps every block
+    keep_bodies: Option<u64>,
+    /// Height up to which the bodies of the blocks were discarded, 0 when none were
+    pruned: u64,
+    /// Whether the records of the last prune are still being encoded, so two prunes do not race
+    /// to store their ledgers
+    pruning: bool,
+    /// Time new blocks are stamped with and checked against
+    clock: Arc<dyn Clock>,
+    /// Where the transactions of the blocks are, rebuilt by `reindex`
+    index: ChainIndex,
+    /// Where the blocks after the genesis block are kept, one record per height
+    store: Box<dyn Storage>,
+}
+
+/// Blocks are kept one record per height, as they were before versions were recorded, the tip
+/// record is written again when the blocks are loaded
+const CHAIN_SCHEMA: Schema = Schema {
+    name: "blocks",
+    migrations: &[storage::unversioned],
+};
+
+/// Key of the genesis hash of the stored blocks
+const GENESIS_KEY: &[u8] = b"chain/genesis";
+
+/// Key of the hash of the last stored block, written in the batch of every block
+const TIP_KEY: &[u8] = b"chain/tip";
+
+/// Key of the ledger the stored blocks continue from, written with the headers before it when a
+/// snapshot is imported or bodies are pruned
+const BASE_KEY: &[u8] = b"chain/base";
+
+/// Key prefix of the stored blocks, followed by the height in big endian
+pub const BLOCK_PREFIX: &[u8] = b"block/";
+
+/// The ledger after the block at the height, the blocks up to it are stored without their bodies
+#[derive(Debug, Serialize, Deserialize)]
+struct Base {
+    height: u64,
+    ledger: LedgerSnapshot,
+}
+
+fn block_key(height: u64) -> Vec<u8> {
+    [BLOCK_PREFIX, &height.to_be_bytes()[..]].concat()
+}
+
+fn block_put(block: &Block) -> Result<Op> {
+    let mut value = Vec::new();
+    ciborium::into_writer(block, &mut value)?;
+    Ok(Op::Put {
+        key: block_key(block.header.index),
+        value,
+    })
+}
+
+fn base_put(height: u64, ledger: &dyn LedgerModel) -> Result<Op> {
+    let base = Base {
+        height,
+        ledger: ledger.snapshot(),
+    };
+    let mut value = Vec::new();
+    ciborium::into_writer(&base, &mut value)?;
+    Ok(Op::Put {
+        key: BASE_KEY.to_vec(),
+        value,
+    })
+}
+
+/// The block of the header without its body, as imported and pruned blocks are kept
+fn header_only(header: BlockHeader) -> Block {
+    Block {
+        hash: header.hash(),
+        header,
+        data: String::new(),
+        transactions: Vec::new(),
+    }
+}
+
+fn tip_record(tip: &Block) -> Op {
+    Op::Put {
+        key: TIP_KEY.to_vec(),
+        value: tip.hash.0.to_vec(),
+    }
+}
+
+impl Default for Chain {
+    fn default() -> Self {
+        Chain::from_genesis(&Genesis::default())
+    }
+}
+
+impl Chain {
+    pub fn from_genesis(genesis: &Genesis) -> Chain {
+        let block = genesis.block();
+        Chain {
+            ledger: genesis.params.ledger.genesis_state(
+                block.hash,
+                &genesis.allocations,
+                &genesis.validators,
+                genesis.params.slash_percent,
+                genesis.params.gas_price,
+            ),
+            work: vec![block.header.work()],
+            blocks: vec![block],
+            chain_id: genesis.chain_id.clone(),
+            params: genesis.params.clone(),
+            allocations: genesis.allocations.clone(),
+            undo: VecDeque::new(),
+            consensus: genesis
+                .params
+                .consensus
+                .engine(genesis.timestamp, genesis.params.target_block_time),
+            genesis_validators: genesis.validators.clone(),
+            finalized: 0,
+            checkpoints: BTreeMap::new(),
+            keep_bodies: None,
+            pruned: 0,
+            pruning: false,
+            clock: Arc::new(SystemClock::default()),
+            index: ChainIndex::default(),
+            store: Box::new(MemoryStore::default()),
+        }
+    }
+
+    pub fn consensus(&self) -> ConsensusKind {
+        self.consensus.kind()
+    }
+
+    /// Take the time from the clock instead of the system clock
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.clock = clock;
+    }
+
+    /// Keep the blocks in the storage from now on, after adding the blocks it holds from an
+    /// earlier run, returns how many were added
+    ///
+    /// Blocks up to the stored ledger of an imported snapshot or of pruning are stored as headers
+    /// and are final once loaded, the blocks after it are replayed. A stored block after it that
+    /// is invalid or can not be read ends the chain there and is dropped with the blocks after it
+    pub fn set_storage(&mut self, mut store: Box<dyn Storage>) -> Result<u64> {
+        if self.height() > 0 {
+            bail!("the chain has blocks already");
+        }
+        storage::migrate(store.as_mut(), &CHAIN_SCHEMA)?;
+        let genesis = self.genesis_hash();
+        match store.get(GENESIS_KEY)? {
+            Some(stored) if stored != genesis.0 => bail!(
+                "the stored blocks follow genesis {}, not {}",
+                hex::encode(stored),
+                genesis
+            ),
+            Some(_) => {}
+            None => store.put(GENESIS_KEY.to_vec(), genesis.0.to_vec())?,
+        }
+        let mut height = 1;
+        if let Some(bytes) = store.get(BASE_KEY)? {
+            let base: Base = ciborium::from_reader(bytes.as_slice())
+                .map_err(|e| anyhow!("the stored ledger can not be read: {}", e))?;
+            let mut headers = Vec::new();
+            for height in 1..=base.height {
+                let bytes = store
+                    .get(&block_key(height))?
+                    .ok_or_else(|| anyhow!("stored header {} is missing", height))?;
+                let block: Block = ciborium::from_reader(bytes.as_slice())
+                    .with_context(|| format!("stored header {} can not be read", height))?;
+                headers.push(block.header);
+            }
+            let ledger = base.ledger.into_ledger();
+            self.check_base(&headers, ledger.as_ref())?;
+            self.start_from(headers, ledger);
+            height = base.height + 1;
+        }
+        while let Some(bytes) = store.get(&block_key(height))? {
+            let added = ciborium::from_reader::<Block, _>(bytes.as_slice())
+                .map_err(anyhow::Error::from)
+                .and_then(|block| self.try_add_block(block).map_err(anyhow::Error::from));
+            if let Err(e) = added {
+                warn!("dropping stored blocks from {}: {}", height, e);
+                break;
+            }
+            height += 1;
+        }
+        let mut batch = Vec::new();
+        for entry in store.iter(BLOCK_PREFIX) {
+            let (key, _) = entry?;
+            if key >= block_key(height) {
+                batch.push(Op::Delete { key });
+            }
+        }
+        batch.push(tip_record(self.tip()));
+        store.batch(batch)?;
+        self.store = store;
+        Ok(height - 1)
+    }
+
+    /// Records of the block storage, the blocks themselves are kept decoded by the chain
+    pub fn storage_records(&self) -> Result<usize> {
+        Ok(self.store.iter(b"").collect::<Result<Vec<_>>>()?.len())
+    }
+
+    /// Compact the block storage, answers the bytes reclaimed
+    pub fn compact_storage(&mut self) -> Pending<u64> {
+        self.store.compact()
+    }
+
+    /// Answered once every stored block is written
+    pub fn flush_storage(&self) -> Pending<()> {
+        self.store.flush()
+    }
+
+    /// Copy of the stored records, see `set_storage`
+    pub fn storage_snapshot(&self) -> Result<Vec<Record>> {
+        storage::snapshot(self.store.as_ref())
+    }
+
+    /// Start over from the stored records of a backup of our chain, returns the height reached
+    ///
+    /// The records are loaded like stored blocks at startup before they replace ours, so the
+    /// chain and the storage are left as they were when the backup is of another chain or of a
+    /// newer schema. Finality votes are not part of the backup
+    pub fn restore_storage(&mut self, records: Vec<Record>) -> Result<u64> {
+        let mut staged = MemoryStore::default();
+        staged.batch(storage::replacement(&staged, records)?)?;
+        if staged.get(GENESIS_KEY)?.as_deref() != Some(&self.genesis_hash().0[..]) {
+            bail!(
+                "the backup holds no blocks following genesis {}",
+                self.genesis_hash()
+            );
+        }
+        let mut restored = self.empty();
+        restored.keep_bodies = self.keep_bodies;
+        let height = restored.set_storage(Box::new(staged))?;
+        let batch = storage::replacement(self.store.as_ref(), restored.storage_snapshot()?)?;
+        self.store.batch(batch)?;
+        restored.store = std::mem::replace(&mut self.store, Box::new(MemoryStore::default()));
+        *self = restored;
+        Ok(height)
+    }
+
+    /// Check the storage and that it holds our blocks from the genesis block up to the tip and no
+    /// other, returns how many blocks were checked and the check of the storage itself, which is
+    /// answered once the writes before it are done
+    pub fn verify_storage(&self) -> Result<(u64, Pending<()>)> {
+        if self.store.get(GENESIS_KEY)?.as_deref() != Some(&self.genesis_hash().0[..]) {
+            bail!("the stored genesis hash is not {}", self.genesis_hash());
+        }
+        if self.store.get(TIP_KEY)?.as_deref() != Some(&self.tip().hash.0[..]) {
+            bail!("the stored tip is not block {}", self.tip().hash);
+        }
+        let mut height = 0;
+        for entry in self.store.iter(BLOCK_PREFIX) {
+            let (key, bytes) = entry?;
+            height += 1;
+            if key != block_key(height) {
+                bail!("stored block {} is missing", height);
+            }
+            let block: Block = ciborium::from_reader(bytes.as_slice())
+                .with_context(|| format!("stored block {} can not be read", height))?;
+            let ours = match self.block(height) {
+                Some(ours) => ours,
+                None => bail!("block {} is stored beyond the tip", height),
+            };
+            if block.hash != block.compute_hash() || block.hash != ours.hash {
+                bail!("stored block {} is not block {}", height, ours.hash);
+            }
+        }
+        if height != self.height() {
+            bail!("blocks after {} are not stored", height);
+        }
+        Ok((height, self.store.verify()))
+    }
+
+    /// Blocks the chain must contain, checked from now on
+    pub fn set_checkpoints(&mut self, checkpoints: &[Checkpoint]) {
+        self.checkpoints = checkpoints
+            .iter()
+            .map(|checkpoint| (checkpoint.height, checkpoint.hash))
+            .collect();
+    }
+
+    /// Discard the bodies of blocks more than `keep` blocks behind the tip on every `prune`,
+    /// headers and the ledger are always kept
+    pub fn set_pruning(&mut self, keep: u64) {
+        self.keep_bodies = Some(keep);
+    }
+
+    /// Height up to which block bodies were discarded, 0 when none were
+    pub fn pruned_height(&self) -> u64 {
+        self.pruned
+    }
+
+    /// Whether the body of the block at the height was discarded, the genesis block is kept
+    pub fn is_pruned(&self, height: u64) -> bool {
+        height > 0 && height <= self.pruned
+    }
+
+    /// Discard the bodies that are old enough and can not be taken off any more, with finality
+    /// only bodies of final blocks, otherwise bodies deeper than a reorganization may reach or up
+    /// to a checkpoint
+    ///
+    /// Bodies go `PRUNE_INTERVAL` blocks at a time, their records are rewritten as headers in
+    /// the batch that stores the ledger after them, so a restarted node continues from there.
+    /// The bodies are dropped at once, the returned work encodes the batch off the event loop
+    /// and `store_pruned` stores it, none is returned while the last batch is not stored yet
+    pub fn prune(&mut self) -> Option<Prune> {
+        let keep = self.keep_bodies?;
+        if self.pruning {
+            return None;
+        }
+        let height = self.height();
+        let settled = if self.params.finality {
+            self.finalized
+        } else {
+            cmp::max(self.finalized, height.saturating_sub(MAX_REORG_DEPTH))
+        };
+        let target = cmp::min(height.saturating_sub(keep), settled);
+        // 每次裁剪都要写入裁剪处的账本，攒够一批再裁剪
+        if target < self.pruned + PRUNE_INTERVAL {
+            return None;
+        }
+        // 裁剪处的账本从链尖的账本撤销之后的区块得到，放到阻塞线程上算
+        let unwound = (target + 1..=height)
+            .map(|index| {
+                let undo = self.undo[(index - self.pruned - 1) as usize].clone();
+                (self.blocks[index as usize].clone(), undo)
+            })
+            .collect();
+        let mut headers = Vec::new();
+        while self.pruned < target {
+            self.pruned += 1;
+            let block = &mut self.blocks[self.pruned as usize];
+            block.transactions = Vec::new();
+            block.data = String::new();
+            self.undo.pop_front();
+            headers.push(block.clone());
+        }
+        // 区块体已经丢弃的区块无法再撤销
+        self.finalized = cmp::max(self.finalized, self.pruned);
+        self.pruning = true;
+        Some(Prune {
+            target,
+            hash: self.blocks[target as usize].hash,
+            headers,
+            tip: self.ledger.snapshot(),
+            unwound,
+        })
+    }
+
+    /// Store the batch of a finished `prune`, a batch of a chain that was restored or imported
+    /// since is dropped
+    pub fn store_pruned(&mut self, pruned: Result<Pruned>) {
+        self.pruning = false;
+        let pruned = match pruned {
+            Ok(pruned) => pruned,
+            // 写入失败时存储里仍是完整的区块和上一次的账本，重启后照样能重放
+            Err(e) => {
+                error!("can not store the pruned blocks: {:#}", e);
+                return;
+            }
+        };
+        // 导入快照或恢复备份后裁剪处变了，旧的账本不能盖过新的
+        if self.pruned != pruned.target
+            || self.block(pruned.target).map(|block| block.hash) != Some(pruned.hash)
+        {
+            warn!(
+                "dropping the blocks pruned up to {}, the chain changed",
+                pruned.target
+            );
+            return;
+        }
+        if let Err(e) = self.store.batch(pruned.batch) {
+            error!(
+                "can not store the blocks pruned up to {}: {}",
+                pruned.target, e
+            );
+        }
+    }
+
+    /// The checkpoint with the greatest height
+    pub fn last_checkpoint(&self) -> Option<Checkpoint> {
+        self.checkpoints
+            .iter()
+            .next_back()
+            .map(|(height, hash)| Checkpoint {
+                height: *height,
+                hash: *hash,
+            })
+    }
+
+    /// Whether there is a checkpoint at the height
+    pub fn is_checkpoint(&self, height: u64) -> bool {
+        self.checkpoints.contains_key(&height)
+    }
+
+    fn check_checkpoint(&self, height: u64, hash: Hash) -> Result<(), ValidationError> {
+        match self.checkpoints.get(&height) {
+            Some(expected) if *expected != hash => Err(ValidationError::CheckpointMismatch {
+                height,
+                expected: *expected,
+                actual: hash,
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Consensus parameters of the network, from the genesis file
+    pub fn params(&self) -> &ChainParams {
+        &self.params
+    }
+
+    /// Whether the validators vote to make blocks final
+    pub fn finality(&self) -> bool {
+        self.params.finality
+    }
+
+    pub fn finalized_height(&self) -> u64 {
+        self.finalized
+    }
+
+    /// Make the block at the height final if it is the one of our chain, returns whether the
+    /// finalized height moved
+    pub fn finalize(&mut self, height: u64, hash: &Hash) -> bool {
+        if height <= self.finalized || self.block(height).map(|block| block.hash) != Some(*hash) {
+            return false;
+        }
+        self.finalized = height;
+        true
+    }
+
+    /// Slot of the unix time, none when the engine has no slots
+    pub fn slot(&self, timestamp: u64) -> Option<u64> {
+        self.consensus.slot(timestamp)
+    }
+
+    /// Whether the equivocation evidence of the transaction holds, checked before it is pooled
+    pub fn check_evidence(&self, tx: &Transaction) -> Result<(), ValidationError> {
+        validation::check_evidence(tx, self.consensus.as_ref())
+    }
+
+    /// Validators with stake at the tip, ordered by address
+    pub fn validators(&self) -> Vec<Validator> {
+        self.ledger.validators()
+    }
+
+    pub fn chain_id(&self) -> &str {
+        &self.chain_id
+    }
+
+    /// Identifies the network, blocks of chains with another genesis hash are never accepted
+    pub fn genesis_hash(&self) -> Hash {
+        self.blocks[0].hash
+    }
+
+    /// The most recent block
+    pub fn tip(&self) -> &Block {
+        self.blocks.last().expect("chain has a genesis block")
+    }
+
+    pub fn height(&self) -> u64 {
+        self.tip().header.index
+    }
+
+    pub fn ledger(&self) -> &dyn LedgerModel {
+        self.ledger.as_ref()
+    }
+
+    /// The block at the height, none above the tip
+    pub fn block(&self, index: u64) -> Option<&Block> {
+        self.blocks.get(index as usize)
+    }
+
+    /// Work of the blocks up to the height, forks are resolved in favour of the most work
+    pub fn work_until(&self, index: u64) -> u128 {
+        self.work[cmp::min(index, self.height()) as usize]
+    }
+
+    pub fn work(&self) -> u128 {
+        self.work_until(self.height())
+    }
+
+    /// Hashes of the tip and blocks ever further below it down to the genesis block, a peer
+    /// finds the last block we share with it among them
+    pub fn locator(&self) -> Vec<Hash> {
+        let mut locator = Vec::new();
+        let mut index = self.height();
+        let mut step = 1;
+        while index > 0 {
+            locator.push(self.blocks[index as usize].hash);
+            // 前十个区块逐个列出，之后步长翻倍
+            if locator.len() >= 10 {
+                step *= 2;
+            }
+            index = index.saturating_sub(step);
+        }
+        locator.push(self.genesis_hash());
+        locator
+    }
+
+    /// Headers after the first locator block that is on our chain, none when no block is
+    pub fn headers_after(&self, locator: &[Hash], count: usize) -> Vec<BlockHeader> {
+        let start = locator
+            .iter()
+            .find_map(|hash| self.blocks.iter().rposition(|block| &block.hash == hash));
+        match start {
+            Some(start) => self
+                .blocks
+                .iter()
+                .skip(start + 1)
+                .take(count)
+                .map(|block| block.header.clone())
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Coins the coinbase of the block on top of the tip may create besides the fees
+    pub fn next_subsidy(&self) -> u64 {
+        self.params.subsidy(self.height() + 1)
+    }
+
+    /// Sign the block as its proposer, fails unless the key is entitled to the slot of the block
+    pub fn propose(&self, mut block: Block, keys: &Keypair) -> Result<Block, ValidationError> {
+        consensus::sign_header(&mut block.header, keys)
+            .map_err(|_| ValidationError::InvalidProposerSignature)?;
+        block.hash = block.compute_hash();
+        self.consensus.check_seal(
+            &block.header,
+            &self.tip().header,
+            block.header.difficulty,
+            &self.validators(),
+        )?;
+        Ok(block)
+    }
+
+    /// Unmined block on top of the tip, to be completed by the miner
+    ///
+    /// The state and receipts roots are computed by applying the transactions to a copy of the
+    /// ledger, they stay zero when the transactions do not apply
+    pub fn next_block(&self, data: String, transactions: Vec<Transaction>) -> Block {
+        let tip = self.tip();
+        let difficulty = self.next_difficulty();
+        let mut block = Block::new(
+            tip.header.index + 1,
+            tip.hash,
+            difficulty,
+            data,
+            transactions,
+        );
+        // 时钟落后于最近区块的中位时间时，用刚好合法的最早时间戳
+        block.header.timestamp = cmp::max(self.clock.now(), self.median_time_past() + 1);
+        let mut ledger = self.ledger.snapshot().into_ledger();
+        if ledger.connect_block(&block).is_ok() {
+            block.header.state_root = ledger.state_root();
+            block.header.receipts_root = receipts_root(ledger.as_ref(), &block);
+        }
+        block.hash = block.compute_hash();
+        block
+    }
+
+    /// Difficulty the block on top of the tip must have
+    ///
+    /// Every `retarget_interval` blocks the time the last interval took is compared with the
+    /// target, the difficulty goes up a bit when blocks came more than twice as fast and down a
+    /// bit when they came more than twice as slow
+    pub fn next_difficulty(&self) -> u32 {
+        self.difficulty_after(self.height(), &[])
+    }
+
+    /// Difficulty the block after `pending` must have, `pending` being headers that follow the
+    /// block at `fork` in order
+    pub fn difficulty_after(&self, fork: u64, pending: &[BlockHeader]) -> u32 {
+        // 权益证明的区块不需要工作量，难度为 0，分叉选择就是最长链
+        if !self.consensus.needs_work() {
+            return 0;
+        }
+        let header_at = |index: u64| match index.checked_sub(fork + 1) {
+            Some(offset) => &pending[offset as usize],
+            None => &self.blocks[index as usize].header,
+        };
+        let tip = pending.last().unwrap_or(&self.blocks[fork as usize].header);
+        let interval = self.params.retarget_interval.max(1);
+        let next = tip.index + 1;
+        if next % interval != 0 || next < interval {
+            return tip.difficulty;
+        }
+        // 创世块的时间戳与挖矿开始的时间无关，不参与计算
+        let first = header_at(cmp::max(next - interval, 1));
+        let expected = (tip.index - first.index) * self.params.target_block_time;
+        if expected == 0 {
+            return tip.difficulty;
+        }
+        let actual = tip.timestamp.saturating_sub(first.timestamp);
+        let difficulty = if actual < expected / 2 {
+            tip.difficulty + 1
+        } else if actual > expected.saturating_mul(2) {
+            tip.difficulty.saturating_sub(1)
+        } else {
+            tip.difficulty
+        };
+        difficulty.clamp(self.params.min_difficulty, self.params.max_difficulty)
+    }
+
+    /// Median timestamp of the last `MEDIAN_TIME_SPAN` blocks, the block on top of the tip must be
+    /// stamped later
+    pub fn median_time_past(&self) -> u64 {
+        self.median_time_past_after(self.height(), &[])
+    }
+
+    /// Median timestamp of the last `MEDIAN_TIME_SPAN` headers up to the last of `pending`,
+    /// `pending` being headers that follow the block at `fork` in order
+    pub fn median_time_past_after(&self, fork: u64, pending: &[BlockHeader]) -> u64 {
+        let mut timestamps: Vec<u64> = pending
+            .iter()
+            .rev()
+            .map(|header| header.timestamp)
+            .chain(
+                self.blocks[..=fork as usize]
+                    .iter()
+                    .rev()
+                    .map(|block| block.header.timestamp),
+            )
+            .take(MEDIAN_TIME_SPAN)
+            .collect();
+        timestamps.sort_unstable();
+        timestamps[timestamps.len() / 2]
+    }
+
+    /// Check a header on top of `pending`, headers that follow a block of the chain in order, so
+    /// a branch can be followed before its blocks are downloaded
+    pub fn check_header(
+        &self,
+        pending: &[BlockHeader],
+        header: &BlockHeader,
+    ) -> Result<(), ValidationError> {
+        let first = pending.first().unwrap_or(header);
+        let fork = match first
+            .index
+            .checked_sub(1)
+            .and_then(|index| self.block(index))
+        {
+            Some(block) => block,
+            None => {
+                return Err(ValidationError::UnknownParent {
+                    prev_hash: first.prev_hash,
+                    tip: self.tip().hash,
+                })
+            }
+        };
+        let (parent, parent_hash) = match pending.last() {
+            Some(parent) => (parent, parent.hash()),
+            None => (&fork.header, fork.hash),
+        };
+        // 分支上的质押变化在下载区块体之前无从得知，按链尖的验证者检查
+        let validators = self.validators();
+        let rules = Rules {
+            consensus: self.consensus.as_ref(),
+            difficulty: self.difficulty_after(fork.header.index, pending),
+            validators: &validators,
+            subsidy: 0,
+            params: &self.params,
+            median_time_past: self.median_time_past_after(fork.header.index, pending),
+            now: self.clock.now(),
+            trusted: false,
+        };
+        self.check_checkpoint(header.index, header.hash())?;
+        validation::check_header(header, parent, &parent_hash, &rules)
+    }
+
+    /// Blocks from the genesis block up to the tip
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = &Block> {
+        self.blocks.iter()
+    }
+
+    pub fn find_block(&self, hash: &Hash) -> Option<&Block> {
+        self.blocks.iter().find(|block| &block.hash == hash)
+    }
+
+    /// A confirmed transaction with the block that contains it
+    pub fn find_tx(&self, txid: &Hash) -> Option<(&Block, &Transaction)> {
+        let block = self.block(self.index.tx_height(txid)?)?;
+        let tx = block.transactions.iter().find(|tx| &tx.id() == txid)?;
+        Some((block, tx))
+    }
+
+    /// Confirmed transactions sent or received by the address with their blocks, oldest first,
+    /// those in blocks with pruned bodies are left out
+    pub fn history(&self, address: &Address) -> Vec<(&Block, &Transaction)> {
+        self.index
+            .address_txs(address)
+            .iter()
+            .filter_map(|txid| self.find_tx(txid))
+            .collect()
+    }
+
+    /// The `count` addresses with the highest balances, read from the ledger so holders whose
+    /// blocks were pruned or came in a snapshot are counted too
+    pub fn richlist(&self, count: usize) -> Vec<(Address, u64)> {
+        let mut balances = self.ledger.balances();
+        balances.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        balances.truncate(count);
+        balances
+    }
+
+    /// Merkle branch of a confirmed transaction, none when no block of the chain contains it
+    pub fn prove_tx(&self, txid: &Hash) -> Option<MerkleProof> {
+        let block = self.block(self.index.tx_height(txid)?)?;
+        let leaves: Vec<Hash> = block.transactions.iter().map(Transaction::id).collect();
+        let index = leaves.iter().position(|id| id == txid)?;
+        let branch = MerkleTree::new(leaves).branch(index)?;
+        Some(MerkleProof {
+            tx: *txid,
+            block: block.hash,
+            branch,
+        })
+    }
+
+    /// Events of the confirmed contract calls the filter matches, oldest first, blocks with
+    /// pruned bodies are skipped
+    pub fn logs(&self, filter: &LogFilter) -> Vec<Log> {
+        let to = filter
+            .to
+            .map_or(self.height(), |to| cmp::min(to, self.height()));
+        let mut logs = Vec::new();
+        for block in (filter.from..=to).filter_map(|index| self.block(index)) {
+            for tx in block.transactions.iter() {
+                let txid = tx.id();
+                let receipt = match self.ledger.receipt(&txid) {
+                    Some(receipt) => receipt,
+                    None => continue,
+                };
+                let contract = match receipt.contract {
+                    Some(contract) => contract,
+                    None => continue,
+                };
+                logs.extend(
+                    receipt
+                        .events
+                        .iter()
+                        .filter(|event| filter.matches(&contract, event))
+                        .map(|event| Log {
+                            height: block.header.index,
+                            txid,
+                            contract,
+                            data: event.clone(),
+                        }),
+                );
+            }
+        }
+        logs
+    }
+
+    /// Proof of the account of the address at the tip, none for ledgers without accounts and
+    /// before the first block, the genesis header commits to no state
+    pub fn prove_account(&self, address: &Address) -> Option<AccountProof> {
+        if self.height() == 0 {
+            return None;
+        }
+        Some(AccountProof {
+            address: *address,
+            block: self.tip().hash,
+            roots: self.ledger.state_roots(),
+            nodes: self.ledger.prove_account(address)?,
+        })
+    }
+
+    /// Append the block if it extends the tip and follows every consensus rule
+    pub fn try_add_block(&mut self, block: Block) -> Result<(), ValidationError> {
+        self.add_block(block, false)
+    }
+
+    /// Append a block whose header leads to a checkpoint, its seal was checked with the header
+    /// and its signatures are vouched for by the checkpoint, so only its body and its
+    /// transactions against the ledger are checked
+    pub fn add_checkpointed_block(&mut self, block: Block) -> Result<(), ValidationError> {
+        self.add_block(block, true)
+    }
+
+    fn add_block(&mut self, block: Block, trusted: bool) -> Result<(), ValidationError> {
+        self.check_checkpoint(block.header.index, block.hash)?;
+        let validators = self.validators();
+        let rules = Rules {
+            consensus: self.consensus.as_ref(),
+            difficulty: self.next_difficulty(),
+            validators: &validators,
+            subsidy: self.next_subsidy(),
+            params: &self.params,
+            median_time_past: self.median_time_past(),
+            now: self.clock.now(),
+            trusted,
+        };
+        validation::check_block(&block, self.tip(), &rules)?;
+        let undo = self
+            .ledger
+            .connect_block(&block)
+            .map_err(ValidationError::Ledger)?;
+        // 状态根不一致说明节点之间的状态出现分歧，在出问题的第一个区块就能发现
+        let computed = self.ledger.state_root();
+        if block.header.state_root != computed {
+            self.ledger.disconnect_block(&block, undo);
+            return Err(ValidationError::StateRootMismatch {
+                header: block.header.state_root,
+                computed,
+            });
+        }
+        let computed = receipts_root(self.ledger.as_ref(), &block);
+        if block.header.receipts_root != computed {
+            self.ledger.disconnect_block(&block, undo);
+            return Err(ValidationError::ReceiptsRootMismatch {
+                header: block.header.receipts_root,
+                computed,
+            });
+        }
+        // 检查点之前的区块不会再被撤销
+        if self.is_checkpoint(block.header.index) {
+            self.finalized = cmp::max(self.finalized, block.header.index);
+        }
+        self.index.connect(&block);
+        if let Err(e) =
+            block_put(&block).and_then(|put| self.store.batch(vec![put, tip_record(&block)]))
+        {
+            // 区块已经通过验证，存储失败只影响重启后的恢复
+            error!("can not store block {}: {}", block.header.index, e);
+        }
+        self.work.push(self.work() + block.header.work());
+        self.blocks.push(block);
+        self.undo.push_back(undo);
+        Ok(())
+    }
+
+    /// Replace the blocks after the one at `fork` with the branch when it has more work, the
+    /// chain is left as it was when a block of the branch is invalid
+    pub fn reorganize(&mut self, fork: u64, branch: Vec<Block>) -> Result<Reorg, ValidationError> {
+        if fork < self.finalized {
+            return Err(ValidationError::RevertsFinalized {
+                fork,
+                finalized: self.finalized,
+            });
+        }
+        let depth = self.height().saturating_sub(fork);
+        if depth > MAX_REORG_DEPTH {
+            return Err(ValidationError::ReorgTooDeep {
+                depth,
+                max: MAX_REORG_DEPTH,
+            });
+        }
+        let replaced = self.work() - self.work_until(fork);
+        let work = branch.iter().map(|block| block.header.work()).sum();
+        if work <= replaced {
+            return Err(ValidationError::LessWork { work, replaced });
+        }
+        let old_tip = self.tip().hash;
+        let mut disconnected = Vec::new();
+        while self.height() > fork {
+            disconnected.extend(self.pop_block());
+        }
+        disconnected.reverse();
+        for (connected, block) in branch.into_iter().enumerate() {
+            if let Err(e) = self.try_add_block(block) {
+                // 新分支中有无效区块，换回原来的分支
+                for _ in 0..connected {
+                    self.pop_block();
+                }
+                for block in disconnected {
+                    self.try_add_block(block)
+                        .expect("blocks of the old branch were valid");
+                }
+                return Err(e);
+            }
+        }
+        Ok(Reorg {
+            fork,
+            old_tip,
+            disconnected,
+        })
+    }
+
+    /// Remove the tip and revert its transactions, the genesis block and final blocks are never
+    /// removed
+    pub fn pop_block(&mut self) -> Option<Block> {
+        if self.blocks.len() == 1 || self.height() <= self.finalized {
+            return None;
+        }
+        let block = self.blocks.pop()?;
+        self.work.pop();
+        let undo = self.undo.pop_back()?;
+        self.ledger.disconnect_block(&block, undo);
+        self.index.disconnect(&block);
+        let removed = Op::Delete {
+            key: block_key(block.header.index),
+        };
+        if let Err(e) = self.store.batch(vec![removed, tip_record(self.tip())]) {
+            error!("can not remove stored block {}: {}", block.header.index, e);
+        }
+        Some(block)
+    }
+
+    /// The ledger at the tip with every header after the genesis block
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            chain_id: self.chain_id.clone(),
+            genesis: self.genesis_hash(),
+            headers: self
+                .blocks
+                .iter()
+                .skip(1)
+                .map(|block| block.header.clone())
+                .collect(),
+            ledger: self.ledger.snapshot(),
+        }
+    }
+
+    /// Start from the tip of a snapshot of our network instead of the genesis block, only while
+    /// no block was added
+    ///
+    /// The headers are checked like synced headers, the ledger is trusted as the node operator
+    /// trusts the snapshot file, and the blocks of the snapshot are final as their bodies are
+    /// missing. The headers and the ledger are stored in one batch before the chain takes them,
+    /// so a restarted node continues from the snapshot
+    pub fn import(&mut self, snapshot: Snapshot) -> Result<()> {
+        if self.height() > 0 {
+            bail!("the chain has blocks already, only a new node can import a snapshot");
+        }
+        if snapshot.chain_id != self.chain_id || snapshot.genesis != self.genesis_hash() {
+            bail!(
+                "the snapshot is of chain {} with genesis {}",
+                snapshot.chain_id,
+                snapshot.genesis
+            );
+        }
+        if snapshot.ledger.kind() != self.params.ledger {
+            bail!("the snapshot holds a {} ledger", snapshot.ledger.kind());
+        }
+        let height = snapshot.height();
+        let ledger = snapshot.ledger.into_ledger();
+        self.check_base(&snapshot.headers, ledger.as_ref())?;
+        let mut batch = snapshot
+            .headers
+            .iter()
+            .map(|header| block_put(&header_only(header.clone())))
+            .collect::<Result<Vec<Op>>>()?;
+        batch.push(base_put(height, ledger.as_ref())?);
+        if let Some(tip) = snapshot.headers.last() {
+            batch.push(Op::Put {
+                key: TIP_KEY.to_vec(),
+                value: tip.hash().0.to_vec(),
+            });
+        }
+        self.store
+            .batch(batch)
+            .context("can not store the snapshot")?;
+        self.start_from(snapshot.headers, ledger);
+        Ok(())
+    }
+
+    /// Whether the headers follow the genesis block like synced headers and the ledger is the one
+    /// the last of them commits to
+    fn check_base(&self, headers: &[BlockHeader], ledger: &dyn LedgerModel) -> Result<()> {
+        for (index, header) in headers.iter().enumerate() {
+            self.check_header(&headers[..index], header)
+                .with_context(|| format!("header {} is invalid", header.index))?;
+        }
+        if let Some(tip) = headers.last() {
+            if ledger.state_root() != tip.state_root {
+                bail!(
+                    "the ledger has state root {} where the tip header has {}",
+                    ledger.state_root(),
+                    tip.state_root
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Continue from the ledger after the headers checked by `check_base`, the blocks of the
+    /// headers are final as their bodies are missing
+    fn start_from(&mut self, headers: Vec<BlockHeader>, ledger: Box<dyn LedgerModel>) {
+        let height = headers.len() as u64;
+        let mut work = self.work();
+        self.work.extend(headers.iter().map(|header| {
+            work += header.work();
+            work
+        }));
+        self.blocks.extend(headers.into_iter().map(header_only));
+        self.ledger = ledger;
+        self.pruned = height;
+        self.finalized = height;
+    }
+
+    /// Take the ledger and the index of the chain rebuilt from our blocks by a replay, the replay
+    /// is dropped when blocks were added, taken off or pruned since it was copied
+    ///
+    /// Returns how many transactions were indexed
+    pub fn reindex(&mut self, replay: Chain) -> Result<usize> {
+        if replay.tip().hash != self.tip().hash || self.pruned > 0 {
+            bail!("the chain changed while it was replayed");
+        }
+        self.ledger = replay.ledger;
+        self.undo = replay.undo;
+        self.index = replay.index;
+        Ok(self.index.tx_count())
+    }
+
+    /// Only the genesis block, on the same network with the same checkpoints and clock
+    fn empty(&self) -> Chain {
+        let genesis = &self.blocks[0];
+        Chain {
+            blocks: vec![genesis.clone()],
+            work: vec![genesis.header.work()],
+            chain_id: self.chain_id.clone(),
+            params: self.params.clone(),
+            allocations: self.allocations.clone(),
+            ledger: self.params.ledger.genesis_state(
+                genesis.hash,
+                &self.allocations,
+                &self.genesis_validators,
+                self.params.slash_percent,
+                self.params.gas_price,
+            ),
+            undo: VecDeque::new(),
+            consensus: self
+                .params
+                .consensus
+                .engine(genesis.header.timestamp, self.params.target_block_time),
+            genesis_validators: self.genesis_validators.clone(),
+            finalized: 0,
+            checkpoints: self.checkpoints.clone(),
+            keep_bodies: None,
+            pruned: 0,
+            pruning: false,
+            clock: self.clock.clone(),
+            index: ChainIndex::default(),
+            store: Box::new(MemoryStore::default()),
+        }
+    }
+
+    /// Our blocks and the genesis block to replay them on away from the chain, checking every
+    /// block against its parent needs every body
+    pub fn replayer(&self) -> Result<Replay> {
+        if self.pruned > 0 {
+            bail!(
+                "bodies up to block {} are pruned, the chain can not be replayed",
+                self.pruned
+            );
+        }
+        let genesis = &self.blocks[0];
+        if genesis.hash != genesis.compute_hash() {
+            bail!("genesis block has an invalid hash {}", genesis.hash);
+        }
+        Ok(Replay {
+            chain: self.empty(),
+            blocks: self.blocks[1..].to_vec(),
+        })
+    }
+}
+
+/// Bodies discarded by `Chain::prune` and what the ledger after them is unwound from
+#[derive(Debug)]
+pub struct Prune {
+    target: u64,
+    hash: Hash,
+    /// Blocks whose bodies were discarded, as they are stored from now on
+    headers: Vec<Block>,
+    /// Ledger at the tip
+    tip: LedgerSnapshot,
+    /// Blocks after the target with their undo, oldest first
+    unwound: Vec<(Block, BlockUndo)>,
+}
+
+impl Prune {
+    /// Unwind the ledger to the target and encode the batch storing it with the headers
+    pub fn run(self) -> Result<Pruned> {
+        let mut ledger = self.tip.into_ledger();
+        for (block, undo) in self.unwound.into_iter().rev() {
+            ledger.disconnect_block(&block, undo);
+        }
+        let mut batch = self
+            .headers
+            .iter()
+            .map(block_put)
+            .collect::<Result<Vec<Op>>>()?;
+        batch.push(base_put(self.target, ledger.as_ref())?);
+        Ok(Pruned {
+            target: self.target,
+            hash: self.hash,
+            batch,
+        })
+    }
+}
+
+/// Batch of a finished `Prune`, for `Chain::store_pruned`
+#[derive(Debug)]
+pub struct Pruned {
+    target: u64,
+    hash: Hash,
+    batch: Vec<Op>,
+}
+
+/// Copy of the blocks after the genesis block, replayed on a thread of its own so a long chain
+/// does not hold up the node
+#[derive(Debug)]
+pub struct Replay {
+    chain: Chain,
+    blocks: Vec<Block>,
+}
+
+impl Replay {
+    /// Add the blocks to the genesis block one by one, verifying every one on the way
+    pub fn run(self) -> Result<Chain> {
+        let mut chain = self.chain;
+        for block in self.blocks {
+            let index = block.header.index;
+            chain
+                .try_add_block(block)
+                .with_context(|| format!("block {} is invalid", index))?;
+        }
+        Ok(chain)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use libp2p::identity::Keypair;
+
+    use super::*;
+    use crate::storage::StorageBackend;
+
+    fn genesis() -> Genesis {
+        let mut genesis = Genesis::default();
+        genesis.params.initial_difficulty = 0;
+        genesis.params.min_difficulty = 0;
+        genesis.params.max_difficulty = 0;
+        genesis
+    }
+
+    fn mine(chain: &mut Chain, blocks: usize) {
+        for _ in 0..blocks {
+            let block = chain.next_block(String::new(), Vec::new());
+            chain.try_add_block(block).unwrap();
+        }
+    }
+
+    /// The chain of a node started again with the blocks it stored at the path
+    fn restart(genesis: &Genesis, path: &std::path::Path) -> Chain {
+        let mut chain = Chain::from_genesis(genesis);
+        chain
+            .set_storage(storage::open(StorageBackend::Disk, path).unwrap())
+            .unwrap();
+        chain
+    }
+
+    #[test]
+    fn an_imported_snapshot_is_kept_across_a_restart() {
+        let genesis = genesis();
+        let mut source = Chain::from_genesis(&genesis);
+        mine(&mut source, 5);
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("blocks.db");
+
+        let mut chain = restart(&genesis, &path);
+        chain.import(source.snapshot()).unwrap();
+        mine(&mut chain, 2);
+        let (tip, root) = (chain.tip().hash, chain.ledger().state_root());
+        drop(chain);
+
+        let mut chain = restart(&genesis, &path);
+        assert_eq!(chain.height(), 7);
+        assert_eq!(chain.tip().hash, tip);
+        assert_eq!(chain.ledger().state_root(), root);
+        assert_eq!(chain.pruned_height(), 5);
+        chain.verify_storage().unwrap().1.wait().unwrap();
+        mine(&mut chain, 1);
+    }
+
+    #[test]
+    fn the_richlist_counts_holders_that_came_in_a_snapshot() {
+        for kind in [LedgerKind::Utxo, LedgerKind::Account] {
+            let keys = Keypair::generate_ed25519();
+            let from = Address::of(&keys.public()).unwrap();
+            let mut genesis = genesis();
+            genesis.params.ledger = kind;
+            genesis.allocations = vec![Allocation {
+                address: from,
+                amount: 100,
+            }];
+            let mut source = Chain::from_genesis(&genesis);
+            let mut tx = Transaction::new(from, Address([9; 32]), 60, 0);
+            source.ledger().fund(&mut tx, &|_| false).unwrap();
+            tx.sign(&keys).unwrap();
+            let block = source.next_block(String::new(), vec![tx]);
+            source.try_add_block(block).unwrap();
+
+            let mut chain = Chain::from_genesis(&genesis);
+            chain.import(source.snapshot()).unwrap();
+            assert_eq!(chain.richlist(10), vec![(Address([9; 32]), 60), (from, 40)]);
+            assert_eq!(chain.richlist(1), vec![(Address([9; 32]), 60)]);
+        }
+    }
+
+    #[test]
+    fn pruned_bodies_are_stored_as_headers_with_the_ledger_after_them() {
+        let genesis = genesis();
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("blocks.db");
+        let mut chain = restart(&genesis, &path);
+        chain.set_pruning(1);
+        mine(
+            &mut chain,
+            MAX_REORG_DEPTH as usize + PRUNE_INTERVAL as usize,
+        );
+        let prune = chain.prune().unwrap();
+        assert_eq!(chain.pruned_height(), PRUNE_INTERVAL);
+        // 上一批还没写入时不再裁剪
+        assert!(chain.prune().is_none());
+        chain.store_pruned(prune.run());
+        assert!(chain.prune().is_none());
+        let (tip, root) = (chain.tip().hash, chain.ledger().state_root());
+        drop(chain);
+
+        let mut chain = restart(&genesis, &path);
+        assert_eq!(chain.height(), MAX_REORG_DEPTH + PRUNE_INTERVAL);
+        assert_eq!(chain.tip().hash, tip);
+        assert_eq!(chain.ledger().state_root(), root);
+        assert!(chain.is_pruned(PRUNE_INTERVAL));
+        chain.verify_storage().unwrap().1.wait().unwrap();
+        // 重启后高于裁剪处的区块仍能撤销
+        assert!(chain.pop_block().is_some());
+    }
+
+    #[test]
+    fn a_prune_finished_after_a_restore_is_not_stored() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut chain = restart(&genesis(), &dir.path().join("blocks.db"));
+        chain.set_pruning(1);
+        mine(
+            &mut chain,
+            MAX_REORG_DEPTH as usize + PRUNE_INTERVAL as usize,
+        );
+        let records = chain.storage_snapshot().unwrap();
+        let prune = chain.prune().unwrap();
+        chain.restore_storage(records).unwrap();
+        chain.store_pruned(prune.run());
+        assert_eq!(chain.store.get(BASE_KEY).unwrap(), None);
+        assert!(chain.prune().is_some());
+    }
+
+    #[test]
+    fn the_work_of_the_chain_follows_its_blocks() {
+        let mut chain = Chain::from_genesis(&genesis());
+        mine(&mut chain, 3);
+        let sum = |chain: &Chain| -> u128 { chain.iter().map(|block| block.header.work()).sum() };
+        assert_eq!(chain.work(), sum(&chain));
+        chain.pop_block().unwrap();
+        assert_eq!(chain.work(), sum(&chain));
+        assert_eq!(chain.work_until(u64::MAX), chain.work());
+        mine(&mut chain, 2);
+        assert_eq!(chain.work(), sum(&chain));
+        assert_eq!(
+            chain.work_until(1),
+            sum(&chain) - 3 * chain.tip().header.work()
+        );
+    }
+
+    /// Blocks of another chain of the same genesis block, whose blocks carry the data
+    fn branch(genesis: &Genesis, data: &str, blocks: usize) -> Chain {
+        let mut chain = Chain::from_genesis(genesis);
+        for _ in 0..blocks {
+            let block = chain.next_block(data.to_owned(), Vec::new());
+            chain.try_add_block(block).unwrap();
+        }
+        chain
+    }
+
+    #[test]
+    fn a_branch_with_more_work_replaces_the_blocks_after_the_fork() {
+        let genesis = genesis();
+        let mut chain = Chain::from_genesis(&genesis);
+        mine(&mut chain, 2);
+        let old: Vec<Hash> = chain.iter().skip(1).map(|block| block.hash).collect();
+        let other = branch(&genesis, "other", 3);
+
+        let reorg = chain
+            .reorganize(0, other.iter().skip(1).cloned().collect())
+            .unwrap();
+        let disconnected: Vec<Hash> = reorg.disconnected.iter().map(|block| block.hash).collect();
+        assert_eq!(disconnected, old);
+        assert_eq!(chain.tip().hash, other.tip().hash);
+        assert_eq!(chain.work(), other.work());
+        assert_eq!(chain.ledger().state_root(), other.ledger().state_root());
+    }
+
+    #[test]
+    fn a_branch_that_is_invalid_or_has_less_work_leaves_the_chain_as_it_was() {
+        let genesis = genesis();
+        let mut chain = Chain::from_genesis(&genesis);
+        mine(&mut chain, 2);
+        let tip = chain.tip().hash;
+        let other = branch(&genesis, "other", 3);
+
+        let shorter: Vec<Block> = other.iter().skip(1).take(2).cloned().collect();
+        assert!(matches!(
+            chain.reorganize(0, shorter),
+            Err(ValidationError::LessWork { .. })
+        ));
+        let mut invalid: Vec<Block> = other.iter().skip(1).cloned().collect();
+        invalid[1].header.merkle_root = Hash::digest(b"forged");
+        invalid[1].hash = invalid[1].compute_hash();
+        assert!(chain.reorganize(0, invalid).is_err());
+        assert_eq!(chain.height(), 2);
+        assert_eq!(chain.tip().hash, tip);
+    }
+
+    #[test]
+    fn a_replay_checks_the_blocks_as_they_were_copied() {
+        let mut chain = Chain::from_genesis(&genesis());
+        mine(&mut chain, 3);
+        let replay = chain.replayer().unwrap();
+        chain.blocks[2].data = String::from("forged");
+        let replayed = replay.run().unwrap();
+        assert_eq!(replayed.tip().hash, chain.tip().hash);
+        assert_eq!(replayed.ledger().state_root(), chain.ledger().state_root());
+        let err = chain.replayer().unwrap().run().unwrap_err();
+        assert_eq!(err.to_string(), "block 2 is invalid");
+    }
+
+    #[test]
+    fn a_replay_is_only_swapped_in_while_the_chain_is_unchanged() {
+        let mut chain = Chain::from_genesis(&genesis());
+        mine(&mut chain, 2);
+        let replay = chain.replayer().unwrap().run().unwrap();
+        mine(&mut chain, 1);
+        assert!(chain.reindex(replay).is_err());
+        assert_eq!(chain.height(), 3);
+
+        let replay = chain.replayer().unwrap().run().unwrap();
+        let root = chain.ledger().state_root();
+        chain.reindex(replay).unwrap();
+        assert_eq!(chain.ledger().state_root(), root);
+        mine(&mut chain, 1);
+        assert_eq!(chain.height(), 4);
+    }
+}
@@ -0,0 +1,119 @@
+use std::cmp;
+use std::collections::HashMap;
+
+use libp2p::multiaddr::Protocol;
+use libp2p::{Multiaddr, PeerId, Swarm};
+use log::{error, info, warn};
+use tokio::sync::mpsc;
+
+use crate::behaviour::RecipeBehaviour;
+use crate::consts::{
+    BOOTSTRAP_DIAL_BASE_DELAY, BOOTSTRAP_DIAL_MAX_ATTEMPTS, BOOTSTRAP_DIAL_MAX_DELAY,
+};
+use crate::models::EventType;
+
+/// Extract the peer id from a multiaddr ending with `/p2p/<peer id>`
+pub fn peer_id_of(addr: &Multiaddr) -> Option<PeerId> {
+    match addr.iter().last() {
+        Some(Protocol::P2p(peer_id)) => Some(peer_id),
+        _ => None,
+    }
+}
+
+/// Dials the bootstrap nodes and recently seen peers on startup and retries failed dials with exponential backoff
+#[derive(Debug, Default)]
+pub struct Bootstrapper {
+    nodes: HashMap<PeerId, Multiaddr>,
+    /// Failed dial attempts of nodes we are not connected to yet
+    attempts: HashMap<PeerId, u32>,
+}
+
+impl Bootstrapper {
+    pub fn new(nodes: &[Multiaddr]) -> Self {
+        let mut bootstrapper = Bootstrapper::default();
+        for addr in nodes {
+            bootstrapper.add(addr);
+        }
+        bootstrapper
+    }
+
+    /// Add a node to dial, the address must end with `/p2p/<peer id>`
+    pub fn add(&mut self, addr: &Multiaddr) {
+        match peer_id_of(addr) {
+            Some(peer_id) => {
+                self.nodes.entry(peer_id).or_insert_with(|| addr.clone());
+            }
+            None => error!(
+                "bootstrap address {} does not end with /p2p/<peer id>",
+                addr
+            ),
+        }
+    }
+
+    pub fn dial_all(
+        &mut self,
+        swarm: &mut Swarm<RecipeBehaviour>,
+        sender: &mpsc::UnboundedSender<EventType>,
+    ) {
+        let peers: Vec<PeerId> = self.nodes.keys().copied().collect();
+        for peer_id in peers {
+            self.dial(swarm, sender, peer_id);
+        }
+    }
+
+    pub fn dial(
+        &mut self,
+        swarm: &mut Swarm<RecipeBehaviour>,
+        sender: &mpsc::UnboundedSender<EventType>,
+        peer_id: PeerId,
+    ) {
+        let addr = match self.nodes.get(&peer_id) {
+            Some(addr) => addr.clone(),
+            None => return,
+        };
+        if swarm.is_connected(&peer_id) {
+            self.attempts.remove(&peer_id);
+            return;
+        }
+        self.attempts.entry(peer_id).or_insert(0);
+        if let Err(e) = swarm.dial(addr.clone()) {
+            error!("error dialing bootstrap node {}: {}", addr, e);
+            self.on_dial_failure(sender, peer_id);
+        }
+    }
+
+    pub fn on_connected(&mut self, peer_id: &PeerId) {
+        if self.attempts.remove(peer_id).is_some() {
+            info!("Connected to bootstrap node {}", peer_id);
+        }
+    }
+
+    /// Schedule a redial through the event channel, giving up after too many attempts
+    pub fn on_dial_failure(&mut self, sender: &mpsc::UnboundedSender<EventType>, peer_id: PeerId) {
+        let attempts = match self.attempts.get_mut(&peer_id) {
+            Some(attempts) => attempts,
+            None => return,
+        };
+        *attempts += 1;
+        if *attempts >= BOOTSTRAP_DIAL_MAX_ATTEMPTS {
+            warn!(
+                "giving up on bootstrap node {} after {} attempts",
+                peer_id, attempts
+            );
+            self.attempts.remove(&peer_id);
+            return;
+        }
+        let delay = cmp::min(
+            BOOTSTRAP_DIAL_BASE_DELAY * 2u32.pow(*attempts - 1),
+            BOOTSTRAP_DIAL_MAX_DELAY,
+        );
+        info!("Retrying bootstrap node {} in {:?}", peer_id, delay);
+        let sender = sender.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(delay).await;
+            if let Err(e) = sender.send(EventType::DialBootstrap(peer_id)) {
+                error!("error sending bootstrap redial via channel, {}", e);
+            }
+        });
+    }
+}
@@ -0,0 +1,72 @@
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use crate::blockchain::Hash;
+use crate::storage;
+
+/// A file of a CBOR encoded value with the SHA-256 of the encoding, as backups and snapshots are
+/// written
+#[derive(Serialize, Deserialize)]
+struct ChecksummedFile {
+    checksum: Hash,
+    #[serde(with = "serde_bytes")]
+    payload: Vec<u8>,
+}
+
+/// Write the value to the file, replacing it whole, returns its size in bytes
+pub async fn write<T: Serialize>(path: &Path, value: &T) -> Result<usize> {
+    let mut payload = Vec::new();
+    ciborium::into_writer(value, &mut payload)?;
+    let file = ChecksummedFile {
+        checksum: Hash::digest(&payload),
+        payload,
+    };
+    let mut bytes = Vec::new();
+    ciborium::into_writer(&file, &mut bytes)?;
+    let len = bytes.len();
+    storage::replace_file(path, bytes).await?;
+    Ok(len)
+}
+
+/// Read the value of a file written by `write`, which must match its checksum, `kind` names what
+/// the file holds in errors
+pub async fn read<T: DeserializeOwned>(path: &Path, kind: &str) -> Result<T> {
+    let bytes = tokio::fs::read(path)
+        .await
+        .with_context(|| format!("can not read {}", path.display()))?;
+    let file: ChecksummedFile = ciborium::from_reader(&bytes[..])
+        .with_context(|| format!("{} is not a {}", path.display(), kind))?;
+    let checksum = Hash::digest(&file.payload);
+    if checksum != file.checksum {
+        bail!(
+            "checksum {} does not match the recorded {}, the {} is corrupt",
+            checksum,
+            file.checksum,
+            kind
+        );
+    }
+    Ok(ciborium::from_reader(&file.payload[..])?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn a_damaged_file_fails_its_checksum() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("value.bin");
+        write(&path, &vec![1u32, 2, 3]).await.unwrap();
+        assert_eq!(read::<Vec<u32>>(&path, "list").await.unwrap(), [1, 2, 3]);
+
+        let mut bytes = std::fs::read(&path).unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 0xff;
+        std::fs::write(&path, bytes).unwrap();
+        let error = read::<Vec<u32>>(&path, "list").await.unwrap_err();
+        assert!(error.to_string().contains("the list is corrupt"));
+    }
+}
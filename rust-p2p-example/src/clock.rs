@@ -0,0 +1,28 @@
+use std::fmt;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Where the chain takes the current time from, replaced to simulate a node whose clock drifts
+pub trait Clock: fmt::Debug + Send + Sync {
+    /// Seconds since the unix epoch
+    fn now(&self) -> u64;
+}
+
+/// The system clock shifted by `drift` seconds, negative drift makes it run behind
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock {
+    pub drift: i64,
+}
+
+impl Clock for SystemClock {
+    fn now(&self) -> u64 {
+        now().saturating_add_signed(self.drift)
+    }
+}
+
+/// Seconds since the unix epoch by the system clock, for what a drifting clock does not apply to
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
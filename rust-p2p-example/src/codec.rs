@@ -0,0 +1,81 @@
+use std::borrow::Cow;
+
+use anyhow::{bail, ensure, Result};
+use log::warn;
+
+use crate::consts::{COMPRESSION_MIN_SIZE, IDENTIFY_PROTOCOL_VERSION, ZSTD_COMPRESSION_LEVEL};
+
+/// Header byte of an uncompressed payload
+const CODEC_RAW: u8 = 0x00;
+
+/// Header byte of a zstd compressed payload
+const CODEC_ZSTD: u8 = 0x01;
+
+/// Prefix the payload with its codec byte, compressing it with zstd when worth it
+pub fn encode(data: &[u8], compress: bool) -> Vec<u8> {
+    if compress && data.len() >= COMPRESSION_MIN_SIZE {
+        match zstd::bulk::compress(data, ZSTD_COMPRESSION_LEVEL) {
+            Ok(compressed) if compressed.len() < data.len() => {
+                let mut out = Vec::with_capacity(compressed.len() + 1);
+                out.push(CODEC_ZSTD);
+                out.extend_from_slice(&compressed);
+                return out;
+            }
+            Ok(_) => {}
+            Err(e) => warn!("zstd compression failed, sending raw payload: {}", e),
+        }
+    }
+    let mut out = Vec::with_capacity(data.len() + 1);
+    out.push(CODEC_RAW);
+    out.extend_from_slice(data);
+    out
+}
+
+/// Strip the codec byte and decompress if needed, failing when the result exceeds `max_size`
+///
+/// Payloads of peers predating the codec header are plain JSON and passed through as they are
+pub fn decode(data: &[u8], max_size: usize) -> Result<Cow<'_, [u8]>> {
+    ensure!(
+        data.len() <= max_size + 1,
+        "payload of {} bytes exceeds the maximum message size of {} bytes",
+        data.len(),
+        max_size
+    );
+    match data.first() {
+        Some(&CODEC_RAW) => Ok(Cow::Borrowed(&data[1..])),
+        Some(&CODEC_ZSTD) => match zstd::bulk::decompress(&data[1..], max_size) {
+            Ok(decompressed) => Ok(Cow::Owned(decompressed)),
+            // 解压后的大小超过上限时 zstd 直接报错，不会分配超出上限的内存
+            Err(e) => bail!(
+                "can not decompress within the maximum message size of {} bytes: {}",
+                max_size,
+                e
+            ),
+        },
+        Some(b'{') | Some(b'[') => Ok(Cow::Borrowed(data)),
+        Some(codec) => bail!("unknown codec {:#04x}", codec),
+        None => bail!("empty payload"),
+    }
+}
+
+/// Whether a peer announcing this identify protocol version runs at least the given version
+pub fn protocol_at_least(protocol_version: &str, min_version: &str) -> bool {
+    let prefix = IDENTIFY_PROTOCOL_VERSION
+        .rsplit_once('/')
+        .map(|(prefix, _)| prefix)
+        .unwrap_or_default();
+    match protocol_version
+        .strip_prefix(prefix)
+        .and_then(|v| v.strip_prefix('/'))
+    {
+        Some(version) => parse_version(version) >= parse_version(min_version),
+        None => false,
+    }
+}
+
+fn parse_version(version: &str) -> Vec<u32> {
+    version
+        .split('.')
+        .map(|part| part.parse().unwrap_or_default())
+        .collect()
+}
@@ -0,0 +1,482 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use clap::Parser;
+use libp2p::{Multiaddr, PeerId};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+
+use crate::blockchain::Checkpoint;
+use crate::consts::{
+    BOOTSTRAP_NODES, DEFAULT_DERIVATION_PATH, DEFAULT_LISTEN_ADDRS, IDENTITY_FILE_PATH,
+};
+use crate::security::SecurityProtocol;
+use crate::storage::StorageBackend;
+use crate::wire::WireFormat;
+
+/// Node configuration, loaded once on first access
+///
+/// Values come from the optional JSON config file, command line flags take precedence
+pub static CONFIG: Lazy<Config> = Lazy::new(|| Config::load().expect("can load config"));
+
+/// Command line flags of the recipe node
+#[derive(Debug, Parser)]
+#[command(about = "A peer to peer recipe network")]
+pub struct Cli {
+    /// Path of a JSON config file
+    #[arg(long)]
+    pub config: Option<PathBuf>,
+
+    /// TCP address to listen on, replaces the configured ones, repeatable
+    #[arg(long = "listen")]
+    pub listen_addrs: Vec<Multiaddr>,
+
+    /// Security handshake of TCP and WebSocket connections
+    #[arg(long, value_enum)]
+    pub security: Option<SecurityProtocol>,
+
+    /// Also listen and dial over QUIC
+    #[arg(long)]
+    pub quic: bool,
+
+    /// Accept WebSocket connections, e.g. from browser peers
+    #[arg(long)]
+    pub websocket: bool,
+
+    /// TCP port of the WebSocket listener, 0 picks a random port
+    #[arg(long)]
+    pub websocket_port: Option<u16>,
+
+    /// Do not discover peers on the local network via multicast DNS
+    #[arg(long)]
+    pub no_mdns: bool,
+
+    /// Do not map the TCP listen port on the router via UPnP
+    #[arg(long)]
+    pub no_upnp: bool,
+
+    /// Publish gossip payloads uncompressed
+    #[arg(long)]
+    pub no_compression: bool,
+
+    /// Encoding of gossip messages, peers too old for CBOR are sent JSON
+    #[arg(long, value_enum)]
+    pub wire_format: Option<WireFormat>,
+
+    /// Largest gossip message in bytes that is published or accepted
+    #[arg(long)]
+    pub max_message_size: Option<usize>,
+
+    /// Seconds a connection without open streams is kept before it is closed
+    #[arg(long)]
+    pub idle_timeout: Option<u64>,
+
+    /// Never close idle connections, so long-lived peers stay connected between messages
+    #[arg(long)]
+    pub keep_alive: bool,
+
+    /// Redials of a disconnected peer before giving up on it, 0 disables reconnecting
+    #[arg(long)]
+    pub reconnect_attempts: Option<u32>,
+
+    /// Seconds to wait for connections to close on Ctrl-C before exiting anyway
+    #[arg(long)]
+    pub shutdown_timeout: Option<u64>,
+
+    /// Relay traffic for peers behind NAT, only useful on a publicly reachable node
+    #[arg(long)]
+    pub relay_server: bool,
+
+    /// Relay to reserve a slot on when AutoNAT finds the node is not publicly reachable, repeatable
+    #[arg(long = "relay")]
+    pub relays: Vec<Multiaddr>,
+
+    /// Serve as a rendezvous point other nodes register at
+    #[arg(long)]
+    pub rendezvous_server: bool,
+
+    /// Rendezvous point to register at and discover peers from, repeatable
+    #[arg(long = "rendezvous")]
+    pub rendezvous_points: Vec<Multiaddr>,
+
+    /// Bootstrap node dialed on startup, in addition to the configured ones, repeatable
+    #[arg(long = "bootstrap")]
+    pub bootstrap: Vec<Multiaddr>,
+
+    /// Append a JSON line for every swarm event to this file
+    #[arg(long)]
+    pub telemetry_file: Option<PathBuf>,
+
+    /// Genesis file defining the network to join
+    #[arg(long)]
+    pub genesis: Option<PathBuf>,
+
+    /// Path of the file holding the node keypair
+    #[arg(long)]
+    pub identity_file: Option<PathBuf>,
+
+    /// Replace the stored keypair with a freshly generated one, changing the peer id
+    #[arg(long)]
+    pub new_identity: bool,
+
+    /// Swarm key file, only peers sharing the same key can connect
+    #[arg(long)]
+    pub swarm_key: Option<PathBuf>,
+
+    /// Passphrase encrypting the wallet keys
+    #[arg(long, env = "ANT_WALLET_PASSPHRASE", hide_env_values = true)]
+    pub wallet_passphrase: Option<String>,
+
+    /// SLIP-10 path of the account wallet keys are derived from, e.g. `m/44'/1'/0'`
+    #[arg(long)]
+    pub derivation_path: Option<String>,
+
+    /// Only accept connections from this peer, repeatable
+    #[arg(long = "allow-peer")]
+    pub allowed_peers: Vec<PeerId>,
+
+    /// Block the chain must contain, as `<height>:<hash>`, repeatable
+    #[arg(long = "checkpoint")]
+    pub checkpoints: Vec<Checkpoint>,
+
+    /// Follow block headers only and ask full nodes for proofs
+    #[arg(long)]
+    pub light: bool,
+
+    /// Keep the bodies of this many blocks behind the tip only
+    #[arg(long, value_name = "BLOCKS")]
+    pub prune: Option<u64>,
+
+    /// Shift the clock blocks are stamped and checked with, to simulate a node whose clock drifts
+    #[arg(long, value_name = "SECONDS", allow_negative_numbers = true)]
+    pub clock_drift: Option<i64>,
+
+    /// Where recipes and blocks are kept
+    #[arg(long, value_enum)]
+    pub storage: Option<StorageBackend>,
+
+    /// Seconds between two runs of the storage maintenance, 0 disables it
+    #[arg(long, value_name = "SECONDS")]
+    pub maintenance_interval: Option<u64>,
+
+    /// Seconds a deleted recipe can be brought back before the maintenance removes it
+    #[arg(long, value_name = "SECONDS")]
+    pub recipe_retention: Option<u64>,
+}
+
+/// Settings read from the config file
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    /// Addresses of the TCP listeners, IPv4 and IPv6 on random ports by default
+    pub listen_addrs: Vec<Multiaddr>,
+
+    /// Security handshakes offered on TCP and WebSocket connections, QUIC always uses TLS
+    pub security: SecurityProtocol,
+
+    /// Enable the QUIC transport alongside TCP
+    pub quic: bool,
+
+    /// Listen on `/ws` for js-libp2p peers
+    pub websocket: bool,
+
+    /// TCP port of the WebSocket listener, 0 picks a random port
+    pub websocket_port: u16,
+
+    /// Discover peers on the local network via mDNS, usually unwanted on cloud networks
+    pub mdns: bool,
+
+    /// Seconds between two mDNS queries
+    pub mdns_query_interval: u64,
+
+    /// Seconds the peers we announce via mDNS are remembered by others
+    pub mdns_ttl: u64,
+
+    /// Map the TCP listen port on UPnP capable routers
+    pub upnp: bool,
+
+    /// Compress large gossip payloads with zstd when all peers of the topic support it
+    pub compression: bool,
+
+    /// Preferred encoding of gossip messages
+    pub wire_format: WireFormat,
+
+    /// Largest decoded gossip message in bytes, bigger ones are neither published nor accepted
+    pub max_message_size: usize,
+
+    /// Seconds a connection without open streams is kept before it is closed
+    pub idle_timeout: u64,
+
+    /// Keep idle connections open forever, overrides `idle_timeout`
+    pub keep_alive: bool,
+
+    /// Redials of a known peer that disconnected before giving up on it, 0 disables reconnecting
+    pub reconnect_max_attempts: u32,
+
+    /// Seconds to wait for connections to close on Ctrl-C before exiting anyway
+    pub shutdown_timeout: u64,
+
+    /// Act as a circuit relay v2 server
+    pub relay_server: bool,
+
+    /// Maximum number of reservations the relay server accepts in total
+    pub relay_max_reservations: usize,
+
+    /// Maximum number of reservations one peer may hold on the relay server
+    pub relay_max_reservations_per_peer: usize,
+
+    /// Maximum number of relayed circuits one peer may have open
+    pub relay_max_circuits_per_peer: usize,
+
+    /// Relays used as fallback when the node is behind NAT, each ending with `/p2p/<peer id>`
+    pub relays: Vec<Multiaddr>,
+
+    /// Act as a rendezvous point
+    pub rendezvous_server: bool,
+
+    /// Rendezvous points to register under the recipe namespace, each ending with `/p2p/<peer id>`
+    pub rendezvous_points: Vec<Multiaddr>,
+
+    /// Nodes dialed on startup and used to join the DHT, each ending with `/p2p/<peer id>`
+    ///
+    /// Hostnames work too, e.g. `/dns4/boot.example.com/tcp/4001/p2p/<peer id>` or
+    /// `/dnsaddr/boot.example.com/p2p/<peer id>`, so nodes can be moved without reconfiguring peers
+    pub bootstrap: Vec<Multiaddr>,
+
+    /// File receiving a JSON line per swarm event, for external monitoring
+    pub telemetry_file: Option<PathBuf>,
+
+    /// Genesis file defining the network, `./genesis.json` or the development network when unset
+    pub genesis_file: Option<PathBuf>,
+
+    /// File holding the node keypair, created on first start
+    pub identity_file: PathBuf,
+
+    /// Regenerate the keypair instead of loading it, only settable from the command line
+    #[serde(skip)]
+    pub new_identity: bool,
+
+    /// Pre-shared key file turning the recipe network into a private one
+    pub swarm_key_file: Option<PathBuf>,
+
+    /// Passphrase of the wallet keystore, never read from the config file
+    #[serde(skip)]
+    pub wallet_passphrase: Option<String>,
+
+    /// Account path keys are derived from when a mnemonic is created or restored, the path is
+    /// stored in the wallet so changing it later does not change existing addresses
+    pub wallet_derivation_path: String,
+
+    /// When not empty, the node only talks to these peers
+    pub allowed_peers: Vec<PeerId>,
+
+    /// Caps on pending and established connections
+    pub connection_limits: ConnectionLimitsConfig,
+
+    /// Trusted blocks, the chain is never reorganized below the last one and history up to it is
+    /// synced without verifying signatures
+    pub checkpoints: Vec<Checkpoint>,
+
+    /// Keep block headers only, transactions and block payloads are checked with proofs fetched
+    /// from full nodes on demand
+    pub light: bool,
+
+    /// Blocks behind the tip whose bodies are kept, older bodies are discarded by the storage
+    /// maintenance once they can no longer be taken off the chain, unset keeps every block
+    pub prune: Option<u64>,
+
+    /// Seconds the clock of the chain is ahead of the system clock, behind when negative
+    pub clock_drift: i64,
+
+    /// Where recipes and blocks are kept, on disk across restarts or in memory only
+    pub storage: StorageBackend,
+
+    /// Seconds between two runs of the storage maintenance, which prunes block bodies, expires
+    /// deleted recipes, collects unreferenced blobs and compacts the databases, 0 leaves it to
+    /// `storage compact`
+    pub maintenance_interval: u64,
+
+    /// Seconds a deleted recipe is kept as a tombstone, `undelete r` brings it back until then
+    pub recipe_retention: u64,
+}
+
+/// Connection caps, `null` in the config file lifts a limit
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ConnectionLimitsConfig {
+    pub max_pending_incoming: Option<u32>,
+    pub max_pending_outgoing: Option<u32>,
+    pub max_established_incoming: Option<u32>,
+    pub max_established_outgoing: Option<u32>,
+    pub max_established_per_peer: Option<u32>,
+    pub max_established_total: Option<u32>,
+}
+
+impl Default for ConnectionLimitsConfig {
+    fn default() -> Self {
+        ConnectionLimitsConfig {
+            max_pending_incoming: Some(32),
+            max_pending_outgoing: Some(32),
+            max_established_incoming: Some(128),
+            max_established_outgoing: Some(128),
+            max_established_per_peer: Some(4),
+            max_established_total: Some(256),
+        }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            listen_addrs: DEFAULT_LISTEN_ADDRS
+                .iter()
+                .map(|addr| addr.parse().expect("valid listen address"))
+                .collect(),
+            security: SecurityProtocol::Noise,
+            quic: false,
+            websocket: false,
+            websocket_port: 0,
+            mdns: true,
+            mdns_query_interval: 5 * 60,
+            mdns_ttl: 6 * 60,
+            upnp: true,
+            compression: true,
+            wire_format: WireFormat::Cbor,
+            max_message_size: 1024 * 1024,
+            idle_timeout: 60,
+            keep_alive: false,
+            reconnect_max_attempts: 8,
+            shutdown_timeout: 5,
+            relay_server: false,
+            relay_max_reservations: 128,
+            relay_max_reservations_per_peer: 4,
+            relay_max_circuits_per_peer: 4,
+            relays: Vec::new(),
+            rendezvous_server: false,
+            rendezvous_points: Vec::new(),
+            bootstrap: BOOTSTRAP_NODES
+                .iter()
+                .map(|addr| addr.parse().expect("valid bootstrap address"))
+                .collect(),
+            telemetry_file: None,
+            genesis_file: None,
+            identity_file: PathBuf::from(IDENTITY_FILE_PATH),
+            new_identity: false,
+            swarm_key_file: None,
+            wallet_passphrase: None,
+            wallet_derivation_path: DEFAULT_DERIVATION_PATH.to_owned(),
+            allowed_peers: Vec::new(),
+            connection_limits: ConnectionLimitsConfig::default(),
+            checkpoints: Vec::new(),
+            light: false,
+            prune: None,
+            clock_drift: 0,
+            storage: StorageBackend::Disk,
+            maintenance_interval: 60 * 60,
+            recipe_retention: 7 * 24 * 60 * 60,
+        }
+    }
+}
+
+impl Config {
+    /// How long idle connections are kept
+    pub fn idle_connection_timeout(&self) -> Duration {
+        if self.keep_alive {
+            // libp2p 用足够长的空闲超时代替已废弃的 keep-alive 行为
+            Duration::from_secs(u64::MAX)
+        } else {
+            Duration::from_secs(self.idle_timeout)
+        }
+    }
+
+    pub fn load() -> Result<Config> {
+        let cli = Cli::parse();
+        let mut config = match &cli.config {
+            Some(path) => Config::from_file(path)?,
+            None => Config::default(),
+        };
+        if !cli.listen_addrs.is_empty() {
+            config.listen_addrs = cli.listen_addrs;
+        }
+        if let Some(security) = cli.security {
+            config.security = security;
+        }
+        config.quic |= cli.quic;
+        config.websocket |= cli.websocket;
+        if let Some(port) = cli.websocket_port {
+            config.websocket_port = port;
+        }
+        config.mdns &= !cli.no_mdns;
+        config.upnp &= !cli.no_upnp;
+        config.compression &= !cli.no_compression;
+        if let Some(size) = cli.max_message_size {
+            config.max_message_size = size;
+        }
+        if let Some(timeout) = cli.idle_timeout {
+            config.idle_timeout = timeout;
+        }
+        config.keep_alive |= cli.keep_alive;
+        if let Some(attempts) = cli.reconnect_attempts {
+            config.reconnect_max_attempts = attempts;
+        }
+        if let Some(timeout) = cli.shutdown_timeout {
+            config.shutdown_timeout = timeout;
+        }
+        if let Some(format) = cli.wire_format {
+            config.wire_format = format;
+        }
+        config.relay_server |= cli.relay_server;
+        config.relays.extend(cli.relays);
+        config.rendezvous_server |= cli.rendezvous_server;
+        config.rendezvous_points.extend(cli.rendezvous_points);
+        config.bootstrap.extend(cli.bootstrap);
+        if cli.telemetry_file.is_some() {
+            config.telemetry_file = cli.telemetry_file;
+        }
+        if cli.genesis.is_some() {
+            config.genesis_file = cli.genesis;
+        }
+        if let Some(path) = cli.identity_file {
+            config.identity_file = path;
+        }
+        config.new_identity = cli.new_identity;
+        if cli.swarm_key.is_some() {
+            config.swarm_key_file = cli.swarm_key;
+        }
+        if cli.wallet_passphrase.is_some() {
+            config.wallet_passphrase = cli.wallet_passphrase;
+        }
+        if let Some(path) = cli.derivation_path {
+            config.wallet_derivation_path = path;
+        }
+        config.allowed_peers.extend(cli.allowed_peers);
+        config.checkpoints.extend(cli.checkpoints);
+        config.light |= cli.light;
+        if cli.prune.is_some() {
+            config.prune = cli.prune;
+        }
+        if let Some(drift) = cli.clock_drift {
+            config.clock_drift = drift;
+        }
+        if let Some(storage) = cli.storage {
+            config.storage = storage;
+        }
+        if let Some(interval) = cli.maintenance_interval {
+            config.maintenance_interval = interval;
+        }
+        if let Some(retention) = cli.recipe_retention {
+            config.recipe_retention = retention;
+        }
+        Ok(config)
+    }
+
+    fn from_file(path: &Path) -> Result<Config> {
+        let content =
+            fs::read(path).with_context(|| format!("can not read config {}", path.display()))?;
+        let config = serde_json::from_slice(&content)
+            .with_context(|| format!("invalid config {}", path.display()))?;
+        Ok(config)
+    }
+}
@@ -0,0 +1,331 @@
+use std::fmt;
+
+use libp2p::identity::{ed25519, Keypair, SigningError};
+use serde::{Deserialize, Serialize};
+
+use crate::blockchain::{BlockHeader, Hash};
+use crate::transaction::Address;
+use crate::validation::ValidationError;
+
+/// How the blocks of a network are sealed, fixed by the genesis file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConsensusKind {
+    /// Miners search for a hash with enough leading zero bits
+    #[default]
+    Pow,
+    /// Validators take turns proposing blocks in proportion to their stake
+    Pos,
+}
+
+impl ConsensusKind {
+    /// The engine checking the seals of the chain, proof-of-stake slots are `slot_time` seconds
+    /// long and counted from the genesis block
+    pub fn engine(&self, genesis_timestamp: u64, slot_time: u64) -> Box<dyn Consensus> {
+        match self {
+            ConsensusKind::Pow => Box::new(ProofOfWork),
+            ConsensusKind::Pos => Box::new(ProofOfStake {
+                genesis_timestamp,
+                slot_time: slot_time.max(1),
+            }),
+        }
+    }
+}
+
+impl fmt::Display for ConsensusKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConsensusKind::Pow => write!(f, "proof of work"),
+            ConsensusKind::Pos => write!(f, "proof of stake"),
+        }
+    }
+}
+
+/// Address allowed to propose blocks and the coins it has at stake
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Validator {
+    pub address: Address,
+    pub stake: u64,
+}
+
+/// Rules for who may seal a block and how
+pub trait Consensus: fmt::Debug + Send {
+    fn kind(&self) -> ConsensusKind;
+
+    /// Whether blocks carry proof of work, the difficulty schedule only applies to those
+    fn needs_work(&self) -> bool;
+
+    /// Slot of the unix time, only engines that take turns have slots
+    fn slot(&self, timestamp: u64) -> Option<u64>;
+
+    /// Whether the header is sealed as the engine demands on top of `parent`, `difficulty` being
+    /// what the retarget schedule demands and `validators` those entitled to propose it
+    fn check_seal(
+        &self,
+        header: &BlockHeader,
+        parent: &BlockHeader,
+        difficulty: u32,
+        validators: &[Validator],
+    ) -> Result<(), ValidationError>;
+}
+
+#[derive(Debug)]
+pub struct ProofOfWork;
+
+impl Consensus for ProofOfWork {
+    fn kind(&self) -> ConsensusKind {
+        ConsensusKind::Pow
+    }
+
+    fn needs_work(&self) -> bool {
+        true
+    }
+
+    fn slot(&self, _timestamp: u64) -> Option<u64> {
+        None
+    }
+
+    fn check_seal(
+        &self,
+        header: &BlockHeader,
+        _parent: &BlockHeader,
+        difficulty: u32,
+        _validators: &[Validator],
+    ) -> Result<(), ValidationError> {
+        if header.difficulty != difficulty {
+            return Err(ValidationError::DifficultyMismatch {
+                expected: difficulty,
+                actual: header.difficulty,
+            });
+        }
+        let bits = header.hash().leading_zero_bits();
+        if bits < header.difficulty {
+            return Err(ValidationError::InsufficientWork {
+                bits,
+                difficulty: header.difficulty,
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Time is split into slots, each slot has one proposer drawn by stake and at most one block
+///
+/// The draw is seeded with the hash of the parent, so every node agrees on the proposer of a
+/// slot without talking to the others
+#[derive(Debug)]
+pub struct ProofOfStake {
+    genesis_timestamp: u64,
+    slot_time: u64,
+}
+
+impl ProofOfStake {
+    fn slot_of(&self, timestamp: u64) -> u64 {
+        timestamp.saturating_sub(self.genesis_timestamp) / self.slot_time
+    }
+}
+
+/// The validator entitled to propose the block of the slot on top of `parent`, each validator
+/// wins with a chance in proportion to its stake
+pub fn proposer(parent: &Hash, slot: u64, validators: &[Validator]) -> Option<Address> {
+    let total = validators
+        .iter()
+        .fold(0u64, |sum, validator| sum.saturating_add(validator.stake));
+    if total == 0 {
+        return None;
+    }
+    let mut seed = Vec::with_capacity(40);
+    seed.extend_from_slice(&parent.0);
+    seed.extend_from_slice(&slot.to_be_bytes());
+    let digest = Hash::digest(&seed);
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&digest.0[..8]);
+    let mut ticket = u64::from_be_bytes(bytes) % total;
+    // 按地址排序，所有节点按同样的顺序累加权益
+    let mut sorted: Vec<&Validator> = validators.iter().collect();
+    sorted.sort_by_key(|validator| validator.address);
+    for validator in sorted {
+        if ticket < validator.stake {
+            return Some(validator.address);
+        }
+        ticket -= validator.stake;
+    }
+    None
+}
+
+/// Sign the header as its proposer, the signature covers the header hash which commits to the
+/// proposer
+pub fn sign_header(header: &mut BlockHeader, keys: &Keypair) -> Result<(), SigningError> {
+    header.proposer = Address::of(&keys.public());
+    header.signature = keys.sign(&header.hash().0)?;
+    Ok(())
+}
+
+/// Whether the header is signed by the proposer it names
+pub fn verify_proposer_signature(header: &BlockHeader) -> bool {
+    header
+        .proposer
+        .and_then(|proposer| ed25519::PublicKey::try_from_bytes(&proposer.0).ok())
+        .map(|key| key.verify(&header.hash().0, &header.signature))
+        .unwrap_or(false)
+}
+
+impl Consensus for ProofOfStake {
+    fn kind(&self) -> ConsensusKind {
+        ConsensusKind::Pos
+    }
+
+    fn needs_work(&self) -> bool {
+        false
+    }
+
+    fn slot(&self, timestamp: u64) -> Option<u64> {
+        Some(self.slot_of(timestamp))
+    }
+
+    fn check_seal(
+        &self,
+        header: &BlockHeader,
+        parent: &BlockHeader,
+        _difficulty: u32,
+        validators: &[Validator],
+    ) -> Result<(), ValidationError> {
+        if header.difficulty != 0 {
+            return Err(ValidationError::DifficultyMismatch {
+                expected: 0,
+                actual: header.difficulty,
+            });
+        }
+        let actual = header.proposer.ok_or(ValidationError::MissingProposer)?;
+        let (slot, parent_slot) = (
+            self.slot_of(header.timestamp),
+            self.slot_of(parent.timestamp),
+        );
+        if slot <= parent_slot {
+            return Err(ValidationError::SlotNotAfterParent {
+                slot,
+                parent: parent_slot,
+            });
+        }
+        let expected =
+            proposer(&parent.hash(), slot, validators).ok_or(ValidationError::NoValidators)?;
+        if actual != expected {
+            return Err(ValidationError::WrongProposer {
+                slot,
+                expected,
+                actual,
+            });
+        }
+        if !verify_proposer_signature(header) {
+            return Err(ValidationError::InvalidProposerSignature);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::Block;
+
+    const SLOT_TIME: u64 = 10;
+
+    fn validator(keys: &Keypair, stake: u64) -> Validator {
+        Validator {
+            address: Address::of(&keys.public()).unwrap(),
+            stake,
+        }
+    }
+
+    /// Header of a block at the time on top of the parent, unsealed
+    fn header(parent: &BlockHeader, timestamp: u64) -> BlockHeader {
+        let mut header = Block::new(1, parent.hash(), 0, String::new(), Vec::new()).header;
+        header.timestamp = timestamp;
+        header
+    }
+
+    #[test]
+    fn proposers_are_drawn_in_proportion_to_their_stake() {
+        let (large, small) = (Keypair::generate_ed25519(), Keypair::generate_ed25519());
+        let validators = [
+            validator(&large, 3),
+            validator(&small, 1),
+            validator(&Keypair::generate_ed25519(), 0),
+        ];
+        let parent = Hash::digest(b"parent");
+        let won = (0..1_000)
+            .filter(|slot| proposer(&parent, *slot, &validators) == Some(validators[0].address))
+            .count();
+        assert!((650..850).contains(&won), "won {} of 1000 slots", won);
+        assert!((0..1_000)
+            .all(|slot| { proposer(&parent, slot, &validators) != Some(validators[2].address) }));
+        assert_eq!(
+            proposer(&parent, 7, &validators),
+            proposer(&parent, 7, &validators)
+        );
+        assert_eq!(proposer(&parent, 7, &[]), None);
+    }
+
+    #[test]
+    fn a_block_of_a_slot_is_sealed_by_its_proposer() {
+        let (keys, other) = (Keypair::generate_ed25519(), Keypair::generate_ed25519());
+        let validators = [validator(&keys, 1)];
+        let engine = ConsensusKind::Pos.engine(0, SLOT_TIME);
+        let parent = Block::new(0, Hash::default(), 0, String::new(), Vec::new()).header;
+        let parent = BlockHeader {
+            timestamp: 5,
+            ..parent
+        };
+
+        let mut sealed = header(&parent, 2 * SLOT_TIME);
+        sign_header(&mut sealed, &keys).unwrap();
+        engine.check_seal(&sealed, &parent, 0, &validators).unwrap();
+
+        let mut forged = sealed.clone();
+        forged.signature[0] ^= 1;
+        assert!(matches!(
+            engine.check_seal(&forged, &parent, 0, &validators),
+            Err(ValidationError::InvalidProposerSignature)
+        ));
+        let mut stranger = header(&parent, 2 * SLOT_TIME);
+        sign_header(&mut stranger, &other).unwrap();
+        assert!(matches!(
+            engine.check_seal(&stranger, &parent, 0, &validators),
+            Err(ValidationError::WrongProposer { slot: 2, .. })
+        ));
+        let mut same_slot = header(&parent, SLOT_TIME - 1);
+        sign_header(&mut same_slot, &keys).unwrap();
+        assert!(matches!(
+            engine.check_seal(&same_slot, &parent, 0, &validators),
+            Err(ValidationError::SlotNotAfterParent { slot: 0, parent: 0 })
+        ));
+        assert!(matches!(
+            engine.check_seal(&header(&parent, 2 * SLOT_TIME), &parent, 0, &validators),
+            Err(ValidationError::MissingProposer)
+        ));
+    }
+
+    #[test]
+    fn proof_of_work_needs_the_scheduled_difficulty_and_enough_work() {
+        let engine = ConsensusKind::Pow.engine(0, SLOT_TIME);
+        let parent = Block::new(0, Hash::default(), 0, String::new(), Vec::new()).header;
+        let mut block = header(&parent, 1);
+        engine.check_seal(&block, &parent, 0, &[]).unwrap();
+        assert!(matches!(
+            engine.check_seal(&block, &parent, 1, &[]),
+            Err(ValidationError::DifficultyMismatch {
+                expected: 1,
+                actual: 0
+            })
+        ));
+        // 256 个零比特的哈希找不到
+        block.difficulty = 256;
+        assert!(matches!(
+            engine.check_seal(&block, &parent, 256, &[]),
+            Err(ValidationError::InsufficientWork {
+                difficulty: 256,
+                ..
+            })
+        ));
+    }
+}
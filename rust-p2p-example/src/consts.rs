@@ -1,11 +1,365 @@
-use libp2p::floodsub::Topic;
-use libp2p::{identity, PeerId};
+use std::time::Duration;
+
+use libp2p::gossipsub::IdentTopic;
+use libp2p::rendezvous::Namespace;
+use libp2p::{identity, PeerId, StreamProtocol};
 use once_cell::sync::Lazy;
 
+use crate::config::CONFIG;
+use crate::node_identity;
+
+/// Recipes of nodes predating the recipe database, imported into a new database
 pub const STORAGE_FILE_PATH: &str = "./recipes.json";
 
+/// Recipe database, the backend names it with its own extension
+pub const RECIPES_DB_PATH: &str = "./recipes.db";
+
+/// Database of the blob references
+pub const BLOBS_DB_PATH: &str = "./blobs.db";
+
+/// Directory of the blob contents, one file per content named by its hash
+pub const BLOBS_DIR: &str = "./blobs";
+
+/// Largest blob accepted, e.g. as a recipe image
+pub const BLOB_MAX_SIZE: usize = 16 * 1024 * 1024;
+
+/// Recipes kept decoded by the recipe storage
+pub const RECIPE_CACHE_CAPACITY: usize = 1024;
+
+/// Addresses whose unspent outputs the UTXO ledger keeps listed
+pub const UTXO_CACHE_CAPACITY: usize = 1024;
+
+/// Batches written by the `bench storage` command
+pub const STORAGE_BENCHMARK_BATCHES: usize = 200;
+
+/// Database of the blocks, loaded at startup
+pub const CHAIN_DB_PATH: &str = "./chain.db";
+
+/// Database of the headers a light node follows, loaded at startup
+pub const HEADERS_DB_PATH: &str = "./headers.db";
+
+/// Peers banned by the operator
+pub const BANNED_PEERS_FILE_PATH: &str = "./banned_peers.json";
+
+/// Peers we connected to before, used to reconnect after a restart
+pub const ADDRESS_BOOK_FILE_PATH: &str = "./address_book.json";
+
+/// Reputation of the peers we dealt with, restored at startup
+pub const PEER_SCORES_FILE_PATH: &str = "./peer_scores.json";
+
+/// Peers not seen for this long are dropped from the address book
+pub const ADDRESS_BOOK_MAX_AGE: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// Addresses remembered per peer
+pub const ADDRESS_BOOK_MAX_ADDRS_PER_PEER: usize = 4;
+
+/// How many of the most recently seen peers are redialed on startup
+pub const ADDRESS_BOOK_RECONNECT_PEERS: usize = 20;
+
+/// Encrypted keys of the wallet
+pub const WALLET_FILE_PATH: &str = "./wallet.json";
+
+/// PBKDF2 rounds turning the wallet passphrase into the keystore encryption key
+pub const WALLET_KDF_ROUNDS: u32 = 100_000;
+
+/// Account wallet keys are derived from, coin type 1 as used by test networks
+pub const DEFAULT_DERIVATION_PATH: &str = "m/44'/1'/0'";
+
+/// Unused addresses in a row after which restoring a wallet stops deriving keys
+pub const WALLET_RESTORE_GAP: u32 = 20;
+
+/// Prefix of the checksummed hex form addresses were shown in before bech32, still accepted
+pub const ADDRESS_PREFIX: &str = "ant";
+
+/// Human readable part of the bech32 form of mainnet addresses
+pub const MAINNET_ADDRESS_HRP: &str = "ant";
+
+/// Human readable part of the bech32 form of testnet addresses
+pub const TESTNET_ADDRESS_HRP: &str = "tant";
+
+/// Most signers one shared address may have
+pub const MULTISIG_MAX_SIGNERS: usize = 16;
+
+/// Default location of the persisted node keypair
+pub const IDENTITY_FILE_PATH: &str = "./identity.key";
+
+/// TCP listeners used unless configured otherwise
+pub const DEFAULT_LISTEN_ADDRS: &[&str] = &["/ip4/0.0.0.0/tcp/0", "/ip6/::/tcp/0"];
+
+/// How often gossipsub maintains its mesh and emits gossip about recently seen messages
+pub const GOSSIPSUB_HEARTBEAT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How long a pruned peer has to wait before it can join the mesh again
+pub const GOSSIPSUB_PRUNE_BACKOFF: Duration = Duration::from_secs(60);
+
+/// How long fanout peers of a topic we are not subscribed to are kept after the last publish
+pub const GOSSIPSUB_FANOUT_TTL: Duration = Duration::from_secs(60);
+
+/// Kademlia protocol name, kept apart from the public IPFS DHT so recipe nodes only route to each other
+pub const KAD_PROTOCOL_NAME: StreamProtocol = StreamProtocol::new("/ant-chain/kad/1.0.0");
+
+/// Identify protocol version, peers announcing a different major version speak an incompatible
+/// dialect
+pub const IDENTIFY_PROTOCOL_VERSION: &str = "/ant-chain/id/1.4.0";
+
+/// First protocol version able to read zstd compressed gossip payloads
+pub const COMPRESSION_MIN_PROTOCOL_VERSION: &str = "1.1.0";
+
+/// First protocol version able to read CBOR encoded gossip messages
+pub const CBOR_MIN_PROTOCOL_VERSION: &str = "1.2.0";
+
+/// First protocol version wrapping gossip messages in a `MessageEnvelope`
+pub const ENVELOPE_MIN_PROTOCOL_VERSION: &str = "1.3.0";
+
+/// First protocol version taking part in peer exchange
+pub const PEX_MIN_PROTOCOL_VERSION: &str = "1.4.0";
+
+/// Envelope version of the messages this node sends, newer ones are ignored
+pub const MESSAGE_VERSION: u16 = 1;
+
+/// Encode and decode rounds of the `bench wire` command
+pub const WIRE_BENCHMARK_ITERATIONS: u32 = 100;
+
+/// Payloads smaller than this are sent uncompressed
+pub const COMPRESSION_MIN_SIZE: usize = 512;
+
+/// zstd level trading speed for ratio, 3 is the zstd default
+pub const ZSTD_COMPRESSION_LEVEL: i32 = 3;
+
+/// Payload bytes per chunk, leaves room for the chunk header and gossipsub framing below the
+/// default 64 KiB transmit size
+pub const TRANSFER_CHUNK_SIZE: usize = 60 * 1024;
+
+/// Upper bound on the chunks of one transfer, about 15 MiB
+pub const TRANSFER_MAX_CHUNKS: usize = 256;
+
+/// Incomplete transfers are dropped after this long
+pub const TRANSFER_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Protocol used to fetch the shared recipes of one peer directly instead of over the topic
+pub const RECIPE_PROTOCOL_NAME: StreamProtocol = StreamProtocol::new("/ant-chain/recipes/1.0.0");
+
+/// How long to wait for a peer to answer a direct recipe request
+pub const RECIPE_REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Protocol used to exchange chain status, download headers and blocks from peers, compare
+/// mempools and answer the proof requests of light nodes
+pub const SYNC_PROTOCOL_NAME: StreamProtocol = StreamProtocol::new("/ant-chain/sync/1.4.0");
+
+/// How long to wait for a peer to answer a sync request
+pub const SYNC_REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Blocks per sync response, keeps a batch of full blocks under the 10 MB response limit of the
+/// CBOR codec
+pub const SYNC_BATCH_SIZE: u32 = 8;
+
+/// Headers per sync response, a header is a couple hundred bytes
+pub const SYNC_HEADERS_BATCH_SIZE: u32 = 512;
+
+/// How far above the tip blocks are downloaded, bounds the blocks held in memory while one
+/// below them is still missing
+pub const SYNC_DOWNLOAD_WINDOW: u64 = 64;
+
+/// How often the node refreshes its Kademlia routing table by bootstrapping again
+pub const KAD_BOOTSTRAP_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// Well known nodes used to join the DHT, in the form `/ip4/1.2.3.4/tcp/4001/p2p/<peer id>` or
+/// `/dnsaddr/<host>/p2p/<peer id>`
+pub const BOOTSTRAP_NODES: &[&str] = &[];
+
+/// Delay before the first redial of an unreachable bootstrap node, doubled on every failure
+pub const BOOTSTRAP_DIAL_BASE_DELAY: Duration = Duration::from_secs(2);
+
+/// Upper bound of the delay between two bootstrap dials
+pub const BOOTSTRAP_DIAL_MAX_DELAY: Duration = Duration::from_secs(5 * 60);
+
+/// Number of failed dials after which a bootstrap node is given up
+pub const BOOTSTRAP_DIAL_MAX_ATTEMPTS: u32 = 10;
+
+/// Delay before the first redial of a known peer that disconnected, doubled on every failure
+pub const RECONNECT_BASE_DELAY: Duration = Duration::from_secs(1);
+
+/// Upper bound of the delay between two redials, before jitter
+pub const RECONNECT_MAX_DELAY: Duration = Duration::from_secs(5 * 60);
+
+/// Length of the window over which per-peer message rates are measured
+pub const PEER_SCORE_WINDOW: Duration = Duration::from_secs(10);
+
+/// Messages a peer may deliver per window before it is disconnected
+pub const PEER_SCORE_MAX_MESSAGES_PER_WINDOW: u32 = 50;
+
+/// Score lost for every message that can not be decoded
+pub const PEER_SCORE_INVALID_MESSAGE_PENALTY: i64 = 10;
+
+/// Score lost for every block that breaks a consensus rule
+pub const PEER_SCORE_INVALID_BLOCK_PENALTY: i64 = 50;
+
+/// Score lost every time a peer exceeds the message rate
+pub const PEER_SCORE_RATE_VIOLATION_PENALTY: i64 = 25;
+
+/// Score gained for every valid response to one of our requests
+pub const PEER_SCORE_GOOD_RESPONSE_REWARD: i64 = 1;
+
+/// Upper bound of the score gained from good responses
+pub const PEER_SCORE_MAX_REWARD: i64 = 50;
+
+/// Score lost every time a connection to the peer fails instead of being closed
+pub const PEER_SCORE_ABRUPT_DISCONNECT_PENALTY: i64 = 5;
+
+/// Peers whose score drops to this value are banned
+pub const PEER_SCORE_BAN_THRESHOLD: i64 = -100;
+
+/// Recipe messages a peer may deliver per second before further ones are dropped
+pub const RATE_LIMIT_MESSAGES_PER_SECOND: f64 = 10.0;
+
+/// Messages a peer may deliver in a burst after being quiet for a while
+pub const RATE_LIMIT_BURST: f64 = 20.0;
+
+/// Messages remembered to drop duplicates delivered via different peers
+pub const SEEN_CACHE_CAPACITY: usize = 4096;
+
+/// A message seen again after this long is handled again, matching gossipsub's duplicate cache
+pub const SEEN_CACHE_TTL: Duration = Duration::from_secs(60);
+
+/// How often the network health is checked, logging a warning when it degrades
+pub const HEALTH_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Peers seen within this window count as recently seen in `net health`
+pub const HEALTH_RECENT_PEERS_WINDOW: Duration = Duration::from_secs(10 * 60);
+
+/// Without any gossip for this long the node considers itself partitioned, peer exchange alone
+/// sends a message every few minutes while connected
+pub const HEALTH_PARTITION_THRESHOLD: Duration = Duration::from_secs(10 * 60);
+
+/// How often peers are looked up at the rendezvous points
+pub const RENDEZVOUS_DISCOVER_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How often a sample of the address book is shared with connected peers
+pub const PEX_INTERVAL: Duration = Duration::from_secs(2 * 60);
+
+/// Peers shared in one peer exchange message
+pub const PEX_MAX_PEERS: usize = 16;
+
+/// Peers learned via peer exchange are only dialed while we have fewer connections than this
+pub const PEX_TARGET_PEERS: usize = 24;
+
+/// Genesis file read when none is configured
+pub const GENESIS_FILE_PATH: &str = "./genesis.json";
+
+/// Network joined without a genesis file
+pub const DEFAULT_CHAIN_ID: &str = "ant-chain-dev";
+
+/// Seconds the difficulty schedule aims for between two blocks, unless the chain config says otherwise
+pub const DEFAULT_TARGET_BLOCK_TIME: u64 = 30;
+
+/// Blocks between two difficulty adjustments
+pub const DEFAULT_RETARGET_INTERVAL: u64 = 10;
+
+/// Leading zero bits the hashes of the first blocks must have
+pub const DEFAULT_INITIAL_DIFFICULTY: u32 = 16;
+
+/// Bounds of the difficulty schedule
+pub const DEFAULT_MIN_DIFFICULTY: u32 = 8;
+pub const DEFAULT_MAX_DIFFICULTY: u32 = 64;
+
+/// Coins the coinbase of the first blocks may create
+pub const DEFAULT_INITIAL_REWARD: u64 = 50;
+
+/// Blocks after which the subsidy is halved
+pub const DEFAULT_HALVING_INTERVAL: u64 = 1_000;
+
+/// Percent of its stake a validator loses for signing two blocks of a slot
+pub const DEFAULT_SLASH_PERCENT: u64 = 10;
+
+/// Pending transactions kept before new ones are rejected
+pub const MEMPOOL_CAPACITY: usize = 10_000;
+
+/// Nonces a transaction may be ahead of its sender's next one to be queued until the gap fills
+pub const MEMPOOL_MAX_NONCE_GAP: u64 = 16;
+
+/// Transactions waiting for an earlier nonce of their sender kept before new ones are rejected
+pub const MEMPOOL_MAX_QUEUED: usize = 1_000;
+
+/// Recent blocks `ls validators` counts the proposals of each validator in
+pub const VALIDATOR_LIVENESS_BLOCKS: usize = 100;
+
+/// Proposals of recent slots remembered to catch validators signing two blocks of a slot
+pub const EQUIVOCATION_CACHE_SIZE: usize = 4096;
+
+/// Recent blocks whose transactions the fee estimate is based on
+pub const FEE_ESTIMATE_BLOCKS: usize = 20;
+
+/// Most transactions sent to a new peer when comparing mempools, keeps the response well under
+/// the 10 MB limit of the CBOR codec
+pub const MEMPOOL_RECONCILE_MAX_TXS: usize = 2_000;
+
+/// Most transactions a block may contain, unless the genesis file sets another limit
+pub const DEFAULT_MAX_BLOCK_TRANSACTIONS: usize = 1_000;
+
+/// Nonces the miner tries before it looks for a newer template
+pub const MINING_BATCH_NONCES: u64 = 10_000;
+
+/// Largest block in bytes of its JSON encoding, unless the genesis file sets another limit
+pub const DEFAULT_MAX_BLOCK_SIZE: usize = 1_000_000;
+
+/// Most gas a contract call may be given, gas is the fuel of the VM, roughly one unit per WASM
+/// instruction
+pub const VM_MAX_GAS: u64 = 10_000_000;
+
+/// Least gas a contract call may be given, so every call pays a fee
+pub const VM_MIN_GAS: u64 = 1_000;
+
+/// Gas limit of a call that can not be tried on the tip first, e.g. of a contract still pending
+pub const DEFAULT_CALL_GAS: u64 = 100_000;
+
+/// Gas the calls of a block may be given together, unless the genesis file sets another limit
+pub const DEFAULT_MAX_BLOCK_GAS: u64 = 50_000_000;
+
+/// Coins a call pays for every 1000 gas of its limit, unless the genesis file sets another price
+pub const DEFAULT_GAS_PRICE: u64 = 1;
+
+/// Largest memory in bytes a contract may grow to
+pub const VM_MAX_MEMORY: usize = 16 * 1024 * 1024;
+
+/// Seconds a block timestamp may be ahead of our clock
+pub const MAX_FUTURE_BLOCK_TIME: u64 = 2 * 60 * 60;
+
+/// Blocks whose median timestamp a new block must be stamped after, so a few miners lying about
+/// the time can not drag it back to lower the difficulty
+pub const MEDIAN_TIME_SPAN: usize = 11;
+
+/// Most blocks a reorganization may take off the chain, a branch forking off deeper is refused
+/// so blocks this far below the tip are final
+pub const MAX_REORG_DEPTH: u64 = 32;
+
+/// Blocks whose bodies are discarded together, the ledger below them is stored each time
+pub const PRUNE_INTERVAL: u64 = 100;
+
+/// Heights above our tip votes are counted for, votes may arrive before their block
+pub const FINALITY_VOTE_WINDOW: u64 = 16;
+
+/// Blocks kept while their parent is missing, the oldest one is dropped for a new one
+pub const ORPHAN_POOL_CAPACITY: usize = 64;
+
+/// How long a block waits for its parent before it is dropped
+pub const ORPHAN_TTL: Duration = Duration::from_secs(10 * 60);
+
+/// How often the topics we serve recipes on are announced
+pub const PRESENCE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Announcements older than this are considered stale, the peer probably left
+pub const PRESENCE_TTL: Duration = Duration::from_secs(3 * 60);
+
+/// Time given to gossipsub to tell peers about our unsubscriptions before disconnecting
+pub const SHUTDOWN_UNSUBSCRIBE_GRACE: Duration = Duration::from_millis(500);
+
 /// Key pair enables us to communicate securely with the rest of the network, making sure no one can impersonate
-pub static KEYS: Lazy<identity::Keypair> = Lazy::new(identity::Keypair::generate_ed25519);
+///
+/// Persisted in the identity file so the peer id survives restarts
+pub static KEYS: Lazy<identity::Keypair> = Lazy::new(|| {
+    node_identity::load_or_generate(&CONFIG.identity_file, CONFIG.new_identity)
+        .expect("can load node identity")
+});
 
 /// A unique identifier for a specific peer within the whole peer to peer network
 ///
@@ -13,5 +367,24 @@ pub static KEYS: Lazy<identity::Keypair> = Lazy::new(identity::Keypair::generate
 pub static PEER_ID: Lazy<PeerId> = Lazy::new(|| PeerId::from(KEYS.public()));
 
 /// 适合 静态变量 或 全局变量 需要惰性初始化的场景。
-/// A Topic is a concept from Floodsub, which is an implementation of libp2p’s pub/sub interface
-pub static TOPIC: Lazy<Topic> = Lazy::new(|| Topic::new("recipes"));
\ No newline at end of file
+/// A Topic is a concept from Gossipsub, which is an implementation of libp2p’s pub/sub interface
+pub static TOPIC: Lazy<IdentTopic> = Lazy::new(|| IdentTopic::new("recipes"));
+
+/// Topic peer exchange messages are published on
+pub static PEX_TOPIC: Lazy<IdentTopic> = Lazy::new(|| IdentTopic::new("ant-chain/pex"));
+
+/// Topic new transactions are published on
+pub static TXS_TOPIC: Lazy<IdentTopic> = Lazy::new(|| IdentTopic::new("ant-chain/txs"));
+
+/// Topic newly mined blocks are published on
+pub static BLOCKS_TOPIC: Lazy<IdentTopic> = Lazy::new(|| IdentTopic::new("ant-chain/blocks"));
+
+/// Topic finality votes of the validators are published on
+pub static VOTES_TOPIC: Lazy<IdentTopic> = Lazy::new(|| IdentTopic::new("ant-chain/votes"));
+
+/// Topic presence announcements are published on
+pub static PRESENCE_TOPIC: Lazy<IdentTopic> = Lazy::new(|| IdentTopic::new("ant-chain/presence"));
+
+/// Namespace recipe nodes register under at rendezvous points
+pub static RENDEZVOUS_NAMESPACE: Lazy<Namespace> =
+    Lazy::new(|| Namespace::from_static("ant-chain/recipes"));
@@ -0,0 +1,364 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::blockchain::Hash;
+use crate::consts::{VM_MAX_GAS, VM_MIN_GAS};
+use crate::ledger::LedgerError;
+use crate::transaction::{Address, ContractOp, Transaction, TxKind};
+use crate::trie::StateTrie;
+use crate::vm::{self, Storage};
+
+/// Code of a deployed contract and what it stored so far
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Contract {
+    pub creator: Address,
+    #[serde(with = "serde_bytes")]
+    pub code: Vec<u8>,
+    pub storage: Storage,
+}
+
+/// Outcome of a confirmed transaction, a failed call pays its fee and changes no storage
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Receipt {
+    /// Why the transaction failed, none when it succeeded
+    pub error: Option<String>,
+    /// Gas the contract burned, zero for transactions that run no contract
+    pub gas_used: u64,
+    /// Contract the transaction deployed or called
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub contract: Option<Address>,
+    #[serde(with = "serde_bytes")]
+    pub output: Vec<u8>,
+    /// Payloads the contract emitted, in order
+    pub events: Vec<Vec<u8>>,
+}
+
+impl Receipt {
+    /// SHA-256 over the CBOR encoding, the leaves of the receipts root of a block
+    pub fn hash(&self) -> Hash {
+        let mut bytes = Vec::new();
+        ciborium::into_writer(self, &mut bytes).expect("receipts encode");
+        Hash::digest(&bytes)
+    }
+}
+
+/// Which contract events a log query returns
+#[derive(Debug, Clone, Default)]
+pub struct LogFilter {
+    pub from: u64,
+    /// Last height searched, the tip when none
+    pub to: Option<u64>,
+    pub contract: Option<Address>,
+    /// Bytes the event starts with, e.g. the name a contract gives its events
+    pub prefix: Vec<u8>,
+}
+
+impl LogFilter {
+    pub fn matches(&self, contract: &Address, event: &[u8]) -> bool {
+        self.contract.map_or(true, |wanted| wanted == *contract) && event.starts_with(&self.prefix)
+    }
+}
+
+/// A contract event with the transaction that emitted it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Log {
+    pub height: u64,
+    pub txid: Hash,
+    pub contract: Address,
+    pub data: Vec<u8>,
+}
+
+/// What a transaction changed in the contracts and receipts, so it can be reverted
+#[derive(Debug, Clone)]
+pub enum ContractUndo {
+    /// Only the receipt was recorded
+    Recorded(Hash),
+    Deployed {
+        txid: Hash,
+        contract: Address,
+    },
+    /// Storage of the contract before the call
+    Called {
+        txid: Hash,
+        contract: Address,
+        storage: Storage,
+    },
+}
+
+/// Contracts deployed on the chain and the receipts of the confirmed transactions, kept next to
+/// the balances by both ledger models
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ContractSet {
+    contracts: StateTrie<Address, Contract>,
+    /// Not part of the state root, receipts follow from the transactions
+    receipts: HashMap<Hash, Receipt>,
+    gas_price: u64,
+}
+
+/// Coins `gas` costs at the price per 1000 gas, rounded up to at least one coin
+///
+/// Even at a price of zero a call pays, without a fee it spends no inputs and only its nonce
+/// tells it apart from a replay
+pub fn gas_fee(gas: u64, price: u64) -> u64 {
+    let fee = (gas as u128 * price as u128 + 999) / 1000;
+    fee.clamp(1, u64::MAX as u128) as u64
+}
+
+impl ContractSet {
+    /// No contracts yet, calls pay `gas_price` coins for every 1000 gas of their limit
+    pub fn new(gas_price: u64) -> ContractSet {
+        ContractSet {
+            gas_price,
+            ..ContractSet::default()
+        }
+    }
+
+    pub fn contract(&self, address: &Address) -> Option<&Contract> {
+        self.contracts.get(address)
+    }
+
+    pub fn receipt(&self, txid: &Hash) -> Option<&Receipt> {
+        self.receipts.get(txid)
+    }
+
+    /// Root committing to the code and storage of every contract
+    pub fn root(&self) -> Hash {
+        self.contracts.root()
+    }
+
+    /// Contract transactions move no coins, a deployment goes to the address derived from its
+    /// sender and nonce which must be free, and a call goes to a contract deployed before it or by
+    /// the pending transactions of the sender with a fee paying for its gas limit, at least
+    /// `VM_MIN_GAS`. A transaction with a receipt is confirmed already and refused
+    ///
+    /// Calls are not run here, a call that fails in its block still pays its whole fee
+    pub fn check(&self, tx: &Transaction, pending: &[&Transaction]) -> Result<(), LedgerError> {
+        let txid = tx.id();
+        if self.receipts.contains_key(&txid) {
+            return Err(LedgerError::Confirmed(txid));
+        }
+        let op = match (tx.kind, tx.contract.as_deref()) {
+            (TxKind::Deploy, Some(op @ ContractOp::Deploy { .. }))
+            | (TxKind::Call, Some(op @ ContractOp::Call { .. })) => op,
+            (TxKind::Deploy, _) | (TxKind::Call, _) | (_, Some(_)) => {
+                return Err(LedgerError::ContractMismatch)
+            }
+            (_, None) => return Ok(()),
+        };
+        if tx.amount != 0 {
+            return Err(LedgerError::ContractMismatch);
+        }
+        let deployed = |address: &Address| {
+            self.contracts.contains_key(address)
+                || pending
+                    .iter()
+                    .any(|tx| tx.kind == TxKind::Deploy && tx.to == *address)
+        };
+        match op {
+            ContractOp::Deploy { code } => {
+                if tx.to != Address::of_contract(&tx.from, tx.nonce) {
+                    return Err(LedgerError::ContractMismatch);
+                }
+                if deployed(&tx.to) {
+                    return Err(LedgerError::ContractExists(tx.to));
+                }
+                vm::check_code(code).map_err(LedgerError::InvalidCode)
+            }
+            ContractOp::Call {
+                contract,
+                gas_limit,
+                ..
+            } => {
+                if *contract != tx.to {
+                    return Err(LedgerError::ContractMismatch);
+                }
+                if !deployed(contract) {
+                    return Err(LedgerError::NoContract(*contract));
+                }
+                if *gas_limit > VM_MAX_GAS {
+                    return Err(LedgerError::GasLimitTooHigh {
+                        limit: *gas_limit,
+                        max: VM_MAX_GAS,
+                    });
+                }
+                if *gas_limit < VM_MIN_GAS {
+                    return Err(LedgerError::GasLimitTooLow {
+                        limit: *gas_limit,
+                        min: VM_MIN_GAS,
+                    });
+                }
+                let required = gas_fee(*gas_limit, self.gas_price);
+                if tx.fee < required {
+                    return Err(LedgerError::FeeBelowGas {
+                        fee: tx.fee,
+                        required,
+                    });
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Deploy the contract or run the call of a checked transaction and record its receipt,
+    /// every transaction of a block gets one
+    pub fn apply(&mut self, tx: &Transaction) -> ContractUndo {
+        let txid = tx.id();
+        let (receipt, undo) = match tx.contract.as_deref() {
+            None => (Receipt::default(), ContractUndo::Recorded(txid)),
+            Some(ContractOp::Deploy { code }) => {
+                let contract = Contract {
+                    creator: tx.from,
+                    code: code.clone(),
+                    storage: Storage::new(),
+                };
+                self.contracts.insert(tx.to, contract);
+                let receipt = Receipt {
+                    contract: Some(tx.to),
+                    ..Receipt::default()
+                };
+                let undo = ContractUndo::Deployed {
+                    txid,
+                    contract: tx.to,
+                };
+                (receipt, undo)
+            }
+            Some(ContractOp::Call {
+                contract,
+                method,
+                args,
+                gas_limit,
+            }) => match self.contracts.get(contract).cloned() {
+                // 交易检查过合约存在，这里只是兜底
+                None => {
+                    let receipt = Receipt {
+                        error: Some(LedgerError::NoContract(*contract).to_string()),
+                        contract: Some(*contract),
+                        ..Receipt::default()
+                    };
+                    (receipt, ContractUndo::Recorded(txid))
+                }
+                Some(mut deployed) => {
+                    let storage = deployed.storage.clone();
+                    let receipt = match vm::call(
+                        &deployed.code,
+                        &storage,
+                        method,
+                        args,
+                        &tx.from,
+                        *gas_limit,
+                    ) {
+                        Ok(execution) => {
+                            deployed.storage = execution.storage;
+                            self.contracts.insert(*contract, deployed);
+                            Receipt {
+                                error: None,
+                                gas_used: execution.gas_used,
+                                contract: Some(*contract),
+                                output: execution.output,
+                                events: execution.events,
+                            }
+                        }
+                        // 超出 gas 上限的调用按上限计，存储保持不变
+                        Err(failure) => Receipt {
+                            error: Some(failure.error.to_string()),
+                            gas_used: failure.gas_used,
+                            contract: Some(*contract),
+                            ..Receipt::default()
+                        },
+                    };
+                    let undo = ContractUndo::Called {
+                        txid,
+                        contract: *contract,
+                        storage,
+                    };
+                    (receipt, undo)
+                }
+            },
+        };
+        self.receipts.insert(txid, receipt);
+        undo
+    }
+
+    /// Revert `apply`, the changes of a block are reverted newest first
+    pub fn revert(&mut self, undo: Vec<ContractUndo>) {
+        for change in undo.into_iter().rev() {
+            match change {
+                ContractUndo::Recorded(txid) => {
+                    self.receipts.remove(&txid);
+                }
+                ContractUndo::Deployed { txid, contract } => {
+                    self.receipts.remove(&txid);
+                    self.contracts.remove(&contract);
+                }
+                ContractUndo::Called {
+                    txid,
+                    contract,
+                    storage,
+                } => {
+                    self.receipts.remove(&txid);
+                    if let Some(deployed) = self.contracts.get(&contract) {
+                        let mut deployed = deployed.clone();
+                        deployed.storage = storage;
+                        self.contracts.insert(contract, deployed);
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::consts::DEFAULT_GAS_PRICE;
+
+    const SENDER: Address = Address([3; 32]);
+
+    /// A contract set at the gas price with a contract doing nothing, and its address
+    fn deployed(gas_price: u64) -> (ContractSet, Address) {
+        let code = br#"(module (memory (export "memory") 1) (func (export "ping")))"#.to_vec();
+        let deploy = Transaction::deploy(SENDER, code, 0);
+        let mut contracts = ContractSet::new(gas_price);
+        contracts.check(&deploy, &[]).unwrap();
+        contracts.apply(&deploy);
+        (contracts, deploy.to)
+    }
+
+    fn ping(contract: Address, gas: u64, fee: u64) -> Transaction {
+        let mut tx = Transaction::call(SENDER, contract, "ping".to_owned(), Vec::new(), gas, 1);
+        tx.fee = fee;
+        tx
+    }
+
+    #[test]
+    fn a_call_always_pays_for_some_gas() {
+        let (contracts, contract) = deployed(0);
+        assert!(matches!(
+            contracts.check(&ping(contract, 0, 0), &[]),
+            Err(LedgerError::GasLimitTooLow { limit: 0, .. })
+        ));
+        assert!(matches!(
+            contracts.check(&ping(contract, VM_MIN_GAS, 0), &[]),
+            Err(LedgerError::FeeBelowGas {
+                fee: 0,
+                required: 1
+            })
+        ));
+        contracts
+            .check(&ping(contract, VM_MIN_GAS, 1), &[])
+            .unwrap();
+    }
+
+    #[test]
+    fn a_confirmed_call_is_refused() {
+        let (mut contracts, contract) = deployed(DEFAULT_GAS_PRICE);
+        let call = ping(contract, VM_MIN_GAS, 1);
+        contracts.check(&call, &[]).unwrap();
+        contracts.apply(&call);
+        assert!(matches!(
+            contracts.check(&call, &[]),
+            Err(LedgerError::Confirmed(txid)) if txid == call.id()
+        ));
+    }
+}
@@ -0,0 +1,287 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+
+use libp2p::identity::{ed25519, Keypair, SigningError};
+use serde::{Deserialize, Serialize};
+
+use crate::blockchain::Hash;
+use crate::consensus::Validator;
+use crate::consts::FINALITY_VOTE_WINDOW;
+use crate::transaction::Address;
+
+/// The two rounds of voting a block goes through before it is final
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum VotePhase {
+    /// The validator saw the block at the height and considers it valid
+    Prevote,
+    /// The validator saw two thirds of the stake prevote for the block
+    Precommit,
+}
+
+impl fmt::Display for VotePhase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VotePhase::Prevote => write!(f, "prevote"),
+            VotePhase::Precommit => write!(f, "precommit"),
+        }
+    }
+}
+
+/// Vote of a validator for the block at a height, gossiped on the votes topic
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Vote {
+    pub phase: VotePhase,
+    pub height: u64,
+    pub block: Hash,
+    pub voter: Address,
+    #[serde(with = "serde_bytes")]
+    pub signature: Vec<u8>,
+}
+
+impl Vote {
+    /// A vote signed with the key of the validator
+    pub fn signed(
+        phase: VotePhase,
+        height: u64,
+        block: Hash,
+        voter: Address,
+        keys: &Keypair,
+    ) -> Result<Vote, SigningError> {
+        let mut vote = Vote {
+            phase,
+            height,
+            block,
+            voter,
+            signature: Vec::new(),
+        };
+        vote.signature = keys.sign(&vote.signing_bytes())?;
+        Ok(vote)
+    }
+
+    fn signing_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(73);
+        bytes.push(match self.phase {
+            VotePhase::Prevote => 1,
+            VotePhase::Precommit => 2,
+        });
+        bytes.extend_from_slice(&self.height.to_be_bytes());
+        bytes.extend_from_slice(&self.block.0);
+        bytes.extend_from_slice(&self.voter.0);
+        bytes
+    }
+
+    /// Whether the signature was made by the key of the voter
+    pub fn verify(&self) -> bool {
+        match ed25519::PublicKey::try_from_bytes(&self.voter.0) {
+            Ok(key) => key.verify(&self.signing_bytes(), &self.signature),
+            Err(_) => false,
+        }
+    }
+}
+
+/// Tendermint-like voting on top of the chain, a block is final once validators with more than
+/// two thirds of the stake precommitted it, and precommits follow a two thirds prevote
+///
+/// Votes are weighed with the stake at our tip, and only the first vote of a validator for each
+/// phase and height counts
+#[derive(Debug, Default)]
+pub struct FinalityGadget {
+    /// Block each validator voted for, by height and phase, above the finalized height
+    votes: BTreeMap<(u64, VotePhase), HashMap<Address, Hash>>,
+    /// Block a quorum voted for in each phase and height, reported once
+    quorums: HashMap<(u64, VotePhase), Hash>,
+    /// Phases and heights we voted in ourselves
+    voted: HashSet<(u64, VotePhase)>,
+    finalized: Option<(u64, Hash)>,
+}
+
+impl FinalityGadget {
+    /// Height and hash of the last block two thirds of the stake precommitted
+    pub fn finalized(&self) -> Option<(u64, Hash)> {
+        self.finalized
+    }
+
+    /// Block validators with more than two thirds of the stake voted for in the phase
+    pub fn quorum(&self, height: u64, phase: VotePhase) -> Option<Hash> {
+        self.quorums.get(&(height, phase)).copied()
+    }
+
+    /// Whether we may still vote in the phase at the height, marks it voted
+    pub fn should_vote(&mut self, height: u64, phase: VotePhase) -> bool {
+        height > self.finalized_height() && self.voted.insert((height, phase))
+    }
+
+    /// Count the vote, returns the block once validators with more than two thirds of the stake
+    /// voted for it in the phase
+    ///
+    /// Votes of addresses without stake, far ahead of `tip` or at finalized heights are ignored
+    pub fn observe(&mut self, vote: &Vote, tip: u64, validators: &[Validator]) -> Option<Hash> {
+        if vote.height <= self.finalized_height()
+            || vote.height > tip.saturating_add(FINALITY_VOTE_WINDOW)
+            || validators
+                .iter()
+                .all(|validator| validator.address != vote.voter)
+        {
+            return None;
+        }
+        let key = (vote.height, vote.phase);
+        let votes = self.votes.entry(key).or_default();
+        votes.entry(vote.voter).or_insert(vote.block);
+        if self.quorums.contains_key(&key) {
+            return None;
+        }
+        let total = validators
+            .iter()
+            .fold(0u128, |sum, validator| sum + validator.stake as u128);
+        let stake = validators
+            .iter()
+            .filter(|validator| votes.get(&validator.address) == Some(&vote.block))
+            .fold(0u128, |sum, validator| sum + validator.stake as u128);
+        // 超过三分之二的权益
+        if stake * 3 <= total * 2 {
+            return None;
+        }
+        self.quorums.insert(key, vote.block);
+        Some(vote.block)
+    }
+
+    /// Mark the block final and forget the votes up to its height
+    pub fn finalize(&mut self, height: u64, block: Hash) {
+        if height <= self.finalized_height() {
+            return;
+        }
+        self.finalized = Some((height, block));
+        self.votes = self.votes.split_off(&(height + 1, VotePhase::Prevote));
+        self.quorums.retain(|(voted, _), _| *voted > height);
+        self.voted.retain(|(voted, _)| *voted > height);
+    }
+
+    fn finalized_height(&self) -> u64 {
+        self.finalized.map_or(0, |(height, _)| height)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn staked(stakes: &[u64]) -> Vec<Validator> {
+        stakes
+            .iter()
+            .enumerate()
+            .map(|(i, stake)| Validator {
+                address: Address([i as u8 + 1; 32]),
+                stake: *stake,
+            })
+            .collect()
+    }
+
+    /// Unsigned vote, the gadget leaves checking signatures to the caller
+    fn vote(phase: VotePhase, height: u64, block: &[u8], voter: &Validator) -> Vote {
+        Vote {
+            phase,
+            height,
+            block: Hash::digest(block),
+            voter: voter.address,
+            signature: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn a_vote_is_verified_against_the_key_of_its_voter() {
+        let keys = Keypair::generate_ed25519();
+        let voter = Address::of(&keys.public()).unwrap();
+        let signed = Vote::signed(VotePhase::Prevote, 3, Hash::digest(b"a"), voter, &keys).unwrap();
+        assert!(signed.verify());
+        let moved = Vote {
+            height: 4,
+            ..signed.clone()
+        };
+        assert!(!moved.verify());
+        let precommit = Vote {
+            phase: VotePhase::Precommit,
+            ..signed
+        };
+        assert!(!precommit.verify());
+    }
+
+    #[test]
+    fn a_quorum_needs_more_than_two_thirds_of_the_stake_for_one_block() {
+        let validators = staked(&[1, 1, 1]);
+        let mut gadget = FinalityGadget::default();
+        let phase = VotePhase::Prevote;
+        assert_eq!(
+            gadget.observe(&vote(phase, 1, b"a", &validators[0]), 1, &validators),
+            None
+        );
+        // 第二票投给了别的区块，也不能改票
+        assert_eq!(
+            gadget.observe(&vote(phase, 1, b"b", &validators[1]), 1, &validators),
+            None
+        );
+        assert_eq!(
+            gadget.observe(&vote(phase, 1, b"b", &validators[0]), 1, &validators),
+            None
+        );
+        assert_eq!(
+            gadget.observe(&vote(phase, 1, b"a", &validators[2]), 1, &validators),
+            None
+        );
+        assert_eq!(gadget.quorum(1, phase), None);
+
+        let validators = staked(&[3, 1, 1]);
+        let mut gadget = FinalityGadget::default();
+        assert_eq!(
+            gadget.observe(&vote(phase, 1, b"a", &validators[1]), 1, &validators),
+            None
+        );
+        assert_eq!(
+            gadget.observe(&vote(phase, 1, b"a", &validators[0]), 1, &validators),
+            Some(Hash::digest(b"a"))
+        );
+        assert_eq!(gadget.quorum(1, phase), Some(Hash::digest(b"a")));
+        assert_eq!(gadget.quorum(1, VotePhase::Precommit), None);
+        // 达成后只报告一次
+        assert_eq!(
+            gadget.observe(&vote(phase, 1, b"a", &validators[2]), 1, &validators),
+            None
+        );
+    }
+
+    #[test]
+    fn votes_at_finalized_heights_far_ahead_or_without_stake_are_ignored() {
+        let validators = staked(&[1]);
+        let stranger = Validator {
+            address: Address([9; 32]),
+            stake: 1,
+        };
+        let phase = VotePhase::Precommit;
+        let mut gadget = FinalityGadget::default();
+        assert_eq!(
+            gadget.observe(&vote(phase, 2, b"a", &stranger), 2, &validators),
+            None
+        );
+        let far = 2 + FINALITY_VOTE_WINDOW + 1;
+        assert_eq!(
+            gadget.observe(&vote(phase, far, b"a", &validators[0]), 2, &validators),
+            None
+        );
+        assert!(gadget.should_vote(2, phase));
+        assert!(!gadget.should_vote(2, phase));
+
+        gadget.finalize(2, Hash::digest(b"a"));
+        assert_eq!(gadget.finalized(), Some((2, Hash::digest(b"a"))));
+        assert_eq!(
+            gadget.observe(&vote(phase, 2, b"a", &validators[0]), 2, &validators),
+            None
+        );
+        assert!(!gadget.should_vote(1, VotePhase::Prevote));
+        gadget.finalize(1, Hash::digest(b"b"));
+        assert_eq!(gadget.finalized(), Some((2, Hash::digest(b"a"))));
+        assert_eq!(
+            gadget.observe(&vote(phase, 3, b"c", &validators[0]), 2, &validators),
+            Some(Hash::digest(b"c"))
+        );
+    }
+}
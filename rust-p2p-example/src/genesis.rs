@@ -0,0 +1,88 @@
+use std::fs;
+use std::io::ErrorKind;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use log::info;
+use serde::{Deserialize, Serialize};
+
+use crate::blockchain::{Block, ChainParams, Hash};
+use crate::consensus::Validator;
+use crate::consts::{DEFAULT_CHAIN_ID, GENESIS_FILE_PATH};
+use crate::transaction::Address;
+use crate::wallet::Network;
+
+/// Coins credited to an address in the genesis block
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Allocation {
+    pub address: Address,
+    pub amount: u64,
+}
+
+/// Definition of a network, nodes only talk chain with nodes that started from the same one
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Genesis {
+    pub chain_id: String,
+    /// Decides the prefix of the addresses, left out for mainnet so its genesis hash is unchanged
+    #[serde(default, skip_serializing_if = "Network::is_mainnet")]
+    pub network: Network,
+    /// Unix time in seconds of the genesis block
+    pub timestamp: u64,
+    #[serde(default)]
+    pub allocations: Vec<Allocation>,
+    /// Stake of the validators of a proof-of-stake network
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub validators: Vec<Validator>,
+    /// Consensus parameters, including the initial difficulty
+    #[serde(flatten)]
+    pub params: ChainParams,
+}
+
+impl Default for Genesis {
+    fn default() -> Self {
+        Genesis {
+            chain_id: DEFAULT_CHAIN_ID.to_owned(),
+            network: Network::Mainnet,
+            timestamp: 0,
+            allocations: Vec::new(),
+            validators: Vec::new(),
+            params: ChainParams::default(),
+        }
+    }
+}
+
+impl Genesis {
+    /// Load the configured genesis file, without one the default file is used when it exists and
+    /// the built-in development network otherwise
+    pub fn load(path: Option<&Path>) -> Result<Genesis> {
+        let (path, required) = match path {
+            Some(path) => (path, true),
+            None => (Path::new(GENESIS_FILE_PATH), false),
+        };
+        let content = match fs::read(path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == ErrorKind::NotFound && !required => {
+                info!("No genesis file, joining the {} network", DEFAULT_CHAIN_ID);
+                return Ok(Genesis::default());
+            }
+            Err(e) => {
+                return Err(e)
+                    .with_context(|| format!("can not read genesis file {}", path.display()))
+            }
+        };
+        let genesis = serde_json::from_slice(&content)
+            .with_context(|| format!("invalid genesis file {}", path.display()))?;
+        Ok(genesis)
+    }
+
+    /// The genesis block commits to the whole definition, so networks that differ in any
+    /// parameter have different genesis hashes
+    pub fn block(&self) -> Block {
+        let data = serde_json::to_string(self).expect("genesis can be serialized");
+        let difficulty = self.params.initial_difficulty;
+        let mut block = Block::new(0, Hash::default(), difficulty, data, Vec::new());
+        block.header.timestamp = self.timestamp;
+        block.hash = block.compute_hash();
+        block
+    }
+}
@@ -1,152 +1,4216 @@
-use std::collections::HashSet;
+use std::cmp;
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use std::time::{Duration, Instant};
 
 use anyhow::Result;
-use libp2p::floodsub::FloodsubEvent;
+use libp2p::bandwidth::BandwidthSinks;
+use libp2p::core::ConnectedPoint;
 use libp2p::futures::StreamExt;
+use libp2p::gossipsub::{IdentTopic, TopicHash};
+use libp2p::identity::Keypair;
 use libp2p::mdns::Event;
-use libp2p::swarm::SwarmEvent;
-use libp2p::Swarm;
-use log::{debug, error, info};
+use libp2p::multiaddr::Protocol;
+use libp2p::request_response::{self, Message, RequestId};
+use libp2p::swarm::dial_opts::DialOpts;
+use libp2p::swarm::{
+    ConnectionDenied, ConnectionError, ConnectionId, DialError, ListenError, SwarmEvent,
+};
+use libp2p::{
+    autonat, connection_limits, dcutr, gossipsub, identify, kad, ping, relay, rendezvous, upnp,
+    Multiaddr, PeerId, Swarm,
+};
+use log::{debug, error, info, warn};
 use tokio::fs;
 use tokio::sync::mpsc;
 
+use crate::accounts::AccountProof;
+use crate::backup::{self, Backup};
 use crate::behaviour::{RecipeBehaviour, RecipeBehaviourEvent};
-use crate::consts::{PEER_ID, STORAGE_FILE_PATH, TOPIC};
-use crate::models::{ListMode, ListRequest, ListResponse, Recipe};
+use crate::blobs;
+use crate::blockchain::{Block, BlockHeader, Chain, Hash};
+use crate::bootstrap::peer_id_of;
+use crate::codec;
+use crate::config::CONFIG;
+use crate::consensus::ConsensusKind;
+use crate::consts::{
+    BLOCKS_TOPIC, CBOR_MIN_PROTOCOL_VERSION, COMPRESSION_MIN_PROTOCOL_VERSION, DEFAULT_CALL_GAS,
+    ENVELOPE_MIN_PROTOCOL_VERSION, FEE_ESTIMATE_BLOCKS, HEALTH_RECENT_PEERS_WINDOW, KEYS,
+    MEMPOOL_RECONCILE_MAX_TXS, MESSAGE_VERSION, PEER_ID, PEX_MAX_PEERS, PEX_MIN_PROTOCOL_VERSION,
+    PEX_TARGET_PEERS, PEX_TOPIC, PRESENCE_TOPIC, PRESENCE_TTL, SHUTDOWN_UNSUBSCRIBE_GRACE,
+    STORAGE_BENCHMARK_BATCHES, SYNC_BATCH_SIZE, SYNC_HEADERS_BATCH_SIZE, TOPIC, TXS_TOPIC,
+    VALIDATOR_LIVENESS_BLOCKS, VM_MAX_GAS, VM_MIN_GAS, VOTES_TOPIC, WALLET_RESTORE_GAP,
+    WIRE_BENCHMARK_ITERATIONS,
+};
+use crate::contracts::{self, LogFilter};
+use crate::finality::{Vote, VotePhase};
+use crate::light::Query;
+use crate::merkle::{self, MerkleProof};
+use crate::miner::Miner;
+use crate::models::{
+    EventType, GossipMessage, ListMode, ListRequest, ListResponse, MessageEnvelope, MessageKind,
+    PeerExchange, PeerRecord, Presence,
+};
+use crate::multisig::{MultisigSpend, Policy};
+use crate::peer_score::Verdict;
+use crate::snapshot;
+use crate::state::{NodeState, PeerPresence, UpnpStatus};
+use crate::storage;
+use crate::sync::{ChainStatus, Download, Fetch, SyncEvent, SyncRequest, SyncResponse};
+use crate::transaction::{Address, ContractOp, LockTime, OutPoint, Transaction, TxKind};
+use crate::transfer;
+use crate::validation::ValidationError;
+use crate::vm::{self, Storage};
+use crate::wallet;
+use crate::wire::{self, WireFormat};
 
-pub async fn handle_list_peers(swarm: &mut Swarm<RecipeBehaviour>) {
-    info!("Discovered Peers:");
-    let nodes = swarm.behaviour().mdns.discovered_nodes();
+pub async fn handle_list_peers(swarm: &mut Swarm<RecipeBehaviour>, state: &NodeState) {
+    let mut unique_peers = HashSet::new();
+    match swarm.behaviour().mdns.as_ref() {
+        Some(mdns) => {
+            info!("Discovered Peers:");
+            for peer in mdns.discovered_nodes() {
+                unique_peers.insert(*peer);
+            }
+            unique_peers
+                .iter()
+                .for_each(|p| info!("{}", peer_topics(swarm, state, p)));
+        }
+        None => info!("mDNS is disabled, start the node without --no-mdns"),
+    }
+    // 通过 DHT、引导节点等其他途径认识的节点
+    let others: Vec<&PeerId> = state
+        .presence
+        .keys()
+        .filter(|p| !unique_peers.contains(*p))
+        .collect();
+    if !others.is_empty() {
+        info!("Other Peers Announcing Presence:");
+        others
+            .iter()
+            .for_each(|p| info!("{}", peer_topics(swarm, state, p)));
+    }
+}
+
+/// The topics a peer serves recipes on according to its presence announcements, and the topics
+/// it is subscribed to
+fn peer_topics(swarm: &Swarm<RecipeBehaviour>, state: &NodeState, peer_id: &PeerId) -> String {
+    let serves = match state.presence.get(peer_id) {
+        Some(presence) if presence.received.elapsed() < PRESENCE_TTL => {
+            format!("serves [{}]", presence.topics.join(", "))
+        }
+        Some(_) => "presence expired".to_owned(),
+        None => "no presence announced".to_owned(),
+    };
+    let subscribed: Vec<&str> = swarm
+        .behaviour()
+        .gossipsub
+        .all_peers()
+        .find(|(p, _)| *p == peer_id)
+        .map(|(_, topics)| topics.into_iter().map(|t| t.as_str()).collect())
+        .unwrap_or_default();
+    format!(
+        "{}: {}, subscribed to [{}]",
+        peer_id,
+        serves,
+        subscribed.join(", ")
+    )
+}
+
+pub async fn handle_list_dht_peers(swarm: &mut Swarm<RecipeBehaviour>) {
+    info!("DHT Routing Table:");
+    for bucket in swarm.behaviour_mut().kad.kbuckets() {
+        for entry in bucket.iter() {
+            let addrs: Vec<String> = entry.node.value.iter().map(|a| a.to_string()).collect();
+            info!(
+                "{} ({:?}): {}",
+                entry.node.key.preimage(),
+                entry.status,
+                addrs.join(", ")
+            );
+        }
+    }
+}
+
+pub async fn handle_list_peer_latencies(swarm: &mut Swarm<RecipeBehaviour>, state: &NodeState) {
+    info!("Connected Peers Latency:");
+    for peer in swarm.connected_peers() {
+        match state.latencies.get(peer) {
+            Some(latency) => info!(
+                "{}: last {:?}, average {:?} over {} pings",
+                peer,
+                latency.last,
+                latency.average(),
+                latency.samples
+            ),
+            None => info!("{}: no ping yet", peer),
+        }
+    }
+}
+
+pub async fn handle_list_peer_scores(state: &NodeState) {
+    info!("Peer Scores:");
+    state.peer_scores.iter().for_each(|(peer, score)| {
+        info!(
+            "{}: score {}, messages {}, rate {:.2}/s, good responses {}, invalid {}, invalid blocks {}, rate violations {}, abrupt disconnects {}",
+            peer,
+            score.score(),
+            score.messages,
+            score.rate(),
+            score.good_responses,
+            score.invalid_messages,
+            score.invalid_blocks,
+            score.rate_violations,
+            score.abrupt_disconnects
+        );
+        if let Some(reason) = &score.last_rejection {
+            info!("  last block rejected: {}", reason);
+        }
+    });
+}
+
+pub async fn handle_net_stats(state: &NodeState, bandwidth: &BandwidthSinks) {
+    info!(
+        "Transport: {} bytes received, {} bytes sent",
+        bandwidth.total_inbound(),
+        bandwidth.total_outbound()
+    );
+    let events = &state.telemetry.counters;
+    info!(
+        "Connections: {} opened, {} closed, {} failed dials, {} failed incoming",
+        events.connections_opened,
+        events.connections_closed,
+        events.dial_failures,
+        events.incoming_failures
+    );
+    info!(
+        "Swarm events: {} gossip messages, {} other behaviour events",
+        events.messages_received, events.behaviour_events
+    );
+    info!(
+        "Duplicate messages dropped: {}",
+        state.seen_messages.duplicates
+    );
+    let dials = &state.net_stats.dials;
+    info!("Dials Per Transport:");
+    dials.routes.iter().for_each(|(route, c)| {
+        info!(
+            "{}: {} attempted, {} succeeded, {} failed",
+            route, c.attempted, c.succeeded, c.failed
+        )
+    });
+    info!(
+        "Dials failed without an address: {}",
+        dials.failed_without_address
+    );
+    info!(
+        "Hole punches: {} attempted, {} succeeded, {} failed",
+        dials.hole_punches.attempted, dials.hole_punches.succeeded, dials.hole_punches.failed
+    );
+    info!("Gossip Per Peer:");
+    state.net_stats.peers.iter().for_each(|(peer, c)| {
+        info!(
+            "{}: {} messages ({} bytes) received, {} dropped",
+            peer, c.messages_in, c.bytes_in, c.dropped
+        )
+    });
+    info!("Gossip Per Topic:");
+    state.net_stats.topics.iter().for_each(|(topic, c)| {
+        info!(
+            "{}: {} messages ({} bytes) received, {} dropped, {} messages ({} bytes) sent",
+            topic, c.messages_in, c.bytes_in, c.dropped, c.messages_out, c.bytes_out
+        )
+    });
+}
+
+pub async fn handle_net_health(swarm: &Swarm<RecipeBehaviour>, state: &NodeState) {
+    let report = state.health.report(swarm);
+    info!("Network Health:");
+    info!("Connected peers: {}", report.connected_peers);
+    info!(
+        "Peers seen in the last {:?}: {}",
+        HEALTH_RECENT_PEERS_WINDOW, report.recent_peers
+    );
+    let empty: Vec<&str> = report.empty_topics.iter().map(|t| t.as_str()).collect();
+    info!("Topics without subscribers: {}", empty.join(", "));
+    info!("No gossip received for {:?}", report.silent_for);
+    if report.partitioned() {
+        warn!(
+            "no messages for {:?}, the node appears partitioned",
+            report.silent_for
+        );
+    } else if report.connected_peers == 0 {
+        warn!("no connected peers");
+    } else {
+        info!("Status: healthy");
+    }
+}
+
+pub async fn handle_list_topics(swarm: &mut Swarm<RecipeBehaviour>) {
+    info!("Subscribed Topics:");
+    let gossipsub = &swarm.behaviour().gossipsub;
+    for topic in gossipsub.topics() {
+        let peers = gossipsub
+            .all_peers()
+            .filter(|(_, topics)| topics.contains(&topic))
+            .count();
+        info!(
+            "{}: {} peers, {} in mesh",
+            topic,
+            peers,
+            gossipsub.mesh_peers(topic).count()
+        );
+    }
+}
+
+pub async fn handle_topic_mesh(cmd: &str, swarm: &Swarm<RecipeBehaviour>, state: &NodeState) {
+    if let Some(rest) = cmd.strip_prefix("topic mesh") {
+        let topic = IdentTopic::new(rest.trim()).hash();
+        let gossipsub = &swarm.behaviour().gossipsub;
+        let report = state.mesh.report(gossipsub, &topic);
+        // 只有支持 gossipsub 协议的节点才能加入 mesh
+        let kinds: HashMap<_, _> = gossipsub.peer_protocol().collect();
+        let peer_line = |p: &PeerId| match kinds.get(p) {
+            Some(kind) => format!("  {} ({})", p, kind),
+            None => format!("  {}", p),
+        };
+        if report.subscribed {
+            info!("Mesh of {}:", topic);
+        } else {
+            info!("Mesh of {} (not subscribed):", topic);
+        }
+        info!("Mesh peers: {}", report.mesh.len());
+        report.mesh.iter().for_each(|p| info!("{}", peer_line(p)));
+        info!("Fanout peers: {}", report.fanout.len());
+        report.fanout.iter().for_each(|p| info!("{}", peer_line(p)));
+        info!("Backoff: {}", report.backoff.len());
+        report
+            .backoff
+            .iter()
+            .for_each(|(p, remaining)| info!("{}: {}s left", peer_line(p), remaining.as_secs()));
+        info!("Other subscribers: {}", report.gossip_only.len());
+        report
+            .gossip_only
+            .iter()
+            .for_each(|p| info!("{}", peer_line(p)));
+    }
+}
+
+pub async fn handle_subscribe(cmd: &str, swarm: &mut Swarm<RecipeBehaviour>) {
+    if let Some(rest) = cmd.strip_prefix("sub") {
+        let topic = IdentTopic::new(rest.trim());
+        match swarm.behaviour_mut().gossipsub.subscribe(&topic) {
+            Ok(true) => info!("Subscribed to {}", topic),
+            Ok(false) => info!("already subscribed to {}", topic),
+            Err(e) => error!("error subscribing to {}: {:?}", topic, e),
+        }
+    }
+}
+
+pub async fn handle_unsubscribe(cmd: &str, swarm: &mut Swarm<RecipeBehaviour>) {
+    if let Some(rest) = cmd.strip_prefix("unsub") {
+        let topic = IdentTopic::new(rest.trim());
+        match swarm.behaviour_mut().gossipsub.unsubscribe(&topic) {
+            Ok(true) => info!("Unsubscribed from {}", topic),
+            Ok(false) => info!("not subscribed to {}", topic),
+            Err(e) => error!("error unsubscribing from {}: {:?}", topic, e),
+        }
+    }
+}
+
+pub async fn handle_list_chain(state: &NodeState) {
+    info!("Chain ({} blocks):", state.chain.height() + 1);
+    state.chain.iter().for_each(|block| {
+        info!(
+            "#{} {} prev {} at {} difficulty {} nonce {}, {} transactions: {:?}",
+            block.header.index,
+            block.hash,
+            block.header.prev_hash,
+            block.header.timestamp,
+            block.header.difficulty,
+            block.header.nonce,
+            block.transactions.len(),
+            block.data
+        )
+    });
+}
+
+/// Show a block by height or hash, e.g. `block 12` or `block <hash>`
+pub async fn handle_show_block(cmd: &str, state: &NodeState) {
+    let arg = cmd.strip_prefix("block").unwrap_or_default().trim();
+    let block = match arg.parse::<u64>() {
+        Ok(index) => state.chain.block(index),
+        Err(_) => match arg.parse::<Hash>() {
+            Ok(hash) => state.chain.find_block(&hash),
+            Err(_) => {
+                error!("usage: block <height|hash>");
+                return;
+            }
+        },
+    };
+    let block = match block {
+        Some(block) => block,
+        None => {
+            error!("no block {} in the chain", arg);
+            return;
+        }
+    };
+    let header = &block.header;
+    info!("Block {} {}", header.index, block.hash);
+    info!(
+        "  confirmations: {}",
+        state.chain.height() - header.index + 1
+    );
+    info!("  prev:          {}", header.prev_hash);
+    info!("  timestamp:     {}", header.timestamp);
+    info!("  difficulty:    {}", header.difficulty);
+    info!("  nonce:         {}", header.nonce);
+    info!("  merkle root:   {}", header.merkle_root);
+    info!("  state root:    {}", header.state_root);
+    if state.chain.is_pruned(header.index) {
+        info!("  body:          pruned");
+        return;
+    }
+    info!("  data:          {:?}", block.data);
+    info!("  transactions:  {}", block.transactions.len());
+    for tx in block.transactions.iter() {
+        info!("    {}", tx_summary(tx));
+    }
+}
+
+/// Show a confirmed or pending transaction, e.g. `tx <txid>`
+pub async fn handle_show_tx(cmd: &str, state: &NodeState) {
+    let txid: Hash = match cmd.strip_prefix("tx").map(|rest| rest.trim().parse()) {
+        Some(Ok(txid)) => txid,
+        _ => {
+            error!("usage: tx <txid>");
+            return;
+        }
+    };
+    let (tx, status) = match state.chain.find_tx(&txid) {
+        Some((block, tx)) => (
+            tx,
+            format!(
+                "confirmed in block {} {}, {} confirmations",
+                block.header.index,
+                block.hash,
+                state.chain.height() - block.header.index + 1
+            ),
+        ),
+        None => match state.mempool.get(&txid) {
+            Some(tx) => (tx, "pending".to_owned()),
+            None => {
+                error!("transaction {} is neither in the chain nor pending", txid);
+                return;
+            }
+        },
+    };
+    info!("Transaction {}", txid);
+    info!("  status: {}", status);
+    if tx.is_coinbase() {
+        info!("  from:   coinbase");
+    } else {
+        info!("  from:   {}", tx.from);
+    }
+    info!("  to:     {}", tx.to);
+    info!("  kind:   {}", tx.kind);
+    info!("  amount: {}", tx.amount);
+    info!("  fee:    {}", tx.fee);
+    info!("  nonce:  {}", tx.nonce);
+    if let Some(locktime) = tx.locktime {
+        info!("  locked: until {}", locktime);
+    }
+    if !tx.inputs.is_empty() {
+        info!("  change: {}", tx.change);
+        for input in tx.inputs.iter() {
+            info!("  input:  {}", input);
+        }
+    }
+    if let Some(ContractOp::Call { method, args, .. }) = tx.contract.as_deref() {
+        info!("  method: {} {:?}", method, String::from_utf8_lossy(args));
+    }
+    if let Some(receipt) = state.chain.ledger().receipt(&txid) {
+        match &receipt.error {
+            Some(e) => info!("  result: failed, {}", e),
+            None if receipt.contract.is_some() => info!(
+                "  result: returned {:?} using {} gas",
+                String::from_utf8_lossy(&receipt.output),
+                receipt.gas_used
+            ),
+            None => info!("  result: ok"),
+        }
+        for event in receipt.events.iter() {
+            info!("  event:  {:?}", String::from_utf8_lossy(event));
+        }
+    }
+}
+
+/// Show the receipt of a confirmed transaction, e.g. `receipt <txid>`
+pub async fn handle_receipt(cmd: &str, state: &NodeState) {
+    let txid: Hash = match cmd.strip_prefix("receipt").map(|rest| rest.trim().parse()) {
+        Some(Ok(txid)) => txid,
+        _ => {
+            error!("usage: receipt <txid>");
+            return;
+        }
+    };
+    let receipt = match state.chain.ledger().receipt(&txid) {
+        Some(receipt) => receipt,
+        None => {
+            error!("transaction {} is not confirmed", txid);
+            return;
+        }
+    };
+    info!("Receipt of {}", txid);
+    if let Some((block, _)) = state.chain.find_tx(&txid) {
+        info!("  block:    {} {}", block.header.index, block.hash);
+    }
+    match &receipt.error {
+        Some(e) => info!("  status:   failed, {}", e),
+        None => info!("  status:   ok"),
+    }
+    info!("  gas used: {}", receipt.gas_used);
+    if let Some(contract) = receipt.contract {
+        info!("  contract: {}", contract);
+        info!("  output:   {:?}", String::from_utf8_lossy(&receipt.output));
+    }
+    for event in receipt.events.iter() {
+        info!("  event:    {:?}", String::from_utf8_lossy(event));
+    }
+}
+
+/// Contract events of the confirmed transactions, e.g. `logs from 10 to 20 contract <address>
+/// prefix transfer`, every part of the filter is optional
+pub async fn handle_logs(cmd: &str, state: &NodeState) {
+    let usage = "usage: logs [from <height>] [to <height>] [contract <address>] [prefix <text>]";
+    let mut filter = LogFilter::default();
+    let mut args = cmd
+        .strip_prefix("logs")
+        .unwrap_or_default()
+        .split_whitespace();
+    while let Some(key) = args.next() {
+        let value = match args.next() {
+            Some(value) => value,
+            None => {
+                error!("{}", usage);
+                return;
+            }
+        };
+        let parsed = match key {
+            "from" => value.parse().map(|from| filter.from = from).is_ok(),
+            "to" => value.parse().map(|to| filter.to = Some(to)).is_ok(),
+            "contract" => match value.parse() {
+                Ok(contract) => {
+                    filter.contract = Some(contract);
+                    true
+                }
+                Err(e) => {
+                    error!("invalid address: {}", e);
+                    return;
+                }
+            },
+            "prefix" => {
+                filter.prefix = value.as_bytes().to_vec();
+                true
+            }
+            _ => false,
+        };
+        if !parsed {
+            error!("{}", usage);
+            return;
+        }
+    }
+    let logs = state.chain.logs(&filter);
+    info!("{} events:", logs.len());
+    for log in logs.iter() {
+        info!(
+            "  #{} {} {} {:?}",
+            log.height,
+            log.txid,
+            log.contract,
+            String::from_utf8_lossy(&log.data)
+        );
+    }
+}
+
+/// Summary of the block at the tip, e.g. `chain tip`
+pub async fn handle_chain_tip(state: &NodeState) {
+    let tip = state.chain.tip();
+    info!("Tip {} {}", tip.header.index, tip.hash);
+    info!("  consensus:       {}", state.chain.consensus());
+    if let Some(proposer) = tip.header.proposer {
+        info!("  proposer:        {}", proposer);
+    }
+    info!("  timestamp:       {}", tip.header.timestamp);
+    info!("  difficulty:      {}", tip.header.difficulty);
+    info!("  transactions:    {}", tip.transactions.len());
+    info!("  chain work:      {}", state.chain.work());
+    if state.chain.consensus() == ConsensusKind::Pos {
+        let stake: u64 = state.chain.validators().iter().map(|v| v.stake).sum();
+        info!(
+            "  validators:      {} with a stake of {}",
+            state.chain.validators().len(),
+            stake
+        );
+    } else {
+        info!("  next difficulty: {}", state.chain.next_difficulty());
+    }
+    info!("  next subsidy:    {}", state.chain.next_subsidy());
+    if state.chain.pruned_height() > 0 {
+        info!("  pruned up to:    {}", state.chain.pruned_height());
+    }
+    if let Some(checkpoint) = state.chain.last_checkpoint() {
+        info!(
+            "  checkpoint:      {} {}",
+            checkpoint.height, checkpoint.hash
+        );
+    }
+}
+
+/// One line per block from height to height, e.g. `chain range 10 20`
+pub async fn handle_chain_range(cmd: &str, state: &NodeState) {
+    let mut args = cmd
+        .strip_prefix("chain range")
+        .unwrap_or_default()
+        .split_whitespace()
+        .map(str::parse::<u64>);
+    let (from, to) = match (args.next(), args.next()) {
+        (Some(Ok(from)), Some(Ok(to))) if from <= to => (from, to),
+        _ => {
+            error!("usage: chain range <from> <to>");
+            return;
+        }
+    };
+    let to = cmp::min(to, state.chain.height());
+    info!("Blocks {} to {}:", from, to);
+    for block in (from..=to).filter_map(|index| state.chain.block(index)) {
+        info!(
+            "#{} {} at {} difficulty {}, {} transactions, data {:?}",
+            block.header.index,
+            block.hash,
+            block.header.timestamp,
+            block.header.difficulty,
+            block.transactions.len(),
+            block.data
+        );
+    }
+}
+
+fn tx_summary(tx: &Transaction) -> String {
+    if tx.is_coinbase() {
+        return format!("{} coinbase -> {} amount {}", tx.id(), tx.to, tx.amount);
+    }
+    if let Some(evidence) = tx.evidence.as_ref() {
+        return format!(
+            "{} slash {} -> {} for slot {} fee {}",
+            tx.id(),
+            tx.from,
+            tx.to,
+            evidence.slot,
+            tx.fee
+        );
+    }
+    format!(
+        "{} {} {} -> {} amount {} fee {}",
+        tx.id(),
+        tx.kind,
+        tx.from,
+        tx.to,
+        tx.amount,
+        tx.fee
+    )
+}
+
+/// Mine a block with the rest of the command as its data off the event loop, on a proof-of-stake
+/// chain the block is proposed instead when the node key is entitled to the current slot
+///
+/// `mine start [data]` keeps mining one block after another and `mine stop` stops
+pub async fn handle_mine(
+    cmd: &str,
+    event_sender: &mpsc::UnboundedSender<EventType>,
+    state: &mut NodeState,
+) {
+    let rest = match cmd.strip_prefix("mine") {
+        Some(rest) => rest.trim(),
+        None => return,
+    };
+    if state.light.is_some() {
+        error!("light nodes keep no blocks to mine on");
+        return;
+    }
+    if rest == "stop" {
+        match state.miner.take() {
+            Some(_) => info!("Stopped mining"),
+            None => info!("not mining"),
+        }
+        return;
+    }
+    let (data, continuous) = match rest.strip_prefix("start") {
+        Some(data) => (data.trim(), true),
+        None => (rest, false),
+    };
+    if let Some(miner) = state.miner.as_mut() {
+        if continuous && !miner.continuous {
+            miner.continuous = true;
+            info!("Mining continuously");
+        } else {
+            info!("already mining a block");
+        }
+        return;
+    }
+    let template = block_template(state, data.to_owned());
+    if state.chain.consensus() == ConsensusKind::Pos {
+        if continuous {
+            error!("proof-of-stake blocks are proposed in the slots of the node, use mine");
+            return;
+        }
+        let index = template.header.index;
+        match state.chain.propose(template, &KEYS) {
+            Ok(block) => {
+                info!(
+                    "Proposing block {} with {} transactions",
+                    index,
+                    block.transactions.len()
+                );
+                if let Err(e) = event_sender.send(EventType::BlockMined(block)) {
+                    error!("error sending proposed block via channel, {}", e);
+                }
+            }
+            Err(e) => error!("can not propose block {}: {}", index, e),
+        }
+        return;
+    }
+    log_template(&template);
+    state.miner = Some(Miner::start(
+        event_sender.clone(),
+        template,
+        data.to_owned(),
+        continuous,
+    ));
+}
+
+/// The next block with the pending transactions paying the most that fit in it and a coinbase
+/// paying the node
+fn block_template(state: &NodeState, data: String) -> Block {
+    let params = state.chain.params();
+    let miner = Address::of(&KEYS.public());
+    let height = state.chain.height() + 1;
+    // 给区块头、数据和奖励交易留出空间
+    let reserved = state.chain.next_block(data.clone(), Vec::new()).size()
+        + miner.map_or(0, |miner| {
+            Transaction::coinbase(miner, u64::MAX, height).size() + 1
+        });
+    let mut transactions = state.mempool.select(
+        params.max_block_transactions.saturating_sub(1),
+        params.max_block_size.saturating_sub(reserved),
+        params.max_block_gas,
+    );
+    // 矿工地址为节点地址，奖励为区块补贴加上交易费
+    if let Some(miner) = miner {
+        let reward = transactions
+            .iter()
+            .fold(state.chain.next_subsidy(), |sum, tx| {
+                sum.saturating_add(tx.fee)
+            });
+        if reward > 0 {
+            let coinbase = Transaction::coinbase(miner, reward, height);
+            transactions.insert(0, coinbase);
+        }
+    }
+    state.chain.next_block(data, transactions)
+}
+
+fn log_template(template: &Block) {
+    info!(
+        "Mining block {} with difficulty {} and {} transactions",
+        template.header.index,
+        template.header.difficulty,
+        template.transactions.len()
+    );
+}
+
+/// Move the miner on to the tip, the block it was mining can no longer be appended
+fn restart_mining(state: &NodeState) {
+    if let Some(miner) = &state.miner {
+        let template = block_template(state, miner.data.clone());
+        log_template(&template);
+        miner.restart(template);
+    }
+}
+
+/// Append a block the miner found to the chain and publish it, then mine the next block when
+/// mining continuously
+pub fn handle_block_mined(swarm: &mut Swarm<RecipeBehaviour>, state: &mut NodeState, block: Block) {
+    let (index, hash, nonce) = (block.header.index, block.hash, block.header.nonce);
+    match state.chain.try_add_block(block.clone()) {
+        Ok(()) => {
+            info!("Mined block {} {} with nonce {}", index, hash, nonce);
+            remove_confirmed(state, &block.transactions);
+            publish(swarm, state, BLOCKS_TOPIC.hash(), &block);
+            vote_tip(swarm, state);
+            if state.miner.as_ref().is_some_and(|miner| !miner.continuous) {
+                state.miner = None;
+            }
+        }
+        // 挖矿期间链可能已经接上了其他节点的区块
+        Err(e) => error!("mined block {} rejected: {}", index, e),
+    }
+    restart_mining(state);
+}
+
+/// Append a block published by another node, blocks breaking a consensus rule count against the
+/// peer that forwarded them
+pub async fn handle_block_received(
+    swarm: &mut Swarm<RecipeBehaviour>,
+    state: &mut NodeState,
+    source: PeerId,
+    block: Block,
+) {
+    if state.light.is_some() {
+        receive_light_header(swarm, state, source, block.header).await;
+        return;
+    }
+    let (index, hash) = (block.header.index, block.hash);
+    detect_equivocation(swarm, state, &block.header);
+    match state.chain.try_add_block(block.clone()) {
+        Ok(()) => {
+            info!("Added block {} {} from {}", index, hash, source);
+            remove_confirmed(state, &block.transactions);
+            connect_orphans(swarm, state).await;
+            vote_tip(swarm, state);
+            restart_mining(state);
+        }
+        // 区块超前于本地链或者在另一条分支上，向对端要最新状态，它的链更重时从它同步
+        Err(ValidationError::NotNextHeight { expected, actual }) if actual > expected => {
+            debug!(
+                "block {} from {} is ahead of our tip, syncing",
+                index, source
+            );
+            keep_orphan(state, source, block);
+            state.sync.send_status(swarm, source, chain_status(state));
+        }
+        Err(ValidationError::UnknownParent { .. }) => {
+            debug!("block {} from {} is on another branch", index, source);
+            keep_orphan(state, source, block);
+            state.sync.send_status(swarm, source, chain_status(state));
+        }
+        Err(e) if e.is_peer_fault() => {
+            warn!("rejected block {} {} from {}: {}", index, hash, source, e);
+            let verdict = state.peer_scores.record_invalid_block(source, &e);
+            enforce_verdict(swarm, state, source, verdict).await;
+        }
+        Err(e) => debug!("ignoring block {} {} from {}: {}", index, hash, source, e),
+    }
+}
+
+/// Follow the header of a published block, a header we can not connect makes us ask the peer for
+/// its status like a block ahead of our tip does
+async fn receive_light_header(
+    swarm: &mut Swarm<RecipeBehaviour>,
+    state: &mut NodeState,
+    source: PeerId,
+    header: BlockHeader,
+) {
+    let light = match state.light.as_mut() {
+        Some(light) => light,
+        None => return,
+    };
+    let (index, hash) = (header.index, header.hash());
+    match light.headers.add(&state.chain, vec![header]) {
+        Ok(0) => {}
+        Ok(_) => info!("Added header {} {} from {}", index, hash, source),
+        Err(ValidationError::UnknownParent { .. }) => {
+            debug!("header {} from {} does not connect, syncing", index, source);
+            state.sync.send_status(swarm, source, chain_status(state));
+        }
+        Err(e) if e.is_peer_fault() => {
+            warn!("rejected header {} {} from {}: {}", index, hash, source, e);
+            let verdict = state.peer_scores.record_invalid_block(source, &e);
+            enforce_verdict(swarm, state, source, verdict).await;
+        }
+        Err(e) => debug!("ignoring header {} {} from {}: {}", index, hash, source, e),
+    }
+}
+
+/// Our status as sent to peers, light nodes report their header chain
+fn chain_status(state: &NodeState) -> ChainStatus {
+    match &state.light {
+        Some(light) => light.headers.status(&state.chain),
+        None => ChainStatus::of(&state.chain),
+    }
+}
+
+/// Ask the best peer for what we download next, light nodes download headers only
+fn sync_next(swarm: &mut Swarm<RecipeBehaviour>, state: &mut NodeState) {
+    match &state.light {
+        Some(light) => {
+            let locator = light.headers.locator(&state.chain);
+            let (known, work) = (light.headers.height(), light.headers.work(&state.chain));
+            state.sync.request_headers(swarm, locator, known, work);
+        }
+        None => state.sync.request_next(swarm, &state.chain),
+    }
+}
+
+/// Keep a block until its parent arrives, blocks building on a block of our chain are on a branch
+/// that is downloaded by the syncer instead
+fn keep_orphan(state: &mut NodeState, source: PeerId, block: Block) {
+    let prev_hash = block.header.prev_hash;
+    if state.chain.iter().any(|block| block.hash == prev_hash) {
+        return;
+    }
+    let (index, hash) = (block.header.index, block.hash);
+    if state.orphans.insert(source, block) {
+        debug!(
+            "keeping block {} {} until its parent {} arrives",
+            index, hash, prev_hash
+        );
+    }
+}
+
+/// Connect the orphans waiting for the tip, then the orphans waiting for those and so on
+async fn connect_orphans(swarm: &mut Swarm<RecipeBehaviour>, state: &mut NodeState) {
+    loop {
+        let children = state.orphans.take_children(&state.chain.tip().hash);
+        if children.is_empty() {
+            return;
+        }
+        // 同一父区块的其他子区块已经接不上，直接丢弃
+        let mut connected = false;
+        for (source, block) in children {
+            if connected {
+                continue;
+            }
+            let (index, hash) = (block.header.index, block.hash);
+            match state.chain.try_add_block(block.clone()) {
+                Ok(()) => {
+                    info!("Added orphan block {} {} from {}", index, hash, source);
+                    remove_confirmed(state, &block.transactions);
+                    connected = true;
+                }
+                Err(e) if e.is_peer_fault() => {
+                    warn!(
+                        "rejected orphan block {} {} from {}: {}",
+                        index, hash, source, e
+                    );
+                    let verdict = state.peer_scores.record_invalid_block(source, &e);
+                    enforce_verdict(swarm, state, source, verdict).await;
+                }
+                Err(e) => debug!("dropping orphan block {} {}: {}", index, hash, e),
+            }
+        }
+        if !connected {
+            return;
+        }
+    }
+}
+
+/// Drop the transactions a new block confirmed from the mempool, along with those it invalidated
+fn remove_confirmed(state: &mut NodeState, transactions: &[Transaction]) {
+    state.mempool.remove_confirmed(transactions);
+    state
+        .mempool
+        .revalidate(Vec::new(), state.chain.ledger(), state.chain.height() + 1);
+}
+
+/// Height of the chain compared with the best chain of our peers, e.g. `sync status`
+pub async fn handle_sync_status(state: &NodeState) {
+    if let Some(light) = &state.light {
+        let height = light.headers.height();
+        let best = state
+            .sync
+            .best()
+            .map_or(height, |(_, status)| cmp::max(status.height, height));
+        info!("Sync: light node with headers up to {} of {}", height, best);
+        return;
+    }
+    let height = state.chain.height();
+    let best = state
+        .sync
+        .best()
+        .map_or(height, |(_, status)| cmp::max(status.height, height));
+    info!(
+        "Sync: height {} of {}, {} blocks downloaded",
+        height, best, state.sync.applied
+    );
+    if state.chain.finality() || state.chain.last_checkpoint().is_some() {
+        let finalized = state.chain.finalized_height();
+        match state.finality.finalized() {
+            Some((voted, _)) if voted > finalized => info!(
+                "Finalized height {}, validators finalized {} which we do not have yet",
+                finalized, voted
+            ),
+            _ => info!("Finalized height {}", finalized),
+        }
+    }
+    if let Some(header) = state.sync.headers().last() {
+        info!(
+            "Headers known up to {}, {} blocks waiting to be appended",
+            header.index,
+            state.sync.pending_blocks()
+        );
+    }
+    let mut downloading = false;
+    for download in state.sync.downloads() {
+        downloading = true;
+        info!(
+            "Downloading {} {}..{} from {}",
+            download.fetch,
+            download.from,
+            download.from + download.count as u64,
+            download.peer
+        );
+    }
+    if !downloading {
+        if best > height {
+            info!("Behind the best peer, waiting for a download");
+        } else {
+            info!("In sync");
+        }
+    }
+    if state.orphans.len() > 0 {
+        info!(
+            "{} orphan blocks waiting for their parent",
+            state.orphans.len()
+        );
+    }
+    let counters = &state.telemetry.counters;
+    if counters.reorgs > 0 {
+        info!(
+            "Reorganizations: {}, the deepest took off {} blocks",
+            counters.reorgs, counters.deepest_reorg
+        );
+    }
+    let (work, tip) = (state.chain.work(), state.chain.tip().hash);
+    state.sync.peers().for_each(|(peer, status)| {
+        let relation = match status.work.cmp(&work) {
+            cmp::Ordering::Greater => "more work than ours",
+            cmp::Ordering::Less => "less work than ours",
+            cmp::Ordering::Equal if status.tip == tip => "our chain",
+            cmp::Ordering::Equal => "as much work as ours on another branch",
+        };
+        info!(
+            "{}: height {} tip {} work {}, {}",
+            peer, status.height, status.tip, status.work, relation
+        )
+    });
+}
+
+/// Remove blocks from the tip, e.g. `chain rewind 2`, their transactions go back to the mempool
+pub async fn handle_rewind_chain(cmd: &str, state: &mut NodeState) {
+    let count: u64 = match cmd
+        .strip_prefix("chain rewind")
+        .map(|rest| rest.trim().parse())
+    {
+        Some(Ok(count)) => count,
+        _ => {
+            error!("usage: chain rewind <blocks>");
+            return;
+        }
+    };
+    for _ in 0..count {
+        let block = match state.chain.pop_block() {
+            Some(block) => block,
+            None => break,
+        };
+        info!("Removed block {} {}", block.header.index, block.hash);
+        let dropped = state.mempool.revalidate(
+            block.transactions,
+            state.chain.ledger(),
+            state.chain.height() + 1,
+        );
+        if dropped > 0 {
+            info!(
+                "Dropped {} pending transactions that no longer apply",
+                dropped
+            );
+        }
+    }
+    info!("Chain tip is now block {}", state.chain.height());
+}
+
+/// Create a transaction and publish it, e.g. `tx send <address> <amount> [fee] [from]`, sent
+/// from the node address unless a wallet address is given
+///
+/// `until <height>` or `until @<unix-time>` locks the transaction, no earlier block may confirm it
+pub async fn handle_send_tx(cmd: &str, swarm: &mut Swarm<RecipeBehaviour>, state: &mut NodeState) {
+    let rest = match cmd.strip_prefix("tx send") {
+        Some(rest) => rest,
+        None => return,
+    };
+    let usage =
+        "usage: tx send <address> <amount> [fee] [from] [until <height>|until @<unix-time>]";
+    let mut args = rest.split_whitespace();
+    let to: Address = match args.next().map(str::parse) {
+        Some(Ok(to)) => to,
+        Some(Err(e)) => {
+            error!("invalid address: {}", e);
+            return;
+        }
+        None => {
+            error!("{}", usage);
+            return;
+        }
+    };
+    let amount: u64 = match args.next().map(str::parse) {
+        Some(Ok(amount)) => amount,
+        _ => {
+            error!("{}", usage);
+            return;
+        }
+    };
+    let mut args = args.peekable();
+    // 地址不可能解析为数字，数字参数就是交易费
+    let fee = match args.peek().map(|arg| arg.parse::<u64>()) {
+        Some(Ok(fee)) => {
+            args.next();
+            fee
+        }
+        _ => 0,
+    };
+    let from = args.next_if(|arg| *arg != "until");
+    let locktime = match (args.next(), args.next().map(str::parse::<LockTime>)) {
+        (None, _) => None,
+        (Some("until"), Some(Ok(locktime))) => Some(locktime),
+        _ => {
+            error!("{}", usage);
+            return;
+        }
+    };
+    let (from, keys) = match sender_keys(from, state) {
+        Some(sender) => sender,
+        None => return,
+    };
+    let nonce = state.mempool.next_nonce(&from, state.chain.ledger());
+    let mut tx = Transaction::new(from, to, amount, nonce);
+    tx.fee = fee;
+    tx.locktime = locktime;
+    submit_tx(swarm, state, tx, &keys);
+}
+
+/// Bond or unbond stake of the node address, e.g. `tx bond <amount> [fee]` or
+/// `tx unbond <amount> [fee]`
+pub async fn handle_stake_tx(cmd: &str, swarm: &mut Swarm<RecipeBehaviour>, state: &mut NodeState) {
+    let (kind, rest) = if let Some(rest) = cmd.strip_prefix("tx bond") {
+        (TxKind::Bond, rest)
+    } else if let Some(rest) = cmd.strip_prefix("tx unbond") {
+        (TxKind::Unbond, rest)
+    } else {
+        return;
+    };
+    let mut args = rest.split_whitespace().map(str::parse::<u64>);
+    let (amount, fee) = match (args.next(), args.next()) {
+        (Some(Ok(amount)), None) => (amount, 0),
+        (Some(Ok(amount)), Some(Ok(fee))) => (amount, fee),
+        _ => {
+            error!("usage: tx bond|unbond <amount> [fee]");
+            return;
+        }
+    };
+    let (from, keys) = match sender_keys(None, state) {
+        Some(sender) => sender,
+        None => return,
+    };
+    let nonce = state.mempool.next_nonce(&from, state.chain.ledger());
+    let mut tx = Transaction::staking(kind, from, amount, nonce);
+    tx.fee = fee;
+    submit_tx(swarm, state, tx, &keys);
+}
+
+/// Deploy or call a contract from the node address, or show a deployed one, e.g.
+/// `contract deploy <wasm-file> [fee]`, `contract call <address> <method> [args]` or
+/// `contract show <address>`
+pub async fn handle_contract(cmd: &str, swarm: &mut Swarm<RecipeBehaviour>, state: &mut NodeState) {
+    let rest = cmd.strip_prefix("contract").unwrap_or_default().trim();
+    if let Some(rest) = rest.strip_prefix("deploy ") {
+        let mut args = rest.split_whitespace();
+        let path = args.next().unwrap_or_default();
+        let fee = match args.next().map(str::parse::<u64>) {
+            Some(Ok(fee)) => fee,
+            None => 0,
+            Some(Err(_)) => {
+                error!("usage: contract deploy <wasm-file> [fee]");
+                return;
+            }
+        };
+        let code = match fs::read(path).await {
+            Ok(code) => code,
+            Err(e) => {
+                error!("error reading {}: {}", path, e);
+                return;
+            }
+        };
+        let (from, keys) = match sender_keys(None, state) {
+            Some(sender) => sender,
+            None => return,
+        };
+        let nonce = state.mempool.next_nonce(&from, state.chain.ledger());
+        let mut tx = Transaction::deploy(from, code, nonce);
+        tx.fee = fee;
+        info!("Deploying contract {}", tx.to);
+        submit_tx(swarm, state, tx, &keys);
+    } else if let Some(rest) = rest.strip_prefix("call ") {
+        let mut parts = rest.trim().splitn(3, ' ');
+        let (contract, method) = match (parts.next().map(str::parse::<Address>), parts.next()) {
+            (Some(Ok(contract)), Some(method)) => (contract, method.to_owned()),
+            (Some(Err(e)), _) => {
+                error!("invalid address: {}", e);
+                return;
+            }
+            _ => {
+                error!("usage: contract call <address> <method> [args]");
+                return;
+            }
+        };
+        let args = parts.next().unwrap_or_default().as_bytes().to_vec();
+        let (from, keys) = match sender_keys(None, state) {
+            Some(sender) => sender,
+            None => return,
+        };
+        // 先在链尖的状态上试运行，确认后的结果见 `receipt <txid>`
+        let mut gas = DEFAULT_CALL_GAS;
+        if let Some(deployed) = state.chain.ledger().contract(&contract) {
+            let gas_used = match vm::call(
+                &deployed.code,
+                &deployed.storage,
+                &method,
+                &args,
+                &from,
+                VM_MAX_GAS,
+            ) {
+                Ok(execution) => {
+                    info!(
+                        "{} would return {:?} and emit {} events using {} gas",
+                        method,
+                        String::from_utf8_lossy(&execution.output),
+                        execution.events.len(),
+                        execution.gas_used
+                    );
+                    execution.gas_used
+                }
+                Err(failure) => {
+                    warn!("{} would fail: {}", method, failure);
+                    failure.gas_used
+                }
+            };
+            // 确认前状态可能改变，上限多留一半
+            gas = gas_used
+                .saturating_add(gas_used / 2)
+                .clamp(VM_MIN_GAS, VM_MAX_GAS);
+        }
+        let nonce = state.mempool.next_nonce(&from, state.chain.ledger());
+        let mut tx = Transaction::call(from, contract, method, args, gas, nonce);
+        tx.fee = contracts::gas_fee(gas, state.chain.params().gas_price);
+        info!(
+            "Calling with a gas limit of {} for a fee of {}",
+            gas, tx.fee
+        );
+        submit_tx(swarm, state, tx, &keys);
+    } else if let Some(address) = rest.strip_prefix("show ") {
+        let address: Address = match address.trim().parse() {
+            Ok(address) => address,
+            Err(e) => {
+                error!("invalid address: {}", e);
+                return;
+            }
+        };
+        let contract = match state.chain.ledger().contract(&address) {
+            Some(contract) => contract,
+            None => {
+                error!("no contract is deployed at {}", address);
+                return;
+            }
+        };
+        info!("Contract {}", address);
+        info!("  creator: {}", contract.creator);
+        info!("  code:    {} bytes", contract.code.len());
+        for (key, value) in contract.storage.iter() {
+            info!(
+                "  storage {:?} = {:?}",
+                String::from_utf8_lossy(key),
+                String::from_utf8_lossy(value)
+            );
+        }
+    } else {
+        error!("usage: contract deploy <wasm-file> [fee] | contract call <address> <method> [args] | contract show <address>");
+    }
+}
+
+/// Fund, sign and publish a transaction of the key
+fn submit_tx(
+    swarm: &mut Swarm<RecipeBehaviour>,
+    state: &mut NodeState,
+    mut tx: Transaction,
+    keys: &Keypair,
+) {
+    let ledger = state.chain.ledger();
+    let mempool = &state.mempool;
+    if let Err(e) = ledger.fund(&mut tx, &|outpoint| mempool.is_spent(outpoint)) {
+        error!("{}", e);
+        return;
+    }
+    if let Err(e) = tx.sign(keys) {
+        error!("error signing transaction: {}", e);
+        return;
+    }
+    broadcast_tx(swarm, state, tx);
+}
+
+/// Add a signed transaction to the mempool and gossip it
+fn broadcast_tx(swarm: &mut Swarm<RecipeBehaviour>, state: &mut NodeState, tx: Transaction) {
+    match state
+        .mempool
+        .insert(tx.clone(), state.chain.ledger(), state.chain.height() + 1)
+    {
+        Ok(id) => {
+            info!("Sending transaction {}", id);
+            publish(swarm, state, TXS_TOPIC.hash(), &tx);
+        }
+        Err(e) => error!("{}", e),
+    }
+}
+
+/// Count a finality vote of another validator
+pub fn handle_vote_received(
+    swarm: &mut Swarm<RecipeBehaviour>,
+    state: &mut NodeState,
+    source: PeerId,
+    vote: Vote,
+) {
+    if !state.chain.finality() || state.light.is_some() {
+        return;
+    }
+    debug!(
+        "[Finality] {} of {} for block {} {} from {}",
+        vote.phase, vote.voter, vote.height, vote.block, source
+    );
+    count_vote(swarm, state, vote);
+}
+
+/// Prevote for our new tip, and precommit it right away when the prevotes for it arrived first
+fn vote_tip(swarm: &mut Swarm<RecipeBehaviour>, state: &mut NodeState) {
+    if !state.chain.finality() {
+        return;
+    }
+    let (height, hash) = (state.chain.height(), state.chain.tip().hash);
+    cast_vote(swarm, state, VotePhase::Prevote, height, hash);
+    if state.finality.quorum(height, VotePhase::Prevote) == Some(hash) {
+        cast_vote(swarm, state, VotePhase::Precommit, height, hash);
+    }
+    // 投票可能先于区块到达，区块接上之后才能确认
+    if let Some((height, hash)) = state.finality.finalized() {
+        if state.chain.finalize(height, &hash) {
+            info!("Finalized block {} {}", height, hash);
+        }
+    }
+}
+
+/// Sign and publish a vote when the node key is a validator that did not vote in the phase yet,
+/// our own vote is counted like the others
+fn cast_vote(
+    swarm: &mut Swarm<RecipeBehaviour>,
+    state: &mut NodeState,
+    phase: VotePhase,
+    height: u64,
+    block: Hash,
+) {
+    let voter = match Address::of(&KEYS.public()) {
+        Some(voter) => voter,
+        None => return,
+    };
+    let staked = state.chain.validators();
+    if staked.iter().all(|validator| validator.address != voter)
+        || !state.finality.should_vote(height, phase)
+    {
+        return;
+    }
+    let vote = match Vote::signed(phase, height, block, voter, &KEYS) {
+        Ok(vote) => vote,
+        Err(e) => {
+            error!("error signing {}: {}", phase, e);
+            return;
+        }
+    };
+    debug!("[Finality] {} for block {} {}", phase, height, block);
+    publish(swarm, state, VOTES_TOPIC.hash(), &vote);
+    count_vote(swarm, state, vote);
+}
+
+/// Precommit a block of our chain two thirds prevoted for, and finalize a block two thirds
+/// precommitted
+fn count_vote(swarm: &mut Swarm<RecipeBehaviour>, state: &mut NodeState, vote: Vote) {
+    let validators = state.chain.validators();
+    let block = match state
+        .finality
+        .observe(&vote, state.chain.height(), &validators)
+    {
+        Some(block) => block,
+        None => return,
+    };
+    match vote.phase {
+        VotePhase::Prevote => {
+            if state.chain.block(vote.height).map(|ours| ours.hash) == Some(block) {
+                cast_vote(swarm, state, VotePhase::Precommit, vote.height, block);
+            }
+        }
+        VotePhase::Precommit => {
+            state.finality.finalize(vote.height, block);
+            if state.chain.finalize(vote.height, &block) {
+                info!("Finalized block {} {}", vote.height, block);
+            } else if vote.height > state.chain.height() {
+                debug!("[Finality] block {} is final, waiting for it", vote.height);
+            } else {
+                warn!(
+                    "validators finalized block {} {} which is not on our chain",
+                    vote.height, block
+                );
+            }
+        }
+    }
+}
+
+/// Report the proposer of the header if it signed another header of the same slot, the report is
+/// gossiped like any transaction so every validator learns of the equivocation
+fn detect_equivocation(
+    swarm: &mut Swarm<RecipeBehaviour>,
+    state: &mut NodeState,
+    header: &BlockHeader,
+) {
+    let slot = match state.chain.slot(header.timestamp) {
+        Some(slot) => slot,
+        None => return,
+    };
+    let evidence = match state.equivocations.observe(header, slot) {
+        Some(evidence) => evidence,
+        None => return,
+    };
+    let offender = evidence.offender().unwrap_or_default();
+    warn!(
+        "validator {} signed blocks {} and {} for slot {}",
+        offender,
+        evidence.first.hash(),
+        evidence.second.hash(),
+        slot
+    );
+    let staked = state.chain.validators();
+    if staked.iter().all(|validator| validator.address != offender) {
+        debug!("validator {} has no stake left to slash", offender);
+        return;
+    }
+    let (from, keys) = match sender_keys(None, state) {
+        Some(sender) => sender,
+        None => return,
+    };
+    let nonce = state.mempool.next_nonce(&from, state.chain.ledger());
+    submit_tx(
+        swarm,
+        state,
+        Transaction::slash(from, evidence, nonce),
+        &keys,
+    );
+}
+
+/// Address and key a transaction is sent with, a wallet key when an address is given
+fn sender_keys(from: Option<&str>, state: &NodeState) -> Option<(Address, Keypair)> {
+    let from = match from {
+        Some(from) => from,
+        None => match Address::of(&KEYS.public()) {
+            Some(address) => return Some((address, KEYS.clone())),
+            None => {
+                error!("the node key is not an ed25519 key and can not send transactions");
+                return None;
+            }
+        },
+    };
+    let address: Address = match from.parse() {
+        Ok(address) => address,
+        Err(e) => {
+            error!("invalid address {}: {}", from, e);
+            return None;
+        }
+    };
+    match wallet::passphrase().and_then(|passphrase| state.wallet.keypair(&address, passphrase)) {
+        Ok(keys) => Some((address, keys)),
+        Err(e) => {
+            error!("{}", e);
+            None
+        }
+    }
+}
+
+/// Write the keystore on a task of its own, the select loop does not wait for the file
+fn save_wallet(state: &NodeState) {
+    let save = state.wallet.save();
+    tokio::spawn(async move {
+        if let Err(e) = save.await {
+            error!("error saving wallet: {:#}", e);
+        }
+    });
+}
+
+/// Add a key to the wallet
+pub async fn handle_wallet_new(state: &mut NodeState) {
+    match wallet::passphrase().and_then(|passphrase| state.wallet.generate(passphrase)) {
+        Ok(address) => {
+            save_wallet(state);
+            info!("New wallet address {}", address)
+        }
+        Err(e) => error!("error creating wallet key: {}", e),
+    }
+}
+
+pub async fn handle_wallet_list(state: &NodeState) {
+    info!("Wallet Addresses:");
+    state
+        .wallet
+        .addresses()
+        .for_each(|(address, path)| match path {
+            Some(path) => info!("{} {}", address, path),
+            None => info!("{}", address),
+        });
+    state.wallet.multisigs().for_each(|policy| {
+        info!(
+            "{} multisig {} of {}",
+            policy.address(),
+            policy.threshold,
+            policy.signers.len()
+        )
+    });
+}
+
+/// Create the mnemonic new wallet keys are derived from, e.g. `wallet init 24`, 12 words by default
+pub async fn handle_wallet_init(cmd: &str, state: &mut NodeState) {
+    let words = match cmd.strip_prefix("wallet init").map(str::trim) {
+        Some("") => 12,
+        Some(words) => match words.parse() {
+            Ok(words) => words,
+            Err(_) => {
+                error!("usage: wallet init [12|24]");
+                return;
+            }
+        },
+        None => return,
+    };
+    match wallet::passphrase().and_then(|passphrase| state.wallet.init_mnemonic(words, passphrase))
+    {
+        Ok(mnemonic) => {
+            save_wallet(state);
+            info!("Write down the mnemonic, it is the only way to restore the wallet:");
+            info!("{}", mnemonic);
+        }
+        Err(e) => error!("error creating mnemonic: {}", e),
+    }
+}
+
+/// Recreate the wallet keys from a mnemonic, e.g. `wallet restore <word> <word> ...`
+pub async fn handle_wallet_restore(cmd: &str, state: &mut NodeState) {
+    let phrase = match cmd.strip_prefix("wallet restore") {
+        Some(phrase) => phrase.split_whitespace().collect::<Vec<_>>().join(" "),
+        None => return,
+    };
+    let ledger = state.chain.ledger();
+    // 有余额或发送过交易的地址视为已使用
+    let used = |address: &Address| ledger.balance(address) > 0 || ledger.nonce(address) > 0;
+    let wallet = &mut state.wallet;
+    let restored = wallet::passphrase()
+        .and_then(|passphrase| wallet.restore(&phrase, passphrase, WALLET_RESTORE_GAP, used));
+    match restored {
+        Ok(addresses) => {
+            save_wallet(state);
+            info!("Restored {} wallet addresses:", addresses.len());
+            addresses.iter().for_each(|address| info!("{}", address));
+        }
+        Err(e) => error!("error restoring wallet: {}", e),
+    }
+}
+
+/// Shared M-of-N addresses, e.g. `wallet multisig create <m> <address>...`,
+/// `wallet multisig spend <shared-address> <to> <amount> <file> [fee]`,
+/// `wallet multisig sign <file> [address]` or `wallet multisig send <file>`
+///
+/// A spend is written to the file for the signers to pass around, each adds its signature on its
+/// own node and whoever holds enough of them sends it
+pub async fn handle_wallet_multisig(
+    cmd: &str,
+    swarm: &mut Swarm<RecipeBehaviour>,
+    state: &mut NodeState,
+) {
+    let usage = "usage: wallet multisig create <m> <address>... | spend <shared-address> <to> \
+                 <amount> <file> [fee] | sign <file> [address] | send <file>";
+    let rest = cmd.strip_prefix("wallet multisig").unwrap_or_default();
+    let mut args = rest.split_whitespace();
+    match args.next() {
+        Some("create") => {
+            let threshold = match args.next().map(str::parse::<u32>) {
+                Some(Ok(threshold)) => threshold,
+                _ => {
+                    error!("{}", usage);
+                    return;
+                }
+            };
+            let signers = match args.map(str::parse).collect::<Result<Vec<Address>>>() {
+                Ok(signers) => signers,
+                Err(e) => {
+                    error!("invalid address: {}", e);
+                    return;
+                }
+            };
+            let policy = match Policy::new(threshold, signers) {
+                Ok(policy) => policy,
+                Err(e) => {
+                    error!("{}", e);
+                    return;
+                }
+            };
+            let (threshold, signers) = (policy.threshold, policy.signers.len());
+            match state.wallet.add_multisig(policy) {
+                Ok(address) => {
+                    save_wallet(state);
+                    info!(
+                        "Multisig address {} spends with {} of {} signatures",
+                        address, threshold, signers
+                    )
+                }
+                Err(e) => error!("error saving multisig address: {}", e),
+            }
+        }
+        Some("spend") => {
+            let (from, to, amount, path) = match (
+                args.next().map(str::parse::<Address>),
+                args.next().map(str::parse::<Address>),
+                args.next().map(str::parse::<u64>),
+                args.next(),
+            ) {
+                (Some(Ok(from)), Some(Ok(to)), Some(Ok(amount)), Some(path)) => {
+                    (from, to, amount, path)
+                }
+                _ => {
+                    error!("{}", usage);
+                    return;
+                }
+            };
+            let fee = match args.next().map(str::parse::<u64>) {
+                Some(Ok(fee)) => fee,
+                None => 0,
+                Some(Err(_)) => {
+                    error!("{}", usage);
+                    return;
+                }
+            };
+            let policy = match state.wallet.multisig(&from) {
+                Some(policy) => policy.clone(),
+                None => {
+                    error!("{} is not a multisig address of the wallet", from);
+                    return;
+                }
+            };
+            let threshold = policy.threshold;
+            let ledger = state.chain.ledger();
+            let mempool = &state.mempool;
+            let mut tx = Transaction::new(from, to, amount, mempool.next_nonce(&from, ledger));
+            tx.fee = fee;
+            tx.multisig = Some(Box::new(MultisigSpend::new(policy)));
+            if let Err(e) = ledger.fund(&mut tx, &|outpoint| mempool.is_spent(outpoint)) {
+                error!("{}", e);
+                return;
+            }
+            if write_spend(path, &tx).await {
+                info!(
+                    "Wrote transaction {} to {}, it needs {} signatures",
+                    tx.id(),
+                    path,
+                    threshold
+                );
+            }
+        }
+        Some("sign") => {
+            let path = match args.next() {
+                Some(path) => path,
+                None => {
+                    error!("{}", usage);
+                    return;
+                }
+            };
+            let mut tx = match read_spend(path).await {
+                Some(tx) => tx,
+                None => return,
+            };
+            let (_, keys) = match sender_keys(args.next(), state) {
+                Some(sender) => sender,
+                None => return,
+            };
+            let signer = match tx.cosign(&keys) {
+                Ok(signer) => signer,
+                Err(e) => {
+                    error!("{}", e);
+                    return;
+                }
+            };
+            if write_spend(path, &tx).await {
+                let spend = tx
+                    .multisig
+                    .as_deref()
+                    .expect("cosigned spends are multisig");
+                info!(
+                    "Signed transaction {} as {}, {} of {} signatures",
+                    tx.id(),
+                    signer,
+                    spend.valid_signers(&tx.signing_bytes()),
+                    spend.policy.threshold
+                );
+            }
+        }
+        Some("send") => {
+            let tx = match args.next() {
+                Some(path) => match read_spend(path).await {
+                    Some(tx) => tx,
+                    None => return,
+                },
+                None => {
+                    error!("{}", usage);
+                    return;
+                }
+            };
+            if !tx.verify() {
+                let spend = tx.multisig.as_deref().expect("read spends are multisig");
+                error!(
+                    "transaction {} has {} of the {} signatures it needs",
+                    tx.id(),
+                    spend.valid_signers(&tx.signing_bytes()),
+                    spend.policy.threshold
+                );
+                return;
+            }
+            broadcast_tx(swarm, state, tx);
+        }
+        _ => error!("{}", usage),
+    }
+}
+
+/// Spend from a shared address passed around between its signers, false when it was not written
+async fn write_spend(path: &str, tx: &Transaction) -> bool {
+    let result = match serde_json::to_vec_pretty(tx) {
+        Ok(json) => fs::write(path, json).await,
+        Err(e) => Err(e.into()),
+    };
+    if let Err(e) = &result {
+        error!("error writing {}: {}", path, e);
+    }
+    result.is_ok()
+}
+
+async fn read_spend(path: &str) -> Option<Transaction> {
+    let content = match fs::read(path).await {
+        Ok(content) => content,
+        Err(e) => {
+            error!("error reading {}: {}", path, e);
+            return None;
+        }
+    };
+    match serde_json::from_slice::<Transaction>(&content) {
+        Ok(tx) if tx.multisig.is_some() => Some(tx),
+        Ok(tx) => {
+            error!("transaction {} in {} is no multisig spend", tx.id(), path);
+            None
+        }
+        Err(e) => {
+            error!("invalid transaction in {}: {}", path, e);
+            None
+        }
+    }
+}
+
+/// Confirmed balance of every wallet address and their sum
+pub async fn handle_wallet_balance(state: &NodeState) {
+    let ledger = state.chain.ledger();
+    let mut total = 0u64;
+    for (address, _) in state.wallet.addresses() {
+        let balance = ledger.balance(address);
+        total = total.saturating_add(balance);
+        info!("{}: {}", address, balance);
+    }
+    info!("Wallet balance: {}", total);
+}
+
+/// Admit a transaction published by another node
+pub fn handle_transaction_received(state: &mut NodeState, source: PeerId, tx: Transaction) {
+    // 轻节点没有账本，无法校验交易
+    if state.light.is_some() {
+        return;
+    }
+    if let Err(e) = state.chain.check_evidence(&tx) {
+        debug!("[Mempool] rejected transaction from {}: {}", source, e);
+        return;
+    }
+    match state
+        .mempool
+        .insert(tx, state.chain.ledger(), state.chain.height() + 1)
+    {
+        Ok(id) => debug!("[Mempool] accepted transaction {} from {}", id, source),
+        Err(e) => debug!("[Mempool] rejected transaction from {}: {}", source, e),
+    }
+}
+
+/// Address given as the argument of the command, our own address without one
+fn address_arg(rest: &str) -> Option<Address> {
+    let rest = rest.trim();
+    if rest.is_empty() {
+        let address = Address::of(&KEYS.public());
+        if address.is_none() {
+            error!("the node key is not an ed25519 key and has no address");
+        }
+        return address;
+    }
+    match rest.parse() {
+        Ok(address) => Some(address),
+        Err(e) => {
+            error!("invalid address {}: {}", rest, e);
+            None
+        }
+    }
+}
+
+/// Confirmed balance of an address, e.g. `balance <address>`
+pub async fn handle_balance(cmd: &str, state: &NodeState) {
+    if let Some(address) = cmd.strip_prefix("balance").and_then(address_arg) {
+        info!(
+            "Balance of {}: {}",
+            address,
+            state.chain.ledger().balance(&address)
+        );
+    }
+}
+
+/// Confirmed transactions sent or received by an address, e.g. `wallet history <address>`
+pub async fn handle_wallet_history(cmd: &str, state: &NodeState) {
+    let address = match cmd.strip_prefix("wallet history").and_then(address_arg) {
+        Some(address) => address,
+        None => return,
+    };
+    let history = state.chain.history(&address);
+    info!("{} transactions of {}:", history.len(), address);
+    for (block, tx) in history {
+        let change = if tx.is_coinbase() {
+            format!("received {} from coinbase", tx.amount)
+        } else if tx.from == address && tx.to == address {
+            format!("{} {}, fee {}", tx.kind, tx.amount, tx.fee)
+        } else if tx.from == address {
+            format!("sent {} to {}, fee {}", tx.amount, tx.to, tx.fee)
+        } else {
+            format!("received {} from {}", tx.amount, tx.from)
+        };
+        info!(
+            "block {} {} {}: {}",
+            block.header.index,
+            tx.id(),
+            tx.kind,
+            change
+        );
+    }
+}
+
+/// Addresses with the highest confirmed balances, e.g. `richlist 10`
+pub async fn handle_richlist(cmd: &str, state: &NodeState) {
+    let count = match cmd.strip_prefix("richlist").map(str::trim) {
+        Some("") => 10,
+        Some(count) => match count.parse() {
+            Ok(count) => count,
+            Err(_) => {
+                error!("usage: richlist [count]");
+                return;
+            }
+        },
+        None => return,
+    };
+    info!("Richest addresses:");
+    for (rank, (address, balance)) in state.chain.richlist(count).iter().enumerate() {
+        info!("{}. {}: {}", rank + 1, address, balance);
+    }
+}
+
+/// Confirmed and pending transactions sent by an address, e.g. `nonce <address>`
+pub async fn handle_nonce(cmd: &str, state: &NodeState) {
+    if let Some(address) = cmd.strip_prefix("nonce").and_then(address_arg) {
+        let ledger = state.chain.ledger();
+        info!(
+            "Nonce of {}: {} confirmed, next {}",
+            address,
+            ledger.nonce(&address),
+            state.mempool.next_nonce(&address, ledger)
+        );
+    }
+}
+
+pub async fn handle_list_mempool(state: &NodeState) {
+    info!(
+        "Mempool ({} transactions, {} waiting for an earlier nonce):",
+        state.mempool.len(),
+        state.mempool.queued_len()
+    );
+    state.mempool.iter().for_each(|(id, tx)| {
+        info!(
+            "{}: {} -> {} amount {} fee {} change {} nonce {}, {} inputs",
+            id,
+            tx.from,
+            tx.to,
+            tx.amount,
+            tx.fee,
+            tx.change,
+            tx.nonce,
+            tx.inputs.len()
+        )
+    });
+}
+
+/// Validators with their stake and how many of the recent blocks they proposed, e.g.
+/// `ls validators`
+pub async fn handle_list_validators(state: &NodeState) {
+    let validators = state.chain.validators();
+    let total: u64 = validators.iter().map(|validator| validator.stake).sum();
+    let recent: Vec<&Block> = state
+        .chain
+        .iter()
+        .rev()
+        .take(VALIDATOR_LIVENESS_BLOCKS)
+        .filter(|block| block.header.index > 0)
+        .collect();
+    info!(
+        "Validators ({} with a stake of {}, last {} blocks):",
+        validators.len(),
+        total,
+        recent.len()
+    );
+    for validator in validators.iter() {
+        let proposed: Vec<&&Block> = recent
+            .iter()
+            .filter(|block| block.header.proposer == Some(validator.address))
+            .collect();
+        // 按权益比例应当提议的区块数
+        let expected = recent.len() as u64 * validator.stake / total.max(1);
+        let last = match proposed.first() {
+            Some(block) => format!("last proposed block {}", block.header.index),
+            None => "no recent block".to_owned(),
+        };
+        let slashed = match state.chain.ledger().slashed(&validator.address) {
+            0 => String::new(),
+            burned => format!(", {} slashed", burned),
+        };
+        info!(
+            "{} stake {}{}, proposed {} of {} expected blocks, {}",
+            validator.address,
+            validator.stake,
+            slashed,
+            proposed.len(),
+            expected,
+            last
+        );
+    }
+}
+
+/// Suggest a fee for a new transaction based on the recent blocks and the pending transactions
+pub async fn handle_fee_estimate(state: &NodeState) {
+    let recent = state.chain.iter().rev().take(FEE_ESTIMATE_BLOCKS);
+    let rate = state
+        .mempool
+        .estimate_fee_rate(recent, state.chain.params());
+    // 一个输入、带签名的转账
+    let mut typical = Transaction::new(Address::default(), Address::default(), 0, 0);
+    typical.inputs.push(OutPoint {
+        tx: Hash::default(),
+        index: 0,
+    });
+    typical.signature = vec![u8::MAX; 64];
+    let size = typical.size() as u64;
+    info!(
+        "Suggested fee rate {} per 1000 bytes, a fee of {} for a transaction of {} bytes",
+        rate,
+        (rate * size + 999) / 1000,
+        size
+    );
+}
+
+/// Ask the best peer for a proof of a transaction or the payload of a block and check it against
+/// our headers, e.g. `spv tx <txid>`, `spv data <height>` or `spv account <address>`
+pub async fn handle_spv(cmd: &str, swarm: &mut Swarm<RecipeBehaviour>, state: &mut NodeState) {
+    let light = match state.light.as_mut() {
+        Some(light) => light,
+        None => {
+            error!("spv needs a node started with --light, full nodes use tx prove");
+            return;
+        }
+    };
+    let rest = cmd.strip_prefix("spv").unwrap_or_default().trim();
+    let (request, query) = if let Some(txid) = rest.strip_prefix("tx ") {
+        match txid.trim().parse() {
+            Ok(txid) => (SyncRequest::Proof(txid), Query::Tx(txid)),
+            Err(e) => {
+                error!("invalid transaction id: {}", e);
+                return;
+            }
+        }
+    } else if let Some(height) = rest.strip_prefix("data ") {
+        let header = match height.trim().parse() {
+            Ok(height) => light.headers.header(&state.chain, height),
+            Err(e) => {
+                error!("invalid height: {}", e);
+                return;
+            }
+        };
+        match header {
+            // 创世区块就在本地
+            Some(header) if header.index == 0 => {
+                let data = state.chain.block(0).map_or("", |block| &block.data);
+                info!("Block 0 {}: {}", state.chain.genesis_hash(), data);
+                return;
+            }
+            Some(header) => {
+                let hash = header.hash();
+                (SyncRequest::Data(hash), Query::Data(hash))
+            }
+            None => {
+                error!("no header at height {}", height.trim());
+                return;
+            }
+        }
+    } else if let Some(address) = rest.strip_prefix("account ") {
+        match address.trim().parse() {
+            Ok(address) => (SyncRequest::Account(address), Query::Account(address)),
+            Err(e) => {
+                error!("invalid address: {}", e);
+                return;
+            }
+        }
+    } else {
+        error!("usage: spv tx <txid> | spv data <height> | spv account <address>");
+        return;
+    };
+    let peer_id = match state.sync.best() {
+        Some((peer_id, _)) => *peer_id,
+        None => {
+            error!("no full node to ask");
+            return;
+        }
+    };
+    let request_id = swarm.behaviour_mut().sync.send_request(&peer_id, request);
+    light.track(request_id, query);
+}
+
+/// Prove a confirmed transaction is in its block, e.g. `tx prove <txid>`, the proof is printed
+/// as JSON and checked against the block header
+pub async fn handle_prove_tx(cmd: &str, state: &NodeState) {
+    let txid: Hash = match cmd.strip_prefix("tx prove").map(|rest| rest.trim().parse()) {
+        Some(Ok(txid)) => txid,
+        _ => {
+            error!("usage: tx prove <txid>");
+            return;
+        }
+    };
+    let proof = match state.chain.prove_tx(&txid) {
+        Some(proof) => proof,
+        None => {
+            error!("transaction {} is not in the chain", txid);
+            return;
+        }
+    };
+    let block = match state.chain.find_block(&proof.block) {
+        Some(block) => block,
+        None => return,
+    };
+    match serde_json::to_string(&proof) {
+        Ok(json) => info!("{}", json),
+        Err(e) => error!("can not encode proof: {}", e),
+    }
+    if merkle::verify_proof(&block.header, &proof) {
+        info!(
+            "Transaction {} is in block {} {}, {} hashes in the branch",
+            txid,
+            block.header.index,
+            block.hash,
+            proof.branch.len()
+        );
+    } else {
+        error!("proof of {} does not verify", txid);
+    }
+}
+
+/// Write the ledger at the tip to a file or start from one instead of the genesis block, e.g.
+/// `snapshot export <file>` or `snapshot import <file>`
+pub async fn handle_snapshot(cmd: &str, swarm: &mut Swarm<RecipeBehaviour>, state: &mut NodeState) {
+    let rest = cmd.strip_prefix("snapshot").unwrap_or_default().trim();
+    if let Some(path) = rest.strip_prefix("export ") {
+        let snapshot = state.chain.snapshot();
+        match snapshot::export(Path::new(path.trim()), &snapshot).await {
+            Ok(size) => info!(
+                "Exported the ledger at block {} {} to {}, {} bytes",
+                snapshot.height(),
+                snapshot.tip(),
+                path.trim(),
+                size
+            ),
+            Err(e) => error!("error exporting snapshot: {}", e),
+        }
+    } else if let Some(path) = rest.strip_prefix("import ") {
+        if state.light.is_some() {
+            error!("light nodes keep no ledger to import");
+            return;
+        }
+        let snapshot = match snapshot::import(Path::new(path.trim())).await {
+            Ok(snapshot) => snapshot,
+            Err(e) => {
+                error!("error importing snapshot: {}", e);
+                return;
+            }
+        };
+        let (height, tip) = (snapshot.height(), snapshot.tip());
+        if let Err(e) = state.chain.import(snapshot) {
+            error!("can not import snapshot: {}", e);
+            return;
+        }
+        state
+            .mempool
+            .revalidate(Vec::new(), state.chain.ledger(), state.chain.height() + 1);
+        info!("Imported the ledger at block {} {}", height, tip);
+        // 从快照的链尖继续同步
+        sync_next(swarm, state);
+    } else {
+        error!("usage: snapshot export <file> | snapshot import <file>");
+    }
+}
+
+/// Write the blocks and recipes to a checksummed file while the node runs, e.g. `backup <file>`,
+/// the wallet is only included with `backup <file> wallet`
+pub async fn handle_backup(cmd: &str, state: &NodeState) {
+    let usage = "usage: backup <file> [wallet]";
+    let mut args = cmd
+        .strip_prefix("backup")
+        .unwrap_or_default()
+        .split_whitespace();
+    let (path, wallet) = match (args.next(), args.next(), args.next()) {
+        (Some(path), None, None) => (path, false),
+        (Some(path), Some("wallet"), None) => (path, true),
+        _ => {
+            error!("{}", usage);
+            return;
+        }
+    };
+    let backup = match Backup::take(
+        &state.chain,
+        state.light.is_some(),
+        wallet.then(|| state.wallet.clone()),
+    ) {
+        Ok(backup) => backup,
+        Err(e) => {
+            error!("can not read the stored records: {:#}", e);
+            return;
+        }
+    };
+    match backup::write(Path::new(path), &backup).await {
+        Ok(size) => info!(
+            "Backed up {} block records and {} recipe records{} to {}, {} bytes",
+            backup.blocks.len(),
+            backup.recipes.len(),
+            if wallet { " with the wallet" } else { "" },
+            path,
+            size
+        ),
+        Err(e) => error!("error writing backup: {}", e),
+    }
+}
+
+/// Replace the blocks and recipes with those of a backup, e.g. `restore <file>`, the wallet of
+/// the backup is only restored with `restore <file> wallet`
+pub async fn handle_restore(cmd: &str, swarm: &mut Swarm<RecipeBehaviour>, state: &mut NodeState) {
+    let usage = "usage: restore <file> [wallet]";
+    let mut args = cmd
+        .strip_prefix("restore")
+        .unwrap_or_default()
+        .split_whitespace();
+    let (path, wallet) = match (args.next(), args.next(), args.next()) {
+        (Some(path), None, None) => (path, false),
+        (Some(path), Some("wallet"), None) => (path, true),
+        _ => {
+            error!("{}", usage);
+            return;
+        }
+    };
+    let backup = match backup::read(Path::new(path)).await {
+        Ok(backup) => backup,
+        Err(e) => {
+            error!("error reading backup: {}", e);
+            return;
+        }
+    };
+    if backup.chain_id != state.chain.chain_id() || backup.genesis != state.chain.genesis_hash() {
+        error!(
+            "the backup is of chain {} with genesis {}",
+            backup.chain_id, backup.genesis
+        );
+        return;
+    }
+    match &backup.wallet {
+        Some(keystore) if wallet => {
+            // 先检查钱包，拒绝时什么都不恢复
+            if let Err(e) = state.wallet.check_replace(keystore) {
+                error!("can not restore the wallet: {:#}", e);
+                return;
+            }
+        }
+        None if wallet => {
+            error!("the backup holds no wallet");
+            return;
+        }
+        _ => {}
+    }
+    info!("Restoring the backup taken at {}", backup.created);
+    // 轻节点不保存区块，只恢复菜谱
+    if state.light.is_none() && !backup.blocks.is_empty() {
+        match state.chain.restore_storage(backup.blocks) {
+            Ok(height) => info!("Restored the chain up to block {}", height),
+            Err(e) => {
+                error!("can not restore the chain: {:#}", e);
+                return;
+            }
+        }
+        state
+            .mempool
+            .revalidate(Vec::new(), state.chain.ledger(), state.chain.height() + 1);
+    }
+    let images = || storage::recipes().images().unwrap_or_default();
+    let replaced = images();
+    match storage::recipes().restore(backup.recipes) {
+        Ok(recipes) => {
+            info!("Restored {} recipes", recipes);
+            // 备份不含 blob，恢复的菜谱引用的图片须仍在本节点
+            let restored = images();
+            for hash in restored.iter() {
+                match blobs::blobs().retain(hash) {
+                    Ok(true) => {}
+                    Ok(false) => warn!("image {} of a restored recipe is not stored here", hash),
+                    Err(e) => error!("error retaining blob {}: {:#}", hash, e),
+                }
+            }
+            for hash in replaced.iter() {
+                if let Err(e) = blobs::blobs().release(hash) {
+                    error!("error releasing blob {}: {:#}", hash, e);
+                }
+            }
+        }
+        Err(e) => error!("can not restore the recipes: {:#}", e),
+    }
+    match backup.wallet {
+        Some(keystore) if wallet => match state.wallet.replace(keystore) {
+            Ok(()) => {
+                save_wallet(state);
+                info!("Restored the wallet")
+            }
+            Err(e) => error!("can not restore the wallet: {:#}", e),
+        },
+        Some(_) => info!(
+            "The backup holds a wallet, restore it with `restore {} wallet`",
+            path
+        ),
+        None => {}
+    }
+    // 从恢复的链尖继续同步
+    sync_next(swarm, state);
+}
+
+/// Try a contract locally on empty storage, e.g. `vm run <wasm-file> <method> [args]`, the
+/// arguments are passed as the bytes of the text
+pub async fn handle_vm_run(cmd: &str) {
+    let rest = cmd.strip_prefix("vm run ").unwrap_or_default().trim();
+    let mut parts = rest.splitn(3, ' ');
+    let (path, method) = match (parts.next(), parts.next()) {
+        (Some(path), Some(method)) => (path, method),
+        _ => {
+            error!("usage: vm run <wasm-file> <method> [args]");
+            return;
+        }
+    };
+    let args = parts.next().unwrap_or_default();
+    let code = match fs::read(path).await {
+        Ok(code) => code,
+        Err(e) => {
+            error!("error reading {}: {}", path, e);
+            return;
+        }
+    };
+    match vm::call(
+        &code,
+        &Storage::new(),
+        method,
+        args.as_bytes(),
+        &Address::default(),
+        VM_MAX_GAS,
+    ) {
+        Ok(execution) => {
+            info!(
+                "{} returned {:?} using {} gas",
+                method,
+                String::from_utf8_lossy(&execution.output),
+                execution.gas_used
+            );
+            for event in execution.events.iter() {
+                info!("  event {:?}", String::from_utf8_lossy(event));
+            }
+            for (key, value) in execution.storage.iter() {
+                info!(
+                    "  storage {:?} = {:?}",
+                    String::from_utf8_lossy(key),
+                    String::from_utf8_lossy(value)
+                );
+            }
+        }
+        Err(e) => error!("error running {}: {}", method, e),
+    }
+}
+
+pub async fn handle_validate_chain(state: &NodeState) {
+    let replay = match state.chain.replayer() {
+        Ok(replay) => replay,
+        Err(e) => {
+            error!("invalid chain: {:#}", e);
+            return;
+        }
+    };
+    let height = state.chain.height();
+    // 重放整条链要验证每个区块，在阻塞线程上对链的副本做，事件循环不用等
+    tokio::task::spawn_blocking(move || match replay.run() {
+        Ok(_) => info!("Chain is valid up to height {}", height),
+        Err(e) => error!("invalid chain: {:#}", e),
+    });
+}
+
+/// Check the stored blocks and recipes against the chain and themselves
+pub async fn handle_verify_storage(state: &NodeState) {
+    let blocks = state.chain.verify_storage();
+    // 读取文件的校验在存储自己的线程上进行，事件循环不用等
+    tokio::spawn(async move {
+        let blocks = match blocks {
+            Ok((blocks, stored)) => stored.finish().await.map(|()| blocks),
+            Err(e) => Err(e),
+        };
+        match blocks {
+            Ok(blocks) => info!("Block storage is consistent, {} blocks", blocks),
+            Err(e) => error!("block storage is inconsistent: {:#}", e),
+        }
+        let recipes = tokio::task::spawn_blocking(|| storage::recipes().verify())
+            .await
+            .unwrap_or_else(|e| Err(e.into()));
+        match recipes {
+            Ok(recipes) => info!("Recipe storage is consistent, {} recipes", recipes),
+            Err(e) => error!("recipe storage is inconsistent: {:#}", e),
+        }
+    });
+}
+
+/// Prune the block bodies that are due, expire deleted recipes past the retention, collect the
+/// blobs nothing refers to any more and compact the databases, run on the maintenance schedule
+/// and by `storage compact`, scheduled runs that found nothing to do are not reported
+pub fn run_storage_maintenance(
+    state: &mut NodeState,
+    sender: &mpsc::UnboundedSender<EventType>,
+    manual: bool,
+) {
+    // 裁剪下的账本在阻塞线程上编码，编码好再回到事件循环写入
+    if let Some(prune) = state.chain.prune() {
+        let sender = sender.clone();
+        tokio::task::spawn_blocking(move || {
+            let _ = sender.send(EventType::BlocksPruned(Box::new(prune.run())));
+        });
+    }
+    // 区块的压缩排在存储自己的线程上，其余的都要读写文件，放到阻塞线程上做完再报告
+    let blocks = state.chain.compact_storage();
+    tokio::task::spawn_blocking(move || {
+        let recipes = storage::recipes();
+        let expired = match recipes.expire(Duration::from_secs(CONFIG.recipe_retention)) {
+            Ok(expired) => expired,
+            Err(e) => {
+                error!("error expiring deleted recipes: {:#}", e);
+                Vec::new()
+            }
+        };
+        for hash in expired.iter().filter_map(|r| r.image.as_ref()) {
+            if let Err(e) = blobs::blobs().release(hash) {
+                error!("error releasing blob {}: {:#}", hash, e);
+            }
+        }
+        let collected = blobs::blobs().gc().unwrap_or_else(|e| {
+            error!("error collecting blobs: {:#}", e);
+            Default::default()
+        });
+        let mut reclaimed = 0;
+        for (name, compacted) in [
+            ("blocks", blocks),
+            ("recipes", recipes.compact()),
+            ("blobs", blobs::blobs().compact()),
+        ] {
+            match compacted.wait() {
+                Ok(bytes) => reclaimed += bytes,
+                Err(e) => error!("error compacting the {} storage: {:#}", name, e),
+            }
+        }
+        if !manual && expired.is_empty() && collected.blobs == 0 && reclaimed == 0 {
+            return;
+        }
+        info!(
+            "Storage maintenance expired {} deleted recipes, removed {} blobs of {} bytes and compacted away {} bytes",
+            expired.len(),
+            collected.blobs,
+            collected.bytes,
+            reclaimed
+        );
+    });
+}
+
+/// Show how many records are stored and how the recipe cache did
+pub async fn handle_storage_stats(state: &NodeState) {
+    let (recipes, blocks) = match storage::recipes()
+        .stats()
+        .and_then(|recipes| Ok((recipes, state.chain.storage_records()?)))
+    {
+        Ok(stats) => stats,
+        Err(e) => {
+            error!("can not read the stored records: {:#}", e);
+            return;
+        }
+    };
+    info!("Storage Stats:");
+    // 区块启动时解码一次后一直留在链上，不用缓存
+    info!("blocks: {} records, all kept decoded by the chain", blocks);
+    info!(
+        "recipes: {} records, {} of {} cached",
+        recipes.records, recipes.cache.len, recipes.cache.capacity
+    );
+    info!(
+        "recipe cache hits: {}, misses: {}, hit rate: {:.1}%",
+        recipes.cache.hits,
+        recipes.cache.misses,
+        recipes.cache.hit_rate()
+    );
+    if let Some(utxos) = state.chain.ledger().cache_stats() {
+        info!(
+            "utxo cache: {} of {} addresses, hits: {}, misses: {}, hit rate: {:.1}%",
+            utxos.len,
+            utxos.capacity,
+            utxos.hits,
+            utxos.misses,
+            utxos.hit_rate()
+        );
+    }
+}
+
+/// Rebuild the ledger and the transaction index from the blocks, e.g. after they got out of step
+/// Rebuild the ledger and the index from our blocks, verifying every block on the way, the
+/// replay runs on a copy of the blocks and is swapped in once it is done
+pub fn handle_reindex_chain(state: &NodeState, sender: &mpsc::UnboundedSender<EventType>) {
+    let replay = match state.chain.replayer() {
+        Ok(replay) => replay,
+        Err(e) => {
+            error!("can not reindex chain: {:#}", e);
+            return;
+        }
+    };
+    let sender = sender.clone();
+    // 和 chain validate 一样在阻塞线程上重放，做完再回到事件循环换掉账本和索引
+    tokio::task::spawn_blocking(move || match replay.run() {
+        Ok(chain) => {
+            let _ = sender.send(EventType::ChainReplayed(Box::new(chain)));
+        }
+        Err(e) => error!("can not reindex chain: {:#}", e),
+    });
+}
+
+/// Swap in the ledger and the index of a finished reindex, the chain is left as it was when it
+/// changed in the meantime
+pub fn handle_chain_replayed(state: &mut NodeState, replay: Chain) {
+    match state.chain.reindex(replay) {
+        Ok(txs) => {
+            info!(
+                "Reindexed {} blocks with {} transactions",
+                state.chain.height(),
+                txs
+            );
+            let next_height = state.chain.height() + 1;
+            state
+                .mempool
+                .revalidate(Vec::new(), state.chain.ledger(), next_height);
+        }
+        Err(e) => error!("can not reindex chain: {:#}", e),
+    }
+}
+
+pub async fn handle_bench_wire() {
+    let recipes = match storage::recipes().list() {
+        Ok(recipes) => recipes,
+        Err(e) => {
+            error!("error fetching local recipes: {}", e);
+            return;
+        }
+    };
+    let resp = ListResponse {
+        mode: ListMode::All,
+        receiver: PEER_ID.to_string(),
+        data: recipes,
+    };
+    match wire::benchmark(&resp, WIRE_BENCHMARK_ITERATIONS) {
+        Ok(results) => {
+            info!(
+                "Wire formats for {} recipes over {} rounds:",
+                resp.data.len(),
+                WIRE_BENCHMARK_ITERATIONS
+            );
+            results.iter().for_each(|r| {
+                info!(
+                    "{:?}: {} bytes, encode {:?}, decode {:?}",
+                    r.format, r.size, r.encode, r.decode
+                )
+            });
+        }
+        Err(e) => error!("error benchmarking wire formats: {}", e),
+    }
+}
+
+/// Measure how long heavy writes hold the event loop with sled flushing in the background and
+/// with every batch flushed, e.g. `bench storage [batches]`, the benchmark itself runs off the
+/// event loop
+pub async fn handle_bench_storage(cmd: &str) {
+    let batches = match cmd.strip_prefix("bench storage").unwrap_or_default().trim() {
+        "" => STORAGE_BENCHMARK_BATCHES,
+        batches => match batches.parse::<usize>() {
+            Ok(batches) if batches > 0 => batches,
+            _ => {
+                error!("usage: bench storage [batches]");
+                return;
+            }
+        },
+    };
+    tokio::spawn(async move {
+        match tokio::task::spawn_blocking(move || storage::benchmark(batches)).await {
+            Ok(Ok(results)) => {
+                info!("Storage writes of {} batches:", batches);
+                results.iter().for_each(|r| {
+                    info!(
+                        "{}: held the loop {:?} in total and {:?} at most, on disk after {:?}",
+                        r.name, r.blocked, r.longest, r.written
+                    )
+                });
+            }
+            Ok(Err(e)) => error!("error benchmarking storage: {}", e),
+            Err(e) => error!("storage benchmark stopped: {}", e),
+        }
+    });
+}
+
+pub async fn handle_create_recipe(cmd: &str) {
+    if let Some(rest) = cmd.strip_prefix("create r") {
+        let elements: Vec<&str> = rest.split('|').collect();
+        if elements.len() < 3 {
+            info!("too few arguments - Format: name|ingredients|instructions");
+        } else {
+            let name = elements.first().expect("name is there");
+            let ingredients = elements.get(1).expect("ingredients is there");
+            let instructions = elements.get(2).expect("instructions is there");
+            match storage::recipes().create(name, ingredients, instructions) {
+                Ok(recipe) => {
+                    info!("Created recipe {}:", recipe.id);
+                    info!("Name: {}", recipe.name);
+                    info!("Ingredients: {}", recipe.ingredients);
+                    info!("Instructions:: {}", recipe.instructions);
+                }
+                Err(e) => error!("error creating recipe: {}", e),
+            }
+        }
+    }
+}
+
+/// Delete a recipe or bring a deleted one back, e.g. `delete r <id>` or `undelete r <id>`, a
+/// deleted recipe is kept for the configured retention
+pub async fn handle_delete_recipe(cmd: &str) {
+    let (undelete, id) = match (cmd.strip_prefix("delete r"), cmd.strip_prefix("undelete r")) {
+        (Some(id), _) => (false, id.trim()),
+        (_, Some(id)) => (true, id.trim()),
+        _ => return,
+    };
+    let id = match id.parse::<usize>() {
+        Ok(id) => id,
+        Err(e) => {
+            error!("invalid id: {}, {}", id, e);
+            return;
+        }
+    };
+    let recipes = storage::recipes();
+    match if undelete {
+        recipes.undelete(id)
+    } else {
+        recipes.delete(id)
+    } {
+        Ok(true) if undelete => info!("Brought back recipe {}", id),
+        Ok(true) => info!(
+            "Deleted recipe {}, `undelete r {}` brings it back within {} seconds",
+            id, id, CONFIG.recipe_retention
+        ),
+        Ok(false) if undelete => error!("no deleted recipe with id {}", id),
+        Ok(false) => error!("no recipe with id {}", id),
+        Err(e) => error!("error updating recipe with id {}, {}", id, e),
+    }
+}
+
+pub async fn handle_publish_recipe(cmd: &str) {
+    if let Some(rest) = cmd.strip_prefix("publish r") {
+        let mut args = rest.split_whitespace();
+        let id = args.next().unwrap_or_default();
+        let topics: Vec<String> = args.map(|t| t.to_owned()).collect();
+        match id.parse::<usize>() {
+            Ok(id) => match storage::recipes().publish(id, topics) {
+                Ok(true) => info!("Published Recipe with id: {}", id),
+                Ok(false) => error!("no recipe with id {}", id),
+                Err(e) => info!("error publishing recipe with id {}, {}", id, e),
+            },
+            Err(e) => error!("invalid id: {}, {}", id, e),
+        };
+    }
+}
+
+/// Attach an image file to a recipe or take it off, e.g. `image r <id> <file>` or
+/// `image r <id> none`, the image is kept in the blob store
+pub async fn handle_recipe_image(cmd: &str) {
+    let usage = "usage: image r <id> <file>|none";
+    let mut args = cmd
+        .strip_prefix("image r")
+        .unwrap_or_default()
+        .split_whitespace();
+    let (id, source) = match (
+        args.next().map(str::parse::<usize>),
+        args.next(),
+        args.next(),
+    ) {
+        (Some(Ok(id)), Some(source), None) => (id, source),
+        _ => {
+            error!("{}", usage);
+            return;
+        }
+    };
+    let image = if source == "none" {
+        None
+    } else {
+        let content = match fs::read(source).await {
+            Ok(content) => content,
+            Err(e) => {
+                error!("error reading {}: {}", source, e);
+                return;
+            }
+        };
+        match blobs::blobs().put(&content) {
+            Ok(hash) => Some(hash),
+            Err(e) => {
+                error!("error storing image: {:#}", e);
+                return;
+            }
+        }
+    };
+    // 新图片先计引用再写菜谱，中断时只会多出引用而不会留下失效的图片
+    let released = match storage::recipes().set_image(id, image) {
+        Ok(Some(before)) => {
+            match image {
+                Some(hash) => info!("Recipe {} shows image {}", id, hash),
+                None => info!("Took the image off recipe {}", id),
+            }
+            before.image
+        }
+        // 菜谱没有改动，放回刚计的引用
+        Ok(None) => {
+            error!("no recipe with id {}", id);
+            image
+        }
+        Err(e) => {
+            error!("error updating recipe with id {}, {}", id, e);
+            image
+        }
+    };
+    if let Some(hash) = released {
+        if let Err(e) = blobs::blobs().release(&hash) {
+            error!("error releasing blob {}: {:#}", hash, e);
+        }
+    }
+}
+
+/// Show, save or collect the blobs, e.g. `blob ls`, `blob get <hash> <file>` or `blob gc`
+pub async fn handle_blob(cmd: &str) {
+    let usage = "usage: blob ls | blob get <hash> <file> | blob gc";
+    let mut args = cmd
+        .strip_prefix("blob")
+        .unwrap_or_default()
+        .split_whitespace();
+    match (args.next(), args.next(), args.next(), args.next()) {
+        (Some("ls"), None, None, None) => match blobs::blobs().list() {
+            Ok(blobs) => {
+                info!("Blobs ({})", blobs.len());
+                blobs.iter().for_each(|(hash, entry)| {
+                    info!("{}: {} bytes, {} refs", hash, entry.size, entry.refs)
+                });
+            }
+            Err(e) => error!("error listing blobs: {:#}", e),
+        },
+        (Some("get"), Some(hash), Some(path), None) => {
+            let hash: Hash = match hash.parse() {
+                Ok(hash) => hash,
+                Err(e) => {
+                    error!("invalid hash: {}", e);
+                    return;
+                }
+            };
+            let content = match blobs::blobs().get(&hash) {
+                Ok(Some(content)) => content,
+                Ok(None) => {
+                    error!("no blob {}", hash);
+                    return;
+                }
+                Err(e) => {
+                    error!("error reading blob: {:#}", e);
+                    return;
+                }
+            };
+            match fs::write(path, &content).await {
+                Ok(()) => info!("Wrote {} bytes to {}", content.len(), path),
+                Err(e) => error!("error writing {}: {}", path, e),
+            }
+        }
+        (Some("gc"), None, None, None) => match blobs::blobs().gc() {
+            Ok(collected) => info!(
+                "Removed {} unreferenced blobs of {} bytes",
+                collected.blobs, collected.bytes
+            ),
+            Err(e) => error!("error collecting blobs: {:#}", e),
+        },
+        _ => error!("{}", usage),
+    }
+}
+
+pub async fn handle_list_recipes(
+    cmd: &str,
+    swarm: &mut Swarm<RecipeBehaviour>,
+    state: &mut NodeState,
+) {
+    let rest = cmd.strip_prefix("ls r ");
+    match rest {
+        Some(rest) if rest.starts_with("all") => {
+            let topic = match rest["all".len()..].trim() {
+                "" => TOPIC.clone(),
+                name => IdentTopic::new(name),
+            };
+            let req = ListRequest {
+                mode: ListMode::All,
+            };
+            publish(swarm, state, topic, &req);
+        }
+        Some(recipes_peer_id) => match recipes_peer_id.parse::<PeerId>() {
+            Ok(peer_id) => {
+                let req = ListRequest {
+                    mode: ListMode::One(recipes_peer_id.to_owned()),
+                };
+                let request_id = swarm
+                    .behaviour_mut()
+                    .request_response
+                    .send_request(&peer_id, req);
+                debug!("Sent recipe request {} to {}", request_id, peer_id);
+            }
+            Err(e) => error!("invalid peer id: {}, {}", recipes_peer_id, e),
+        },
+        None => {
+            match storage::recipes().list() {
+                Ok(v) => {
+                    info!("Local Recipes ({})", v.len());
+                    v.iter().for_each(|r| info!("{:?}", r));
+                }
+                Err(e) => error!("error fetching local recipes: {}", e),
+            };
+        }
+    };
+}
+
+pub async fn handle_relay_connect(cmd: &str, swarm: &mut Swarm<RecipeBehaviour>) {
+    if let Some(rest) = cmd.strip_prefix("relay connect") {
+        match rest.trim().parse::<Multiaddr>() {
+            Ok(relay_addr) => listen_via_relay(swarm, relay_addr),
+            Err(e) => error!("invalid relay address: {}, {}", rest.trim(), e),
+        }
+    }
+}
+
+pub async fn handle_nat_status(swarm: &mut Swarm<RecipeBehaviour>, state: &NodeState) {
+    let autonat = &swarm.behaviour().autonat;
+    match autonat.nat_status() {
+        autonat::NatStatus::Public(addr) => info!("NAT status: public, reachable at {}", addr),
+        autonat::NatStatus::Private => info!("NAT status: private, reachable via relays only"),
+        autonat::NatStatus::Unknown => info!("NAT status: unknown, still probing"),
+    }
+    info!("Confidence: {}", autonat.confidence());
+    if !CONFIG.upnp {
+        info!("UPnP: disabled");
+    } else {
+        match &state.upnp {
+            UpnpStatus::Searching => info!("UPnP: searching for a gateway"),
+            UpnpStatus::Mapped(addrs) => {
+                let addrs: Vec<String> = addrs.iter().map(|a| a.to_string()).collect();
+                info!("UPnP: mapped to {}", addrs.join(", "))
+            }
+            UpnpStatus::GatewayNotFound => info!("UPnP: no gateway found"),
+            UpnpStatus::NonRoutableGateway => info!("UPnP: gateway is not publicly routable"),
+        }
+    }
+    let external: Vec<String> = swarm.external_addresses().map(|a| a.to_string()).collect();
+    info!("External addresses: {}", external.join(", "));
+}
+
+pub async fn handle_dial(cmd: &str, swarm: &mut Swarm<RecipeBehaviour>, state: &mut NodeState) {
+    if let Some(rest) = cmd.strip_prefix("dial") {
+        let addr = match rest.trim().parse::<Multiaddr>() {
+            Ok(addr) => addr,
+            Err(e) => {
+                error!("invalid address: {}, {}", rest.trim(), e);
+                return;
+            }
+        };
+        let opts = match peer_id_of(&addr) {
+            Some(peer_id) => DialOpts::peer_id(peer_id)
+                .addresses(vec![addr.clone()])
+                .build(),
+            None => DialOpts::unknown_peer_id().address(addr.clone()).build(),
+        };
+        let connection_id = opts.connection_id();
+        match swarm.dial(opts) {
+            Ok(()) => {
+                info!("Dialing {}", addr);
+                state.pending_dials.insert(connection_id, addr);
+            }
+            Err(e) => error!("error dialing {}: {}", addr, e),
+        }
+    }
+}
+
+pub async fn handle_peer_info(cmd: &str, state: &NodeState) {
+    if let Some(rest) = cmd.strip_prefix("peer info") {
+        let peer_id = match rest.trim().parse::<PeerId>() {
+            Ok(peer_id) => peer_id,
+            Err(e) => {
+                error!("invalid peer id: {}, {}", rest.trim(), e);
+                return;
+            }
+        };
+        match state.peer_info.get(&peer_id) {
+            Some(info) => {
+                info!("Peer {}:", peer_id);
+                info!("Agent version: {}", info.agent_version);
+                info!("Protocol version: {}", info.protocol_version);
+                let protocols: Vec<String> = info.protocols.iter().map(|p| p.to_string()).collect();
+                info!("Protocols: {}", protocols.join(", "));
+                let addrs: Vec<String> = info.listen_addrs.iter().map(|a| a.to_string()).collect();
+                info!("Listen addresses: {}", addrs.join(", "));
+                info!("Observed us at: {}", info.observed_addr);
+            }
+            None => info!("no identify info for peer {}", peer_id),
+        }
+    }
+}
+
+pub async fn handle_ban(cmd: &str, swarm: &mut Swarm<RecipeBehaviour>, state: &mut NodeState) {
+    if let Some(rest) = cmd.strip_prefix("ban") {
+        match rest.trim().parse::<PeerId>() {
+            Ok(peer_id) => ban_peer(swarm, state, peer_id),
+            Err(e) => error!("invalid peer id: {}, {}", rest.trim(), e),
+        }
+    }
+}
+
+pub async fn handle_unban(cmd: &str, swarm: &mut Swarm<RecipeBehaviour>, state: &mut NodeState) {
+    if let Some(rest) = cmd.strip_prefix("unban") {
+        match rest.trim().parse::<PeerId>() {
+            Ok(peer_id) => {
+                swarm.behaviour_mut().blocked_peers.unblock_peer(peer_id);
+                if state.ban_list.remove(&peer_id) {
+                    save_ban_list(state);
+                    info!("Unbanned peer {}", peer_id);
+                } else {
+                    info!("peer {} was not banned", peer_id);
+                }
+            }
+            Err(e) => error!("invalid peer id: {}, {}", rest.trim(), e),
+        }
+    }
+}
+
+/// Dispatch an enveloped gossip message on its kind, returns the kind that was handled or none
+/// when the payload is malformed
+///
+/// Messages of newer versions or unknown kinds are skipped so old nodes keep working
+fn handle_envelope(
+    envelope: MessageEnvelope,
+    source: PeerId,
+    topic: &TopicHash,
+    sender: &mpsc::UnboundedSender<EventType>,
+) -> Option<MessageKind> {
+    if envelope.version > MESSAGE_VERSION {
+        info!(
+            "ignoring {:?} message of unknown version {} from {}",
+            envelope.kind, envelope.version, source
+        );
+        return Some(MessageKind::Unknown);
+    }
+    match envelope.kind {
+        // 区块只在区块频道上传播
+        MessageKind::Block => return None,
+        MessageKind::ListRequest => match wire::deserialize(&envelope.payload) {
+            Ok(req) => on_list_request(req, source, topic, sender),
+            Err(_) => return None,
+        },
+        MessageKind::ListResponse => match wire::deserialize(&envelope.payload) {
+            Ok(resp) => on_list_response(resp, source),
+            Err(_) => return None,
+        },
+        MessageKind::PeerExchange => match wire::deserialize::<PeerExchange>(&envelope.payload) {
+            Ok(pex) => {
+                if let Err(e) = sender.send(EventType::PeersLearned(source, pex.peers)) {
+                    error!("error sending learned peers via channel, {}", e);
+                }
+            }
+            Err(_) => return None,
+        },
+        MessageKind::Transaction => match wire::deserialize::<Transaction>(&envelope.payload) {
+            // 签名无效的交易与格式错误的消息一样计入转发节点的评分
+            Ok(tx) if !tx.verify() => {
+                debug!(
+                    "transaction {} from {} has an invalid signature",
+                    tx.id(),
+                    source
+                );
+                return None;
+            }
+            Ok(tx) => {
+                if let Err(e) = sender.send(EventType::TransactionReceived(source, tx)) {
+                    error!("error sending transaction via channel, {}", e);
+                }
+            }
+            Err(_) => return None,
+        },
+        MessageKind::Vote => match wire::deserialize::<Vote>(&envelope.payload) {
+            Ok(vote) if !vote.verify() => {
+                debug!(
+                    "vote of {} from {} has an invalid signature",
+                    vote.voter, source
+                );
+                return None;
+            }
+            Ok(vote) => {
+                if let Err(e) = sender.send(EventType::VoteReceived(source, vote)) {
+                    error!("error sending vote via channel, {}", e);
+                }
+            }
+            Err(_) => return None,
+        },
+        MessageKind::Presence => match wire::deserialize::<Presence>(&envelope.payload) {
+            Ok(presence) => {
+                if let Err(e) = sender.send(EventType::PresenceReceived(source, presence.topics)) {
+                    error!("error sending presence via channel, {}", e);
+                }
+            }
+            Err(_) => return None,
+        },
+        MessageKind::Unknown => debug!("ignoring message of unknown kind from {}", source),
+    }
+    Some(envelope.kind)
+}
+
+/// Pass a block gossiped on the blocks topic on to validation, returns none when the message is
+/// not a well-formed block
+///
+/// The topic carries nothing but blocks, so block propagation is kept apart from recipe traffic
+fn handle_block_message(
+    data: &[u8],
+    source: PeerId,
+    sender: &mpsc::UnboundedSender<EventType>,
+) -> Option<MessageKind> {
+    let envelope = wire::deserialize::<MessageEnvelope>(data).ok()?;
+    if envelope.version > MESSAGE_VERSION {
+        info!(
+            "ignoring {:?} message of unknown version {} from {}",
+            envelope.kind, envelope.version, source
+        );
+        return Some(MessageKind::Unknown);
+    }
+    if envelope.kind != MessageKind::Block {
+        return None;
+    }
+    let block = wire::deserialize::<Block>(&envelope.payload).ok()?;
+    if let Err(e) = sender.send(EventType::BlockReceived(source, block)) {
+        error!("error sending block via channel, {}", e);
+    }
+    Some(MessageKind::Block)
+}
+
+/// Messages of peers predating the envelope, returns none when it is neither a request nor a
+/// response
+fn handle_legacy_message(
+    data: &[u8],
+    source: PeerId,
+    topic: &TopicHash,
+    sender: &mpsc::UnboundedSender<EventType>,
+) -> Option<MessageKind> {
+    if let Ok(resp) = wire::deserialize::<ListResponse>(data) {
+        on_list_response(resp, source);
+        Some(MessageKind::ListResponse)
+    } else if let Ok(req) = wire::deserialize::<ListRequest>(data) {
+        on_list_request(req, source, topic, sender);
+        Some(MessageKind::ListRequest)
+    } else {
+        None
+    }
+}
+
+fn on_list_response(resp: ListResponse, source: PeerId) {
+    if resp.receiver == PEER_ID.to_string() {
+        info!("Response from {}:", source);
+        resp.data.iter().for_each(|r| info!("{:?}", r));
+    }
+}
+
+fn on_list_request(
+    req: ListRequest,
+    source: PeerId,
+    topic: &TopicHash,
+    sender: &mpsc::UnboundedSender<EventType>,
+) {
+    match req.mode {
+        ListMode::All => {
+            info!("Received ALL req: {:?} from {:?}", req, source);
+            respond_with_public_recipes(sender.clone(), source.to_string(), topic.clone());
+        }
+        ListMode::One(ref peer_id) => {
+            if peer_id == &PEER_ID.to_string() {
+                info!("Received req: {:?} from {:?}", req, source);
+                respond_with_public_recipes(sender.clone(), source.to_string(), topic.clone());
+            }
+        }
+    }
+}
+
+/// Publish a sample of the address book on the peer exchange topic
+pub fn share_peers(swarm: &mut Swarm<RecipeBehaviour>, state: &mut NodeState) {
+    let topic = PEX_TOPIC.hash();
+    let has_peers = swarm
+        .behaviour()
+        .gossipsub
+        .all_peers()
+        .any(|(_, topics)| topics.contains(&&topic));
+    if !has_peers || !topic_peers_run(swarm, state, &topic, PEX_MIN_PROTOCOL_VERSION) {
+        return;
+    }
+    let peers: Vec<PeerRecord> = state
+        .address_book
+        .sample(PEX_MAX_PEERS)
+        .into_iter()
+        .map(|(peer_id, addrs)| PeerRecord { peer_id, addrs })
+        .collect();
+    if !peers.is_empty() {
+        publish(swarm, state, topic, &PeerExchange { peers });
+    }
+}
+
+/// Announce the topics our shared recipes are served on
+pub async fn announce_presence(swarm: &mut Swarm<RecipeBehaviour>, state: &mut NodeState) {
+    let topic = PRESENCE_TOPIC.hash();
+    let has_peers = swarm
+        .behaviour()
+        .gossipsub
+        .all_peers()
+        .any(|(_, topics)| topics.contains(&&topic));
+    if !has_peers {
+        return;
+    }
+    // 没有本地菜谱文件时也发布空的列表，对端由此知道本节点不会应答
+    let recipes = storage::recipes().list().unwrap_or_default();
+    let mut topics: Vec<String> = Vec::new();
+    for recipe in recipes.iter().filter(|r| r.shared) {
+        if recipe.topics.is_empty() {
+            topics.push(TOPIC.hash().into_string());
+        } else {
+            topics.extend(recipe.topics.iter().cloned());
+        }
+    }
+    topics.sort();
+    topics.dedup();
+    publish(swarm, state, topic, &Presence { topics });
+}
+
+/// Remember the topics a peer announced
+pub fn handle_presence(state: &mut NodeState, peer_id: PeerId, topics: Vec<String>) {
+    debug!("[Presence] {} serves {:?}", peer_id, topics);
+    state.presence.insert(
+        peer_id,
+        PeerPresence {
+            topics,
+            received: Instant::now(),
+        },
+    );
+}
+
+/// Dial peers learned via peer exchange while the node has few connections
+///
+/// Successful dials end up in the address book and are shared on in turn
+pub fn handle_peers_learned(
+    swarm: &mut Swarm<RecipeBehaviour>,
+    state: &NodeState,
+    source: PeerId,
+    peers: Vec<PeerRecord>,
+) {
+    for record in peers.into_iter().take(PEX_MAX_PEERS) {
+        if swarm.network_info().num_peers() >= PEX_TARGET_PEERS {
+            break;
+        }
+        if record.peer_id == *PEER_ID
+            || record.addrs.is_empty()
+            || swarm.is_connected(&record.peer_id)
+            || state.ban_list.contains(&record.peer_id)
+        {
+            continue;
+        }
+        debug!("[PEX] dialing {} learned from {}", record.peer_id, source);
+        let opts = DialOpts::peer_id(record.peer_id)
+            .addresses(record.addrs)
+            .build();
+        if let Err(e) = swarm.dial(opts) {
+            debug!("[PEX] error dialing {}: {}", record.peer_id, e);
+        }
+    }
+}
+
+/// Block the peer, drop its connections and persist the ban
+fn ban_peer(swarm: &mut Swarm<RecipeBehaviour>, state: &mut NodeState, peer_id: PeerId) {
+    // 同时断开与该节点已有的连接
+    swarm.behaviour_mut().blocked_peers.block_peer(peer_id);
+    state.peer_scores.remove(&peer_id);
+    state.rate_limiter.remove(&peer_id);
+    state.reconnector.cancel(peer_id);
+    state.address_book.forget(&peer_id);
+    if state.ban_list.insert(peer_id) {
+        save_ban_list(state);
+    }
+    info!("Banned peer {}", peer_id);
+}
+
+/// Write the bans on a task of their own, waiting for the file in the select loop would stall it
+fn save_ban_list(state: &NodeState) {
+    let save = state.ban_list.save();
+    tokio::spawn(async move {
+        if let Err(e) = save.await {
+            error!("error saving ban list: {}", e);
+        }
+    });
+}
+
+/// Apply the scoring verdict for a peer that just delivered a message
+async fn enforce_verdict(
+    swarm: &mut Swarm<RecipeBehaviour>,
+    state: &mut NodeState,
+    peer_id: PeerId,
+    verdict: Verdict,
+) {
+    match verdict {
+        Verdict::Accept => {}
+        Verdict::Disconnect => {
+            warn!("peer {} exceeded the message rate, disconnecting", peer_id);
+            state.reconnector.cancel(peer_id);
+            let _ = swarm.disconnect_peer_id(peer_id);
+        }
+        Verdict::Ban => {
+            warn!("peer {} score dropped below the ban threshold", peer_id);
+            ban_peer(swarm, state, peer_id);
+        }
+    }
+}
+
+/// Chain status exchange and block download
+async fn handle_sync_event(
+    swarm: &mut Swarm<RecipeBehaviour>,
+    state: &mut NodeState,
+    event: SyncEvent,
+) {
+    match event {
+        request_response::Event::Message { peer, message } => match message {
+            Message::Request {
+                request, channel, ..
+            } => {
+                let (response, status) = match request {
+                    SyncRequest::Status(status) => {
+                        (SyncResponse::Status(chain_status(state)), Some(status))
+                    }
+                    // 区块体已经裁剪时不回复区块，对端会换一个节点下载
+                    SyncRequest::Blocks { from, .. }
+                        if state.chain.is_pruned(cmp::max(from, 1)) =>
+                    {
+                        (SyncResponse::Blocks(Vec::new()), None)
+                    }
+                    SyncRequest::Blocks { from, count } => {
+                        let count = cmp::min(count, SYNC_BATCH_SIZE) as usize;
+                        let blocks = state
+                            .chain
+                            .iter()
+                            .skip(from as usize)
+                            .take(count)
+                            .cloned()
+                            .collect();
+                        (SyncResponse::Blocks(blocks), None)
+                    }
+                    SyncRequest::Headers { locator, count } => {
+                        let count = cmp::min(count, SYNC_HEADERS_BATCH_SIZE) as usize;
+                        let headers = match &state.light {
+                            Some(light) => {
+                                light.headers.headers_after(&state.chain, &locator, count)
+                            }
+                            None => state.chain.headers_after(&locator, count),
+                        };
+                        (SyncResponse::Headers(headers), None)
+                    }
+                    SyncRequest::Mempool(short_ids) => {
+                        let txs = state
+                            .mempool
+                            .missing_from(&short_ids, MEMPOOL_RECONCILE_MAX_TXS);
+                        (SyncResponse::Transactions(txs), None)
+                    }
+                    SyncRequest::Proof(txid) => {
+                        (SyncResponse::Proof(state.chain.prove_tx(&txid)), None)
+                    }
+                    SyncRequest::Data(hash) => {
+                        let data = state
+                            .chain
+                            .find_block(&hash)
+                            .filter(|block| !state.chain.is_pruned(block.header.index))
+                            .map(|block| block.data.clone());
+                        (SyncResponse::Data(data), None)
+                    }
+                    SyncRequest::Account(address) => (
+                        SyncResponse::Account(state.chain.prove_account(&address)),
+                        None,
+                    ),
+                };
+                if swarm
+                    .behaviour_mut()
+                    .sync
+                    .send_response(channel, response)
+                    .is_err()
+                {
+                    debug!("error sending sync response to {}", peer);
+                }
+                // 不在这里断开，对端收到我们的状态后自行断开，否则回复可能丢失
+                if let Some(status) = status {
+                    if accept_status(state, peer, status) {
+                        sync_next(swarm, state);
+                    }
+                }
+            }
+            Message::Response {
+                request_id,
+                response,
+            } => match response {
+                // 对端回复时已经从我们的请求中得知我们的状态
+                SyncResponse::Status(status) => {
+                    if accept_status(state, peer, status) {
+                        sync_next(swarm, state);
+                        // 对端在同一网络上，再比较双方的交易池
+                        if state.light.is_none() {
+                            let short_ids = state.mempool.short_ids();
+                            state.sync.send_mempool(swarm, peer, short_ids);
+                        }
+                    } else {
+                        let _ = swarm.disconnect_peer_id(peer);
+                    }
+                }
+                SyncResponse::Headers(headers) => match state.sync.take_download(&request_id) {
+                    Some(download) if download.fetch == Fetch::Headers => {
+                        receive_headers(swarm, state, download, headers).await
+                    }
+                    _ => debug!("[Sync] ignoring unrequested headers from {}", peer),
+                },
+                SyncResponse::Transactions(txs) => receive_mempool(swarm, state, peer, txs).await,
+                SyncResponse::Blocks(blocks) => match state.sync.take_download(&request_id) {
+                    Some(download) if download.fetch == Fetch::Blocks => {
+                        receive_blocks(swarm, state, download, blocks).await
+                    }
+                    _ => debug!("[Sync] ignoring unrequested blocks from {}", peer),
+                },
+                SyncResponse::Proof(proof) => {
+                    receive_proof(swarm, state, peer, request_id, proof).await
+                }
+                SyncResponse::Data(data) => {
+                    receive_data(swarm, state, peer, request_id, data).await
+                }
+                SyncResponse::Account(proof) => {
+                    receive_account_proof(swarm, state, peer, request_id, proof).await
+                }
+            },
+        },
+        request_response::Event::OutboundFailure {
+            peer,
+            request_id,
+            error,
+        } => {
+            warn!("sync request {} to {} failed: {}", request_id, peer, error);
+            if let Some(light) = state.light.as_mut() {
+                light.take_query(&request_id);
+            }
+            // 下载失败时换一个节点继续
+            if state.sync.take_download(&request_id).is_some() {
+                state.sync.remove(&peer);
+                sync_next(swarm, state);
+            }
+        }
+        request_response::Event::InboundFailure {
+            peer,
+            request_id,
+            error,
+        } => debug!(
+            "sync request {} from {} failed: {}",
+            request_id, peer, error
+        ),
+        request_response::Event::ResponseSent { .. } => {}
+    }
+}
 
-    let mut unique_peers = HashSet::new();
-    for peer in nodes {
-        unique_peers.insert(peer);
+/// Check the proof a full node sent for a transaction we asked about against our headers, a proof
+/// that does not lead to the header counts against the peer
+async fn receive_proof(
+    swarm: &mut Swarm<RecipeBehaviour>,
+    state: &mut NodeState,
+    peer: PeerId,
+    request_id: RequestId,
+    proof: Option<MerkleProof>,
+) {
+    let light = match state.light.as_mut() {
+        Some(light) => light,
+        None => return,
+    };
+    let txid = match light.take_query(&request_id) {
+        Some(Query::Tx(txid)) => txid,
+        _ => {
+            debug!("[Sync] ignoring unrequested proof from {}", peer);
+            return;
+        }
+    };
+    let proof = match proof {
+        Some(proof) => proof,
+        None => {
+            info!("{} knows no confirmed transaction {}", peer, txid);
+            return;
+        }
+    };
+    let header = match light.headers.find(&proof.block) {
+        Some(header) => header,
+        None => {
+            warn!(
+                "the proof of {} from {} names block {} which is not in our headers",
+                txid, peer, proof.block
+            );
+            return;
+        }
+    };
+    if proof.tx == txid && merkle::verify_proof(header, &proof) {
+        info!(
+            "Transaction {} is in block {} {}, {} confirmations",
+            txid,
+            header.index,
+            proof.block,
+            light.headers.height() - header.index + 1
+        );
+        return;
     }
-    unique_peers.iter().for_each(|p| info!("{}", p));
+    warn!("{} sent an invalid proof for transaction {}", peer, txid);
+    let verdict = state.peer_scores.record_invalid_message(peer);
+    enforce_verdict(swarm, state, peer, verdict).await;
 }
 
-pub async fn handle_create_recipe(cmd: &str) {
-    if let Some(rest) = cmd.strip_prefix("create r") {
-        let elements: Vec<&str> = rest.split('|').collect();
-        if elements.len() < 3 {
-            info!("too few arguments - Format: name|ingredients|instructions");
-        } else {
-            let name = elements.first().expect("name is there");
-            let ingredients = elements.get(1).expect("ingredients is there");
-            let instructions = elements.get(2).expect("instructions is there");
-            if let Err(e) = create_new_recipe(name, ingredients, instructions).await {
-                error!("error creating recipe: {}", e);
-            };
+/// Check the payload a full node sent for a block we asked about against the data hash of our
+/// header, e.g. the recipes anchored in the block
+async fn receive_data(
+    swarm: &mut Swarm<RecipeBehaviour>,
+    state: &mut NodeState,
+    peer: PeerId,
+    request_id: RequestId,
+    data: Option<String>,
+) {
+    let light = match state.light.as_mut() {
+        Some(light) => light,
+        None => return,
+    };
+    let hash = match light.take_query(&request_id) {
+        Some(Query::Data(hash)) => hash,
+        _ => {
+            debug!("[Sync] ignoring unrequested block data from {}", peer);
+            return;
         }
+    };
+    let (header, data) = match (light.headers.find(&hash), data) {
+        (Some(header), Some(data)) => (header, data),
+        _ => {
+            info!("{} has no block {}", peer, hash);
+            return;
+        }
+    };
+    if header.data_hash == Hash::digest(data.as_bytes()) {
+        info!("Block {} {}: {}", header.index, hash, data);
+        return;
     }
+    warn!("{} sent data that does not match block {}", peer, hash);
+    let verdict = state.peer_scores.record_invalid_message(peer);
+    enforce_verdict(swarm, state, peer, verdict).await;
 }
 
-pub async fn handle_publish_recipe(cmd: &str) {
-    if let Some(rest) = cmd.strip_prefix("publish r") {
-        match rest.trim().parse::<usize>() {
-            Ok(id) => {
-                if let Err(e) = publish_recipe(id).await {
-                    info!("error publishing recipe with id {}, {}", id, e)
-                } else {
-                    info!("Published Recipe with id: {}", id);
-                }
-            }
-            Err(e) => error!("invalid id: {}, {}", rest.trim(), e),
-        };
+/// Check the state proof a full node sent for an account we asked about against the state root of
+/// our header, a proof that does not lead to the root counts against the peer
+async fn receive_account_proof(
+    swarm: &mut Swarm<RecipeBehaviour>,
+    state: &mut NodeState,
+    peer: PeerId,
+    request_id: RequestId,
+    proof: Option<AccountProof>,
+) {
+    let light = match state.light.as_mut() {
+        Some(light) => light,
+        None => return,
+    };
+    let address = match light.take_query(&request_id) {
+        Some(Query::Account(address)) => address,
+        _ => {
+            debug!("[Sync] ignoring unrequested account proof from {}", peer);
+            return;
+        }
+    };
+    let proof = match proof {
+        Some(proof) => proof,
+        None => {
+            info!("{} can not prove accounts, its ledger has none", peer);
+            return;
+        }
+    };
+    let header = match light.headers.find(&proof.block) {
+        Some(header) => header,
+        None => {
+            warn!(
+                "the proof of {} from {} names block {} which is not in our headers",
+                address, peer, proof.block
+            );
+            return;
+        }
+    };
+    match proof.verify(header) {
+        Ok(account) if proof.address == address => {
+            info!(
+                "Account {} at block {} {}: balance {}, nonce {}",
+                address, header.index, proof.block, account.balance, account.nonce
+            );
+            return;
+        }
+        Ok(_) => {}
+        Err(e) => debug!("[Sync] account proof from {}: {}", peer, e),
     }
+    warn!("{} sent an invalid proof for account {}", peer, address);
+    let verdict = state.peer_scores.record_invalid_message(peer);
+    enforce_verdict(swarm, state, peer, verdict).await;
 }
 
-pub async fn handle_list_recipes(cmd: &str, swarm: &mut Swarm<RecipeBehaviour>) {
-    let rest = cmd.strip_prefix("ls r ");
-    match rest {
-        Some("all") => {
-            let req = ListRequest {
-                mode: ListMode::All,
-            };
-            let json = serde_json::to_string(&req).expect("can jsonify request");
-            swarm
-                .behaviour_mut()
-                .flood_sub
-                .publish(TOPIC.clone(), json.as_bytes());
+/// Admit the pending transactions of a peer we did not have, a transaction with an invalid
+/// signature counts against the peer
+async fn receive_mempool(
+    swarm: &mut Swarm<RecipeBehaviour>,
+    state: &mut NodeState,
+    peer: PeerId,
+    txs: Vec<Transaction>,
+) {
+    let received = txs.len();
+    let mut accepted = 0;
+    for tx in txs.into_iter().take(MEMPOOL_RECONCILE_MAX_TXS) {
+        if !tx.verify() {
+            warn!(
+                "transaction {} from {} has an invalid signature",
+                tx.id(),
+                peer
+            );
+            let verdict = state.peer_scores.record_invalid_message(peer);
+            enforce_verdict(swarm, state, peer, verdict).await;
+            return;
         }
-        Some(recipes_peer_id) => {
-            let req = ListRequest {
-                mode: ListMode::One(recipes_peer_id.to_owned()),
-            };
-            let json = serde_json::to_string(&req).expect("can jsonify request");
-            swarm
-                .behaviour_mut()
-                .flood_sub
-                .publish(TOPIC.clone(), json.as_bytes());
+        if let Err(e) = state.chain.check_evidence(&tx) {
+            debug!("[Mempool] rejected transaction from {}: {}", peer, e);
+            continue;
         }
+        match state
+            .mempool
+            .insert(tx, state.chain.ledger(), state.chain.height() + 1)
+        {
+            Ok(_) => accepted += 1,
+            Err(e) => debug!("[Mempool] rejected transaction from {}: {}", peer, e),
+        }
+    }
+    if received > 0 {
+        info!(
+            "Took {} of {} pending transactions from {}",
+            accepted, received, peer
+        );
+    }
+}
+
+/// Whether the peer is on our network, peers of another network are refused from now on
+fn accept_status(state: &mut NodeState, peer_id: PeerId, status: ChainStatus) -> bool {
+    if status.genesis != state.chain.genesis_hash() {
+        warn!(
+            "peer {} is on another network with genesis {}, disconnecting",
+            peer_id, status.genesis
+        );
+        state.sync.reject(peer_id);
+        state.reconnector.cancel(peer_id);
+        return false;
+    }
+    debug!(
+        "[Sync] {} is at height {} {}",
+        peer_id, status.height, status.tip
+    );
+    state.sync.on_status(peer_id, status);
+    true
+}
+
+/// Follow a downloaded batch of headers, invalid headers count against the peer that sent them
+async fn receive_headers(
+    swarm: &mut Swarm<RecipeBehaviour>,
+    state: &mut NodeState,
+    download: Download,
+    headers: Vec<BlockHeader>,
+) {
+    let peer = download.peer;
+    if headers.is_empty() {
+        debug!(
+            "[Sync] {} has no header {}, forgetting its status",
+            peer, download.from
+        );
+        state.sync.remove(&peer);
+    }
+    let result = match state.light.as_mut() {
+        Some(light) => light.headers.add(&state.chain, headers),
         None => {
-            match read_local_recipes().await {
-                Ok(v) => {
-                    info!("Local Recipes ({})", v.len());
-                    v.iter().for_each(|r| info!("{:?}", r));
+            for header in headers.iter() {
+                detect_equivocation(swarm, state, header);
+            }
+            state.sync.add_headers(&state.chain, &download, headers)
+        }
+    };
+    match result {
+        Ok(added) => debug!("[Sync] {} headers from {}", added, peer),
+        Err(e) if e.is_peer_fault() => {
+            warn!("rejected headers from {}: {}", peer, e);
+            state.sync.remove(&peer);
+            let verdict = state.peer_scores.record_invalid_block(peer, &e);
+            enforce_verdict(swarm, state, peer, verdict).await;
+        }
+        // 对端的链与本地链分叉，无法直接接在本地链之后
+        Err(e) => {
+            warn!("can not follow the headers of {}: {}", peer, e);
+            state.sync.remove(&peer);
+        }
+    }
+    sync_next(swarm, state);
+}
+
+/// Keep a downloaded batch of blocks and append the ones that are next in line
+async fn receive_blocks(
+    swarm: &mut Swarm<RecipeBehaviour>,
+    state: &mut NodeState,
+    download: Download,
+    blocks: Vec<Block>,
+) {
+    let peer = download.peer;
+    if blocks.is_empty() {
+        debug!(
+            "[Sync] {} has no block {}, forgetting its status",
+            peer, download.from
+        );
+        state.sync.remove(&peer);
+    } else if !state.sync.add_blocks(&state.chain, &download, blocks) {
+        warn!(
+            "peer {} sent blocks {}..{} of another chain",
+            peer,
+            download.from,
+            download.from + download.count as u64
+        );
+        state.sync.remove(&peer);
+    }
+    let ready = state.sync.take_ready(&state.chain);
+    let applied = match ready.first() {
+        Some((_, first)) if first.header.index <= state.chain.height() => {
+            reorganize(state, ready).await
+        }
+        _ => connect_blocks(swarm, state, ready).await,
+    };
+    state.sync.applied += applied;
+    if applied > 0 {
+        connect_orphans(swarm, state).await;
+        vote_tip(swarm, state);
+        restart_mining(state);
+    }
+    sync_next(swarm, state);
+    let height = state.chain.height();
+    let behind = matches!(state.sync.best(), Some((_, best)) if best.height > height);
+    if applied > 0 && !behind {
+        info!("Synced to height {} {}", height, state.chain.tip().hash);
+    }
+}
+
+/// Append downloaded blocks on top of the tip, returns how many were appended
+async fn connect_blocks(
+    swarm: &mut Swarm<RecipeBehaviour>,
+    state: &mut NodeState,
+    blocks: Vec<(PeerId, Block)>,
+) -> u64 {
+    let mut applied = 0;
+    // 通向检查点的区块头已经校验过，区块体只需对照账本
+    let checkpointed = state.sync.checkpointed(&state.chain);
+    for (source, block) in blocks {
+        let (index, hash) = (block.header.index, block.hash);
+        let transactions = block.transactions.clone();
+        let added = if checkpointed.is_some_and(|height| index <= height) {
+            state.chain.add_checkpointed_block(block)
+        } else {
+            state.chain.try_add_block(block)
+        };
+        match added {
+            Ok(()) => {
+                applied += 1;
+                remove_confirmed(state, &transactions);
+                state.orphans.remove(&hash);
+            }
+            Err(e) => {
+                warn!("rejected block {} {} from {}: {}", index, hash, source, e);
+                // 区块头有效但区块无效，这条链走不通，重新下载区块头
+                state.sync.reset();
+                if e.is_peer_fault() {
+                    state.sync.remove(&source);
+                    let verdict = state.peer_scores.record_invalid_block(source, &e);
+                    enforce_verdict(swarm, state, source, verdict).await;
                 }
-                Err(e) => error!("error fetching local recipes: {}", e),
-            };
+                break;
+            }
+        }
+    }
+    applied
+}
+
+/// Switch to a downloaded branch with more work than ours, the transactions of the blocks taken
+/// off go back to the mempool, returns how many blocks were connected
+async fn reorganize(state: &mut NodeState, branch: Vec<(PeerId, Block)>) -> u64 {
+    let fork = branch[0].1.header.index - 1;
+    let count = branch.len() as u64;
+    let hashes: Vec<Hash> = branch.iter().map(|(_, block)| block.hash).collect();
+    let blocks = branch.into_iter().map(|(_, block)| block).collect();
+    let reorg = match state.chain.reorganize(fork, blocks) {
+        Ok(reorg) => reorg,
+        // 无法确定是哪个对端的区块出了问题，只放弃这条分支
+        Err(e) => {
+            warn!("rejected branch forking off after block {}: {}", fork, e);
+            state.sync.reset();
+            return 0;
         }
     };
+    hashes.iter().for_each(|hash| state.orphans.remove(hash));
+    let new_tip = state.chain.tip().hash;
+    warn!(
+        "Reorganized the chain {} blocks deep after block {}, tip {} replaced by {}",
+        reorg.depth(),
+        reorg.fork,
+        reorg.old_tip,
+        new_tip
+    );
+    state
+        .telemetry
+        .record_reorg(reorg.fork, reorg.depth(), reorg.old_tip, new_tip);
+    let returned = reorg
+        .disconnected
+        .into_iter()
+        .flat_map(|block| block.transactions)
+        .collect();
+    let dropped =
+        state
+            .mempool
+            .revalidate(returned, state.chain.ledger(), state.chain.height() + 1);
+    if dropped > 0 {
+        info!(
+            "Dropped {} pending transactions that no longer apply",
+            dropped
+        );
+    }
+    count
+}
+
+/// Reserve a slot on a relay, once accepted we receive a /p2p-circuit listen address
+fn listen_via_relay(swarm: &mut Swarm<RecipeBehaviour>, relay_addr: Multiaddr) {
+    if !matches!(relay_addr.iter().last(), Some(Protocol::P2p(_))) {
+        error!("relay address must end with /p2p/<relay peer id>");
+        return;
+    }
+    match swarm.listen_on(relay_addr.clone().with(Protocol::P2pCircuit)) {
+        Ok(_) => info!("Requesting reservation on relay {}", relay_addr),
+        Err(e) => error!("error listening via relay {}: {}", relay_addr, e),
+    }
+}
+
+pub async fn handle_relay_stats(state: &NodeState) {
+    if !CONFIG.relay_server {
+        info!("relay server is disabled, start the node with --relay-server");
+        return;
+    }
+    let stats = &state.relay_stats;
+    info!("Relay Stats:");
+    info!(
+        "reservations accepted: {}, denied: {}, timed out: {}",
+        stats.reservations_accepted, stats.reservations_denied, stats.reservations_timed_out
+    );
+    info!(
+        "circuits accepted: {}, denied: {}, failed: {}, closed: {}",
+        stats.circuits_accepted,
+        stats.circuits_denied,
+        stats.circuits_failed,
+        stats.circuits_closed
+    );
+    stats.per_peer.iter().for_each(|(peer, s)| {
+        info!(
+            "{}: reservations: {}, circuits: {}",
+            peer, s.reservations, s.circuits
+        )
+    });
+}
+
+/// Leave all topics, persist the node state and close every connection, giving up after the
+/// configured shutdown timeout
+pub async fn handle_shutdown(
+    event_sender: mpsc::UnboundedSender<EventType>,
+    swarm: &mut Swarm<RecipeBehaviour>,
+    state: &mut NodeState,
+) {
+    info!("Shutting down");
+    let topics: Vec<TopicHash> = swarm.behaviour().gossipsub.topics().cloned().collect();
+    for topic in topics {
+        if let Err(e) = swarm
+            .behaviour_mut()
+            .gossipsub
+            .unsubscribe(&IdentTopic::new(topic.as_str()))
+        {
+            error!("error unsubscribing from {}: {:?}", topic, e);
+        }
+    }
+    if let Err(e) = state.address_book.save().await {
+        error!("error saving address book: {}", e);
+    }
+    if let Err(e) = state.ban_list.save().await {
+        error!("error saving ban list: {}", e);
+    }
+    if let Err(e) = state.peer_scores.save().await {
+        error!("error saving peer scores: {}", e);
+    }
+    if let Err(e) = state.chain.flush_storage().finish().await {
+        error!("error writing blocks: {}", e);
+    }
+    if let Some(light) = state.light.as_ref() {
+        if let Err(e) = light.headers.flush().finish().await {
+            error!("error writing headers: {}", e);
+        }
+    }
+    if let Err(e) = storage::recipes().flush().finish().await {
+        error!("error writing recipes: {}", e);
+    }
+    if let Err(e) = blobs::blobs().flush().finish().await {
+        error!("error writing blobs: {}", e);
+    }
+
+    let timeout = Duration::from_secs(CONFIG.shutdown_timeout);
+    let closed = tokio::time::timeout(timeout, async {
+        // 先让 gossipsub 把退订消息发出去，再断开连接
+        let _ = tokio::time::timeout(
+            SHUTDOWN_UNSUBSCRIBE_GRACE,
+            handle_swarm_events_forever(&event_sender, swarm, state),
+        )
+        .await;
+        let peers: Vec<PeerId> = swarm.connected_peers().copied().collect();
+        for peer_id in peers {
+            let _ = swarm.disconnect_peer_id(peer_id);
+        }
+        while swarm.network_info().num_peers() > 0 {
+            handle_swarm_event(event_sender.clone(), swarm, state).await;
+        }
+    })
+    .await;
+    match closed {
+        Ok(()) => info!("All connections closed"),
+        Err(_) => warn!(
+            "{} connections still open after {:?}, exiting anyway",
+            swarm.network_info().num_peers(),
+            timeout
+        ),
+    }
+}
+
+async fn handle_swarm_events_forever(
+    event_sender: &mpsc::UnboundedSender<EventType>,
+    swarm: &mut Swarm<RecipeBehaviour>,
+    state: &mut NodeState,
+) {
+    loop {
+        handle_swarm_event(event_sender.clone(), swarm, state).await;
+    }
 }
 
 pub async fn handle_swarm_event(
-    response_sender: mpsc::UnboundedSender<ListResponse>,
+    event_sender: mpsc::UnboundedSender<EventType>,
     swarm: &mut Swarm<RecipeBehaviour>,
+    state: &mut NodeState,
 ) {
     let event = swarm.select_next_some().await;
-    info!("Income swarm Event: {:?}", event);
+    debug!("Income swarm Event: {:?}", event);
+    state.telemetry.record(&event);
 
     match event {
         SwarmEvent::Behaviour(recipe_behaviours) => match recipe_behaviours {
-            RecipeBehaviourEvent::Floodsub(flood_sub_event) => match flood_sub_event {
-                FloodsubEvent::Message(msg) => {
-                    if let Ok(resp) = serde_json::from_slice::<ListResponse>(&msg.data) {
-                        if resp.receiver == PEER_ID.to_string() {
-                            info!("Response from {}:", msg.source);
-                            resp.data.iter().for_each(|r| info!("{:?}", r));
+            RecipeBehaviourEvent::Gossipsub(gossipsub_event) => match gossipsub_event {
+                gossipsub::Event::Message {
+                    propagation_source,
+                    message,
+                    ..
+                } => {
+                    state.net_stats.record_gossip_in(
+                        propagation_source,
+                        &message.topic,
+                        message.data.len(),
+                    );
+                    state.health.record_message(propagation_source);
+                    let verdict = state.peer_scores.record_message(propagation_source);
+                    enforce_verdict(swarm, state, propagation_source, verdict).await;
+                    let source = message.source.unwrap_or(propagation_source);
+                    // 分片收齐并校验通过后才得到完整的负载
+                    let payload = if transfer::is_chunk(&message.data) {
+                        match state
+                            .transfers
+                            .accept(source, &message.data, CONFIG.max_message_size)
+                        {
+                            Ok(Some(payload)) => payload,
+                            Ok(None) => return,
+                            Err(e) => {
+                                warn!("invalid chunk from {}: {}", propagation_source, e);
+                                let verdict =
+                                    state.peer_scores.record_invalid_message(propagation_source);
+                                enforce_verdict(swarm, state, propagation_source, verdict).await;
+                                return;
+                            }
+                        }
+                    } else {
+                        message.data
+                    };
+                    // 对端节点转发的消息过多时直接丢弃，避免消息风暴
+                    // 区块和投票不受菜谱流量的限制，无效区块由区块校验扣分
+                    // 限流在去重之前，被丢弃的消息不记为已见，其他节点转发时仍会处理
+                    if message.topic != PEX_TOPIC.hash()
+                        && message.topic != PRESENCE_TOPIC.hash()
+                        && message.topic != BLOCKS_TOPIC.hash()
+                        && message.topic != VOTES_TOPIC.hash()
+                        && !state.rate_limiter.allow(propagation_source)
+                    {
+                        debug!(
+                            "rate limit exceeded by {}, dropping message",
+                            propagation_source
+                        );
+                        state
+                            .net_stats
+                            .record_dropped(propagation_source, &message.topic);
+                        return;
+                    }
+                    // 同一条消息经由不同节点转发多次时只处理第一次
+                    if !state.seen_messages.insert(&source, &payload) {
+                        debug!("dropping duplicate message from {}", source);
+                        return;
+                    }
+                    let data = match codec::decode(&payload, CONFIG.max_message_size) {
+                        Ok(data) => data,
+                        Err(e) => {
+                            warn!("dropping message from {}: {}", propagation_source, e);
+                            let verdict =
+                                state.peer_scores.record_invalid_message(propagation_source);
+                            enforce_verdict(swarm, state, propagation_source, verdict).await;
+                            return;
                         }
-                    } else if let Ok(req) = serde_json::from_slice::<ListRequest>(&msg.data) {
-                        match req.mode {
-                            ListMode::All => {
-                                info!("Received ALL req: {:?} from {:?}", req, msg.source);
-                                respond_with_public_recipes(
-                                    response_sender.clone(),
-                                    msg.source.to_string(),
-                                );
+                    };
+                    let kind = if message.topic == BLOCKS_TOPIC.hash() {
+                        handle_block_message(&data, source, &event_sender)
+                    } else {
+                        match wire::deserialize::<MessageEnvelope>(&data) {
+                            Ok(envelope) => {
+                                handle_envelope(envelope, source, &message.topic, &event_sender)
                             }
-                            ListMode::One(ref peer_id) => {
-                                if peer_id == &PEER_ID.to_string() {
-                                    info!("Received req: {:?} from {:?}", req, msg.source);
-                                    respond_with_public_recipes(
-                                        response_sender.clone(),
-                                        msg.source.to_string(),
-                                    );
-                                }
+                            // 旧版本节点直接发送消息本身，没有信封
+                            Err(_) => {
+                                handle_legacy_message(&data, source, &message.topic, &event_sender)
                             }
                         }
+                    };
+                    match kind {
+                        Some(MessageKind::ListResponse) => {
+                            state.peer_scores.record_good_response(source)
+                        }
+                        Some(_) => {}
+                        None => {
+                            warn!("invalid message from {}", propagation_source);
+                            let verdict =
+                                state.peer_scores.record_invalid_message(propagation_source);
+                            enforce_verdict(swarm, state, propagation_source, verdict).await;
+                        }
+                    }
+                }
+                // 新节点加入时立即公布，不必等到下一次定时公布
+                gossipsub::Event::Subscribed { topic, .. } if topic == PRESENCE_TOPIC.hash() => {
+                    if let Err(e) = event_sender.send(EventType::AnnouncePresence) {
+                        error!("error sending presence announcement via channel, {}", e);
                     }
                 }
-                FloodsubEvent::Subscribed { .. } => {}
-                FloodsubEvent::Unsubscribed { .. } => {}
+                gossipsub::Event::Subscribed { .. } => {}
+                gossipsub::Event::Unsubscribed { .. } => {}
+                gossipsub::Event::GossipsubNotSupported { .. } => {}
             },
             RecipeBehaviourEvent::Mdns(mdns_event) => match mdns_event {
                 Event::Discovered(discovered_list) => {
                     let behavior_mut = swarm.behaviour_mut();
-                    for (peer, _addr) in discovered_list {
-                        behavior_mut.flood_sub.add_node_to_partial_view(peer);
+                    for (peer, addr) in discovered_list {
+                        behavior_mut.gossipsub.add_explicit_peer(&peer);
+                        behavior_mut.kad.add_address(&peer, addr);
                     }
                 }
                 Event::Expired(expired_list) => {
                     let behavior_mut = swarm.behaviour_mut();
                     for (peer, _addr) in expired_list {
-                        if !behavior_mut.mdns.has_node(&peer) {
-                            behavior_mut.flood_sub.remove_node_from_partial_view(&peer);
+                        let still_known = behavior_mut
+                            .mdns
+                            .as_ref()
+                            .is_some_and(|mdns| mdns.has_node(&peer));
+                        if !still_known {
+                            behavior_mut.gossipsub.remove_explicit_peer(&peer);
+                        }
+                    }
+                }
+            },
+            RecipeBehaviourEvent::Kad(kad_event) => match kad_event {
+                kad::Event::OutboundQueryProgressed {
+                    result: kad::QueryResult::Bootstrap(result),
+                    ..
+                } => match result {
+                    Ok(kad::BootstrapOk {
+                        peer,
+                        num_remaining,
+                    }) => debug!(
+                        "[Kademlia bootstrap] reached peer: {}, remaining: {}",
+                        peer, num_remaining
+                    ),
+                    Err(e) => error!("kademlia bootstrap failed: {:?}", e),
+                },
+                kad::Event::RoutingUpdated {
+                    peer,
+                    is_new_peer: true,
+                    ..
+                } => debug!("[Kademlia] added peer to routing table: {}", peer),
+                _ => {}
+            },
+            RecipeBehaviourEvent::Sync(sync_event) => {
+                handle_sync_event(swarm, state, sync_event).await
+            }
+            RecipeBehaviourEvent::RequestResponse(rr_event) => match rr_event {
+                request_response::Event::Message { peer, message } => match message {
+                    Message::Request {
+                        request, channel, ..
+                    } => {
+                        info!("Received direct req: {:?} from {:?}", request, peer);
+                        match storage::recipes().list() {
+                            Ok(recipes) => {
+                                let resp = ListResponse {
+                                    mode: request.mode,
+                                    receiver: peer.to_string(),
+                                    data: recipes.into_iter().filter(|r| r.shared).collect(),
+                                };
+                                if swarm
+                                    .behaviour_mut()
+                                    .request_response
+                                    .send_response(channel, resp)
+                                    .is_err()
+                                {
+                                    error!("error sending direct response to {}", peer);
+                                }
+                            }
+                            Err(e) => error!(
+                                "error fetching local recipes to answer direct request, {}",
+                                e
+                            ),
+                        }
+                    }
+                    Message::Response { response, .. } => {
+                        state.peer_scores.record_good_response(peer);
+                        info!("Response from {}:", peer);
+                        response.data.iter().for_each(|r| info!("{:?}", r));
+                    }
+                },
+                request_response::Event::OutboundFailure {
+                    peer,
+                    request_id,
+                    error,
+                } => error!(
+                    "recipe request {} to {} failed: {}",
+                    request_id, peer, error
+                ),
+                request_response::Event::InboundFailure {
+                    peer,
+                    request_id,
+                    error,
+                } => error!(
+                    "recipe request {} from {} failed: {}",
+                    request_id, peer, error
+                ),
+                request_response::Event::ResponseSent { .. } => {}
+            },
+            RecipeBehaviourEvent::RelayClient(relay_event) => match relay_event {
+                relay::client::Event::ReservationReqAccepted {
+                    relay_peer_id,
+                    renewal,
+                    ..
+                } => {
+                    if !renewal {
+                        info!("Reservation accepted by relay {}", relay_peer_id);
+                    }
+                }
+                relay::client::Event::ReservationReqFailed {
+                    relay_peer_id,
+                    error,
+                    ..
+                } => error!("reservation on relay {} failed: {}", relay_peer_id, error),
+                relay::client::Event::OutboundCircuitEstablished { relay_peer_id, .. } => {
+                    debug!("[Relay] outbound circuit via {}", relay_peer_id)
+                }
+                relay::client::Event::OutboundCircuitReqFailed {
+                    relay_peer_id,
+                    error,
+                } => error!("circuit via relay {} failed: {}", relay_peer_id, error),
+                relay::client::Event::InboundCircuitEstablished { src_peer_id, .. } => {
+                    debug!("[Relay] inbound circuit from {}", src_peer_id)
+                }
+                relay::client::Event::InboundCircuitReqDenied { .. } => {}
+                relay::client::Event::InboundCircuitReqDenyFailed { .. } => {}
+            },
+            RecipeBehaviourEvent::RelayServer(relay_event) => {
+                let stats = &mut state.relay_stats;
+                match relay_event {
+                    relay::Event::ReservationReqAccepted {
+                        src_peer_id,
+                        renewed,
+                    } => {
+                        if !renewed {
+                            stats.reservations_accepted += 1;
+                            stats.peer_mut(src_peer_id).reservations += 1;
+                            debug!("[Relay server] reservation accepted for {}", src_peer_id);
+                        }
+                    }
+                    relay::Event::ReservationReqDenied { src_peer_id } => {
+                        stats.reservations_denied += 1;
+                        info!("relay reservation denied for {}", src_peer_id);
+                    }
+                    relay::Event::ReservationTimedOut { src_peer_id } => {
+                        stats.reservations_timed_out += 1;
+                        debug!("[Relay server] reservation timed out for {}", src_peer_id);
+                    }
+                    relay::Event::CircuitReqAccepted {
+                        src_peer_id,
+                        dst_peer_id,
+                    } => {
+                        stats.circuits_accepted += 1;
+                        stats.peer_mut(src_peer_id).circuits += 1;
+                        debug!(
+                            "[Relay server] circuit {} -> {} accepted",
+                            src_peer_id, dst_peer_id
+                        );
+                    }
+                    relay::Event::CircuitReqDenied {
+                        src_peer_id,
+                        dst_peer_id,
+                    } => {
+                        stats.circuits_denied += 1;
+                        info!("relay circuit {} -> {} denied", src_peer_id, dst_peer_id);
+                    }
+                    relay::Event::CircuitReqOutboundConnectFailed { .. }
+                    | relay::Event::CircuitReqAcceptFailed { .. } => stats.circuits_failed += 1,
+                    relay::Event::CircuitClosed { .. } => stats.circuits_closed += 1,
+                    relay::Event::ReservationReqAcceptFailed { .. } => {}
+                    relay::Event::ReservationReqDenyFailed { .. } => {}
+                    relay::Event::CircuitReqDenyFailed { .. } => {}
+                }
+            }
+            RecipeBehaviourEvent::Autonat(autonat_event) => match autonat_event {
+                autonat::Event::StatusChanged { old, new } => {
+                    info!("NAT status changed from {:?} to {:?}", old, new);
+                    match new {
+                        autonat::NatStatus::Public(addr) => swarm.add_external_address(addr),
+                        autonat::NatStatus::Private => {
+                            // 本节点不可被直接访问，改为通过配置的 relay 对外提供服务
+                            if !state.relay_fallback_active {
+                                state.relay_fallback_active = true;
+                                for relay_addr in CONFIG.relays.iter() {
+                                    listen_via_relay(swarm, relay_addr.clone());
+                                }
+                            }
+                        }
+                        autonat::NatStatus::Unknown => {}
+                    }
+                }
+                autonat::Event::InboundProbe(_) => {}
+                autonat::Event::OutboundProbe(_) => {}
+            },
+            RecipeBehaviourEvent::Identify(identify_event) => match identify_event {
+                identify::Event::Received { peer_id, info } => {
+                    debug!(
+                        "[Identify] {} runs {} ({})",
+                        peer_id, info.agent_version, info.protocol_version
+                    );
+                    let kad = &mut swarm.behaviour_mut().kad;
+                    for addr in info.listen_addrs.iter() {
+                        kad.add_address(&peer_id, addr.clone());
+                    }
+                    state.peer_info.insert(peer_id, info);
+                }
+                identify::Event::Error { peer_id, error } => {
+                    debug!("[Identify] error with {}: {}", peer_id, error)
+                }
+                identify::Event::Sent { .. } => {}
+                identify::Event::Pushed { .. } => {}
+            },
+            RecipeBehaviourEvent::Ping(ping::Event { peer, result, .. }) => match result {
+                Ok(rtt) => state.latencies.entry(peer).or_default().record(rtt),
+                Err(e) => debug!("[Ping] {} failed: {}", peer, e),
+            },
+            RecipeBehaviourEvent::Rendezvous(rendezvous_event) => match rendezvous_event {
+                rendezvous::client::Event::Discovered {
+                    rendezvous_node,
+                    registrations,
+                    cookie,
+                } => {
+                    state.rendezvous_cookies.insert(rendezvous_node, cookie);
+                    for registration in registrations {
+                        let peer_id = registration.record.peer_id();
+                        if peer_id == *PEER_ID || swarm.is_connected(&peer_id) {
+                            continue;
+                        }
+                        info!(
+                            "[Rendezvous] discovered {} via {}",
+                            peer_id, rendezvous_node
+                        );
+                        let opts = DialOpts::peer_id(peer_id)
+                            .addresses(registration.record.addresses().to_vec())
+                            .build();
+                        if let Err(e) = swarm.dial(opts) {
+                            error!("error dialing {}: {}", peer_id, e);
                         }
                     }
                 }
+                rendezvous::client::Event::DiscoverFailed {
+                    rendezvous_node,
+                    error,
+                    ..
+                } => error!(
+                    "[Rendezvous] discovery at {} failed: {:?}",
+                    rendezvous_node, error
+                ),
+                rendezvous::client::Event::Registered {
+                    rendezvous_node,
+                    ttl,
+                    namespace,
+                } => info!(
+                    "[Rendezvous] registered as {} at {} for {}s",
+                    namespace, rendezvous_node, ttl
+                ),
+                rendezvous::client::Event::RegisterFailed {
+                    rendezvous_node,
+                    error,
+                    ..
+                } => error!(
+                    "[Rendezvous] registration at {} failed: {:?}",
+                    rendezvous_node, error
+                ),
+                rendezvous::client::Event::Expired { peer } => {
+                    debug!("[Rendezvous] registration of {} expired", peer)
+                }
+            },
+            RecipeBehaviourEvent::RendezvousServer(rendezvous_event) => match rendezvous_event {
+                rendezvous::server::Event::PeerRegistered { peer, registration } => info!(
+                    "[Rendezvous] {} registered for {}",
+                    peer, registration.namespace
+                ),
+                rendezvous::server::Event::PeerNotRegistered { peer, error, .. } => {
+                    debug!(
+                        "[Rendezvous] rejected registration of {}: {:?}",
+                        peer, error
+                    )
+                }
+                other => debug!("[Rendezvous] {:?}", other),
+            },
+            RecipeBehaviourEvent::Upnp(upnp_event) => match upnp_event {
+                // 映射成功的地址由 upnp 行为自动上报为外部地址
+                upnp::Event::NewExternalAddr(addr) => {
+                    info!("[UPnP] mapped external address {}", addr);
+                    match &mut state.upnp {
+                        UpnpStatus::Mapped(addrs) => addrs.push(addr),
+                        status => *status = UpnpStatus::Mapped(vec![addr]),
+                    }
+                }
+                upnp::Event::ExpiredExternalAddr(addr) => {
+                    warn!("[UPnP] mapping of {} expired", addr);
+                    if let UpnpStatus::Mapped(addrs) = &mut state.upnp {
+                        addrs.retain(|a| a != &addr);
+                    }
+                }
+                upnp::Event::GatewayNotFound => {
+                    info!("[UPnP] no gateway found");
+                    state.upnp = UpnpStatus::GatewayNotFound;
+                }
+                upnp::Event::NonRoutableGateway => {
+                    warn!("[UPnP] gateway is not exposed to the public network");
+                    state.upnp = UpnpStatus::NonRoutableGateway;
+                }
             },
+            RecipeBehaviourEvent::Dcutr(dcutr_event) => {
+                let hole_punch = match dcutr_event {
+                    dcutr::Event::DirectConnectionUpgradeSucceeded { remote_peer_id } => {
+                        state.net_stats.dials.record_hole_punch(true);
+                        Some(EventType::HolePunchSucceeded(remote_peer_id))
+                    }
+                    dcutr::Event::DirectConnectionUpgradeFailed {
+                        remote_peer_id,
+                        error,
+                    } => {
+                        state.net_stats.dials.record_hole_punch(false);
+                        Some(EventType::HolePunchFailed(
+                            remote_peer_id,
+                            error.to_string(),
+                        ))
+                    }
+                    dcutr::Event::InitiatedDirectConnectionUpgrade { remote_peer_id, .. }
+                    | dcutr::Event::RemoteInitiatedDirectConnectionUpgrade {
+                        remote_peer_id, ..
+                    } => {
+                        debug!("[DCUtR] hole punching with {}", remote_peer_id);
+                        None
+                    }
+                };
+                if let Some(event) = hole_punch {
+                    if let Err(e) = event_sender.send(event) {
+                        error!("error sending hole punch result via channel, {}", e);
+                    }
+                }
+            }
         },
         SwarmEvent::ConnectionEstablished {
             peer_id,
@@ -155,6 +4219,37 @@ pub async fn handle_swarm_event(
             num_established,
             ..
         } => {
+            state.bootstrapper.on_connected(&peer_id);
+            state.reconnector.on_connected(&peer_id);
+            if state.sync.is_foreign(&peer_id) {
+                debug!("dropping connection to {} of another network", peer_id);
+                state.reconnector.cancel(peer_id);
+                let _ = swarm.disconnect_peer_id(peer_id);
+            } else if num_established.get() == 1 {
+                let status = chain_status(state);
+                state.sync.send_status(swarm, peer_id, status);
+            }
+            state.health.record_peer(peer_id);
+            report_dial(state, &event_sender, connection_id, Ok(peer_id));
+            // 只有主动拨号的地址可以再次拨通，对端的来源端口是临时的
+            if let ConnectedPoint::Dialer { address, .. } = &endpoint {
+                state.net_stats.dials.record_success(address);
+                if num_established.get() == 1 {
+                    state.address_book.record(peer_id, address.clone());
+                    // 在 select 里等待写文件可能被取消，交给单独的任务
+                    let save = state.address_book.save();
+                    tokio::spawn(async move {
+                        if let Err(e) = save.await {
+                            error!("error saving address book: {}", e);
+                        }
+                    });
+                }
+            }
+            if is_rendezvous_point(&peer_id) && num_established.get() == 1 {
+                let behaviour = swarm.behaviour_mut();
+                behaviour.rendezvous_register(peer_id);
+                behaviour.rendezvous_discover(peer_id, None);
+            }
             debug!("[Connection established] peer_id: {}, connection_id: {}, endpoint: {:?}, num_established: {:?}", peer_id, connection_id, endpoint, num_established);
         }
         SwarmEvent::ConnectionClosed {
@@ -162,80 +4257,253 @@ pub async fn handle_swarm_event(
             connection_id,
             endpoint,
             num_established,
-            ..
+            cause,
         } => {
+            state.health.record_peer(peer_id);
+            // 空闲超时与双方主动关闭都不算异常断开，节点崩溃时多个连接同时断开只记一次
+            let abrupt = match &cause {
+                Some(ConnectionError::KeepAliveTimeout) | None => false,
+                Some(_) => true,
+            };
+            if abrupt && num_established == 0 {
+                let verdict = state.peer_scores.record_abrupt_disconnect(peer_id);
+                enforce_verdict(swarm, state, peer_id, verdict).await;
+            }
+            if num_established == 0 {
+                state.sync.remove(&peer_id);
+                sync_next(swarm, state);
+                // 在 select 里等待写文件可能被取消，交给单独的任务
+                let save = state.peer_scores.save();
+                tokio::spawn(async move {
+                    if let Err(e) = save.await {
+                        error!("error saving peer scores: {}", e);
+                    }
+                });
+                let known = !state.address_book.addrs(&peer_id).is_empty();
+                state
+                    .reconnector
+                    .on_disconnected(&event_sender, peer_id, known);
+            }
             debug!("[Connection closed] peer_id: {}, connection_id: {}, endpoint: {:?}, num_established: {:?}", peer_id, connection_id, endpoint, num_established);
         }
         SwarmEvent::IncomingConnection { .. } => {}
+        SwarmEvent::IncomingConnectionError {
+            send_back_addr,
+            error: ListenError::Denied { cause },
+            ..
+        } => log_denied_connection(&send_back_addr.to_string(), &cause),
         SwarmEvent::IncomingConnectionError { .. } => {}
-        SwarmEvent::OutgoingConnectionError { .. } => {}
-        SwarmEvent::NewListenAddr { .. } => {}
-        SwarmEvent::ExpiredListenAddr { .. } => {}
+        SwarmEvent::OutgoingConnectionError {
+            connection_id,
+            peer_id,
+            error: DialError::Denied { cause },
+        } => {
+            state.net_stats.dials.failed_without_address += 1;
+            report_dial(state, &event_sender, connection_id, Err(cause.to_string()));
+            log_denied_connection(&format!("{:?}", peer_id), &cause)
+        }
+        SwarmEvent::OutgoingConnectionError {
+            connection_id,
+            peer_id,
+            error,
+        } => {
+            debug!("[Dial failed] peer_id: {:?}, error: {}", peer_id, error);
+            state.net_stats.dials.record_failure(&error);
+            report_dial(state, &event_sender, connection_id, Err(error.to_string()));
+            if let Some(peer_id) = peer_id {
+                state.bootstrapper.on_dial_failure(&event_sender, peer_id);
+                state.reconnector.on_dial_failure(&event_sender, peer_id);
+            }
+        }
+        SwarmEvent::NewListenAddr { address, .. } => {
+            info!("Listening on {}", address);
+            // 通过 relay 获得的地址需要对外公布，其他节点才能经由 relay 连接进来
+            if address.iter().any(|p| p == Protocol::P2pCircuit) {
+                swarm.add_external_address(address);
+            } else if !CONFIG.rendezvous_points.is_empty() && !is_loopback(&address) {
+                // 没有 NAT 检测结果时直接公布监听地址，向 rendezvous 注册需要外部地址
+                swarm.add_external_address(address);
+            }
+        }
+        SwarmEvent::ExpiredListenAddr { address, .. } => {
+            info!("No longer listening on {}", address)
+        }
         SwarmEvent::ListenerClosed { .. } => {}
         SwarmEvent::ListenerError { .. } => {}
         SwarmEvent::Dialing { .. } => {}
     };
 }
 
-async fn publish_recipe(id: usize) -> Result<()> {
-    let mut local_recipes = read_local_recipes().await?;
-    local_recipes
-        .iter_mut()
-        .filter(|r| r.id == id)
-        .for_each(|r| r.shared = true);
-    write_local_recipes(&local_recipes).await?;
-    Ok(())
+/// Report the outcome of a dial started by the `dial` command
+fn report_dial(
+    state: &mut NodeState,
+    sender: &mpsc::UnboundedSender<EventType>,
+    connection_id: ConnectionId,
+    result: Result<PeerId, String>,
+) {
+    if let Some(addr) = state.pending_dials.remove(&connection_id) {
+        if let Err(e) = sender.send(EventType::DialResult(addr, result)) {
+            error!("error sending dial result via channel, {}", e);
+        }
+    }
+}
+
+/// Whether the peer is one of the configured rendezvous points
+fn is_rendezvous_point(peer_id: &PeerId) -> bool {
+    CONFIG
+        .rendezvous_points
+        .iter()
+        .any(|addr| peer_id_of(addr) == Some(*peer_id))
 }
 
-async fn create_new_recipe(name: &str, ingredients: &str, instructions: &str) -> Result<()> {
-    let mut local_recipes = read_local_recipes().await?;
-    let new_id = match local_recipes.iter().max_by_key(|r| r.id) {
-        Some(v) => v.id + 1,
-        None => 0,
-    };
-    local_recipes.push(Recipe {
-        id: new_id,
-        name: name.to_owned(),
-        ingredients: ingredients.to_owned(),
-        instructions: instructions.to_owned(),
-        shared: false,
-    });
-    write_local_recipes(&local_recipes).await?;
+/// Look up recipe nodes at every connected rendezvous point
+pub fn discover_via_rendezvous(swarm: &mut Swarm<RecipeBehaviour>, state: &NodeState) {
+    let points: Vec<PeerId> = CONFIG
+        .rendezvous_points
+        .iter()
+        .filter_map(peer_id_of)
+        .filter(|peer_id| swarm.is_connected(peer_id))
+        .collect();
+    for peer_id in points {
+        let cookie = state.rendezvous_cookies.get(&peer_id).cloned();
+        swarm.behaviour_mut().rendezvous_discover(peer_id, cookie);
+    }
+}
 
-    info!("Created recipe:");
-    info!("Name: {}", name);
-    info!("Ingredients: {}", ingredients);
-    info!("Instructions:: {}", instructions);
+fn is_loopback(addr: &Multiaddr) -> bool {
+    addr.iter().any(|p| match p {
+        Protocol::Ip4(ip) => ip.is_loopback(),
+        Protocol::Ip6(ip) => ip.is_loopback(),
+        _ => false,
+    })
+}
 
-    Ok(())
+/// Connections refused by a behaviour, most notably when a connection limit is hit
+fn log_denied_connection(remote: &str, cause: &ConnectionDenied) {
+    match cause.downcast_ref::<connection_limits::Exceeded>() {
+        Some(exceeded) => warn!("connection with {} rejected: {}", remote, exceeded),
+        None => debug!("connection with {} denied: {}", remote, cause),
+    }
 }
 
-async fn write_local_recipes(recipes: &Vec<Recipe>) -> Result<()> {
-    let json = serde_json::to_string(&recipes)?;
-    fs::write(STORAGE_FILE_PATH, &json).await?;
-    Ok(())
+/// Publish a message on a topic, logging instead of failing when gossipsub rejects it
+///
+/// The envelope, CBOR and compression are only used when every peer subscribed to the topic announced support
+/// for them
+pub fn publish<T: GossipMessage>(
+    swarm: &mut Swarm<RecipeBehaviour>,
+    state: &mut NodeState,
+    topic: impl Into<TopicHash>,
+    message: &T,
+) {
+    let topic = topic.into();
+    let format = match CONFIG.wire_format {
+        WireFormat::Cbor if topic_peers_run(swarm, state, &topic, CBOR_MIN_PROTOCOL_VERSION) => {
+            WireFormat::Cbor
+        }
+        _ => WireFormat::Json,
+    };
+    let compress = CONFIG.compression
+        && topic_peers_run(swarm, state, &topic, COMPRESSION_MIN_PROTOCOL_VERSION);
+    // 信封之前的节点只认识列表请求和响应，其他消息不论对端版本都带信封发送
+    let enveloped = !matches!(
+        T::KIND,
+        MessageKind::ListRequest | MessageKind::ListResponse
+    ) || topic_peers_run(swarm, state, &topic, ENVELOPE_MIN_PROTOCOL_VERSION);
+    let data = if enveloped {
+        wire::serialize(message, format).and_then(|payload| {
+            let envelope = MessageEnvelope {
+                version: MESSAGE_VERSION,
+                kind: T::KIND,
+                payload,
+            };
+            wire::serialize(&envelope, format)
+        })
+    } else {
+        wire::serialize(message, format)
+    };
+    let data = match data {
+        Ok(data) => data,
+        Err(e) => {
+            error!("error encoding message for topic {}: {}", topic, e);
+            return;
+        }
+    };
+    if data.len() > CONFIG.max_message_size {
+        error!(
+            "message of {} bytes exceeds the maximum message size of {} bytes, not publishing to {}",
+            data.len(),
+            CONFIG.max_message_size,
+            topic
+        );
+        return;
+    }
+    // 超过单条 gossip 消息上限的负载拆成多个分片依次发布
+    let chunks = match transfer::split(codec::encode(&data, compress)) {
+        Ok(chunks) => chunks,
+        Err(e) => {
+            error!("error publishing to topic {}: {}", topic, e);
+            return;
+        }
+    };
+    for chunk in chunks {
+        let len = chunk.len();
+        if let Err(e) = swarm
+            .behaviour_mut()
+            .gossipsub
+            .publish(topic.clone(), chunk)
+        {
+            error!("error publishing to topic {}: {:?}", topic, e);
+            return;
+        }
+        state.net_stats.record_gossip_out(&topic, len);
+    }
+    state
+        .mesh
+        .record_publish(&swarm.behaviour().gossipsub, &topic);
 }
 
-async fn read_local_recipes() -> Result<Vec<Recipe>> {
-    let content = fs::read(STORAGE_FILE_PATH).await?;
-    let result = serde_json::from_slice(&content)?;
-    Ok(result)
+/// Whether all peers subscribed to the topic run at least the given protocol version
+fn topic_peers_run(
+    swarm: &Swarm<RecipeBehaviour>,
+    state: &NodeState,
+    topic: &TopicHash,
+    min_version: &str,
+) -> bool {
+    swarm
+        .behaviour()
+        .gossipsub
+        .all_peers()
+        .filter(|(_, topics)| topics.contains(&topic))
+        .all(|(peer, _)| {
+            state
+                .peer_info
+                .get(peer)
+                .is_some_and(|info| codec::protocol_at_least(&info.protocol_version, min_version))
+        })
 }
 
-fn respond_with_public_recipes(sender: mpsc::UnboundedSender<ListResponse>, receiver: String) {
+fn respond_with_public_recipes(
+    sender: mpsc::UnboundedSender<EventType>,
+    receiver: String,
+    topic: TopicHash,
+) {
     tokio::spawn(async move {
-        match read_local_recipes().await {
+        match storage::recipes().list() {
             Ok(recipes) => {
                 let resp = ListResponse {
                     mode: ListMode::All,
                     receiver,
-                    data: recipes.into_iter().filter(|r| r.shared).collect(),
+                    data: recipes
+                        .into_iter()
+                        .filter(|r| r.shared_on(&topic))
+                        .collect(),
                 };
-                if let Err(e) = sender.send(resp) {
+                if let Err(e) = sender.send(EventType::Response(topic, resp)) {
                     error!("error sending response via channel, {}", e);
                 }
             }
             Err(e) => error!("error fetching local recipes to answer ALL request, {}", e),
         }
     });
-}
\ No newline at end of file
+}
@@ -0,0 +1,85 @@
+use std::fmt;
+use std::str::FromStr;
+
+use anyhow::{bail, Context, Result};
+use hmac::{Hmac, Mac};
+use sha2::Sha512;
+
+/// Index offset of hardened children, ed25519 only supports hardened derivation
+const HARDENED: u32 = 0x8000_0000;
+
+/// SLIP-10 derivation path of hardened indices, e.g. `m/44'/1'/0'`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DerivationPath(Vec<u32>);
+
+impl DerivationPath {
+    /// The path of the child with the index below this one
+    pub fn child(&self, index: u32) -> DerivationPath {
+        let mut path = self.0.clone();
+        path.push(index);
+        DerivationPath(path)
+    }
+
+    /// Secret ed25519 key at the path, following SLIP-10
+    pub fn derive(&self, seed: &[u8]) -> [u8; 32] {
+        let (mut key, mut chain_code) = split(hmac(b"ed25519 seed", &[seed]));
+        for index in self.0.iter() {
+            let index = (index | HARDENED).to_be_bytes();
+            (key, chain_code) = split(hmac(&chain_code, &[&[0], &key, &index]));
+        }
+        key
+    }
+}
+
+impl fmt::Display for DerivationPath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("m")?;
+        for index in self.0.iter() {
+            write!(f, "/{}'", index)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for DerivationPath {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split('/');
+        if parts.next() != Some("m") {
+            bail!("derivation path {} must start with m", s);
+        }
+        let mut indices = Vec::new();
+        for part in parts {
+            let index = match part.strip_suffix('\'').or_else(|| part.strip_suffix('h')) {
+                Some(index) => index,
+                None => bail!("ed25519 only supports hardened indices, use {}'", part),
+            };
+            let index: u32 = index
+                .parse()
+                .with_context(|| format!("invalid index {} in derivation path {}", part, s))?;
+            if index >= HARDENED {
+                bail!("index {} of derivation path {} is too large", index, s);
+            }
+            indices.push(index);
+        }
+        Ok(DerivationPath(indices))
+    }
+}
+
+fn hmac(key: &[u8], data: &[&[u8]]) -> [u8; 64] {
+    let mut mac = Hmac::<Sha512>::new_from_slice(key).expect("hmac takes keys of any length");
+    for data in data {
+        mac.update(data);
+    }
+    mac.finalize().into_bytes().into()
+}
+
+/// Key and chain code halves of an extended key
+fn split(bytes: [u8; 64]) -> ([u8; 32], [u8; 32]) {
+    let mut key = [0u8; 32];
+    let mut chain_code = [0u8; 32];
+    key.copy_from_slice(&bytes[..32]);
+    chain_code.copy_from_slice(&bytes[32..]);
+    (key, chain_code)
+}
@@ -0,0 +1,125 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use libp2p::gossipsub::TopicHash;
+use libp2p::{PeerId, Swarm};
+use log::{info, warn};
+
+use crate::behaviour::RecipeBehaviour;
+use crate::consts::{HEALTH_PARTITION_THRESHOLD, HEALTH_RECENT_PEERS_WINDOW};
+
+/// Snapshot of the node's view of the network
+#[derive(Debug)]
+pub struct HealthReport {
+    pub connected_peers: usize,
+    /// Peers we were connected to or heard from within the recent window
+    pub recent_peers: usize,
+    /// Subscribed topics no peer is subscribed to
+    pub empty_topics: Vec<TopicHash>,
+    /// Time since the last gossip message, or since startup when none arrived yet
+    pub silent_for: Duration,
+}
+
+impl HealthReport {
+    /// No gossip for too long, the node is probably cut off from the rest of the network
+    pub fn partitioned(&self) -> bool {
+        self.silent_for >= HEALTH_PARTITION_THRESHOLD
+    }
+
+    /// What is wrong, empty when the node is healthy
+    pub fn problems(&self) -> Vec<HealthProblem> {
+        let mut problems = Vec::new();
+        if self.connected_peers == 0 {
+            problems.push(HealthProblem::NoPeers);
+        }
+        if self.partitioned() {
+            problems.push(HealthProblem::Partitioned);
+        }
+        problems
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HealthProblem {
+    NoPeers,
+    /// No gossip received for longer than the partition threshold
+    Partitioned,
+}
+
+/// Tracks peer and message activity to tell whether the node is still part of the network
+#[derive(Debug)]
+pub struct NetHealth {
+    last_seen: HashMap<PeerId, Instant>,
+    last_message: Option<Instant>,
+    started: Instant,
+    /// Problems found by the last check, so each is only warned about once
+    problems: Vec<HealthProblem>,
+}
+
+impl Default for NetHealth {
+    fn default() -> Self {
+        NetHealth {
+            last_seen: HashMap::new(),
+            last_message: None,
+            started: Instant::now(),
+            problems: Vec::new(),
+        }
+    }
+}
+
+impl NetHealth {
+    /// A connection to the peer opened or closed
+    pub fn record_peer(&mut self, peer_id: PeerId) {
+        self.last_seen.insert(peer_id, Instant::now());
+    }
+
+    /// A gossip message delivered by the peer
+    pub fn record_message(&mut self, peer_id: PeerId) {
+        let now = Instant::now();
+        self.last_seen.insert(peer_id, now);
+        self.last_message = Some(now);
+    }
+
+    pub fn report(&self, swarm: &Swarm<RecipeBehaviour>) -> HealthReport {
+        let gossipsub = &swarm.behaviour().gossipsub;
+        let empty_topics = gossipsub
+            .topics()
+            .filter(|topic| {
+                !gossipsub
+                    .all_peers()
+                    .any(|(_, topics)| topics.contains(topic))
+            })
+            .cloned()
+            .collect();
+        HealthReport {
+            connected_peers: swarm.network_info().num_peers(),
+            recent_peers: self
+                .last_seen
+                .values()
+                .filter(|seen| seen.elapsed() < HEALTH_RECENT_PEERS_WINDOW)
+                .count(),
+            empty_topics,
+            silent_for: self.last_message.unwrap_or(self.started).elapsed(),
+        }
+    }
+
+    /// Log a warning for every problem that appeared since the last check and a note once all
+    /// are gone
+    pub fn check(&mut self, swarm: &Swarm<RecipeBehaviour>) {
+        let report = self.report(swarm);
+        let problems = report.problems();
+        for problem in problems.iter().filter(|p| !self.problems.contains(p)) {
+            match problem {
+                HealthProblem::NoPeers => warn!("network health degraded: no connected peers"),
+                HealthProblem::Partitioned => warn!(
+                    "network health degraded: no messages received for {:?}, the node may be partitioned",
+                    report.silent_for
+                ),
+            }
+        }
+        if problems.is_empty() && !self.problems.is_empty() {
+            info!("Network health recovered");
+        }
+        self.problems = problems;
+    }
+}
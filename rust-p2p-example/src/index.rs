@@ -0,0 +1,68 @@
+use std::collections::HashMap;
+
+use crate::blockchain::{Block, Hash};
+use crate::transaction::{Address, Transaction};
+
+/// Lookups of the confirmed transactions derived from the blocks, so answering them needs no
+/// scan of the chain
+///
+/// Nothing here is consensus state, `chain reindex` rebuilds it from the blocks
+#[derive(Debug, Default)]
+pub struct ChainIndex {
+    /// Height of the block confirming each transaction
+    txs: HashMap<Hash, u64>,
+    /// Transactions sent or received by each address, oldest first
+    addresses: HashMap<Address, Vec<Hash>>,
+}
+
+impl ChainIndex {
+    /// Index the transactions of a block added on top of the chain
+    pub fn connect(&mut self, block: &Block) {
+        for tx in block.transactions.iter() {
+            let id = tx.id();
+            self.txs.insert(id, block.header.index);
+            for address in touched(tx) {
+                self.addresses.entry(address).or_default().push(id);
+            }
+        }
+    }
+
+    /// Forget the transactions of the tip taken off the chain
+    pub fn disconnect(&mut self, block: &Block) {
+        // 撤销的是最新的区块，它的交易在每个地址列表的末尾
+        for tx in block.transactions.iter().rev() {
+            self.txs.remove(&tx.id());
+            for address in touched(tx) {
+                if let Some(txs) = self.addresses.get_mut(&address) {
+                    txs.pop();
+                    if txs.is_empty() {
+                        self.addresses.remove(&address);
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn tx_height(&self, txid: &Hash) -> Option<u64> {
+        self.txs.get(txid).copied()
+    }
+
+    /// Confirmed transactions of the address, oldest first
+    pub fn address_txs(&self, address: &Address) -> &[Hash] {
+        self.addresses.get(address).map_or(&[], Vec::as_slice)
+    }
+
+    /// Transactions indexed
+    pub fn tx_count(&self) -> usize {
+        self.txs.len()
+    }
+}
+
+/// Addresses a transaction is listed under, the sender of a coinbase is no address
+fn touched(tx: &Transaction) -> Vec<Address> {
+    let mut addresses = vec![tx.to];
+    if !tx.is_coinbase() && tx.from != tx.to {
+        addresses.push(tx.from);
+    }
+    addresses
+}
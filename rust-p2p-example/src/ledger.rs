@@ -0,0 +1,753 @@
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fmt;
+use std::sync::Mutex;
+
+use serde::{Deserialize, Serialize};
+
+use crate::accounts::{Account, AccountState};
+use crate::blockchain::{Block, Hash};
+use crate::consensus::Validator;
+use crate::consts::UTXO_CACHE_CAPACITY;
+use crate::contracts::{Contract, ContractSet, ContractUndo, Receipt};
+use crate::genesis::Allocation;
+use crate::lru_cache::{CacheStats, LruCache};
+use crate::staking::StakeSet;
+use crate::storage;
+use crate::transaction::{Address, OutPoint, Output, Transaction};
+use crate::trie::{self, ProofNode, StateTrie};
+use crate::vm::VmError;
+
+/// Why a transaction can not be applied to the ledger
+#[derive(Debug, PartialEq, Eq)]
+pub enum LedgerError {
+    NoInputs,
+    /// The output does not exist or was spent already
+    Spent(OutPoint),
+    NotOwner(OutPoint),
+    /// The transaction creates an output that exists already, it was confirmed before
+    OutputExists(OutPoint),
+    /// The inputs are worth a different amount than the payment, the change and the fee together
+    ValueMismatch {
+        inputs: u64,
+        outputs: u64,
+    },
+    InsufficientFunds {
+        available: u64,
+        required: u64,
+    },
+    /// The transaction is not the next one of its sender
+    NonceMismatch {
+        expected: u64,
+        actual: u64,
+    },
+    /// Inputs or change on a ledger that keeps balances
+    UnexpectedInputs,
+    /// A staking transaction sent to another address than the sender
+    StakeRecipient,
+    InsufficientStake {
+        available: u64,
+        required: u64,
+    },
+    /// The equivocation was reported already
+    AlreadySlashed,
+    /// The transaction reports no equivocation of a validator with stake
+    NothingToSlash,
+    /// A contract transaction moving coins, sent to another address than its contract or without
+    /// what it hands to the VM, or another transaction with contract data
+    ContractMismatch,
+    ContractExists(Address),
+    NoContract(Address),
+    InvalidCode(VmError),
+    GasLimitTooHigh {
+        limit: u64,
+        max: u64,
+    },
+    GasLimitTooLow {
+        limit: u64,
+        min: u64,
+    },
+    /// The transaction has a receipt, it was confirmed before
+    Confirmed(Hash),
+    /// The fee does not pay for the gas limit of the call
+    FeeBelowGas {
+        fee: u64,
+        required: u64,
+    },
+}
+
+impl fmt::Display for LedgerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LedgerError::NoInputs => write!(f, "transaction spends no outputs"),
+            LedgerError::Spent(outpoint) => write!(f, "output {} is spent or unknown", outpoint),
+            LedgerError::OutputExists(outpoint) => {
+                write!(
+                    f,
+                    "output {} exists already, the transaction is confirmed",
+                    outpoint
+                )
+            }
+            LedgerError::NotOwner(outpoint) => {
+                write!(f, "output {} is not owned by the sender", outpoint)
+            }
+            LedgerError::ValueMismatch { inputs, outputs } => write!(
+                f,
+                "inputs worth {} do not match outputs worth {}",
+                inputs, outputs
+            ),
+            LedgerError::InsufficientFunds {
+                available,
+                required,
+            } => write!(
+                f,
+                "insufficient funds, {} of {} available",
+                available, required
+            ),
+            LedgerError::NonceMismatch { expected, actual } => {
+                write!(f, "nonce {} where {} was expected", actual, expected)
+            }
+            LedgerError::UnexpectedInputs => {
+                write!(f, "account transactions can not have inputs or change")
+            }
+            LedgerError::StakeRecipient => {
+                write!(f, "staking transactions must be sent to the sender")
+            }
+            LedgerError::InsufficientStake {
+                available,
+                required,
+            } => write!(
+                f,
+                "insufficient stake, {} of {} bonded",
+                available, required
+            ),
+            LedgerError::AlreadySlashed => write!(f, "the equivocation was slashed already"),
+            LedgerError::NothingToSlash => write!(f, "no validator with stake is reported"),
+            LedgerError::ContractMismatch => {
+                write!(f, "contract data does not match the transaction")
+            }
+            LedgerError::ContractExists(address) => {
+                write!(f, "a contract is deployed at {} already", address)
+            }
+            LedgerError::NoContract(address) => write!(f, "no contract is deployed at {}", address),
+            LedgerError::InvalidCode(e) => write!(f, "{}", e),
+            LedgerError::GasLimitTooHigh { limit, max } => {
+                write!(f, "gas limit {} is above the maximum {}", limit, max)
+            }
+            LedgerError::GasLimitTooLow { limit, min } => {
+                write!(f, "gas limit {} is below the minimum {}", limit, min)
+            }
+            LedgerError::Confirmed(txid) => write!(f, "transaction {} is confirmed already", txid),
+            LedgerError::FeeBelowGas { fee, required } => write!(
+                f,
+                "fee {} does not pay for the gas limit, which costs {}",
+                fee, required
+            ),
+        }
+    }
+}
+
+/// How the chain keeps track of who owns which coins, fixed by the genesis file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LedgerKind {
+    /// Transactions spend outputs of earlier transactions
+    #[default]
+    Utxo,
+    /// Every address has a balance and a nonce
+    Account,
+}
+
+impl LedgerKind {
+    /// The ledger with the genesis allocations and the stake of the genesis validators, who lose
+    /// `slash_percent` of their stake when they equivocate
+    pub fn genesis_state(
+        &self,
+        genesis_hash: Hash,
+        allocations: &[Allocation],
+        validators: &[Validator],
+        slash_percent: u64,
+        gas_price: u64,
+    ) -> Box<dyn LedgerModel> {
+        let stakes = StakeSet::from_genesis(validators, slash_percent);
+        let contracts = ContractSet::new(gas_price);
+        match self {
+            LedgerKind::Utxo => Box::new(UtxoSet::from_genesis(
+                genesis_hash,
+                allocations,
+                stakes,
+                contracts,
+            )),
+            LedgerKind::Account => {
+                Box::new(AccountState::from_genesis(allocations, stakes, contracts))
+            }
+        }
+    }
+}
+
+impl fmt::Display for LedgerKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LedgerKind::Utxo => write!(f, "utxo"),
+            LedgerKind::Account => write!(f, "account"),
+        }
+    }
+}
+
+/// Every entry of a ledger, written to snapshots
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LedgerSnapshot {
+    Utxo(UtxoSet),
+    Account(AccountState),
+}
+
+impl LedgerSnapshot {
+    pub fn kind(&self) -> LedgerKind {
+        match self {
+            LedgerSnapshot::Utxo(_) => LedgerKind::Utxo,
+            LedgerSnapshot::Account(_) => LedgerKind::Account,
+        }
+    }
+
+    pub fn into_ledger(self) -> Box<dyn LedgerModel> {
+        match self {
+            LedgerSnapshot::Utxo(set) => Box::new(set.indexed()),
+            LedgerSnapshot::Account(state) => Box::new(state),
+        }
+    }
+}
+
+/// What a block changed in the ledger, so the block can be disconnected again
+#[derive(Debug, Clone)]
+pub enum BlockUndo {
+    /// Outputs spent by each transaction of the block, in the order of the transactions, and the
+    /// changes to contracts and receipts
+    Utxo(Vec<Vec<(OutPoint, Output)>>, Vec<ContractUndo>),
+    /// Accounts touched by the block as they were before it, and the changes to contracts and
+    /// receipts
+    Account(Vec<(Address, Option<Account>)>, Vec<ContractUndo>),
+}
+
+/// State of the ledger at the tip of the chain
+pub trait LedgerModel: fmt::Debug + Send {
+    fn kind(&self) -> LedgerKind;
+
+    /// Whether the transaction applies on top of the state and the pending transactions of the
+    /// same sender, oldest first
+    fn check(&self, tx: &Transaction, pending: &[&Transaction]) -> Result<(), LedgerError>;
+
+    /// Complete a new transaction of the sender so it applies, `reserved` tells which outputs
+    /// pending transactions already spend
+    fn fund(
+        &self,
+        tx: &mut Transaction,
+        reserved: &dyn Fn(&OutPoint) -> bool,
+    ) -> Result<(), LedgerError>;
+
+    /// Apply the transactions of the block in order, leaving the state untouched when one of them
+    /// does not apply
+    fn connect_block(&mut self, block: &Block) -> Result<BlockUndo, LedgerError>;
+
+    /// Revert `connect_block`, the block must be the last one connected
+    fn disconnect_block(&mut self, block: &Block, undo: BlockUndo);
+
+    fn balance(&self, address: &Address) -> u64;
+
+    /// Every address holding coins with its balance
+    fn balances(&self) -> Vec<(Address, u64)>;
+
+    /// Confirmed transactions sent by the address, the nonce its next transaction gets
+    fn nonce(&self, address: &Address) -> u64;
+
+    /// Addresses with bonded stake, ordered by address
+    fn validators(&self) -> Vec<Validator>;
+
+    /// Stake the validator lost for equivocating
+    fn slashed(&self, address: &Address) -> u64;
+
+    /// Contract deployed at the address
+    fn contract(&self, address: &Address) -> Option<&Contract>;
+
+    /// Outcome of a confirmed transaction
+    fn receipt(&self, txid: &Hash) -> Option<&Receipt>;
+
+    /// Roots of the parts of the state, balances first
+    fn state_roots(&self) -> Vec<Hash>;
+
+    /// Root committing to the whole state, every block header carries the root after the block
+    fn state_root(&self) -> Hash {
+        trie::combine(&self.state_roots())
+    }
+
+    /// Proof of the account of the address against the first state root, account ledgers only
+    fn prove_account(&self, address: &Address) -> Option<Vec<ProofNode>>;
+
+    /// Copy of every entry, the ledger is rebuilt from it with `LedgerSnapshot::into_ledger`
+    fn snapshot(&self) -> LedgerSnapshot;
+
+    /// How the cache of the ledger did so far, none for ledgers that look up their state directly
+    fn cache_stats(&self) -> Option<CacheStats> {
+        None
+    }
+}
+
+/// Unspent outputs of recently used addresses with their amounts, so paying from or looking at an
+/// active address does not look up each of its outputs in the trie
+///
+/// Kept out of snapshots, a copy starts empty
+#[derive(Debug)]
+struct UnspentCache(Mutex<LruCache<Address, Vec<(OutPoint, u64)>>>);
+
+impl Default for UnspentCache {
+    fn default() -> UnspentCache {
+        UnspentCache(Mutex::new(LruCache::new(UTXO_CACHE_CAPACITY)))
+    }
+}
+
+impl Clone for UnspentCache {
+    fn clone(&self) -> UnspentCache {
+        UnspentCache::default()
+    }
+}
+
+impl UnspentCache {
+    /// The outputs of the address changed
+    fn forget(&mut self, address: &Address) {
+        self.0
+            .get_mut()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(address);
+    }
+}
+
+/// Outputs not spent by any transaction of the chain
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct UtxoSet {
+    outputs: StateTrie<OutPoint, Output>,
+    sent: StateTrie<Address, u64>,
+    stakes: StakeSet,
+    #[serde(default)]
+    contracts: ContractSet,
+    /// Outputs of each address, left out of snapshots and built again from the outputs
+    #[serde(skip)]
+    by_address: HashMap<Address, BTreeSet<OutPoint>>,
+    #[serde(skip)]
+    cache: UnspentCache,
+}
+
+impl UtxoSet {
+    /// The allocations of the genesis block, spendable as the outputs of the genesis hash in the
+    /// order they are listed
+    pub fn from_genesis(
+        genesis_hash: Hash,
+        allocations: &[Allocation],
+        stakes: StakeSet,
+        contracts: ContractSet,
+    ) -> UtxoSet {
+        let mut set = UtxoSet {
+            outputs: StateTrie::new(),
+            sent: StateTrie::new(),
+            stakes,
+            contracts,
+            by_address: HashMap::new(),
+            cache: UnspentCache::default(),
+        };
+        for (outpoint, output) in allocations.iter().enumerate().map(|(index, allocation)| {
+            let outpoint = OutPoint {
+                tx: genesis_hash,
+                index: index as u32,
+            };
+            let output = Output {
+                address: allocation.address,
+                amount: allocation.amount,
+            };
+            (outpoint, output)
+        }) {
+            set.insert_output(outpoint, output);
+        }
+        set
+    }
+
+    /// The set read from a snapshot, with the outputs of each address found again
+    fn indexed(mut self) -> UtxoSet {
+        let mut by_address: HashMap<Address, BTreeSet<OutPoint>> = HashMap::new();
+        for (outpoint, output) in self.outputs.iter() {
+            by_address
+                .entry(output.address)
+                .or_default()
+                .insert(*outpoint);
+        }
+        self.by_address = by_address;
+        self
+    }
+
+    /// Unspent outputs of the address, oldest transaction id first for a stable order
+    pub fn unspent(&self, address: &Address) -> Vec<(OutPoint, u64)> {
+        let mut cache = storage::lock(&self.cache.0);
+        if let Some(unspent) = cache.get(address) {
+            return unspent;
+        }
+        let unspent: Vec<_> = self
+            .by_address
+            .get(address)
+            .into_iter()
+            .flatten()
+            .filter_map(|outpoint| Some((*outpoint, self.outputs.get(outpoint)?.amount)))
+            .collect();
+        cache.insert(*address, unspent.clone());
+        unspent
+    }
+
+    fn insert_output(&mut self, outpoint: OutPoint, output: Output) {
+        self.cache.forget(&output.address);
+        self.by_address
+            .entry(output.address)
+            .or_default()
+            .insert(outpoint);
+        self.outputs.insert(outpoint, output);
+    }
+
+    fn remove_output(&mut self, outpoint: &OutPoint) -> Option<Output> {
+        let output = self.outputs.remove(outpoint)?;
+        self.cache.forget(&output.address);
+        if let Some(outpoints) = self.by_address.get_mut(&output.address) {
+            outpoints.remove(outpoint);
+            if outpoints.is_empty() {
+                self.by_address.remove(&output.address);
+            }
+        }
+        Some(output)
+    }
+
+    /// Whether the transaction only spends unspent outputs of its sender and spends them fully,
+    /// an unbonding or a report without a fee needs no inputs
+    fn check_inputs(&self, tx: &Transaction) -> Result<(), LedgerError> {
+        if tx.inputs.is_empty() && tx.debit() > 0 {
+            return Err(LedgerError::NoInputs);
+        }
+        let mut seen = HashSet::new();
+        let mut inputs = 0u64;
+        for outpoint in tx.inputs.iter() {
+            // 同一笔交易重复引用同一个输出也是双花
+            if !seen.insert(outpoint) {
+                return Err(LedgerError::Spent(*outpoint));
+            }
+            let output = self
+                .outputs
+                .get(outpoint)
+                .ok_or(LedgerError::Spent(*outpoint))?;
+            if output.address != tx.from {
+                return Err(LedgerError::NotOwner(*outpoint));
+            }
+            inputs = inputs.saturating_add(output.amount);
+        }
+        let outputs = tx.debit().saturating_add(tx.change);
+        if inputs != outputs {
+            return Err(LedgerError::ValueMismatch { inputs, outputs });
+        }
+        Ok(())
+    }
+
+    fn disconnect(
+        &mut self,
+        txs: &[Transaction],
+        undo: Vec<Vec<(OutPoint, Output)>>,
+        contracts: Vec<ContractUndo>,
+    ) {
+        self.contracts.revert(contracts);
+        // 倒序撤销，块内后面的交易可能花费了前面交易的输出
+        for (tx, spent) in txs.iter().zip(undo).rev() {
+            let id = tx.id();
+            for index in 0..tx.outputs().len() {
+                self.remove_output(&OutPoint {
+                    tx: id,
+                    index: index as u32,
+                });
+            }
+            for (outpoint, output) in spent {
+                self.insert_output(outpoint, output);
+            }
+            if tx.is_coinbase() {
+                continue;
+            }
+            self.stakes.revert(tx);
+            match self.nonce(&tx.from) {
+                0 => {}
+                1 => {
+                    self.sent.remove(&tx.from);
+                }
+                sent => {
+                    self.sent.insert(tx.from, sent - 1);
+                }
+            }
+        }
+    }
+}
+
+impl LedgerModel for UtxoSet {
+    fn kind(&self) -> LedgerKind {
+        LedgerKind::Utxo
+    }
+
+    /// The transaction must be the next one of its sender after the pending ones, an unbonding
+    /// spends no outputs and only its nonce keeps it from being confirmed again
+    // 待确认交易之间的双花由交易池按输出检查
+    fn check(&self, tx: &Transaction, pending: &[&Transaction]) -> Result<(), LedgerError> {
+        let expected = self.nonce(&tx.from) + pending.len() as u64;
+        if tx.nonce != expected {
+            return Err(LedgerError::NonceMismatch {
+                expected,
+                actual: tx.nonce,
+            });
+        }
+        self.stakes.check(tx, pending)?;
+        self.contracts.check(tx, pending)?;
+        self.check_inputs(tx)
+    }
+
+    /// Spend outputs of the sender in a stable order until they cover the amount and the fee
+    fn fund(
+        &self,
+        tx: &mut Transaction,
+        reserved: &dyn Fn(&OutPoint) -> bool,
+    ) -> Result<(), LedgerError> {
+        let required = tx.debit();
+        let mut total = 0u64;
+        for (outpoint, value) in self.unspent(&tx.from) {
+            if total >= required {
+                break;
+            }
+            if !reserved(&outpoint) {
+                tx.inputs.push(outpoint);
+                total += value;
+            }
+        }
+        if total < required {
+            return Err(LedgerError::InsufficientFunds {
+                available: total,
+                required,
+            });
+        }
+        tx.change = total - required;
+        Ok(())
+    }
+
+    fn connect_block(&mut self, block: &Block) -> Result<BlockUndo, LedgerError> {
+        let mut undo = Vec::new();
+        let mut contracts = Vec::new();
+        for tx in block.transactions.iter() {
+            let id = tx.id();
+            let outputs = tx.outputs();
+            // 区块校验已经限制了 coinbase 的金额，它没有输入
+            let checked = if tx.is_coinbase() {
+                Ok(())
+            } else {
+                self.check(tx, &[])
+            };
+            // 输出已存在说明同一笔交易确认过，再确认会覆盖它们
+            let checked = checked.and_then(|()| {
+                match (0..outputs.len() as u32)
+                    .map(|index| OutPoint { tx: id, index })
+                    .find(|outpoint| self.outputs.get(outpoint).is_some())
+                {
+                    Some(outpoint) => Err(LedgerError::OutputExists(outpoint)),
+                    None => Ok(()),
+                }
+            });
+            if let Err(e) = checked {
+                self.disconnect(&block.transactions[..undo.len()], undo, contracts);
+                return Err(e);
+            }
+            let spent = tx
+                .inputs
+                .iter()
+                .filter_map(|outpoint| Some((*outpoint, self.remove_output(outpoint)?)))
+                .collect();
+            undo.push(spent);
+            for (index, output) in outputs.into_iter().enumerate() {
+                let outpoint = OutPoint {
+                    tx: id,
+                    index: index as u32,
+                };
+                self.insert_output(outpoint, output);
+            }
+            if !tx.is_coinbase() {
+                self.sent.insert(tx.from, self.nonce(&tx.from) + 1);
+                self.stakes.apply(tx);
+            }
+            contracts.push(self.contracts.apply(tx));
+        }
+        Ok(BlockUndo::Utxo(undo, contracts))
+    }
+
+    fn disconnect_block(&mut self, block: &Block, undo: BlockUndo) {
+        if let BlockUndo::Utxo(undo, contracts) = undo {
+            self.disconnect(&block.transactions, undo, contracts);
+        }
+    }
+
+    fn balance(&self, address: &Address) -> u64 {
+        self.unspent(address).iter().map(|(_, value)| value).sum()
+    }
+
+    fn balances(&self) -> Vec<(Address, u64)> {
+        let mut balances: HashMap<Address, u64> = HashMap::new();
+        for (_, output) in self.outputs.iter() {
+            *balances.entry(output.address).or_default() += output.amount;
+        }
+        balances.into_iter().collect()
+    }
+
+    fn nonce(&self, address: &Address) -> u64 {
+        self.sent.get(address).copied().unwrap_or_default()
+    }
+
+    fn validators(&self) -> Vec<Validator> {
+        self.stakes.validators()
+    }
+
+    fn slashed(&self, address: &Address) -> u64 {
+        self.stakes.slashed(address)
+    }
+
+    fn contract(&self, address: &Address) -> Option<&Contract> {
+        self.contracts.contract(address)
+    }
+
+    fn receipt(&self, txid: &Hash) -> Option<&Receipt> {
+        self.contracts.receipt(txid)
+    }
+
+    fn state_roots(&self) -> Vec<Hash> {
+        vec![
+            self.outputs.root(),
+            self.sent.root(),
+            self.stakes.root(),
+            self.contracts.root(),
+        ]
+    }
+
+    // 输出按交易编号存放，没有账户可以证明
+    fn prove_account(&self, _address: &Address) -> Option<Vec<ProofNode>> {
+        None
+    }
+
+    fn snapshot(&self) -> LedgerSnapshot {
+        LedgerSnapshot::Utxo(self.clone())
+    }
+
+    fn cache_stats(&self) -> Option<CacheStats> {
+        Some(storage::lock(&self.cache.0).stats())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transaction::TxKind;
+
+    const VALIDATOR: Address = Address([7; 32]);
+
+    fn ledger(kind: LedgerKind) -> Box<dyn LedgerModel> {
+        let validators = [Validator {
+            address: VALIDATOR,
+            stake: 100,
+        }];
+        kind.genesis_state(Hash::default(), &[], &validators, 0, 1)
+    }
+
+    fn block(index: u64, transactions: Vec<Transaction>) -> Block {
+        Block::new(index, Hash::default(), 0, String::new(), transactions)
+    }
+
+    #[test]
+    fn an_unbonding_without_a_fee_is_not_confirmed_twice() {
+        for kind in [LedgerKind::Utxo, LedgerKind::Account] {
+            let mut ledger = ledger(kind);
+            let unbond = Transaction::staking(TxKind::Unbond, VALIDATOR, 10, 0);
+            ledger.check(&unbond, &[]).unwrap();
+            ledger
+                .connect_block(&block(1, vec![unbond.clone()]))
+                .unwrap();
+            assert!(matches!(
+                ledger.check(&unbond, &[]),
+                Err(LedgerError::NonceMismatch {
+                    expected: 1,
+                    actual: 0
+                })
+            ));
+            assert!(ledger.connect_block(&block(2, vec![unbond])).is_err());
+            assert_eq!(ledger.validators()[0].stake, 90);
+        }
+    }
+
+    #[test]
+    fn a_disconnected_block_gives_back_what_its_transactions_spent() {
+        let mut ledger = ledger(LedgerKind::Utxo);
+        let coinbase = Transaction::coinbase(VALIDATOR, 50, 1);
+        let first = block(1, vec![coinbase.clone()]);
+        let first_undo = ledger.connect_block(&first).unwrap();
+        let mut pay = Transaction::new(VALIDATOR, Address([8; 32]), 20, 0);
+        pay.inputs.push(OutPoint {
+            tx: coinbase.id(),
+            index: 0,
+        });
+        pay.change = 30;
+        let second = block(2, vec![Transaction::coinbase(VALIDATOR, 50, 2), pay]);
+        let undo = ledger.connect_block(&second).unwrap();
+        assert_eq!(ledger.balance(&VALIDATOR), 80);
+        ledger.disconnect_block(&second, undo);
+        assert_eq!(ledger.balance(&VALIDATOR), 50);
+        assert_eq!(ledger.nonce(&VALIDATOR), 0);
+        ledger.disconnect_block(&first, first_undo);
+        assert_eq!(ledger.balance(&VALIDATOR), 0);
+    }
+
+    #[test]
+    fn the_outputs_of_an_address_are_found_in_a_ledger_read_from_a_snapshot() {
+        let allocations = [
+            Allocation {
+                address: VALIDATOR,
+                amount: 5,
+            },
+            Allocation {
+                address: Address([8; 32]),
+                amount: 6,
+            },
+            Allocation {
+                address: VALIDATOR,
+                amount: 7,
+            },
+        ];
+        let ledger = LedgerKind::Utxo.genesis_state(Hash::default(), &allocations, &[], 0, 1);
+        let json = serde_json::to_vec(&ledger.snapshot()).unwrap();
+        let snapshot: LedgerSnapshot = serde_json::from_slice(&json).unwrap();
+        let restored = snapshot.into_ledger();
+        assert_eq!(restored.balance(&VALIDATOR), 12);
+        assert_eq!(restored.balance(&Address([8; 32])), 6);
+    }
+
+    #[test]
+    fn an_account_is_proven_against_the_first_state_root() {
+        let allocations = [Allocation {
+            address: VALIDATOR,
+            amount: 5,
+        }];
+        let ledger = LedgerKind::Account.genesis_state(Hash::default(), &allocations, &[], 0, 1);
+        let root = ledger.state_roots()[0];
+        let proof = ledger.prove_account(&VALIDATOR).unwrap();
+        let account: Account = trie::verify_proof(&root, &VALIDATOR, &proof)
+            .unwrap()
+            .unwrap();
+        assert_eq!(account.balance, 5);
+        let unknown = Address([8; 32]);
+        let proof = ledger.prove_account(&unknown).unwrap();
+        assert!(trie::verify_proof::<_, Account>(&root, &unknown, &proof)
+            .unwrap()
+            .is_none());
+        assert!(LedgerKind::Utxo
+            .genesis_state(Hash::default(), &allocations, &[], 0, 1)
+            .prove_account(&VALIDATOR)
+            .is_none());
+    }
+}
@@ -0,0 +1,348 @@
+use std::collections::HashMap;
+
+use anyhow::{bail, Context, Result};
+use libp2p::request_response::RequestId;
+use log::error;
+
+use crate::blockchain::{BlockHeader, Chain, Hash};
+use crate::consts::MAX_REORG_DEPTH;
+use crate::storage::{Op, Pending, Storage};
+use crate::sync::ChainStatus;
+use crate::transaction::Address;
+use crate::validation::ValidationError;
+
+/// Key prefix of the stored headers, followed by the height in big endian
+const HEADER_PREFIX: &[u8] = b"header/";
+
+fn header_key(height: u64) -> Vec<u8> {
+    [HEADER_PREFIX, &height.to_be_bytes()[..]].concat()
+}
+
+fn header_put(header: &BlockHeader) -> Result<Op> {
+    let mut value = Vec::new();
+    ciborium::into_writer(header, &mut value)?;
+    Ok(Op::Put {
+        key: header_key(header.index),
+        value,
+    })
+}
+
+/// Headers of the chain with the most work on top of the genesis block, all a light node keeps
+///
+/// Headers are checked with the rules full nodes check headers with, against a chain holding
+/// only the genesis block, so proof-of-stake proposers are weighed with the genesis stake
+#[derive(Debug)]
+pub struct HeaderChain {
+    /// Headers after the genesis block in order, with their hashes
+    headers: Vec<(Hash, BlockHeader)>,
+    /// Work of the headers, the genesis block left out
+    work: u128,
+    /// Where the headers are kept across restarts
+    store: Box<dyn Storage>,
+}
+
+impl HeaderChain {
+    /// Follow the headers stored by an earlier run, checked again as they are read
+    pub fn open(genesis: &Chain, store: Box<dyn Storage>) -> Result<HeaderChain> {
+        let mut headers: Vec<BlockHeader> = Vec::new();
+        for entry in store.iter(HEADER_PREFIX) {
+            let (key, value) = entry?;
+            let height = headers.len() as u64 + 1;
+            if key != header_key(height) {
+                bail!("stored header {} is missing", height);
+            }
+            let header: BlockHeader = ciborium::from_reader(&value[..])
+                .with_context(|| format!("can not decode stored header {}", height))?;
+            genesis
+                .check_header(&headers, &header)
+                .with_context(|| format!("stored header {} is invalid", height))?;
+            headers.push(header);
+        }
+        Ok(HeaderChain {
+            work: headers.iter().map(BlockHeader::work).sum(),
+            headers: headers
+                .into_iter()
+                .map(|header| (header.hash(), header))
+                .collect(),
+            store,
+        })
+    }
+
+    /// Answered once every stored header is written
+    pub fn flush(&self) -> Pending<()> {
+        self.store.flush()
+    }
+
+    pub fn height(&self) -> u64 {
+        self.headers.len() as u64
+    }
+
+    pub fn work(&self, genesis: &Chain) -> u128 {
+        genesis.work_until(0) + self.work
+    }
+
+    /// Header at the height, the genesis header at height 0
+    pub fn header<'a>(&'a self, genesis: &'a Chain, height: u64) -> Option<&'a BlockHeader> {
+        match height {
+            0 => genesis.block(0).map(|block| &block.header),
+            _ => self
+                .headers
+                .get(height as usize - 1)
+                .map(|(_, header)| header),
+        }
+    }
+
+    fn hash_at(&self, genesis: &Chain, height: u64) -> Option<Hash> {
+        match height {
+            0 => Some(genesis.genesis_hash()),
+            _ => self.headers.get(height as usize - 1).map(|(hash, _)| *hash),
+        }
+    }
+
+    pub fn find(&self, hash: &Hash) -> Option<&BlockHeader> {
+        self.headers
+            .iter()
+            .rev()
+            .find(|(known, _)| known == hash)
+            .map(|(_, header)| header)
+    }
+
+    pub fn status(&self, genesis: &Chain) -> ChainStatus {
+        ChainStatus {
+            genesis: genesis.genesis_hash(),
+            height: self.height(),
+            tip: self.hash_at(genesis, self.height()).unwrap_or_default(),
+            work: self.work(genesis),
+        }
+    }
+
+    /// Hashes of the tip and headers ever further below it, like the locator of a full node
+    pub fn locator(&self, genesis: &Chain) -> Vec<Hash> {
+        let mut locator = Vec::new();
+        let mut index = self.height();
+        let mut step = 1;
+        while index > 0 {
+            locator.extend(self.hash_at(genesis, index));
+            if locator.len() >= 10 {
+                step *= 2;
+            }
+            index = index.saturating_sub(step);
+        }
+        locator.push(genesis.genesis_hash());
+        locator
+    }
+
+    /// Headers after the first locator hash we know, so light nodes can serve headers too
+    pub fn headers_after(
+        &self,
+        genesis: &Chain,
+        locator: &[Hash],
+        count: usize,
+    ) -> Vec<BlockHeader> {
+        let start = locator.iter().find_map(|hash| {
+            if *hash == genesis.genesis_hash() {
+                return Some(0);
+            }
+            self.headers
+                .iter()
+                .rposition(|(known, _)| known == hash)
+                .map(|position| position + 1)
+        });
+        match start {
+            Some(start) => self
+                .headers
+                .iter()
+                .skip(start)
+                .take(count)
+                .map(|(_, header)| header.clone())
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Follow the headers as far as they are valid, returns how many were added
+    ///
+    /// Headers forking off below the tip replace ours once they have more work, no deeper than a
+    /// full node would reorganize and never below the last checkpoint we reached
+    pub fn add(
+        &mut self,
+        genesis: &Chain,
+        headers: Vec<BlockHeader>,
+    ) -> Result<usize, ValidationError> {
+        let first = match headers.first() {
+            Some(first) => first,
+            None => return Ok(0),
+        };
+        let fork = first.index.saturating_sub(1);
+        if first.index == 0 || self.hash_at(genesis, fork) != Some(first.prev_hash) {
+            return Err(ValidationError::UnknownParent {
+                prev_hash: first.prev_hash,
+                tip: self.hash_at(genesis, self.height()).unwrap_or_default(),
+            });
+        }
+        // 已经知道的区块头直接跳过
+        let known = headers
+            .iter()
+            .take_while(|header| self.hash_at(genesis, header.index) == Some(header.hash()))
+            .count();
+        let headers = &headers[known..];
+        let first = match headers.first() {
+            Some(first) => first,
+            None => return Ok(0),
+        };
+        let fork = first.index - 1;
+        if let Some(checkpoint) = genesis.last_checkpoint() {
+            if fork < checkpoint.height && checkpoint.height <= self.height() {
+                return Err(ValidationError::RevertsFinalized {
+                    fork,
+                    finalized: checkpoint.height,
+                });
+            }
+        }
+        let depth = self.height() - fork;
+        if depth > MAX_REORG_DEPTH {
+            return Err(ValidationError::ReorgTooDeep {
+                depth,
+                max: MAX_REORG_DEPTH,
+            });
+        }
+        let mut branch: Vec<BlockHeader> = self
+            .headers
+            .iter()
+            .take(fork as usize)
+            .map(|(_, header)| header.clone())
+            .collect();
+        for header in headers.iter() {
+            genesis.check_header(&branch, header)?;
+            branch.push(header.clone());
+        }
+        let added: u128 = headers.iter().map(BlockHeader::work).sum();
+        let replaced: u128 = self
+            .headers
+            .iter()
+            .skip(fork as usize)
+            .map(|(_, header)| header.work())
+            .sum();
+        if depth > 0 && added <= replaced {
+            return Err(ValidationError::LessWork {
+                work: added,
+                replaced,
+            });
+        }
+        // 被替换但没有被覆盖的区块头要删掉，重启后不会再接上
+        let mut batch: Vec<Op> = (fork + headers.len() as u64 + 1..=self.height())
+            .map(|height| Op::Delete {
+                key: header_key(height),
+            })
+            .collect();
+        if let Err(e) = headers
+            .iter()
+            .map(header_put)
+            .collect::<Result<Vec<Op>>>()
+            .and_then(|puts| {
+                batch.extend(puts);
+                self.store.batch(batch)
+            })
+        {
+            // 区块头已经通过验证，存储失败只影响重启后的恢复
+            error!("can not store headers after {}: {}", fork, e);
+        }
+        self.headers.truncate(fork as usize);
+        self.headers
+            .extend(headers.iter().map(|header| (header.hash(), header.clone())));
+        self.work = self.work - replaced + added;
+        Ok(headers.len())
+    }
+}
+
+/// What a light node asked a full node for
+#[derive(Debug, Clone, Copy)]
+pub enum Query {
+    /// Merkle proof of a confirmed transaction
+    Tx(Hash),
+    /// Payload of the block, e.g. recipes anchored on the chain
+    Data(Hash),
+    /// Balance and nonce of the address
+    Account(Address),
+}
+
+/// State of a node started with `--light`, which follows headers only and asks full nodes for
+/// proofs of what it wants to check
+#[derive(Debug)]
+pub struct LightClient {
+    pub headers: HeaderChain,
+    queries: HashMap<RequestId, Query>,
+}
+
+impl LightClient {
+    pub fn new(headers: HeaderChain) -> LightClient {
+        LightClient {
+            headers,
+            queries: HashMap::new(),
+        }
+    }
+
+    pub fn track(&mut self, request_id: RequestId, query: Query) {
+        self.queries.insert(request_id, query);
+    }
+
+    /// The query the response or failure belongs to, which is then finished
+    pub fn take_query(&mut self, request_id: &RequestId) -> Option<Query> {
+        self.queries.remove(request_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::genesis::Genesis;
+    use crate::storage::{self, StorageBackend};
+
+    fn genesis() -> Genesis {
+        let mut genesis = Genesis::default();
+        genesis.params.initial_difficulty = 0;
+        genesis.params.min_difficulty = 0;
+        genesis.params.max_difficulty = 0;
+        genesis
+    }
+
+    /// Headers of a chain of the blocks with the data on top of the genesis block
+    fn headers(genesis: &Genesis, data: &str, blocks: usize) -> Vec<BlockHeader> {
+        let mut chain = Chain::from_genesis(genesis);
+        for _ in 0..blocks {
+            let block = chain.next_block(data.to_owned(), Vec::new());
+            chain.try_add_block(block).unwrap();
+        }
+        chain
+            .iter()
+            .skip(1)
+            .map(|block| block.header.clone())
+            .collect()
+    }
+
+    fn open(genesis: &Chain, path: &std::path::Path) -> HeaderChain {
+        HeaderChain::open(genesis, storage::open(StorageBackend::Disk, path).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn headers_are_kept_across_a_restart_of_a_light_node() {
+        let genesis = genesis();
+        let chain = Chain::from_genesis(&genesis);
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("headers.db");
+
+        let mut light = open(&chain, &path);
+        light.add(&chain, headers(&genesis, "a", 3)).unwrap();
+        drop(light);
+        let mut light = open(&chain, &path);
+        assert_eq!(light.height(), 3);
+
+        let fork = headers(&genesis, "b", 4);
+        assert_eq!(light.add(&chain, fork.clone()).unwrap(), 4);
+        drop(light);
+        let light = open(&chain, &path);
+        assert_eq!(light.height(), 4);
+        assert_eq!(light.hash_at(&chain, 4), Some(fork[3].hash()));
+        assert_eq!(light.work(&chain), chain.work_until(0) + 4 * fork[0].work());
+    }
+}
@@ -0,0 +1,136 @@
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+
+/// How a cache did so far
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CacheStats {
+    pub len: usize,
+    pub capacity: usize,
+    pub hits: u64,
+    pub misses: u64,
+}
+
+impl CacheStats {
+    /// Share of the lookups answered from the cache in percent, 0 before any lookup
+    pub fn hit_rate(&self) -> f64 {
+        let lookups = self.hits + self.misses;
+        if lookups == 0 {
+            0.0
+        } else {
+            self.hits as f64 * 100.0 / lookups as f64
+        }
+    }
+}
+
+/// Values kept by key up to a capacity, the least recently used one evicted first
+#[derive(Debug)]
+pub struct LruCache<K, V> {
+    capacity: usize,
+    /// Value and last use of each key
+    entries: HashMap<K, (V, u64)>,
+    /// Keys by their last use, oldest first
+    order: BTreeMap<u64, K>,
+    /// Counts the uses, stamps the entries
+    clock: u64,
+    /// Lookups answered from the cache so far
+    pub hits: u64,
+    /// Lookups of keys not in the cache so far
+    pub misses: u64,
+}
+
+impl<K: Hash + Eq + Clone, V: Clone> LruCache<K, V> {
+    pub fn new(capacity: usize) -> LruCache<K, V> {
+        LruCache {
+            capacity,
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+            clock: 0,
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// The value of the key, which becomes the most recently used
+    pub fn get(&mut self, key: &K) -> Option<V> {
+        let stamp = self.tick();
+        match self.entries.get_mut(key) {
+            Some((value, used)) => {
+                self.order.remove(used);
+                *used = stamp;
+                self.order.insert(stamp, key.clone());
+                self.hits += 1;
+                Some(value.clone())
+            }
+            None => {
+                self.misses += 1;
+                None
+            }
+        }
+    }
+
+    /// The value of the key without making it the most recently used, for scans that would push
+    /// out every other value
+    pub fn peek(&mut self, key: &K) -> Option<V> {
+        match self.entries.get(key) {
+            Some((value, _)) => {
+                self.hits += 1;
+                Some(value.clone())
+            }
+            None => {
+                self.misses += 1;
+                None
+            }
+        }
+    }
+
+    /// Keep the value, replacing the one of the same key
+    pub fn insert(&mut self, key: K, value: V) {
+        if self.capacity == 0 {
+            return;
+        }
+        let stamp = self.tick();
+        if let Some((_, used)) = self.entries.insert(key.clone(), (value, stamp)) {
+            self.order.remove(&used);
+        }
+        self.order.insert(stamp, key);
+        while self.entries.len() > self.capacity {
+            let oldest = match self.order.keys().next() {
+                Some(oldest) => *oldest,
+                None => break,
+            };
+            if let Some(key) = self.order.remove(&oldest) {
+                self.entries.remove(&key);
+            }
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            len: self.entries.len(),
+            capacity: self.capacity,
+            hits: self.hits,
+            misses: self.misses,
+        }
+    }
+
+    pub fn remove(&mut self, key: &K) {
+        if let Some((_, used)) = self.entries.remove(key) {
+            self.order.remove(&used);
+        }
+    }
+
+    /// Forget every value, the counters are kept
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.order.clear();
+    }
+
+    fn tick(&mut self) -> u64 {
+        self.clock += 1;
+        self.clock
+    }
+}
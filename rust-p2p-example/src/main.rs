@@ -1,25 +1,110 @@
 use std::env;
 use std::error::Error;
+use std::sync::Arc;
 use std::time::Duration;
 
-use libp2p::floodsub::Floodsub;
-use libp2p::{mdns, noise, tcp, yamux, Swarm};
-use log::{error, info};
+use libp2p::{noise, tls, yamux, Swarm};
+use log::{error, info, warn};
 use tokio::io::AsyncBufReadExt;
 use tokio::sync::mpsc;
 
+use crate::address_book::AddressBook;
+use crate::ban_list::BanList;
 use crate::behaviour::RecipeBehaviour;
-use crate::consts::{KEYS, PEER_ID, TOPIC};
+use crate::blockchain::Chain;
+use crate::bootstrap::Bootstrapper;
+use crate::clock::SystemClock;
+use crate::config::CONFIG;
+use crate::consts::{
+    BLOCKS_TOPIC, CHAIN_DB_PATH, GOSSIPSUB_HEARTBEAT_INTERVAL, HEADERS_DB_PATH,
+    HEALTH_CHECK_INTERVAL, KAD_BOOTSTRAP_INTERVAL, KEYS, PEER_ID, PEX_INTERVAL, PEX_TOPIC,
+    PRESENCE_INTERVAL, PRESENCE_TOPIC, RENDEZVOUS_DISCOVER_INTERVAL, TOPIC, TXS_TOPIC, VOTES_TOPIC,
+};
+use crate::genesis::Genesis;
 use crate::handlers::{
-    handle_create_recipe, handle_list_peers, handle_list_recipes, handle_publish_recipe,
-    handle_swarm_event,
+    announce_presence, discover_via_rendezvous, handle_backup, handle_balance, handle_ban,
+    handle_bench_storage, handle_bench_wire, handle_blob, handle_block_mined,
+    handle_block_received, handle_chain_range, handle_chain_replayed, handle_chain_tip,
+    handle_contract, handle_create_recipe, handle_delete_recipe, handle_dial, handle_fee_estimate,
+    handle_list_chain, handle_list_dht_peers, handle_list_mempool, handle_list_peer_latencies,
+    handle_list_peer_scores, handle_list_peers, handle_list_recipes, handle_list_topics,
+    handle_list_validators, handle_logs, handle_mine, handle_nat_status, handle_net_health,
+    handle_net_stats, handle_nonce, handle_peer_info, handle_peers_learned, handle_presence,
+    handle_prove_tx, handle_publish_recipe, handle_receipt, handle_recipe_image,
+    handle_reindex_chain, handle_relay_connect, handle_relay_stats, handle_restore,
+    handle_rewind_chain, handle_richlist, handle_send_tx, handle_show_block, handle_show_tx,
+    handle_shutdown, handle_snapshot, handle_spv, handle_stake_tx, handle_storage_stats,
+    handle_subscribe, handle_swarm_event, handle_sync_status, handle_topic_mesh,
+    handle_transaction_received, handle_unban, handle_unsubscribe, handle_validate_chain,
+    handle_verify_storage, handle_vm_run, handle_vote_received, handle_wallet_balance,
+    handle_wallet_history, handle_wallet_init, handle_wallet_list, handle_wallet_multisig,
+    handle_wallet_new, handle_wallet_restore, publish, run_storage_maintenance, share_peers,
 };
+use crate::light::{HeaderChain, LightClient};
 use crate::models::EventType;
+use crate::peer_score::PeerScores;
+use crate::state::NodeState;
+use crate::transaction::Address;
+use crate::transport::{load_swarm_key, quic_transport, tcp_transport, websocket_transport};
+use crate::wallet::Wallet;
 
+mod accounts;
+mod address_book;
+mod backup;
+mod ban_list;
 mod behaviour;
+mod blobs;
+mod blockchain;
+mod bootstrap;
+mod checksummed;
+mod clock;
+mod codec;
+mod config;
+mod consensus;
 mod consts;
+mod contracts;
+mod finality;
+mod genesis;
 mod handlers;
+mod hd;
+mod health;
+mod index;
+mod ledger;
+mod light;
+mod lru_cache;
+mod mempool;
+mod merkle;
+mod mesh;
+mod metrics;
+mod miner;
 mod models;
+mod multisig;
+// 只在集成测试与模拟中使用，不参与正常运行的节点
+#[cfg(feature = "memory-transport")]
+#[allow(dead_code)]
+mod node;
+mod node_identity;
+mod orphans;
+mod peer_score;
+mod rate_limit;
+mod reconnect;
+mod security;
+mod seen_cache;
+mod slashing;
+mod snapshot;
+mod staking;
+mod state;
+mod storage;
+mod sync;
+mod telemetry;
+mod transaction;
+mod transfer;
+mod transport;
+mod trie;
+mod validation;
+mod vm;
+mod wallet;
+mod wire;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
@@ -28,64 +113,365 @@ async fn main() -> Result<(), Box<dyn Error>> {
 
     info!("Peer Id: {}", PEER_ID.clone());
     // 创建一个无限容量的队列， 返回发送器，接收器
-    let (response_sender, mut response_rcv) = mpsc::unbounded_channel();
+    let (event_sender, mut event_rcv) = mpsc::unbounded_channel();
 
-    let mut swarm = libp2p::SwarmBuilder::with_existing_identity(KEYS.clone())
+    // 配置了 swarm key 时组成私有网络，没有相同 key 的节点在传输层就会被拒绝
+    let psk = match &CONFIG.swarm_key_file {
+        Some(path) => Some(load_swarm_key(path)?),
+        None => None,
+    };
+    if let Some(psk) = psk {
+        info!(
+            "Private network, swarm key fingerprint: {}",
+            psk.fingerprint()
+        );
+    }
+    let quic_enabled = CONFIG.quic && psk.is_none();
+    if CONFIG.quic && !quic_enabled {
+        warn!("QUIC can not be used in a private network, disabling it");
+    }
+
+    // 统计所有连接上收发的字节数，供 net stats 命令查看
+    let (builder, bandwidth) = libp2p::SwarmBuilder::with_existing_identity(KEYS.clone())
         .with_tokio()
-        .with_tcp(
-            tcp::Config::default(),
-            noise::Config::new,
+        .with_other_transport(|key| tcp_transport(key, psk))?
+        .with_other_transport(|key| quic_transport(key, quic_enabled))?
+        .with_other_transport(|key| websocket_transport(key, psk))?
+        // 解析 /dns4、/dns6 与 /dnsaddr 地址，引导节点可以用域名配置
+        .with_dns()?
+        // 经由 relay 的连接同时提供 noise 与 TLS，由双方协商
+        .with_relay_client(
+            (noise::Config::new, tls::Config::new),
             yamux::Config::default,
         )?
-        .with_behaviour(|_key| RecipeBehaviour {
-            flood_sub: Floodsub::new(*PEER_ID),
-            mdns: mdns::tokio::Behaviour::new(mdns::Config::default(), KEYS.public().to_peer_id())
-                .expect("can create mdns"),
+        .with_bandwidth_logging();
+    let mut swarm = builder
+        .with_behaviour(|key, relay_client| {
+            RecipeBehaviour::new(key, relay_client).map_err(Into::into)
         })?
-        .with_swarm_config(|cfg| cfg.with_idle_connection_timeout(Duration::from_secs(5)))
+        .with_swarm_config(|cfg| cfg.with_idle_connection_timeout(CONFIG.idle_connection_timeout()))
         .build();
     // 启动监听
-    Swarm::listen_on(
-        &mut swarm,
-        "/ip4/0.0.0.0/tcp/0"
-            .parse()
-            .expect("can get a local socket"),
-    )
-    .expect("swarm can be started");
+    for addr in CONFIG.listen_addrs.iter() {
+        if let Err(e) = Swarm::listen_on(&mut swarm, addr.clone()) {
+            error!("can not listen on {}: {}", addr, e);
+        }
+    }
+    if quic_enabled {
+        Swarm::listen_on(
+            &mut swarm,
+            "/ip4/0.0.0.0/udp/0/quic-v1"
+                .parse()
+                .expect("can get a local socket"),
+        )
+        .expect("swarm can be started");
+    }
+    if CONFIG.websocket {
+        Swarm::listen_on(
+            &mut swarm,
+            format!("/ip4/0.0.0.0/tcp/{}/ws", CONFIG.websocket_port)
+                .parse()
+                .expect("can get a local socket"),
+        )
+        .expect("swarm can be started");
+    }
+
+    swarm.behaviour_mut().gossipsub.subscribe(&TOPIC)?;
+    swarm.behaviour_mut().gossipsub.subscribe(&PEX_TOPIC)?;
+    swarm.behaviour_mut().gossipsub.subscribe(&PRESENCE_TOPIC)?;
+    swarm.behaviour_mut().gossipsub.subscribe(&TXS_TOPIC)?;
+    swarm.behaviour_mut().gossipsub.subscribe(&BLOCKS_TOPIC)?;
+    swarm.behaviour_mut().gossipsub.subscribe(&VOTES_TOPIC)?;
+
+    let genesis = Genesis::load(CONFIG.genesis_file.as_deref())?;
+    wallet::set_network(genesis.network);
+    transaction::set_genesis(genesis.block().hash);
+    let mut chain = Chain::from_genesis(&genesis);
+    chain.set_checkpoints(&CONFIG.checkpoints);
+    if CONFIG.clock_drift != 0 {
+        chain.set_clock(Arc::new(SystemClock {
+            drift: CONFIG.clock_drift,
+        }));
+    }
+    if let Some(keep) = CONFIG.prune {
+        chain.set_pruning(keep);
+    }
+    // 轻节点只有创世区块，只保存区块头
+    let light = if CONFIG.light {
+        let headers = HeaderChain::open(&chain, storage::open(CONFIG.storage, HEADERS_DB_PATH)?)?;
+        if headers.height() > 0 {
+            info!(
+                "Restored {} headers from {}",
+                headers.height(),
+                HEADERS_DB_PATH
+            );
+        }
+        Some(LightClient::new(headers))
+    } else {
+        let loaded = chain.set_storage(storage::open(CONFIG.storage, CHAIN_DB_PATH)?)?;
+        if loaded > 0 {
+            info!("Restored {} blocks from {}", loaded, CHAIN_DB_PATH);
+        }
+        None
+    };
+    storage::open_recipes(CONFIG.storage)?;
+    blobs::open_blobs(CONFIG.storage)?;
+    let collected = blobs::blobs().gc()?;
+    if collected.blobs > 0 {
+        info!(
+            "Removed {} unreferenced blobs of {} bytes",
+            collected.blobs, collected.bytes
+        );
+    }
+    let mut state = NodeState {
+        // rendezvous 节点与引导节点一样在启动时连接，失败时退避重试
+        bootstrapper: Bootstrapper::new(
+            &[&CONFIG.bootstrap[..], &CONFIG.rendezvous_points[..]].concat(),
+        ),
+        ban_list: BanList::load()?,
+        address_book: AddressBook::load()?,
+        peer_scores: PeerScores::load()?,
+        wallet: Wallet::load()?,
+        chain,
+        light,
+        ..Default::default()
+    };
+    info!(
+        "Chain {}, genesis {}, {} ledger",
+        state.chain.chain_id(),
+        state.chain.genesis_hash(),
+        state.chain.ledger().kind()
+    );
+    if let Some(address) = Address::of(&KEYS.public()) {
+        info!("Address {}", address);
+    }
+    // 重启后重新连接最近连上过的节点
+    for addr in state.address_book.recent() {
+        state.bootstrapper.add(&addr);
+    }
+    if let Some(path) = &CONFIG.telemetry_file {
+        let records = state.telemetry.subscribe();
+        tokio::spawn(telemetry::write_to_file(path.clone(), records));
+    }
+    for peer_id in state.ban_list.iter() {
+        swarm.behaviour_mut().blocked_peers.block_peer(*peer_id);
+    }
+    state.bootstrapper.dial_all(&mut swarm, &event_sender);
+
+    // 收到 Ctrl-C 后通过事件队列通知主循环优雅退出
+    let shutdown_sender = event_sender.clone();
+    tokio::spawn(async move {
+        match tokio::signal::ctrl_c().await {
+            Ok(()) => {
+                let _ = shutdown_sender.send(EventType::Shutdown);
+            }
+            Err(e) => error!("can not listen for Ctrl-C: {}", e),
+        }
+    });
 
-    swarm.behaviour_mut().flood_sub.subscribe(TOPIC.clone());
+    // 定期刷新 Kademlia 路由表，第一次 tick 立即触发
+    let mut bootstrap_timer = tokio::time::interval(KAD_BOOTSTRAP_INTERVAL);
+    let mut rendezvous_timer = tokio::time::interval(RENDEZVOUS_DISCOVER_INTERVAL);
+    let mut pex_timer = tokio::time::interval(PEX_INTERVAL);
+    let mut presence_timer = tokio::time::interval(PRESENCE_INTERVAL);
+    let mut health_timer = tokio::time::interval(HEALTH_CHECK_INTERVAL);
+    // 每个 gossipsub 心跳对比一次 mesh，记录被 prune 的节点
+    let mut mesh_timer = tokio::time::interval(GOSSIPSUB_HEARTBEAT_INTERVAL);
+    // 存储维护不在启动时运行，间隔为 0 时只能手动执行
+    let maintenance_period = Duration::from_secs(CONFIG.maintenance_interval.max(1));
+    let mut maintenance_timer = tokio::time::interval_at(
+        tokio::time::Instant::now() + maintenance_period,
+        maintenance_period,
+    );
 
     // 创建异步输入标准输入是在 Tokio 异步运行时 中创建一个 异步读取标准输入（stdin）的流。我详细拆解一下。
     let mut stdin = tokio::io::BufReader::new(tokio::io::stdin()).lines();
     loop {
         // 1. 异步监听用户输入（stdin）
-        // 2. 异步监听来自其他节点的响应（response channel）
+        // 2. 异步监听后台任务发回的事件（event channel），如节点响应、打洞结果
         // 3. 异步处理 libp2p Swarm 网络事件（连接、消息等）
         let evt: Option<EventType> = {
             tokio::select! {
                 line = stdin.next_line() => Some(EventType::Input(line.expect("can get line").expect("can read line from stdin"))),
-                response = response_rcv.recv() => Some(EventType::Response(response.expect("response exists"))),
-                _ = handle_swarm_event(response_sender.clone(), &mut swarm) => None,
+                event = event_rcv.recv() => Some(event.expect("event exists")),
+                _ = bootstrap_timer.tick() => Some(EventType::KadBootstrap),
+                _ = rendezvous_timer.tick() => Some(EventType::RendezvousDiscover),
+                _ = pex_timer.tick() => Some(EventType::PeerExchange),
+                _ = presence_timer.tick() => Some(EventType::AnnouncePresence),
+                _ = health_timer.tick() => Some(EventType::HealthCheck),
+                _ = mesh_timer.tick() => Some(EventType::MeshUpdate),
+                _ = maintenance_timer.tick(), if CONFIG.maintenance_interval > 0 => Some(EventType::StorageMaintenance),
+                _ = handle_swarm_event(event_sender.clone(), &mut swarm, &mut state) => None,
             }
         };
         // 根据事件类型执行不同逻辑（发布消息、处理命令）
         if let Some(event) = evt {
             match event {
-                EventType::Response(resp) => {
-                    let json = serde_json::to_string(&resp).expect("can jsonify response");
-                    swarm
-                        .behaviour_mut()
-                        .flood_sub
-                        .publish(TOPIC.clone(), json.as_bytes());
+                EventType::Response(topic, resp) => {
+                    publish(&mut swarm, &mut state, topic, &resp);
+                }
+                EventType::Shutdown => break,
+                EventType::KadBootstrap => swarm.behaviour_mut().bootstrap(),
+                EventType::RendezvousDiscover => discover_via_rendezvous(&mut swarm, &state),
+                EventType::PeerExchange => share_peers(&mut swarm, &mut state),
+                EventType::HealthCheck => state.health.check(&swarm),
+                EventType::MeshUpdate => state.mesh.update(&swarm.behaviour().gossipsub),
+                EventType::StorageMaintenance => {
+                    run_storage_maintenance(&mut state, &event_sender, false)
+                }
+                EventType::BlocksPruned(pruned) => state.chain.store_pruned(*pruned),
+                EventType::PeersLearned(source, peers) => {
+                    handle_peers_learned(&mut swarm, &state, source, peers)
+                }
+                EventType::AnnouncePresence => announce_presence(&mut swarm, &mut state).await,
+                EventType::PresenceReceived(peer_id, topics) => {
+                    handle_presence(&mut state, peer_id, topics)
+                }
+                EventType::BlockMined(block) => handle_block_mined(&mut swarm, &mut state, block),
+                EventType::BlockReceived(source, block) => {
+                    handle_block_received(&mut swarm, &mut state, source, block).await
+                }
+                EventType::TransactionReceived(source, tx) => {
+                    handle_transaction_received(&mut state, source, tx)
+                }
+                EventType::VoteReceived(source, vote) => {
+                    handle_vote_received(&mut swarm, &mut state, source, vote)
+                }
+                EventType::ChainReplayed(chain) => handle_chain_replayed(&mut state, *chain),
+                EventType::Reconnect(peer_id) => {
+                    let addrs = state.address_book.addrs(&peer_id);
+                    state
+                        .reconnector
+                        .dial(&mut swarm, &event_sender, peer_id, addrs)
+                }
+                EventType::DialBootstrap(peer_id) => {
+                    state.bootstrapper.dial(&mut swarm, &event_sender, peer_id)
+                }
+                EventType::DialResult(addr, Ok(peer_id)) => {
+                    info!("Connected to {} at {}", peer_id, addr)
+                }
+                EventType::DialResult(addr, Err(e)) => error!("dialing {} failed: {}", addr, e),
+                EventType::HolePunchSucceeded(peer_id) => {
+                    info!(
+                        "Direct connection to {} established by hole punching",
+                        peer_id
+                    )
+                }
+                EventType::HolePunchFailed(peer_id, reason) => {
+                    error!("hole punching to {} failed: {}", peer_id, reason)
                 }
                 EventType::Input(line) => match line.as_str() {
-                    "ls p" => handle_list_peers(&mut swarm).await,
+                    "ls p" => handle_list_peers(&mut swarm, &state).await,
+                    "ls p dht" => handle_list_dht_peers(&mut swarm).await,
+                    "ls p ping" => handle_list_peer_latencies(&mut swarm, &state).await,
+                    "ls p score" => handle_list_peer_scores(&state).await,
+                    "bench wire" => handle_bench_wire().await,
+                    cmd if cmd.starts_with("bench storage") => handle_bench_storage(cmd).await,
+                    "ls chain" => handle_list_chain(&state).await,
+                    "chain validate" => handle_validate_chain(&state).await,
+                    "chain reindex" => handle_reindex_chain(&state, &event_sender),
+                    "storage verify" => handle_verify_storage(&state).await,
+                    "storage stats" => handle_storage_stats(&state).await,
+                    "storage compact" => run_storage_maintenance(&mut state, &event_sender, true),
+                    "chain tip" => handle_chain_tip(&state).await,
+                    cmd if cmd.starts_with("chain range") => handle_chain_range(cmd, &state).await,
+                    cmd if cmd.starts_with("block ") => handle_show_block(cmd, &state).await,
+                    cmd if cmd.starts_with("chain rewind") => {
+                        handle_rewind_chain(cmd, &mut state).await
+                    }
+                    cmd if cmd.starts_with("tx send ") => {
+                        handle_send_tx(cmd, &mut swarm, &mut state).await
+                    }
+                    cmd if cmd.starts_with("tx prove ") => handle_prove_tx(cmd, &state).await,
+                    cmd if cmd.starts_with("backup ") => handle_backup(cmd, &state).await,
+                    cmd if cmd.starts_with("restore ") => {
+                        handle_restore(cmd, &mut swarm, &mut state).await
+                    }
+                    cmd if cmd.starts_with("snapshot ") => {
+                        handle_snapshot(cmd, &mut swarm, &mut state).await
+                    }
+                    cmd if cmd.starts_with("vm run ") => handle_vm_run(cmd).await,
+                    cmd if cmd.starts_with("contract ") => {
+                        handle_contract(cmd, &mut swarm, &mut state).await
+                    }
+                    cmd if cmd.starts_with("spv ") => handle_spv(cmd, &mut swarm, &mut state).await,
+                    cmd if cmd.starts_with("tx bond ") || cmd.starts_with("tx unbond ") => {
+                        handle_stake_tx(cmd, &mut swarm, &mut state).await
+                    }
+                    cmd if cmd.starts_with("tx ") => handle_show_tx(cmd, &state).await,
+                    cmd if cmd.starts_with("receipt ") => handle_receipt(cmd, &state).await,
+                    cmd if cmd == "logs" || cmd.starts_with("logs ") => {
+                        handle_logs(cmd, &state).await
+                    }
+                    "sync status" => handle_sync_status(&state).await,
+                    "ls mempool" => handle_list_mempool(&state).await,
+                    "fee estimate" => handle_fee_estimate(&state).await,
+                    "ls validators" => handle_list_validators(&state).await,
+                    "wallet new" => handle_wallet_new(&mut state).await,
+                    "wallet list" => handle_wallet_list(&state).await,
+                    "wallet balance" => handle_wallet_balance(&state).await,
+                    cmd if cmd.starts_with("wallet history") => {
+                        handle_wallet_history(cmd, &state).await
+                    }
+                    cmd if cmd == "richlist" || cmd.starts_with("richlist ") => {
+                        handle_richlist(cmd, &state).await
+                    }
+                    cmd if cmd.starts_with("wallet multisig") => {
+                        handle_wallet_multisig(cmd, &mut swarm, &mut state).await
+                    }
+                    cmd if cmd.starts_with("wallet init") => {
+                        handle_wallet_init(cmd, &mut state).await
+                    }
+                    cmd if cmd.starts_with("wallet restore ") => {
+                        handle_wallet_restore(cmd, &mut state).await
+                    }
+                    cmd if cmd == "balance" || cmd.starts_with("balance ") => {
+                        handle_balance(cmd, &state).await
+                    }
+                    cmd if cmd == "nonce" || cmd.starts_with("nonce ") => {
+                        handle_nonce(cmd, &state).await
+                    }
+                    cmd if cmd == "mine" || cmd.starts_with("mine ") => {
+                        handle_mine(cmd, &event_sender, &mut state).await
+                    }
+                    "net stats" => handle_net_stats(&state, &bandwidth).await,
+                    "net health" => handle_net_health(&swarm, &state).await,
+                    "ls topics" => handle_list_topics(&mut swarm).await,
+                    cmd if cmd.starts_with("topic mesh ") => {
+                        handle_topic_mesh(cmd, &swarm, &state).await
+                    }
+                    cmd if cmd.starts_with("sub ") => handle_subscribe(cmd, &mut swarm).await,
+                    cmd if cmd.starts_with("unsub ") => handle_unsubscribe(cmd, &mut swarm).await,
                     cmd if cmd.starts_with("create r") => handle_create_recipe(cmd).await,
                     cmd if cmd.starts_with("publish r") => handle_publish_recipe(cmd).await,
-                    cmd if cmd.starts_with("ls r") => handle_list_recipes(cmd, &mut swarm).await,
+                    cmd if cmd.starts_with("image r") => handle_recipe_image(cmd).await,
+                    cmd if cmd.starts_with("delete r") || cmd.starts_with("undelete r") => {
+                        handle_delete_recipe(cmd).await
+                    }
+                    cmd if cmd.starts_with("blob") => handle_blob(cmd).await,
+                    cmd if cmd.starts_with("ls r") => {
+                        handle_list_recipes(cmd, &mut swarm, &mut state).await
+                    }
+                    "relay stats" => handle_relay_stats(&state).await,
+                    "nat status" => handle_nat_status(&mut swarm, &state).await,
+                    cmd if cmd.starts_with("ban ") => handle_ban(cmd, &mut swarm, &mut state).await,
+                    cmd if cmd.starts_with("unban ") => {
+                        handle_unban(cmd, &mut swarm, &mut state).await
+                    }
+                    cmd if cmd.starts_with("dial ") => {
+                        handle_dial(cmd, &mut swarm, &mut state).await
+                    }
+                    cmd if cmd.starts_with("peer info") => handle_peer_info(cmd, &state).await,
+                    cmd if cmd.starts_with("relay connect") => {
+                        handle_relay_connect(cmd, &mut swarm).await
+                    }
                     _ => error!("unknown command: {:?}", line),
                 },
             }
         }
     }
-}
\ No newline at end of file
+
+    handle_shutdown(event_sender, &mut swarm, &mut state).await;
+    // 读取 stdin 的阻塞线程无法取消，直接退出进程而不是等待运行时关闭
+    std::process::exit(0)
+}
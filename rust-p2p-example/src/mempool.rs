@@ -0,0 +1,560 @@
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BinaryHeap, HashMap, HashSet, VecDeque};
+use std::fmt;
+
+use crate::blockchain::{Block, ChainParams, Hash};
+use crate::clock::now;
+use crate::consts::{MEMPOOL_CAPACITY, MEMPOOL_MAX_NONCE_GAP, MEMPOOL_MAX_QUEUED};
+use crate::ledger::{LedgerError, LedgerModel};
+use crate::transaction::{Address, LockTime, OutPoint, Transaction, TxKind};
+
+/// Why a transaction was not admitted to the mempool
+#[derive(Debug, PartialEq, Eq)]
+pub enum MempoolError {
+    Duplicate(Hash),
+    /// The output is already spent by the pending transaction
+    DoubleSpend(OutPoint, Hash),
+    Full,
+    /// The nonce is too far ahead of the next one of the sender to wait for the gap to fill
+    NonceGap {
+        expected: u64,
+        actual: u64,
+    },
+    Invalid(&'static str),
+    /// The next block can not confirm the transaction yet
+    Locked(LockTime),
+    Ledger(LedgerError),
+}
+
+impl fmt::Display for MempoolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MempoolError::Duplicate(id) => write!(f, "transaction {} is already pending", id),
+            MempoolError::DoubleSpend(outpoint, id) => write!(
+                f,
+                "output {} is already spent by pending transaction {}",
+                outpoint, id
+            ),
+            MempoolError::Full => {
+                write!(f, "mempool is full of transactions paying higher fee rates")
+            }
+            MempoolError::NonceGap { expected, actual } => write!(
+                f,
+                "nonce {} is too far ahead of the next nonce {}",
+                actual, expected
+            ),
+            MempoolError::Invalid(reason) => write!(f, "invalid transaction: {}", reason),
+            MempoolError::Locked(locktime) => {
+                write!(f, "transaction is locked until {}", locktime)
+            }
+            MempoolError::Ledger(e) => write!(f, "invalid transaction: {}", e),
+        }
+    }
+}
+
+/// First eight bytes of a transaction id, enough to tell pending transactions apart when two
+/// mempools are compared
+pub type ShortId = u64;
+
+pub fn short_id(id: &Hash) -> ShortId {
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&id.0[..8]);
+    u64::from_be_bytes(bytes)
+}
+
+/// Transactions waiting to be included in a block, oldest first
+#[derive(Debug)]
+pub struct Mempool {
+    txs: HashMap<Hash, Transaction>,
+    order: VecDeque<Hash>,
+    /// Pending transaction spending each output
+    spent: HashMap<OutPoint, Hash>,
+    /// Transactions ahead of the next nonce of their sender by sender and nonce, admitted once the
+    /// transactions before them arrive
+    queued: HashMap<Address, BTreeMap<u64, Transaction>>,
+    capacity: usize,
+}
+
+impl Default for Mempool {
+    fn default() -> Self {
+        Mempool::new(MEMPOOL_CAPACITY)
+    }
+}
+
+impl Mempool {
+    pub fn new(capacity: usize) -> Mempool {
+        Mempool {
+            txs: HashMap::new(),
+            order: VecDeque::new(),
+            spent: HashMap::new(),
+            queued: HashMap::new(),
+            capacity,
+        }
+    }
+
+    /// Admit the transaction if it applies to the ledger after the pending transactions and spends
+    /// no output another pending transaction spends, returning its id
+    ///
+    /// When the mempool is full the transaction takes the place of one with a lower fee rate. A
+    /// transaction ahead of the next nonce of its sender is queued until the gap fills, one behind
+    /// it is a replay and rejected by the ledger. A locked transaction is only admitted once the
+    /// block at `next_height` made now could confirm it
+    pub fn insert(
+        &mut self,
+        tx: Transaction,
+        ledger: &dyn LedgerModel,
+        next_height: u64,
+    ) -> Result<Hash, MempoolError> {
+        let from = tx.from;
+        let id = self.admit(tx, ledger, next_height)?;
+        self.promote(&from, ledger, next_height);
+        Ok(id)
+    }
+
+    fn admit(
+        &mut self,
+        tx: Transaction,
+        ledger: &dyn LedgerModel,
+        next_height: u64,
+    ) -> Result<Hash, MempoolError> {
+        if tx.is_coinbase() {
+            return Err(MempoolError::Invalid("coinbases are only valid in blocks"));
+        }
+        if tx.amount == 0 && !matches!(tx.kind, TxKind::Slash | TxKind::Deploy | TxKind::Call) {
+            return Err(MempoolError::Invalid("amount is zero"));
+        }
+        if tx.kind == TxKind::Transfer && tx.from == tx.to {
+            return Err(MempoolError::Invalid("sender and receiver are the same"));
+        }
+        if let Some(locktime) = tx.locktime {
+            if !locktime.is_reached(next_height, now()) {
+                return Err(MempoolError::Locked(locktime));
+            }
+        }
+        let id = tx.id();
+        if self.txs.contains_key(&id) || self.is_queued(&tx) {
+            return Err(MempoolError::Duplicate(id));
+        }
+        // 同一次双签只能罚一次，第二份举报会让打包它的区块无效
+        if let Some(other) = self.reporting(&tx) {
+            return Err(MempoolError::Duplicate(other));
+        }
+        if let Some((outpoint, other)) = tx
+            .inputs
+            .iter()
+            .find_map(|outpoint| self.spent.get(outpoint).map(|other| (*outpoint, *other)))
+        {
+            return Err(MempoolError::DoubleSpend(outpoint, other));
+        }
+        match ledger.check(&tx, &self.pending(&tx.from)) {
+            Ok(()) => {}
+            Err(LedgerError::NonceMismatch { expected, actual }) if actual > expected => {
+                return self.queue(tx, expected)
+            }
+            Err(e) => return Err(MempoolError::Ledger(e)),
+        }
+        if self.txs.len() >= self.capacity {
+            match self.eviction_candidate(&tx.from) {
+                Some((victim, rate)) if rate < tx.fee_rate() => self.remove(&victim),
+                _ => return Err(MempoolError::Full),
+            }
+        }
+        for outpoint in tx.inputs.iter() {
+            self.spent.insert(*outpoint, id);
+        }
+        self.txs.insert(id, tx);
+        self.order.push_back(id);
+        Ok(id)
+    }
+
+    /// Keep a transaction ahead of the next nonce of its sender until the gap fills
+    fn queue(&mut self, tx: Transaction, expected: u64) -> Result<Hash, MempoolError> {
+        if tx.nonce - expected > MEMPOOL_MAX_NONCE_GAP {
+            return Err(MempoolError::NonceGap {
+                expected,
+                actual: tx.nonce,
+            });
+        }
+        if self.queued_len() >= MEMPOOL_MAX_QUEUED {
+            return Err(MempoolError::Full);
+        }
+        let id = tx.id();
+        // 同一 nonce 只保留最先到的交易，直到缺口补上前无法判断哪一笔有效
+        self.queued
+            .entry(tx.from)
+            .or_default()
+            .entry(tx.nonce)
+            .or_insert(tx);
+        Ok(id)
+    }
+
+    /// Pending report of the same equivocation as the transaction
+    fn reporting(&self, tx: &Transaction) -> Option<Hash> {
+        let evidence = tx.evidence.as_ref()?;
+        self.txs
+            .iter()
+            .filter_map(|(id, pending)| Some((id, pending.evidence.as_ref()?)))
+            .find(|(_, other)| {
+                other.offender() == evidence.offender() && other.slot == evidence.slot
+            })
+            .map(|(id, _)| *id)
+    }
+
+    fn is_queued(&self, tx: &Transaction) -> bool {
+        self.queued
+            .get(&tx.from)
+            .and_then(|queue| queue.get(&tx.nonce))
+            .is_some_and(|queued| queued == tx)
+    }
+
+    /// Admit the queued transactions of the sender that follow its pending ones, dropping those
+    /// with a nonce that was used already
+    fn promote(&mut self, from: &Address, ledger: &dyn LedgerModel, next_height: u64) {
+        loop {
+            let expected = self.next_nonce(from, ledger);
+            let queue = match self.queued.get_mut(from) {
+                Some(queue) => queue,
+                None => return,
+            };
+            *queue = queue.split_off(&expected);
+            let tx = queue.remove(&expected);
+            if queue.is_empty() {
+                self.queued.remove(from);
+            }
+            match tx {
+                Some(tx) => {
+                    if self.admit(tx, ledger, next_height).is_err() {
+                        return;
+                    }
+                }
+                None => return,
+            }
+        }
+    }
+
+    /// Transactions waiting for an earlier nonce of their sender
+    pub fn queued_len(&self) -> usize {
+        self.queued.values().map(BTreeMap::len).sum()
+    }
+
+    pub fn get(&self, id: &Hash) -> Option<&Transaction> {
+        self.txs.get(id)
+    }
+
+    /// Pending transactions, oldest first
+    pub fn iter(&self) -> impl Iterator<Item = (&Hash, &Transaction)> {
+        self.order
+            .iter()
+            .filter_map(move |id| self.txs.get(id).map(|tx| (id, tx)))
+    }
+
+    /// Transactions for a block template, at most `max` of them taking up to `max_bytes` and
+    /// `max_gas` with the highest fee rates first
+    ///
+    /// A transaction is only picked after the earlier pending transactions of its sender, whose
+    /// nonces it follows, so a sender whose next transaction does not fit is left out
+    pub fn select(&self, max: usize, max_bytes: usize, max_gas: u64) -> Vec<Transaction> {
+        let mut queues: HashMap<&Address, VecDeque<(usize, &Transaction)>> = HashMap::new();
+        for (age, (_, tx)) in self.iter().enumerate() {
+            queues.entry(&tx.from).or_default().push_back((age, tx));
+        }
+        // 每个发送方只有最早的待确认交易参与排序，同样费率时先到先得
+        let mut heads: BinaryHeap<(u64, Reverse<usize>, &Address)> = queues
+            .iter()
+            .filter_map(|(from, queue)| {
+                let (age, tx) = queue.front()?;
+                Some((tx.fee_rate(), Reverse(*age), *from))
+            })
+            .collect();
+        let mut selected = Vec::new();
+        let mut bytes = 0;
+        let mut gas = 0u64;
+        while selected.len() < max {
+            let (_, _, from) = match heads.pop() {
+                Some(head) => head,
+                None => break,
+            };
+            let queue = queues.get_mut(from).expect("sender has a queue");
+            if let Some((_, tx)) = queue.pop_front() {
+                // 加上区块 JSON 中交易之间的逗号
+                let size = tx.size().saturating_add(1);
+                if bytes + size > max_bytes || gas.saturating_add(tx.gas_limit()) > max_gas {
+                    continue;
+                }
+                bytes += size;
+                gas += tx.gas_limit();
+                selected.push(tx.clone());
+            }
+            if let Some((age, tx)) = queue.front() {
+                heads.push((tx.fee_rate(), Reverse(*age), from));
+            }
+        }
+        selected
+    }
+
+    /// Fee rate a new transaction likely needs to get into the next block, the median rate of
+    /// the transactions of the recent blocks, or what it takes to get into a full block when more
+    /// is pending than fits
+    pub fn estimate_fee_rate<'a>(
+        &self,
+        recent: impl Iterator<Item = &'a Block>,
+        params: &ChainParams,
+    ) -> u64 {
+        let mut rates: Vec<u64> = recent
+            .flat_map(|block| block.transactions.iter())
+            .filter(|tx| !tx.is_coinbase())
+            .map(Transaction::fee_rate)
+            .collect();
+        rates.sort_unstable();
+        let median = rates.get(rates.len() / 2).copied().unwrap_or_default();
+        // 一个区块装不下所有待确认交易时，要比能进区块的最低费率更高
+        let room = params.max_block_transactions.saturating_sub(1);
+        let cutoff = match self.len() >= room {
+            true => self
+                .select(room, params.max_block_size, params.max_block_gas)
+                .last()
+                .map_or(0, |tx| tx.fee_rate().saturating_add(1)),
+            false => 0,
+        };
+        median.max(cutoff)
+    }
+
+    /// Whether a pending transaction spends the output
+    pub fn is_spent(&self, outpoint: &OutPoint) -> bool {
+        self.spent.contains_key(outpoint)
+    }
+
+    /// Drop the transactions confirmed by a block and those conflicting with them
+    pub fn remove_confirmed(&mut self, confirmed: &[Transaction]) {
+        for tx in confirmed.iter() {
+            self.remove(&tx.id());
+            for outpoint in tx.inputs.iter() {
+                if let Some(conflict) = self.spent.get(outpoint).copied() {
+                    self.remove(&conflict);
+                }
+            }
+        }
+    }
+
+    /// Admit the transactions of a removed block ahead of the pending ones and all pending ones
+    /// again, dropping those that no longer apply to the ledger, returns how many were dropped
+    ///
+    /// Queued transactions whose gap was filled by a block are admitted as well
+    pub fn revalidate(
+        &mut self,
+        returned: Vec<Transaction>,
+        ledger: &dyn LedgerModel,
+        next_height: u64,
+    ) -> usize {
+        let mut txs = std::mem::take(&mut self.txs);
+        let order = std::mem::take(&mut self.order);
+        self.spent.clear();
+        let pending: Vec<Transaction> =
+            order.into_iter().filter_map(|id| txs.remove(&id)).collect();
+        let dropped = returned
+            .into_iter()
+            .chain(pending)
+            .map(|tx| self.insert(tx, ledger, next_height))
+            .filter(Result::is_err)
+            .count();
+        let senders: Vec<Address> = self.queued.keys().copied().collect();
+        for from in senders.iter() {
+            self.promote(from, ledger, next_height);
+        }
+        dropped
+    }
+
+    /// Pending transactions of the sender, oldest first
+    fn pending(&self, from: &Address) -> Vec<&Transaction> {
+        self.iter()
+            .map(|(_, tx)| tx)
+            .filter(|tx| &tx.from == from)
+            .collect()
+    }
+
+    /// Pending transaction with the lowest fee rate among those no later transaction of the same
+    /// sender builds on, the transactions of `except` are kept
+    fn eviction_candidate(&self, except: &Address) -> Option<(Hash, u64)> {
+        let mut senders = HashSet::new();
+        self.order
+            .iter()
+            .rev()
+            .filter_map(|id| self.txs.get(id).map(|tx| (id, tx)))
+            .filter(|(_, tx)| senders.insert(tx.from) && &tx.from != except)
+            .map(|(id, tx)| (*id, tx.fee_rate()))
+            .min_by_key(|(_, rate)| *rate)
+    }
+
+    fn remove(&mut self, id: &Hash) {
+        if let Some(tx) = self.txs.remove(id) {
+            for outpoint in tx.inputs.iter() {
+                self.spent.remove(outpoint);
+            }
+            self.order.retain(|other| other != id);
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.txs.len()
+    }
+
+    /// Short ids of the pending transactions, a peer answers them with the ones we miss
+    pub fn short_ids(&self) -> Vec<ShortId> {
+        self.order.iter().map(short_id).collect()
+    }
+
+    /// Pending transactions whose short id is not among the peer's, oldest first and at most
+    /// `max` of them
+    pub fn missing_from(&self, short_ids: &[ShortId], max: usize) -> Vec<Transaction> {
+        let known: HashSet<&ShortId> = short_ids.iter().collect();
+        self.iter()
+            .filter(|(id, _)| !known.contains(&short_id(id)))
+            .take(max)
+            .map(|(_, tx)| tx.clone())
+            .collect()
+    }
+
+    /// Nonce following the confirmed and pending transactions of the sender
+    pub fn next_nonce(&self, from: &Address, ledger: &dyn LedgerModel) -> u64 {
+        ledger.nonce(from) + self.pending(from).len() as u64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::genesis::Allocation;
+    use crate::ledger::LedgerKind;
+
+    /// Account ledger giving each of the senders 1000 coins
+    fn ledger(senders: &[Address]) -> Box<dyn LedgerModel> {
+        let allocations: Vec<Allocation> = senders
+            .iter()
+            .map(|address| Allocation {
+                address: *address,
+                amount: 1_000,
+            })
+            .collect();
+        LedgerKind::Account.genesis_state(Hash::default(), &allocations, &[], 0, 1)
+    }
+
+    fn sender(n: u8) -> Address {
+        Address([n; 32])
+    }
+
+    fn pay(from: Address, nonce: u64, fee: u64) -> Transaction {
+        Transaction {
+            fee,
+            ..Transaction::new(from, Address([99; 32]), 10, nonce)
+        }
+    }
+
+    #[test]
+    fn a_full_mempool_evicts_the_lowest_fee_rate_for_a_higher_one() {
+        let ledger = ledger(&[sender(1), sender(2), sender(3), sender(4)]);
+        let mut mempool = Mempool::new(2);
+        let cheap = mempool.insert(pay(sender(1), 0, 1), &*ledger, 1).unwrap();
+        let dear = mempool.insert(pay(sender(2), 0, 5), &*ledger, 1).unwrap();
+
+        let better = mempool.insert(pay(sender(3), 0, 3), &*ledger, 1).unwrap();
+        assert!(mempool.get(&cheap).is_none());
+        assert!(mempool.get(&dear).is_some());
+        assert!(mempool.get(&better).is_some());
+        assert_eq!(
+            mempool.insert(pay(sender(4), 0, 2), &*ledger, 1),
+            Err(MempoolError::Full)
+        );
+        assert_eq!(mempool.len(), 2);
+    }
+
+    #[test]
+    fn eviction_keeps_the_transactions_later_ones_of_a_sender_build_on() {
+        let ledger = ledger(&[sender(1), sender(2)]);
+        let mut mempool = Mempool::new(2);
+        let first = mempool.insert(pay(sender(1), 0, 1), &*ledger, 1).unwrap();
+        let second = mempool.insert(pay(sender(1), 1, 2), &*ledger, 1).unwrap();
+
+        // 只能挤掉发送方最后一笔，否则后面的交易 nonce 不连续
+        mempool.insert(pay(sender(2), 0, 3), &*ledger, 1).unwrap();
+        assert!(mempool.get(&first).is_some());
+        assert!(mempool.get(&second).is_none());
+
+        // 发送方自己的交易不互相挤掉
+        let mut mempool = Mempool::new(2);
+        mempool.insert(pay(sender(1), 0, 1), &*ledger, 1).unwrap();
+        mempool.insert(pay(sender(1), 1, 1), &*ledger, 1).unwrap();
+        assert_eq!(
+            mempool.insert(pay(sender(1), 2, 9), &*ledger, 1),
+            Err(MempoolError::Full)
+        );
+    }
+
+    #[test]
+    fn a_transaction_ahead_of_the_next_nonce_waits_for_the_gap_to_fill() {
+        let ledger = ledger(&[sender(1)]);
+        let mut mempool = Mempool::default();
+        let later = mempool.insert(pay(sender(1), 1, 1), &*ledger, 1).unwrap();
+        assert_eq!((mempool.len(), mempool.queued_len()), (0, 1));
+        assert!(mempool.get(&later).is_none());
+
+        let first = mempool.insert(pay(sender(1), 0, 1), &*ledger, 1).unwrap();
+        assert_eq!((mempool.len(), mempool.queued_len()), (2, 0));
+        let order: Vec<Hash> = mempool.iter().map(|(id, _)| *id).collect();
+        assert_eq!(order, vec![first, later]);
+        assert_eq!(mempool.next_nonce(&sender(1), &*ledger), 2);
+    }
+
+    #[test]
+    fn a_nonce_too_far_ahead_or_already_used_is_refused() {
+        let ledger = ledger(&[sender(1)]);
+        let mut mempool = Mempool::default();
+        let far = 1 + MEMPOOL_MAX_NONCE_GAP;
+        assert_eq!(
+            mempool.insert(pay(sender(1), far, 1), &*ledger, 1),
+            Err(MempoolError::NonceGap {
+                expected: 0,
+                actual: far
+            })
+        );
+        mempool.insert(pay(sender(1), 0, 1), &*ledger, 1).unwrap();
+        assert_eq!(
+            mempool.insert(pay(sender(1), 0, 2), &*ledger, 1),
+            Err(MempoolError::Ledger(LedgerError::NonceMismatch {
+                expected: 1,
+                actual: 0
+            }))
+        );
+        assert_eq!(mempool.queued_len(), 0);
+    }
+
+    #[test]
+    fn a_locked_transaction_is_admitted_once_the_next_block_can_confirm_it() {
+        let ledger = ledger(&[sender(1), sender(2)]);
+        let mut mempool = Mempool::default();
+        let locked = Transaction {
+            locktime: Some(LockTime::Height(5)),
+            ..pay(sender(1), 0, 1)
+        };
+        assert_eq!(
+            mempool.insert(locked.clone(), &*ledger, 4),
+            Err(MempoolError::Locked(LockTime::Height(5)))
+        );
+        mempool.insert(locked, &*ledger, 5).unwrap();
+
+        let later = now() + 3_600;
+        let locked = Transaction {
+            locktime: Some(LockTime::Time(later)),
+            ..pay(sender(2), 0, 1)
+        };
+        assert_eq!(
+            mempool.insert(locked, &*ledger, 5),
+            Err(MempoolError::Locked(LockTime::Time(later)))
+        );
+        let unlocked = Transaction {
+            locktime: Some(LockTime::Time(now())),
+            ..pay(sender(2), 0, 1)
+        };
+        mempool.insert(unlocked, &*ledger, 5).unwrap();
+        assert_eq!(mempool.len(), 2);
+    }
+}
@@ -0,0 +1,158 @@
+use serde::{Deserialize, Serialize};
+
+use crate::blockchain::{BlockHeader, Hash};
+
+/// Prefix of inner nodes, keeps a pair of hashes from passing as a leaf
+const NODE_PREFIX: u8 = 1;
+
+/// Binary hash tree over the transaction ids of a block
+///
+/// A node without a sibling moves up a level unchanged instead of being paired with itself, so
+/// two different lists of leaves never share a root
+#[derive(Debug)]
+pub struct MerkleTree {
+    /// Leaves first, the root last
+    levels: Vec<Vec<Hash>>,
+}
+
+impl MerkleTree {
+    pub fn new(leaves: Vec<Hash>) -> MerkleTree {
+        let mut levels = vec![leaves];
+        loop {
+            let level = levels.last().expect("tree has a level");
+            if level.len() <= 1 {
+                break;
+            }
+            let next = level
+                .chunks(2)
+                .map(|pair| match pair {
+                    [left, right] => parent(left, right),
+                    [single] => *single,
+                    _ => unreachable!("chunks of two"),
+                })
+                .collect();
+            levels.push(next);
+        }
+        MerkleTree { levels }
+    }
+
+    /// Root of the tree, the default hash for a block without transactions
+    pub fn root(&self) -> Hash {
+        self.levels
+            .last()
+            .and_then(|level| level.first())
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Hashes needed to get from the leaf at the index to the root, none when it is out of range
+    pub fn branch(&self, mut index: usize) -> Option<Vec<BranchNode>> {
+        if index >= self.levels[0].len() {
+            return None;
+        }
+        let mut branch = Vec::new();
+        for level in self.levels.iter().take(self.levels.len() - 1) {
+            let sibling = index ^ 1;
+            // 没有兄弟的节点原样上移，这一层不需要哈希
+            if let Some(hash) = level.get(sibling) {
+                branch.push(BranchNode {
+                    hash: *hash,
+                    left: sibling < index,
+                });
+            }
+            index /= 2;
+        }
+        Some(branch)
+    }
+}
+
+/// Sibling on the way from a leaf to the root
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BranchNode {
+    pub hash: Hash,
+    /// Whether the sibling is the left child of their parent
+    pub left: bool,
+}
+
+/// Evidence that a transaction is part of a block, checked against the header alone
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MerkleProof {
+    pub tx: Hash,
+    /// Hash of the header the transaction is committed to
+    pub block: Hash,
+    pub branch: Vec<BranchNode>,
+}
+
+/// Whether the proof belongs to the header and leads from the transaction to its Merkle root
+pub fn verify_proof(header: &BlockHeader, proof: &MerkleProof) -> bool {
+    if header.hash() != proof.block {
+        return false;
+    }
+    let root = proof.branch.iter().fold(proof.tx, |hash, node| {
+        if node.left {
+            parent(&node.hash, &hash)
+        } else {
+            parent(&hash, &node.hash)
+        }
+    });
+    root == header.merkle_root
+}
+
+fn parent(left: &Hash, right: &Hash) -> Hash {
+    let mut bytes = Vec::with_capacity(65);
+    bytes.push(NODE_PREFIX);
+    bytes.extend_from_slice(&left.0);
+    bytes.extend_from_slice(&right.0);
+    Hash::digest(&bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::Block;
+    use crate::transaction::{Address, Transaction};
+
+    /// A block of the transactions paying the address with the nonces
+    fn block(count: u64) -> Block {
+        let transactions = (0..count)
+            .map(|nonce| Transaction::new(Address([1; 32]), Address([2; 32]), 1, nonce))
+            .collect();
+        Block::new(1, Hash::default(), 0, String::new(), transactions)
+    }
+
+    fn tree(block: &Block) -> MerkleTree {
+        MerkleTree::new(block.transactions.iter().map(Transaction::id).collect())
+    }
+
+    #[test]
+    fn every_transaction_of_a_block_is_proven_against_its_header() {
+        for count in 1..=5 {
+            let block = block(count);
+            let tree = tree(&block);
+            assert_eq!(tree.root(), block.header.merkle_root);
+            for (index, tx) in block.transactions.iter().enumerate() {
+                let proof = MerkleProof {
+                    tx: tx.id(),
+                    block: block.hash,
+                    branch: tree.branch(index).unwrap(),
+                };
+                assert!(verify_proof(&block.header, &proof));
+                let forged = MerkleProof {
+                    tx: Hash::digest(b"forged"),
+                    ..proof
+                };
+                assert!(!verify_proof(&block.header, &forged));
+            }
+            assert!(tree.branch(count as usize).is_none());
+        }
+    }
+
+    #[test]
+    fn a_node_without_a_sibling_is_not_paired_with_itself() {
+        let three = tree(&block(3));
+        let mut leaves: Vec<Hash> = block(3).transactions.iter().map(Transaction::id).collect();
+        leaves.push(leaves[2]);
+        assert_ne!(three.root(), MerkleTree::new(leaves).root());
+        assert_eq!(MerkleTree::new(Vec::new()).root(), Hash::default());
+    }
+}
@@ -0,0 +1,119 @@
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+
+use libp2p::gossipsub::{self, TopicHash};
+use libp2p::PeerId;
+
+use crate::consts::{GOSSIPSUB_FANOUT_TTL, GOSSIPSUB_PRUNE_BACKOFF};
+
+/// Mesh state of one topic as far as we can tell
+#[derive(Debug)]
+pub struct MeshReport {
+    pub subscribed: bool,
+    /// Peers we exchange full messages with
+    pub mesh: Vec<PeerId>,
+    /// Peers our messages are sent to while we are not subscribed, gossipsub picks up to
+    /// `mesh_n` of them
+    pub fanout: Vec<PeerId>,
+    /// Peers that left our mesh while still subscribed, with the remaining backoff before they
+    /// can be grafted again
+    pub backoff: Vec<(PeerId, Duration)>,
+    /// Subscribed peers in none of the above, they only receive gossip about our messages
+    pub gossip_only: Vec<PeerId>,
+}
+
+/// Follows mesh membership across heartbeats, gossipsub itself only exposes the current mesh
+#[derive(Debug, Default)]
+pub struct MeshTracker {
+    mesh: HashMap<TopicHash, HashSet<PeerId>>,
+    /// When a peer left the mesh of a topic, most likely because one side pruned the other
+    pruned: HashMap<(TopicHash, PeerId), Instant>,
+    /// Last publish to each topic we are not subscribed to
+    fanout: HashMap<TopicHash, Instant>,
+}
+
+impl MeshTracker {
+    /// Compare the current meshes with the previous snapshot
+    pub fn update(&mut self, gossipsub: &gossipsub::Behaviour) {
+        let now = Instant::now();
+        let subscribers = subscribers(gossipsub);
+        let mut mesh = HashMap::new();
+        for topic in gossipsub.topics() {
+            let current: HashSet<PeerId> = gossipsub.mesh_peers(topic).copied().collect();
+            if let Some(previous) = self.mesh.get(topic) {
+                // 离开 mesh 但仍订阅该主题的节点进入退避期
+                for peer in previous.difference(&current) {
+                    if subscribers
+                        .get(topic)
+                        .is_some_and(|peers| peers.contains(peer))
+                    {
+                        self.pruned.insert((topic.clone(), *peer), now);
+                    }
+                }
+            }
+            for peer in current.iter() {
+                self.pruned.remove(&(topic.clone(), *peer));
+            }
+            mesh.insert(topic.clone(), current);
+        }
+        self.mesh = mesh;
+        self.pruned
+            .retain(|_, since| since.elapsed() < GOSSIPSUB_PRUNE_BACKOFF);
+        self.fanout
+            .retain(|_, published| published.elapsed() < GOSSIPSUB_FANOUT_TTL);
+    }
+
+    /// A message was published, topics we are not subscribed to are sent to fanout peers
+    pub fn record_publish(&mut self, gossipsub: &gossipsub::Behaviour, topic: &TopicHash) {
+        if !gossipsub.topics().any(|t| t == topic) {
+            self.fanout.insert(topic.clone(), Instant::now());
+        }
+    }
+
+    pub fn report(&self, gossipsub: &gossipsub::Behaviour, topic: &TopicHash) -> MeshReport {
+        let subscribed = gossipsub.topics().any(|t| t == topic);
+        let mesh: Vec<PeerId> = gossipsub.mesh_peers(topic).copied().collect();
+        let backoff: Vec<(PeerId, Duration)> = self
+            .pruned
+            .iter()
+            .filter(|((t, _), _)| t == topic)
+            .map(|((_, peer), since)| {
+                (
+                    *peer,
+                    GOSSIPSUB_PRUNE_BACKOFF.saturating_sub(since.elapsed()),
+                )
+            })
+            .collect();
+        let subscribers = subscribers(gossipsub).remove(topic).unwrap_or_default();
+        let fanout: Vec<PeerId> = if !subscribed && self.fanout.contains_key(topic) {
+            subscribers.iter().copied().collect()
+        } else {
+            Vec::new()
+        };
+        let gossip_only = subscribers
+            .into_iter()
+            .filter(|peer| {
+                !mesh.contains(peer)
+                    && !fanout.contains(peer)
+                    && !backoff.iter().any(|(p, _)| p == peer)
+            })
+            .collect();
+        MeshReport {
+            subscribed,
+            mesh,
+            fanout,
+            backoff,
+            gossip_only,
+        }
+    }
+}
+
+fn subscribers(gossipsub: &gossipsub::Behaviour) -> HashMap<TopicHash, HashSet<PeerId>> {
+    let mut subscribers: HashMap<TopicHash, HashSet<PeerId>> = HashMap::new();
+    for (peer, topics) in gossipsub.all_peers() {
+        for topic in topics {
+            subscribers.entry(topic.clone()).or_default().insert(*peer);
+        }
+    }
+    subscribers
+}
@@ -0,0 +1,178 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use libp2p::core::ConnectedPoint;
+use libp2p::gossipsub::TopicHash;
+use libp2p::multiaddr::Protocol;
+use libp2p::swarm::DialError;
+use libp2p::{Multiaddr, PeerId};
+
+/// Messages and payload bytes in both directions
+#[derive(Debug, Default)]
+pub struct TrafficCounters {
+    pub messages_in: u64,
+    pub bytes_in: u64,
+    pub messages_out: u64,
+    pub bytes_out: u64,
+    /// Received messages dropped by the rate limiter
+    pub dropped: u64,
+}
+
+impl TrafficCounters {
+    fn record_in(&mut self, bytes: usize) {
+        self.messages_in += 1;
+        self.bytes_in += bytes as u64;
+    }
+
+    fn record_out(&mut self, bytes: usize) {
+        self.messages_out += 1;
+        self.bytes_out += bytes as u64;
+    }
+}
+
+/// How an outbound connection reaches the remote
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DialRoute {
+    Tcp,
+    Quic,
+    WebSocket,
+    /// Through a relay circuit, whatever transport the relay is reached over
+    Relay,
+    Memory,
+    Other,
+}
+
+impl DialRoute {
+    pub fn of(addr: &Multiaddr) -> DialRoute {
+        let mut route = DialRoute::Other;
+        for protocol in addr.iter() {
+            route = match protocol {
+                Protocol::P2pCircuit => return DialRoute::Relay,
+                Protocol::Ws(_) | Protocol::Wss(_) => DialRoute::WebSocket,
+                Protocol::QuicV1 | Protocol::Quic => DialRoute::Quic,
+                Protocol::Tcp(_) if route == DialRoute::Other => DialRoute::Tcp,
+                Protocol::Memory(_) => DialRoute::Memory,
+                _ => route,
+            };
+        }
+        route
+    }
+}
+
+impl fmt::Display for DialRoute {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            DialRoute::Tcp => "tcp",
+            DialRoute::Quic => "quic",
+            DialRoute::WebSocket => "websocket",
+            DialRoute::Relay => "relay",
+            DialRoute::Memory => "memory",
+            DialRoute::Other => "other",
+        };
+        f.write_str(name)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct DialCounters {
+    pub attempted: u64,
+    pub succeeded: u64,
+    pub failed: u64,
+}
+
+impl DialCounters {
+    fn record(&mut self, success: bool) {
+        self.attempted += 1;
+        if success {
+            self.succeeded += 1;
+        } else {
+            self.failed += 1;
+        }
+    }
+}
+
+/// Outcome of outbound dials per route and of hole punches
+#[derive(Debug, Default)]
+pub struct DialStats {
+    pub routes: HashMap<DialRoute, DialCounters>,
+    /// Dials that failed without an address to attribute them to, e.g. denied by a connection
+    /// limit or without any address to try
+    pub failed_without_address: u64,
+    pub hole_punches: DialCounters,
+}
+
+impl DialStats {
+    /// An outbound connection was established
+    pub fn record_success(&mut self, addr: &Multiaddr) {
+        self.routes
+            .entry(DialRoute::of(addr))
+            .or_default()
+            .record(true);
+    }
+
+    /// A dial failed, every address tried counts as a failed attempt
+    pub fn record_failure(&mut self, error: &DialError) {
+        match error {
+            DialError::Transport(errors) => {
+                for (addr, _) in errors {
+                    self.routes
+                        .entry(DialRoute::of(addr))
+                        .or_default()
+                        .record(false);
+                }
+            }
+            DialError::WrongPeerId { endpoint, .. } | DialError::LocalPeerId { endpoint } => {
+                if let ConnectedPoint::Dialer { address, .. } = endpoint {
+                    self.routes
+                        .entry(DialRoute::of(address))
+                        .or_default()
+                        .record(false);
+                } else {
+                    self.failed_without_address += 1;
+                }
+            }
+            _ => self.failed_without_address += 1,
+        }
+    }
+
+    /// A hole punch finished, successfully or not
+    pub fn record_hole_punch(&mut self, success: bool) {
+        self.hole_punches.record(success);
+    }
+}
+
+/// Gossip message counters per peer and per topic, and dial outcomes
+///
+/// Transport level byte totals, which include direct streams and protocol overhead, come from the
+/// swarm's bandwidth sinks
+#[derive(Debug, Default)]
+pub struct NetStats {
+    pub peers: HashMap<PeerId, TrafficCounters>,
+    pub topics: HashMap<TopicHash, TrafficCounters>,
+    pub dials: DialStats,
+}
+
+impl NetStats {
+    /// A gossip message relayed to us by the peer
+    pub fn record_gossip_in(&mut self, peer: PeerId, topic: &TopicHash, bytes: usize) {
+        self.peers.entry(peer).or_default().record_in(bytes);
+        self.topics
+            .entry(topic.clone())
+            .or_default()
+            .record_in(bytes);
+    }
+
+    /// A gossip message of the peer dropped because it exceeded the rate limit
+    pub fn record_dropped(&mut self, peer: PeerId, topic: &TopicHash) {
+        self.peers.entry(peer).or_default().dropped += 1;
+        self.topics.entry(topic.clone()).or_default().dropped += 1;
+    }
+
+    /// A gossip message we published
+    pub fn record_gossip_out(&mut self, topic: &TopicHash, bytes: usize) {
+        self.topics
+            .entry(topic.clone())
+            .or_default()
+            .record_out(bytes);
+    }
+}
@@ -0,0 +1,91 @@
+use log::{debug, error};
+use tokio::runtime::Handle;
+use tokio::sync::{mpsc, watch};
+
+use crate::blockchain::Block;
+use crate::consts::MINING_BATCH_NONCES;
+use crate::models::EventType;
+
+/// Try up to `count` nonces, returns whether the hash of the block has at least as many leading
+/// zero bits as its difficulty
+pub fn try_nonces(block: &mut Block, count: u64) -> bool {
+    for _ in 0..count {
+        block.hash = block.compute_hash();
+        if block.hash.leading_zero_bits() >= block.header.difficulty {
+            return true;
+        }
+        block.header.nonce = block.header.nonce.wrapping_add(1);
+        // 所有 nonce 都试过后更新时间戳继续
+        if block.header.nonce == 0 {
+            block.header.timestamp += 1;
+        }
+    }
+    false
+}
+
+/// Miner working on a blocking thread off the event loop, the job is handed over through a watch
+/// channel so it is abandoned as soon as a new template arrives
+///
+/// Blocks found are delivered as `EventType::BlockMined`, dropping the miner stops the thread
+#[derive(Debug)]
+pub struct Miner {
+    templates: watch::Sender<Block>,
+    /// Data of the blocks, e.g. recipes anchored on the chain
+    pub data: String,
+    /// Whether the next block is mined once one is found, `mine` alone stops after one block
+    pub continuous: bool,
+}
+
+impl Miner {
+    pub fn start(
+        sender: mpsc::UnboundedSender<EventType>,
+        template: Block,
+        data: String,
+        continuous: bool,
+    ) -> Miner {
+        let (templates, receiver) = watch::channel(template);
+        let handle = Handle::current();
+        tokio::task::spawn_blocking(move || run(sender, receiver, handle));
+        Miner {
+            templates,
+            data,
+            continuous,
+        }
+    }
+
+    /// Abandon the block being mined and mine the template instead
+    pub fn restart(&self, template: Block) {
+        self.templates.send_replace(template);
+    }
+}
+
+fn run(
+    sender: mpsc::UnboundedSender<EventType>,
+    mut templates: watch::Receiver<Block>,
+    handle: Handle,
+) {
+    let mut block = templates.borrow_and_update().clone();
+    loop {
+        debug!(
+            "mining block {} with difficulty {}",
+            block.header.index, block.header.difficulty
+        );
+        while !try_nonces(&mut block, MINING_BATCH_NONCES) {
+            match templates.has_changed() {
+                Ok(true) => block = templates.borrow_and_update().clone(),
+                Ok(false) => {}
+                // 矿工已被丢弃，停止挖矿
+                Err(_) => return,
+            }
+        }
+        if let Err(e) = sender.send(EventType::BlockMined(block)) {
+            error!("error sending mined block via channel, {}", e);
+            return;
+        }
+        // 区块接上链之后才有下一个区块的模板
+        if handle.block_on(templates.changed()).is_err() {
+            return;
+        }
+        block = templates.borrow_and_update().clone();
+    }
+}
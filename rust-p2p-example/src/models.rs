@@ -1,13 +1,44 @@
+use libp2p::gossipsub::TopicHash;
+use libp2p::{Multiaddr, PeerId};
 use serde::{Deserialize, Serialize};
 
+use crate::blockchain::{Block, Chain, Hash, Pruned};
+use crate::consts::TOPIC;
+use crate::finality::Vote;
+use crate::transaction::Transaction;
+
 /// The recipe data for cook
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Recipe {
     pub id: usize,
     pub name: String,
     pub ingredients: String,
     pub instructions: String,
     pub shared: bool,
+    /// Topics the recipe is shared on, the default recipe topic when empty
+    #[serde(default)]
+    pub topics: Vec<String>,
+    /// Hash of the image in the blob store
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub image: Option<Hash>,
+    /// Unix time the recipe was deleted, it is kept as a tombstone until the retention window
+    /// passed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub deleted: Option<u64>,
+}
+
+impl Recipe {
+    /// Whether the recipe is served to requests arriving on the topic
+    pub fn shared_on(&self, topic: &TopicHash) -> bool {
+        if !self.shared {
+            return false;
+        }
+        if self.topics.is_empty() {
+            topic == &TOPIC.hash()
+        } else {
+            self.topics.iter().any(|t| t == topic.as_str())
+        }
+    }
 }
 
 /// Fetch data mode
@@ -32,10 +63,131 @@ pub struct ListResponse {
     pub receiver: String,
 }
 
-pub enum EventType {
-    Response(ListResponse),
-    Input(String),
+/// A peer we connected to and the addresses we reached it at
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PeerRecord {
+    pub peer_id: PeerId,
+    pub addrs: Vec<Multiaddr>,
+}
+
+/// Sample of the known good peers of a node, shared periodically so others can grow their view
+/// of the network without a DHT
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PeerExchange {
+    pub peers: Vec<PeerRecord>,
 }
 
+/// Topics a node answers recipe requests on, announced periodically so peers know whether
+/// anyone will answer a broadcast
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Presence {
+    pub topics: Vec<String>,
+}
+
+/// What an enveloped gossip message carries
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MessageKind {
+    Block,
+    ListRequest,
+    ListResponse,
+    PeerExchange,
+    Presence,
+    Transaction,
+    Vote,
+    /// A kind introduced by a newer node
+    #[serde(other)]
+    Unknown,
+}
 
+/// Versioned wrapper of every gossip message
+///
+/// The payload is decoded according to `kind` only after the version was checked, so nodes can
+/// skip messages they do not understand instead of failing on them
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MessageEnvelope {
+    pub version: u16,
+    pub kind: MessageKind,
+    #[serde(with = "serde_bytes")]
+    pub payload: Vec<u8>,
+}
+
+/// A message that can be published in an envelope
+pub trait GossipMessage: Serialize {
+    const KIND: MessageKind;
+}
 
+impl GossipMessage for Block {
+    const KIND: MessageKind = MessageKind::Block;
+}
+
+impl GossipMessage for ListRequest {
+    const KIND: MessageKind = MessageKind::ListRequest;
+}
+
+impl GossipMessage for ListResponse {
+    const KIND: MessageKind = MessageKind::ListResponse;
+}
+
+impl GossipMessage for PeerExchange {
+    const KIND: MessageKind = MessageKind::PeerExchange;
+}
+
+impl GossipMessage for Presence {
+    const KIND: MessageKind = MessageKind::Presence;
+}
+
+impl GossipMessage for Transaction {
+    const KIND: MessageKind = MessageKind::Transaction;
+}
+
+impl GossipMessage for Vote {
+    const KIND: MessageKind = MessageKind::Vote;
+}
+
+pub enum EventType {
+    /// Answer to a list request, published on the topic the request came in on
+    Response(TopicHash, ListResponse),
+    Input(String),
+    KadBootstrap,
+    /// A relayed connection was upgraded to a direct one
+    HolePunchSucceeded(PeerId),
+    /// Upgrading a relayed connection to a direct one failed, with the reason
+    HolePunchFailed(PeerId, String),
+    /// Time to redial a bootstrap node that could not be reached
+    DialBootstrap(PeerId),
+    /// Time to redial a known peer that disconnected
+    Reconnect(PeerId),
+    /// Time to check the network health
+    HealthCheck,
+    /// Time to compare the gossipsub meshes with the last snapshot
+    MeshUpdate,
+    /// Time to expire deleted recipes and compact the storage
+    StorageMaintenance,
+    /// Time to look up recipe nodes at the rendezvous points
+    RendezvousDiscover,
+    /// Outcome of a dial started by the `dial` command
+    DialResult(Multiaddr, Result<PeerId, String>),
+    /// Time to share a sample of our known peers
+    PeerExchange,
+    /// Peers shared by another node
+    PeersLearned(PeerId, Vec<PeerRecord>),
+    /// Time to announce the topics we serve recipes on
+    AnnouncePresence,
+    /// Topics another node serves recipes on
+    PresenceReceived(PeerId, Vec<String>),
+    /// The miner found a valid nonce for the block
+    BlockMined(Block),
+    /// A block mined by another node
+    BlockReceived(PeerId, Block),
+    /// A transaction published by another node
+    TransactionReceived(PeerId, Transaction),
+    /// A finality vote of a validator
+    VoteReceived(PeerId, Vote),
+    /// The chain rebuilt from our blocks by `chain reindex`
+    ChainReplayed(Box<Chain>),
+    /// Headers and ledger of the blocks pruned by the storage maintenance, ready to store
+    BlocksPruned(Box<anyhow::Result<Pruned>>),
+    /// Ctrl-C was pressed
+    Shutdown,
+}
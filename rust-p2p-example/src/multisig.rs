@@ -0,0 +1,198 @@
+use anyhow::{bail, Result};
+use libp2p::identity::{ed25519, Keypair};
+use serde::{Deserialize, Serialize};
+
+use crate::blockchain::Hash;
+use crate::consts::MULTISIG_MAX_SIGNERS;
+use crate::transaction::Address;
+
+/// Prefix of the hashed policy, keeps shared addresses apart from other derived addresses
+const ADDRESS_DOMAIN: &[u8] = b"ant-chain/multisig";
+
+/// M-of-N policy of a shared address, a spend from it needs signatures of `threshold` signers
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Policy {
+    pub threshold: u32,
+    /// Sorted, so the same signers and threshold always share one address
+    pub signers: Vec<Address>,
+}
+
+impl Policy {
+    pub fn new(threshold: u32, mut signers: Vec<Address>) -> Result<Policy> {
+        signers.sort();
+        signers.dedup();
+        let policy = Policy { threshold, signers };
+        policy.check()?;
+        Ok(policy)
+    }
+
+    fn check(&self) -> Result<()> {
+        if self.signers.len() > MULTISIG_MAX_SIGNERS {
+            bail!(
+                "at most {} signers may share an address",
+                MULTISIG_MAX_SIGNERS
+            );
+        }
+        if self.threshold == 0 || self.threshold as usize > self.signers.len() {
+            bail!(
+                "{} of {} signatures can not be required",
+                self.threshold,
+                self.signers.len()
+            );
+        }
+        if self.signers.windows(2).any(|pair| pair[0] >= pair[1]) {
+            bail!("signers must be sorted and distinct");
+        }
+        Ok(())
+    }
+
+    /// Hash of the policy, coins sent there can only be spent with the policy
+    pub fn address(&self) -> Address {
+        let mut bytes = Vec::with_capacity(ADDRESS_DOMAIN.len() + 4 + 32 * self.signers.len());
+        bytes.extend_from_slice(ADDRESS_DOMAIN);
+        bytes.extend_from_slice(&self.threshold.to_be_bytes());
+        for signer in self.signers.iter() {
+            bytes.extend_from_slice(&signer.0);
+        }
+        Address(Hash::digest(&bytes).0)
+    }
+}
+
+/// Signature of one signer of the policy over the transaction
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PartialSignature {
+    pub signer: Address,
+    #[serde(with = "serde_bytes")]
+    pub signature: Vec<u8>,
+}
+
+/// What a spend from a shared address carries instead of the signature of its sender
+///
+/// Signatures are collected one signer at a time, the spend is valid once `threshold` of them
+/// signed
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MultisigSpend {
+    pub policy: Policy,
+    #[serde(default)]
+    pub signatures: Vec<PartialSignature>,
+}
+
+impl MultisigSpend {
+    pub fn new(policy: Policy) -> MultisigSpend {
+        MultisigSpend {
+            policy,
+            signatures: Vec::new(),
+        }
+    }
+
+    /// Add the signature of the key over the message, replacing an earlier one of the same
+    /// signer, returns the signer
+    pub fn sign(&mut self, keys: &Keypair, message: &[u8]) -> Result<Address> {
+        let signer = match Address::of(&keys.public()) {
+            Some(signer) if self.policy.signers.contains(&signer) => signer,
+            _ => bail!(
+                "the key is not one of the signers of {}",
+                self.policy.address()
+            ),
+        };
+        let signature = keys.sign(message)?;
+        self.signatures.retain(|partial| partial.signer != signer);
+        self.signatures.push(PartialSignature { signer, signature });
+        Ok(signer)
+    }
+
+    /// Signers of the policy with a valid signature over the message, each counted once
+    pub fn valid_signers(&self, message: &[u8]) -> usize {
+        let mut signers: Vec<&Address> = self
+            .signatures
+            .iter()
+            .filter(|partial| self.policy.signers.contains(&partial.signer))
+            .filter(|partial| {
+                ed25519::PublicKey::try_from_bytes(&partial.signer.0)
+                    .is_ok_and(|key| key.verify(message, &partial.signature))
+            })
+            .map(|partial| &partial.signer)
+            .collect();
+        signers.sort();
+        signers.dedup();
+        signers.len()
+    }
+
+    /// Whether the policy is the one of the address and enough of its signers signed the message
+    pub fn verify(&self, address: &Address, message: &[u8]) -> bool {
+        self.policy.check().is_ok()
+            && self.policy.address() == *address
+            && self.valid_signers(message) >= self.policy.threshold as usize
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn signers(count: usize) -> (Vec<Keypair>, Vec<Address>) {
+        let keys: Vec<Keypair> = (0..count).map(|_| Keypair::generate_ed25519()).collect();
+        let addresses = keys
+            .iter()
+            .map(|keys| Address::of(&keys.public()).unwrap())
+            .collect();
+        (keys, addresses)
+    }
+
+    #[test]
+    fn the_address_of_a_policy_does_not_depend_on_the_order_of_its_signers() {
+        let (_, addresses) = signers(3);
+        let mut reversed = addresses.clone();
+        reversed.reverse();
+        let policy = Policy::new(2, addresses.clone()).unwrap();
+        assert_eq!(
+            policy.address(),
+            Policy::new(2, reversed).unwrap().address()
+        );
+        assert_ne!(
+            policy.address(),
+            Policy::new(3, addresses.clone()).unwrap().address()
+        );
+        assert!(Policy::new(0, addresses.clone()).is_err());
+        assert!(Policy::new(4, addresses).is_err());
+    }
+
+    #[test]
+    fn a_spend_is_valid_once_threshold_distinct_signers_signed() {
+        let (keys, addresses) = signers(3);
+        let policy = Policy::new(2, addresses).unwrap();
+        let address = policy.address();
+        let mut spend = MultisigSpend::new(policy);
+        let message = b"spend";
+
+        spend.sign(&keys[0], message).unwrap();
+        // 同一签名者只算一次
+        spend.sign(&keys[0], message).unwrap();
+        assert_eq!(spend.signatures.len(), 1);
+        assert!(!spend.verify(&address, message));
+        spend.sign(&keys[2], message).unwrap();
+        assert!(spend.verify(&address, message));
+        assert!(!spend.verify(&address, b"another spend"));
+        assert!(!spend.verify(&Address([1; 32]), message));
+        assert!(spend.sign(&Keypair::generate_ed25519(), message).is_err());
+    }
+
+    #[test]
+    fn forged_or_duplicated_signatures_do_not_count() {
+        let (keys, addresses) = signers(2);
+        let policy = Policy::new(2, addresses.clone()).unwrap();
+        let address = policy.address();
+        let mut spend = MultisigSpend::new(policy);
+        let message = b"spend";
+        spend.sign(&keys[0], message).unwrap();
+        let copied = spend.signatures[0].clone();
+        spend.signatures.push(copied);
+        assert_eq!(spend.valid_signers(message), 1);
+        spend.signatures.push(PartialSignature {
+            signer: addresses[1],
+            signature: keys[0].sign(message).unwrap(),
+        });
+        assert_eq!(spend.valid_signers(message), 1);
+        assert!(!spend.verify(&address, message));
+    }
+}
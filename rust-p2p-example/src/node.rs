@@ -0,0 +1,45 @@
+use anyhow::Result;
+use libp2p::core::muxing::StreamMuxerBox;
+use libp2p::core::transport::MemoryTransport;
+use libp2p::core::upgrade::Version;
+use libp2p::identity::Keypair;
+use libp2p::multiaddr::Protocol;
+use libp2p::{noise, yamux, Multiaddr, Swarm, SwarmBuilder, Transport};
+
+use crate::behaviour::RecipeBehaviour;
+use crate::config::CONFIG;
+
+/// Build a swarm that can only reach other in-memory nodes of this process
+///
+/// Many of these can run in one process without opening sockets, they still read the global
+/// config so it applies to all of them alike
+pub fn memory_swarm(key: Keypair) -> Result<Swarm<RecipeBehaviour>> {
+    let swarm = SwarmBuilder::with_existing_identity(key)
+        .with_tokio()
+        .with_other_transport(|key| {
+            Ok(MemoryTransport::default()
+                .upgrade(Version::V1Lazy)
+                .authenticate(noise::Config::new(key)?)
+                .multiplex(yamux::Config::default())
+                .map(|(peer_id, conn), _| (peer_id, StreamMuxerBox::new(conn))))
+        })?
+        .with_relay_client(noise::Config::new, yamux::Config::default)?
+        .with_behaviour(|key, relay_client| {
+            RecipeBehaviour::without_local_network(key, relay_client).map_err(Into::into)
+        })?
+        .with_swarm_config(|cfg| cfg.with_idle_connection_timeout(CONFIG.idle_connection_timeout()))
+        .build();
+    Ok(swarm)
+}
+
+/// Address of the in-memory listener on the port, 0 picks a free one
+pub fn memory_addr(port: u64) -> Multiaddr {
+    Multiaddr::empty().with(Protocol::Memory(port))
+}
+
+/// Build a node with a fresh identity and start listening on the in-memory port
+pub fn spawn_memory_node(port: u64) -> Result<Swarm<RecipeBehaviour>> {
+    let mut swarm = memory_swarm(Keypair::generate_ed25519())?;
+    swarm.listen_on(memory_addr(port))?;
+    Ok(swarm)
+}
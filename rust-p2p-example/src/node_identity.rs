@@ -0,0 +1,63 @@
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use libp2p::identity::Keypair;
+use log::info;
+
+/// Load the node keypair from `path`, generating and persisting a new one when the file is
+/// missing or `regenerate` is set
+pub fn load_or_generate(path: &Path, regenerate: bool) -> Result<Keypair> {
+    if path.exists() && !regenerate {
+        check_permissions(path)?;
+        let bytes =
+            fs::read(path).with_context(|| format!("can not read identity {}", path.display()))?;
+        let keys = Keypair::from_protobuf_encoding(&bytes)
+            .with_context(|| format!("invalid identity {}", path.display()))?;
+        info!("Loaded identity from {}", path.display());
+        return Ok(keys);
+    }
+
+    let keys = Keypair::generate_ed25519();
+    save(path, &keys)?;
+    info!("Generated new identity in {}", path.display());
+    Ok(keys)
+}
+
+fn save(path: &Path, keys: &Keypair) -> Result<()> {
+    let bytes = keys.to_protobuf_encoding()?;
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options
+        .open(path)
+        .with_context(|| format!("can not write identity {}", path.display()))?;
+    file.write_all(&bytes)?;
+    Ok(())
+}
+
+/// The private key must not be readable by other users
+#[cfg(unix)]
+fn check_permissions(path: &Path) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let mode = fs::metadata(path)?.permissions().mode();
+    if mode & 0o077 != 0 {
+        bail!(
+            "identity {} is accessible by other users (mode {:o}), run `chmod 600` on it",
+            path.display(),
+            mode & 0o777
+        );
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn check_permissions(_path: &Path) -> Result<()> {
+    Ok(())
+}
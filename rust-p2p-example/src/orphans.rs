@@ -0,0 +1,103 @@
+use std::collections::HashMap;
+use std::time::Instant;
+
+use libp2p::PeerId;
+
+use crate::blockchain::{Block, Hash};
+use crate::consts::{ORPHAN_POOL_CAPACITY, ORPHAN_TTL};
+
+/// Block received before its parent
+#[derive(Debug)]
+struct Orphan {
+    block: Block,
+    /// Peer that sent the block, blamed when it turns out invalid
+    source: PeerId,
+    received: Instant,
+}
+
+/// Blocks whose parent we do not have yet, connected once the parent arrives
+///
+/// The pool is small and its blocks expire, so blocks of a chain we never get to see do not
+/// pile up
+#[derive(Debug, Default)]
+pub struct OrphanPool {
+    orphans: HashMap<Hash, Orphan>,
+    /// Orphans waiting for each parent
+    children: HashMap<Hash, Vec<Hash>>,
+}
+
+impl OrphanPool {
+    /// Keep the block until its parent arrives, the oldest orphan makes room when the pool is
+    /// full, returns false if the block is already kept
+    pub fn insert(&mut self, source: PeerId, block: Block) -> bool {
+        self.expire();
+        if self.orphans.contains_key(&block.hash) {
+            return false;
+        }
+        if self.orphans.len() >= ORPHAN_POOL_CAPACITY {
+            let oldest = self
+                .orphans
+                .iter()
+                .min_by_key(|(_, orphan)| orphan.received)
+                .map(|(hash, _)| *hash);
+            if let Some(oldest) = oldest {
+                self.remove(&oldest);
+            }
+        }
+        self.children
+            .entry(block.header.prev_hash)
+            .or_default()
+            .push(block.hash);
+        self.orphans.insert(
+            block.hash,
+            Orphan {
+                block,
+                source,
+                received: Instant::now(),
+            },
+        );
+        true
+    }
+
+    /// Take the orphans waiting for the block, with the peers they came from
+    pub fn take_children(&mut self, parent: &Hash) -> Vec<(PeerId, Block)> {
+        self.expire();
+        self.children
+            .remove(parent)
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|hash| self.orphans.remove(&hash))
+            .map(|orphan| (orphan.source, orphan.block))
+            .collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.orphans.len()
+    }
+
+    /// Drop the block, e.g. once it was connected after being downloaded
+    pub fn remove(&mut self, hash: &Hash) {
+        let parent = match self.orphans.remove(hash) {
+            Some(orphan) => orphan.block.header.prev_hash,
+            None => return,
+        };
+        if let Some(children) = self.children.get_mut(&parent) {
+            children.retain(|child| child != hash);
+            if children.is_empty() {
+                self.children.remove(&parent);
+            }
+        }
+    }
+
+    fn expire(&mut self) {
+        let expired: Vec<Hash> = self
+            .orphans
+            .iter()
+            .filter(|(_, orphan)| orphan.received.elapsed() >= ORPHAN_TTL)
+            .map(|(hash, _)| *hash)
+            .collect();
+        for hash in expired.iter() {
+            self.remove(hash);
+        }
+    }
+}
@@ -0,0 +1,205 @@
+use std::cmp;
+use std::collections::HashMap;
+use std::fs;
+use std::future::Future;
+use std::io::ErrorKind;
+use std::time::Instant;
+
+use anyhow::{Context, Result};
+use libp2p::PeerId;
+use serde::{Deserialize, Serialize};
+
+use crate::consts::{
+    PEER_SCORES_FILE_PATH, PEER_SCORE_ABRUPT_DISCONNECT_PENALTY, PEER_SCORE_BAN_THRESHOLD,
+    PEER_SCORE_GOOD_RESPONSE_REWARD, PEER_SCORE_INVALID_BLOCK_PENALTY,
+    PEER_SCORE_INVALID_MESSAGE_PENALTY, PEER_SCORE_MAX_MESSAGES_PER_WINDOW, PEER_SCORE_MAX_REWARD,
+    PEER_SCORE_RATE_VIOLATION_PENALTY, PEER_SCORE_WINDOW,
+};
+use crate::storage;
+use crate::validation::ValidationError;
+
+/// What to do with a peer after recording its latest message
+#[derive(Debug, PartialEq, Eq)]
+pub enum Verdict {
+    Accept,
+    /// Sent too many messages in the current window
+    Disconnect,
+    /// Score dropped below the ban threshold
+    Ban,
+}
+
+/// Message statistics of a single peer
+///
+/// Everything but the current rate window is persisted across restarts
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PeerScore {
+    pub messages: u64,
+    #[serde(default)]
+    pub good_responses: u64,
+    pub invalid_messages: u64,
+    /// Blocks that broke a consensus rule
+    #[serde(default)]
+    pub invalid_blocks: u64,
+    /// Why the last invalid block was rejected
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_rejection: Option<String>,
+    pub rate_violations: u64,
+    /// Connections that failed instead of being closed by either side
+    #[serde(default)]
+    pub abrupt_disconnects: u64,
+    #[serde(skip, default = "Instant::now")]
+    window_start: Instant,
+    #[serde(skip)]
+    window_messages: u32,
+}
+
+impl Default for PeerScore {
+    fn default() -> Self {
+        PeerScore {
+            messages: 0,
+            good_responses: 0,
+            invalid_messages: 0,
+            invalid_blocks: 0,
+            last_rejection: None,
+            rate_violations: 0,
+            abrupt_disconnects: 0,
+            window_start: Instant::now(),
+            window_messages: 0,
+        }
+    }
+}
+
+impl PeerScore {
+    /// Positive for peers that answered our requests, negative once the peer misbehaved
+    ///
+    /// The reward for good responses is capped so it can not make up for unlimited misbehaviour
+    pub fn score(&self) -> i64 {
+        let reward = cmp::min(
+            self.good_responses as i64 * PEER_SCORE_GOOD_RESPONSE_REWARD,
+            PEER_SCORE_MAX_REWARD,
+        );
+        reward
+            - self.invalid_messages as i64 * PEER_SCORE_INVALID_MESSAGE_PENALTY
+            - self.invalid_blocks as i64 * PEER_SCORE_INVALID_BLOCK_PENALTY
+            - self.rate_violations as i64 * PEER_SCORE_RATE_VIOLATION_PENALTY
+            - self.abrupt_disconnects as i64 * PEER_SCORE_ABRUPT_DISCONNECT_PENALTY
+    }
+
+    /// Nothing worth remembering after a restart
+    fn is_neutral(&self) -> bool {
+        self.good_responses == 0
+            && self.invalid_messages == 0
+            && self.invalid_blocks == 0
+            && self.rate_violations == 0
+            && self.abrupt_disconnects == 0
+    }
+
+    /// Messages per second in the current window
+    pub fn rate(&self) -> f64 {
+        let elapsed = self.window_start.elapsed().as_secs_f64();
+        if elapsed > 0.0 {
+            self.window_messages as f64 / elapsed
+        } else {
+            0.0
+        }
+    }
+
+    fn record(&mut self) -> Verdict {
+        if self.window_start.elapsed() >= PEER_SCORE_WINDOW {
+            self.window_start = Instant::now();
+            self.window_messages = 0;
+        }
+        self.messages += 1;
+        self.window_messages += 1;
+        if self.window_messages == PEER_SCORE_MAX_MESSAGES_PER_WINDOW + 1 {
+            self.rate_violations += 1;
+            return self.verdict(Verdict::Disconnect);
+        }
+        self.verdict(Verdict::Accept)
+    }
+
+    fn verdict(&self, otherwise: Verdict) -> Verdict {
+        if self.score() <= PEER_SCORE_BAN_THRESHOLD {
+            Verdict::Ban
+        } else {
+            otherwise
+        }
+    }
+}
+
+/// Tracks per-peer message rates, responses and misbehaviour to weed out spammy and unreliable
+/// peers, persisted so reputations survive restarts
+#[derive(Debug, Default)]
+pub struct PeerScores {
+    peers: HashMap<PeerId, PeerScore>,
+}
+
+impl PeerScores {
+    /// Load the persisted reputations, a missing file means no peer is known yet
+    pub fn load() -> Result<PeerScores> {
+        let content = match fs::read(PEER_SCORES_FILE_PATH) {
+            Ok(content) => content,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(PeerScores::default()),
+            Err(e) => {
+                return Err(e).with_context(|| format!("can not read {}", PEER_SCORES_FILE_PATH))
+            }
+        };
+        let peers = serde_json::from_slice(&content)
+            .with_context(|| format!("invalid peer scores {}", PEER_SCORES_FILE_PATH))?;
+        Ok(PeerScores { peers })
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&PeerId, &PeerScore)> {
+        self.peers.iter()
+    }
+
+    /// Record a message delivered by the peer
+    pub fn record_message(&mut self, peer_id: PeerId) -> Verdict {
+        self.peers.entry(peer_id).or_default().record()
+    }
+
+    /// Record a message that could not be understood
+    pub fn record_invalid_message(&mut self, peer_id: PeerId) -> Verdict {
+        let score = self.peers.entry(peer_id).or_default();
+        score.invalid_messages += 1;
+        score.verdict(Verdict::Accept)
+    }
+
+    /// Record a block that broke a consensus rule along with the rule
+    pub fn record_invalid_block(&mut self, peer_id: PeerId, reason: &ValidationError) -> Verdict {
+        let score = self.peers.entry(peer_id).or_default();
+        score.invalid_blocks += 1;
+        score.last_rejection = Some(reason.to_string());
+        score.verdict(Verdict::Accept)
+    }
+
+    /// Record a valid answer to one of our requests
+    pub fn record_good_response(&mut self, peer_id: PeerId) {
+        self.peers.entry(peer_id).or_default().good_responses += 1;
+    }
+
+    /// Record a connection to the peer that failed instead of being closed
+    pub fn record_abrupt_disconnect(&mut self, peer_id: PeerId) -> Verdict {
+        let score = self.peers.entry(peer_id).or_default();
+        score.abrupt_disconnects += 1;
+        score.verdict(Verdict::Accept)
+    }
+
+    /// Forget a peer, e.g. after it was banned
+    pub fn remove(&mut self, peer_id: &PeerId) {
+        self.peers.remove(peer_id);
+    }
+
+    /// Persist the peers with a reputation, peers we know nothing about are left out
+    ///
+    /// The peers are copied when it is called, so the write can be spawned off the event loop
+    pub fn save(&self) -> impl Future<Output = Result<()>> + Send + 'static {
+        let peers: HashMap<&PeerId, &PeerScore> = self
+            .peers
+            .iter()
+            .filter(|(_, score)| !score.is_neutral())
+            .collect();
+        let json = serde_json::to_vec(&peers);
+        async move { storage::replace_file(PEER_SCORES_FILE_PATH, json?).await }
+    }
+}
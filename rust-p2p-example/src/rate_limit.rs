@@ -0,0 +1,47 @@
+use std::collections::HashMap;
+use std::time::Instant;
+
+use libp2p::PeerId;
+
+use crate::consts::{RATE_LIMIT_BURST, RATE_LIMIT_MESSAGES_PER_SECOND};
+
+/// Token bucket of a single peer, refilled continuously up to the burst size
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+/// Caps how many recipe messages each peer may deliver per second
+///
+/// Unlike peer scoring, which disconnects spammy peers, excess messages are just dropped
+#[derive(Debug, Default)]
+pub struct RateLimiter {
+    peers: HashMap<PeerId, Bucket>,
+}
+
+impl RateLimiter {
+    /// Take a token for a message of the peer, returns false when the message should be dropped
+    pub fn allow(&mut self, peer_id: PeerId) -> bool {
+        let now = Instant::now();
+        let bucket = self.peers.entry(peer_id).or_insert_with(|| Bucket {
+            tokens: RATE_LIMIT_BURST,
+            last_refill: now,
+        });
+        let elapsed = now.duration_since(bucket.last_refill).as_secs_f64();
+        bucket.tokens =
+            (bucket.tokens + elapsed * RATE_LIMIT_MESSAGES_PER_SECOND).min(RATE_LIMIT_BURST);
+        bucket.last_refill = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Forget a peer, e.g. after it was banned
+    pub fn remove(&mut self, peer_id: &PeerId) {
+        self.peers.remove(peer_id);
+    }
+}
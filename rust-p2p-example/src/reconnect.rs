@@ -0,0 +1,109 @@
+use std::cmp;
+use std::collections::{HashMap, HashSet};
+
+use libp2p::swarm::dial_opts::DialOpts;
+use libp2p::{Multiaddr, PeerId, Swarm};
+use log::{debug, error, info, warn};
+use rand::Rng;
+use tokio::sync::mpsc;
+
+use crate::behaviour::RecipeBehaviour;
+use crate::config::CONFIG;
+use crate::consts::{RECONNECT_BASE_DELAY, RECONNECT_MAX_DELAY};
+use crate::models::EventType;
+
+/// Redials known peers that disconnected, with jittered exponential backoff
+///
+/// Jitter keeps nodes that lost each other at the same moment, e.g. when the idle timeout fired,
+/// from redialing in lockstep
+#[derive(Debug, Default)]
+pub struct Reconnector {
+    /// Failed redials of peers we are trying to get back
+    attempts: HashMap<PeerId, u32>,
+    /// Peers disconnected on purpose, not redialed when their connection closes
+    cancelled: HashSet<PeerId>,
+}
+
+impl Reconnector {
+    /// Do not redial the peer after its upcoming disconnect, e.g. because it misbehaved
+    pub fn cancel(&mut self, peer_id: PeerId) {
+        self.attempts.remove(&peer_id);
+        self.cancelled.insert(peer_id);
+    }
+
+    /// The last connection to a peer closed, schedule a redial if we know where to reach it
+    pub fn on_disconnected(
+        &mut self,
+        sender: &mpsc::UnboundedSender<EventType>,
+        peer_id: PeerId,
+        known: bool,
+    ) {
+        if self.cancelled.remove(&peer_id) || !known || CONFIG.reconnect_max_attempts == 0 {
+            return;
+        }
+        self.attempts.insert(peer_id, 0);
+        self.schedule(sender, peer_id, 0);
+    }
+
+    pub fn on_connected(&mut self, peer_id: &PeerId) {
+        if self.attempts.remove(peer_id).is_some() {
+            info!("Reconnected to {}", peer_id);
+        }
+    }
+
+    /// Redial the peer at its known addresses
+    pub fn dial(
+        &mut self,
+        swarm: &mut Swarm<RecipeBehaviour>,
+        sender: &mpsc::UnboundedSender<EventType>,
+        peer_id: PeerId,
+        addrs: Vec<Multiaddr>,
+    ) {
+        if !self.attempts.contains_key(&peer_id) {
+            return;
+        }
+        if swarm.is_connected(&peer_id) || addrs.is_empty() {
+            self.attempts.remove(&peer_id);
+            return;
+        }
+        debug!("[Reconnect] dialing {}", peer_id);
+        let opts = DialOpts::peer_id(peer_id).addresses(addrs).build();
+        if let Err(e) = swarm.dial(opts) {
+            debug!("[Reconnect] error dialing {}: {}", peer_id, e);
+            self.on_dial_failure(sender, peer_id);
+        }
+    }
+
+    /// Schedule the next redial, giving up after the configured number of attempts
+    pub fn on_dial_failure(&mut self, sender: &mpsc::UnboundedSender<EventType>, peer_id: PeerId) {
+        let attempts = match self.attempts.get_mut(&peer_id) {
+            Some(attempts) => attempts,
+            None => return,
+        };
+        *attempts += 1;
+        if *attempts >= CONFIG.reconnect_max_attempts {
+            warn!("giving up on {} after {} redials", peer_id, attempts);
+            self.attempts.remove(&peer_id);
+            return;
+        }
+        let attempts = *attempts;
+        self.schedule(sender, peer_id, attempts);
+    }
+
+    fn schedule(&self, sender: &mpsc::UnboundedSender<EventType>, peer_id: PeerId, failures: u32) {
+        let backoff = cmp::min(
+            RECONNECT_BASE_DELAY * 2u32.saturating_pow(failures),
+            RECONNECT_MAX_DELAY,
+        );
+        // 在退避时间的 50% 到 150% 之间随机取值
+        let delay = backoff.mul_f64(rand::thread_rng().gen_range(0.5..1.5));
+        info!("Redialing {} in {:?}", peer_id, delay);
+        let sender = sender.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(delay).await;
+            if let Err(e) = sender.send(EventType::Reconnect(peer_id)) {
+                error!("error sending reconnect via channel, {}", e);
+            }
+        });
+    }
+}
@@ -0,0 +1,103 @@
+use std::iter::{Chain, Map};
+
+use clap::ValueEnum;
+use either::Either;
+use libp2p::core::either::EitherFuture;
+use libp2p::core::upgrade::{InboundUpgrade, OutboundUpgrade, UpgradeInfo};
+use libp2p::futures::future::{self, MapOk};
+use libp2p::futures::TryFutureExt;
+use libp2p::PeerId;
+use serde::{Deserialize, Serialize};
+
+/// Security handshakes offered on TCP and WebSocket connections
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum SecurityProtocol {
+    /// Noise only, spoken by every version of the recipe node
+    Noise,
+    /// TLS 1.3 only, for deployments that require it for compliance
+    Tls,
+    /// Both, negotiated per connection with noise preferred when dialing
+    Both,
+}
+
+/// Offers the protocols of two security upgrades and runs whichever the remote picks
+///
+/// Unlike `SelectUpgrade` the outputs are factored into `(PeerId, Either<_, _>)`, which is what
+/// `authenticate` expects
+#[derive(Debug, Clone)]
+pub struct SelectSecurityUpgrade<A, B>(A, B);
+
+impl<A, B> SelectSecurityUpgrade<A, B> {
+    /// The protocols of `a` take priority
+    pub fn new(a: A, b: B) -> Self {
+        SelectSecurityUpgrade(a, b)
+    }
+}
+
+impl<A, B> UpgradeInfo for SelectSecurityUpgrade<A, B>
+where
+    A: UpgradeInfo,
+    B: UpgradeInfo,
+{
+    type Info = Either<A::Info, B::Info>;
+    type InfoIter = Chain<
+        Map<<A::InfoIter as IntoIterator>::IntoIter, fn(A::Info) -> Self::Info>,
+        Map<<B::InfoIter as IntoIterator>::IntoIter, fn(B::Info) -> Self::Info>,
+    >;
+
+    fn protocol_info(&self) -> Self::InfoIter {
+        let a = self
+            .0
+            .protocol_info()
+            .into_iter()
+            .map(Either::Left as fn(A::Info) -> Self::Info);
+        let b = self
+            .1
+            .protocol_info()
+            .into_iter()
+            .map(Either::Right as fn(B::Info) -> Self::Info);
+        a.chain(b)
+    }
+}
+
+type SecurityFuture<FA, FB, TA, TB> = MapOk<
+    EitherFuture<FA, FB>,
+    fn(future::Either<(PeerId, TA), (PeerId, TB)>) -> (PeerId, future::Either<TA, TB>),
+>;
+
+impl<C, A, B, TA, TB, EA, EB> InboundUpgrade<C> for SelectSecurityUpgrade<A, B>
+where
+    A: InboundUpgrade<C, Output = (PeerId, TA), Error = EA>,
+    B: InboundUpgrade<C, Output = (PeerId, TB), Error = EB>,
+{
+    type Output = (PeerId, future::Either<TA, TB>);
+    type Error = Either<EA, EB>;
+    type Future = SecurityFuture<A::Future, B::Future, TA, TB>;
+
+    fn upgrade_inbound(self, socket: C, info: Self::Info) -> Self::Future {
+        match info {
+            Either::Left(info) => EitherFuture::First(self.0.upgrade_inbound(socket, info)),
+            Either::Right(info) => EitherFuture::Second(self.1.upgrade_inbound(socket, info)),
+        }
+        .map_ok(future::Either::factor_first)
+    }
+}
+
+impl<C, A, B, TA, TB, EA, EB> OutboundUpgrade<C> for SelectSecurityUpgrade<A, B>
+where
+    A: OutboundUpgrade<C, Output = (PeerId, TA), Error = EA>,
+    B: OutboundUpgrade<C, Output = (PeerId, TB), Error = EB>,
+{
+    type Output = (PeerId, future::Either<TA, TB>);
+    type Error = Either<EA, EB>;
+    type Future = SecurityFuture<A::Future, B::Future, TA, TB>;
+
+    fn upgrade_outbound(self, socket: C, info: Self::Info) -> Self::Future {
+        match info {
+            Either::Left(info) => EitherFuture::First(self.0.upgrade_outbound(socket, info)),
+            Either::Right(info) => EitherFuture::Second(self.1.upgrade_outbound(socket, info)),
+        }
+        .map_ok(future::Either::factor_first)
+    }
+}
@@ -0,0 +1,58 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, VecDeque};
+use std::hash::{Hash, Hasher};
+use std::time::Instant;
+
+use libp2p::PeerId;
+
+use crate::consts::{SEEN_CACHE_CAPACITY, SEEN_CACHE_TTL};
+
+/// Recently handled messages keyed by their origin and content hash, least recently seen evicted
+/// first
+///
+/// Entries also expire after a while, so a peer repeating the same request later is answered again
+#[derive(Debug, Default)]
+pub struct SeenCache {
+    /// Last time each message was seen
+    seen: HashMap<u64, Instant>,
+    /// Messages in the order they were seen, entries outdated by a later sighting are skipped on
+    /// eviction
+    order: VecDeque<(u64, Instant)>,
+    /// Duplicates dropped so far
+    pub duplicates: u64,
+}
+
+impl SeenCache {
+    /// Remember the message, returns false if it was already seen recently
+    pub fn insert(&mut self, source: &PeerId, payload: &[u8]) -> bool {
+        let mut hasher = DefaultHasher::new();
+        source.hash(&mut hasher);
+        payload.hash(&mut hasher);
+        let key = hasher.finish();
+
+        let now = Instant::now();
+        let fresh = self
+            .seen
+            .get(&key)
+            .map_or(true, |seen| now.duration_since(*seen) >= SEEN_CACHE_TTL);
+        self.seen.insert(key, now);
+        self.order.push_back((key, now));
+        self.evict();
+        if !fresh {
+            self.duplicates += 1;
+        }
+        fresh
+    }
+
+    fn evict(&mut self) {
+        while self.seen.len() > SEEN_CACHE_CAPACITY || self.order.len() > 2 * SEEN_CACHE_CAPACITY {
+            let (key, stamp) = match self.order.pop_front() {
+                Some(entry) => entry,
+                None => break,
+            };
+            if self.seen.get(&key) == Some(&stamp) {
+                self.seen.remove(&key);
+            }
+        }
+    }
+}
@@ -0,0 +1,83 @@
+use std::collections::{HashMap, VecDeque};
+
+use serde::{Deserialize, Serialize};
+
+use crate::blockchain::BlockHeader;
+use crate::consensus::{self, Consensus};
+use crate::consts::EQUIVOCATION_CACHE_SIZE;
+use crate::transaction::Address;
+
+/// Two different headers signed by the same validator for the same slot
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Evidence {
+    pub slot: u64,
+    pub first: BlockHeader,
+    pub second: BlockHeader,
+}
+
+impl Evidence {
+    /// The validator the headers claim to be proposed by
+    pub fn offender(&self) -> Option<Address> {
+        self.first.proposer
+    }
+
+    /// Whether the headers prove that the offender signed two blocks for the slot
+    pub fn verify(&self, consensus: &dyn Consensus) -> bool {
+        let offender = match self.offender() {
+            Some(offender) => offender,
+            None => return false,
+        };
+        self.second.proposer == Some(offender)
+            && self.first.hash() != self.second.hash()
+            && consensus.slot(self.first.timestamp) == Some(self.slot)
+            && consensus.slot(self.second.timestamp) == Some(self.slot)
+            && consensus::verify_proposer_signature(&self.first)
+            && consensus::verify_proposer_signature(&self.second)
+    }
+}
+
+/// Remembers the signed header each validator proposed for the recent slots, so a second header
+/// for the same slot is caught
+#[derive(Debug, Default)]
+pub struct EquivocationDetector {
+    /// Header of each validator and slot, and whether it was reported already
+    seen: HashMap<(Address, u64), (BlockHeader, bool)>,
+    /// Oldest entry first, so the cache stays bounded
+    order: VecDeque<(Address, u64)>,
+}
+
+impl EquivocationDetector {
+    /// Record a header of the slot, returns the evidence the first time another header of the
+    /// same proposer and slot shows up
+    pub fn observe(&mut self, header: &BlockHeader, slot: u64) -> Option<Evidence> {
+        let proposer = header.proposer?;
+        // 签名无效的区头不能作为证据，否则任何人都能诬告
+        if !consensus::verify_proposer_signature(header) {
+            return None;
+        }
+        let key = (proposer, slot);
+        match self.seen.get_mut(&key) {
+            Some((first, reported)) => {
+                if *reported || first.hash() == header.hash() {
+                    return None;
+                }
+                *reported = true;
+                Some(Evidence {
+                    slot,
+                    first: first.clone(),
+                    second: header.clone(),
+                })
+            }
+            None => {
+                if self.order.len() >= EQUIVOCATION_CACHE_SIZE {
+                    if let Some(oldest) = self.order.pop_front() {
+                        self.seen.remove(&oldest);
+                    }
+                }
+                self.seen.insert(key, (header.clone(), false));
+                self.order.push_back(key);
+                None
+            }
+        }
+    }
+}
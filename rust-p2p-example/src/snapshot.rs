@@ -0,0 +1,43 @@
+use std::path::Path;
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::blockchain::{BlockHeader, Hash};
+use crate::checksummed;
+use crate::ledger::LedgerSnapshot;
+
+/// Ledger at the tip of a chain with the headers leading to it, so a new node can start from the
+/// tip instead of replaying every block
+///
+/// Headers are small and keep the chain work, the difficulty schedule and the locators of the
+/// node right, the bodies are left out
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub chain_id: String,
+    pub genesis: Hash,
+    /// Headers after the genesis block up to the block the ledger belongs to
+    pub headers: Vec<BlockHeader>,
+    pub ledger: LedgerSnapshot,
+}
+
+impl Snapshot {
+    pub fn height(&self) -> u64 {
+        self.headers.len() as u64
+    }
+
+    /// Hash of the block the ledger belongs to, the genesis hash when there are no headers
+    pub fn tip(&self) -> Hash {
+        self.headers.last().map_or(self.genesis, BlockHeader::hash)
+    }
+}
+
+/// Write the snapshot to the file, returns its size in bytes
+pub async fn export(path: &Path, snapshot: &Snapshot) -> Result<usize> {
+    checksummed::write(path, snapshot).await
+}
+
+/// Read a snapshot file, which must match its checksum
+pub async fn import(path: &Path) -> Result<Snapshot> {
+    checksummed::read(path, "snapshot").await
+}
@@ -0,0 +1,161 @@
+use serde::{Deserialize, Serialize};
+
+use crate::blockchain::Hash;
+use crate::consensus::Validator;
+use crate::ledger::LedgerError;
+use crate::transaction::{Address, Transaction, TxKind};
+use crate::trie::{self, StateTrie};
+
+/// Coins bonded by each validator, kept next to the balances by both ledger models
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct StakeSet {
+    stakes: StateTrie<Address, u64>,
+    /// Stake burned for each punished offender and slot, so an equivocation is punished once
+    slashed: StateTrie<(Address, u64), u64>,
+    slash_percent: u64,
+}
+
+impl StakeSet {
+    /// The validators of the genesis file with their initial stake, an equivocation costs its
+    /// offender `slash_percent` of its stake
+    pub fn from_genesis(validators: &[Validator], slash_percent: u64) -> StakeSet {
+        let mut set = StakeSet {
+            slash_percent: slash_percent.min(100),
+            ..StakeSet::default()
+        };
+        for validator in validators.iter() {
+            set.give(validator.address, validator.stake);
+        }
+        set
+    }
+
+    pub fn stake(&self, address: &Address) -> u64 {
+        self.stakes.get(address).copied().unwrap_or_default()
+    }
+
+    /// Stake the validator lost to slashing so far
+    pub fn slashed(&self, address: &Address) -> u64 {
+        self.slashed
+            .iter()
+            .filter(|((offender, _), _)| offender == address)
+            .map(|(_, burned)| burned)
+            .sum()
+    }
+
+    /// Validators with stake, ordered by address
+    pub fn validators(&self) -> Vec<Validator> {
+        let mut validators: Vec<Validator> = self
+            .stakes
+            .iter()
+            .map(|(address, stake)| Validator {
+                address: *address,
+                stake: *stake,
+            })
+            .collect();
+        validators.sort_by_key(|validator| validator.address);
+        validators
+    }
+
+    /// Root committing to the stakes and the burns
+    pub fn root(&self) -> Hash {
+        trie::combine(&[self.stakes.root(), self.slashed.root()])
+    }
+
+    /// Staking transactions move coins of the sender only, and unbonding must leave the stake of
+    /// the sender after its pending transactions
+    ///
+    /// Reports are checked against the burns only, whether the evidence holds is up to the block
+    /// validation
+    pub fn check(&self, tx: &Transaction, pending: &[&Transaction]) -> Result<(), LedgerError> {
+        match tx.kind {
+            TxKind::Transfer | TxKind::Deploy | TxKind::Call => return Ok(()),
+            TxKind::Slash => return self.check_slash(tx),
+            TxKind::Bond | TxKind::Unbond => {}
+        }
+        if tx.to != tx.from {
+            return Err(LedgerError::StakeRecipient);
+        }
+        if tx.kind == TxKind::Unbond {
+            let available = pending
+                .iter()
+                .fold(self.stake(&tx.from), |stake, tx| match tx.kind {
+                    TxKind::Bond => stake.saturating_add(tx.amount),
+                    TxKind::Unbond => stake.saturating_sub(tx.amount),
+                    TxKind::Transfer | TxKind::Slash | TxKind::Deploy | TxKind::Call => stake,
+                });
+            if tx.amount > available {
+                return Err(LedgerError::InsufficientStake {
+                    available,
+                    required: tx.amount,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    fn check_slash(&self, tx: &Transaction) -> Result<(), LedgerError> {
+        let key = slash_key(tx).ok_or(LedgerError::NothingToSlash)?;
+        if self.slashed.contains_key(&key) {
+            return Err(LedgerError::AlreadySlashed);
+        }
+        if self.stake(&key.0) == 0 {
+            return Err(LedgerError::NothingToSlash);
+        }
+        Ok(())
+    }
+
+    /// Apply a checked transaction to the stakes
+    pub fn apply(&mut self, tx: &Transaction) {
+        match tx.kind {
+            TxKind::Transfer | TxKind::Deploy | TxKind::Call => {}
+            TxKind::Bond => self.give(tx.from, tx.amount),
+            TxKind::Unbond => self.take(&tx.from, tx.amount),
+            TxKind::Slash => {
+                if let Some(key) = slash_key(tx) {
+                    // 向上取整，权益很少的验证者也会被罚
+                    let stake = self.stake(&key.0) as u128;
+                    let burned = ((stake * self.slash_percent as u128 + 99) / 100) as u64;
+                    self.take(&key.0, burned);
+                    self.slashed.insert(key, burned);
+                }
+            }
+        }
+    }
+
+    /// Revert `apply`, transactions are reverted newest first
+    pub fn revert(&mut self, tx: &Transaction) {
+        match tx.kind {
+            TxKind::Transfer | TxKind::Deploy | TxKind::Call => {}
+            TxKind::Bond => self.take(&tx.from, tx.amount),
+            TxKind::Unbond => self.give(tx.from, tx.amount),
+            TxKind::Slash => {
+                if let Some(key) = slash_key(tx) {
+                    if let Some(burned) = self.slashed.remove(&key) {
+                        self.give(key.0, burned);
+                    }
+                }
+            }
+        }
+    }
+
+    fn give(&mut self, address: Address, amount: u64) {
+        if amount > 0 {
+            let stake = self.stake(&address).saturating_add(amount);
+            self.stakes.insert(address, stake);
+        }
+    }
+
+    fn take(&mut self, address: &Address, amount: u64) {
+        match self.stake(address).saturating_sub(amount) {
+            0 => self.stakes.remove(address),
+            stake => self.stakes.insert(*address, stake),
+        };
+    }
+}
+
+/// Offender and slot of the equivocation a report punishes, the report is sent to the offender
+fn slash_key(tx: &Transaction) -> Option<(Address, u64)> {
+    let evidence = tx.evidence.as_ref()?;
+    let offender = evidence.offender().filter(|offender| *offender == tx.to)?;
+    Some((offender, evidence.slot))
+}
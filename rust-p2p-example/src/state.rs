@@ -0,0 +1,142 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use libp2p::rendezvous::Cookie;
+use libp2p::swarm::ConnectionId;
+use libp2p::{identify, Multiaddr, PeerId};
+
+use crate::address_book::AddressBook;
+use crate::ban_list::BanList;
+use crate::blockchain::Chain;
+use crate::bootstrap::Bootstrapper;
+use crate::finality::FinalityGadget;
+use crate::health::NetHealth;
+use crate::light::LightClient;
+use crate::mempool::Mempool;
+use crate::mesh::MeshTracker;
+use crate::metrics::NetStats;
+use crate::miner::Miner;
+use crate::orphans::OrphanPool;
+use crate::peer_score::PeerScores;
+use crate::rate_limit::RateLimiter;
+use crate::reconnect::Reconnector;
+use crate::seen_cache::SeenCache;
+use crate::slashing::EquivocationDetector;
+use crate::sync::Syncer;
+use crate::telemetry::Telemetry;
+use crate::transfer::Reassembler;
+use crate::wallet::Wallet;
+
+/// Runtime state shared by the swarm event loop and the command handlers
+#[derive(Debug, Default)]
+pub struct NodeState {
+    pub relay_stats: RelayStats,
+    /// Whether reservations on the configured relays were already requested after AutoNAT
+    /// reported the node as private
+    pub relay_fallback_active: bool,
+    /// Latest identify info received from each peer
+    pub peer_info: HashMap<PeerId, identify::Info>,
+    /// Latest presence announcement of each peer
+    pub presence: HashMap<PeerId, PeerPresence>,
+    /// Round-trip times measured by ping
+    pub latencies: HashMap<PeerId, PeerLatency>,
+    pub bootstrapper: Bootstrapper,
+    pub ban_list: BanList,
+    pub address_book: AddressBook,
+    pub reconnector: Reconnector,
+    pub peer_scores: PeerScores,
+    pub rate_limiter: RateLimiter,
+    pub seen_messages: SeenCache,
+    /// Cookie of the last discovery at each rendezvous point, so only new registrations are fetched
+    pub rendezvous_cookies: HashMap<PeerId, Cookie>,
+    pub upnp: UpnpStatus,
+    /// Dials started with the `dial` command, reported once they succeed or fail
+    pub pending_dials: HashMap<ConnectionId, Multiaddr>,
+    /// Chunks of oversized payloads waiting for the rest of their transfer
+    pub transfers: Reassembler,
+    pub net_stats: NetStats,
+    pub health: NetHealth,
+    pub mesh: MeshTracker,
+    pub telemetry: Telemetry,
+    pub chain: Chain,
+    /// Set while blocks are being mined
+    pub miner: Option<Miner>,
+    pub mempool: Mempool,
+    pub orphans: OrphanPool,
+    pub sync: Syncer,
+    pub wallet: Wallet,
+    pub equivocations: EquivocationDetector,
+    pub finality: FinalityGadget,
+    /// Set when started with `--light`, the chain then holds the genesis block only
+    pub light: Option<LightClient>,
+}
+
+/// Outcome of the UPnP port mapping on the local router
+#[derive(Debug, Default)]
+pub enum UpnpStatus {
+    /// Still searching for a gateway, or UPnP is disabled
+    #[default]
+    Searching,
+    /// The listen port is reachable from the internet at these addresses
+    Mapped(Vec<Multiaddr>),
+    GatewayNotFound,
+    /// The gateway is itself behind another NAT, mapping the port does not help
+    NonRoutableGateway,
+}
+
+/// Counters of the relay server
+#[derive(Debug, Default)]
+pub struct RelayStats {
+    pub reservations_accepted: u64,
+    pub reservations_denied: u64,
+    pub reservations_timed_out: u64,
+    pub circuits_accepted: u64,
+    pub circuits_denied: u64,
+    pub circuits_failed: u64,
+    pub circuits_closed: u64,
+    pub per_peer: HashMap<PeerId, PeerRelayStats>,
+}
+
+/// Relay usage of a single peer
+#[derive(Debug, Default)]
+pub struct PeerRelayStats {
+    pub reservations: u64,
+    pub circuits: u64,
+}
+
+impl RelayStats {
+    pub fn peer_mut(&mut self, peer_id: PeerId) -> &mut PeerRelayStats {
+        self.per_peer.entry(peer_id).or_default()
+    }
+}
+
+/// Topics a peer announced it serves recipes on
+#[derive(Debug)]
+pub struct PeerPresence {
+    pub topics: Vec<String>,
+    pub received: Instant,
+}
+
+/// Ping round-trip times of a single peer
+#[derive(Debug, Default)]
+pub struct PeerLatency {
+    pub last: Duration,
+    pub total: Duration,
+    pub samples: u32,
+}
+
+impl PeerLatency {
+    pub fn record(&mut self, rtt: Duration) {
+        self.last = rtt;
+        self.total += rtt;
+        self.samples += 1;
+    }
+
+    pub fn average(&self) -> Duration {
+        if self.samples == 0 {
+            Duration::ZERO
+        } else {
+            self.total / self.samples
+        }
+    }
+}
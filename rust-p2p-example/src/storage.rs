@@ -0,0 +1,1679 @@
+use std::collections::BTreeMap;
+#[cfg(feature = "sqlite")]
+use std::convert::TryFrom;
+use std::convert::TryInto;
+use std::fs::{self, File, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+#[cfg(feature = "rocksdb")]
+use std::sync::Arc;
+use std::sync::{mpsc, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+use std::{env, fmt, process};
+
+use anyhow::{anyhow, bail, Context, Result};
+use clap::ValueEnum;
+use log::{info, warn};
+use once_cell::sync::OnceCell;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::blockchain::Hash;
+use crate::clock::now;
+use crate::consts::{RECIPES_DB_PATH, RECIPE_CACHE_CAPACITY, STORAGE_FILE_PATH};
+use crate::lru_cache::{CacheStats, LruCache};
+use crate::models::Recipe;
+
+/// Length and checksum in front of every batch of the log files of earlier releases
+const FRAME_HEADER_LEN: usize = 8;
+
+/// Key of the mark that the log file of an earlier release was imported, written in the batch of
+/// the records so an import cut short by a crash is not applied twice
+const IMPORTED_LOG_KEY: &[u8] = b"meta/imported-log";
+
+/// Key prefix of the recipe records, followed by the id in big endian so they sort by id
+const RECIPE_PREFIX: &[u8] = b"recipe/";
+
+/// Recipes of the node, opened at startup
+static RECIPES: OnceCell<RecipeStore> = OnceCell::new();
+
+/// Key of the schema version the records of a database follow
+const SCHEMA_KEY: &[u8] = b"meta/schema";
+
+/// Changes taking the records of a database from one schema version to the next
+pub type Migration = fn(&dyn Storage) -> Result<Vec<Op>>;
+
+/// Layout of the records of a database and how older layouts are upgraded
+#[derive(Debug)]
+pub struct Schema {
+    /// Name of the database in messages
+    pub name: &'static str,
+    /// Migrations in order, the one at index `n` takes version `n` to `n + 1`, so the current
+    /// version is their count
+    pub migrations: &'static [Migration],
+}
+
+/// Recipes are kept one record per id, as they were before versions were recorded, next to the
+/// id the next recipe created gets since version 2
+const RECIPE_SCHEMA: Schema = Schema {
+    name: "recipes",
+    migrations: &[unversioned, record_next_id],
+};
+
+/// Key of the id the next recipe created gets, so the id of an expired recipe is not given out
+/// again
+const NEXT_ID_KEY: &[u8] = b"meta/next-id";
+
+/// Records written before the schema version was stored, they need no change
+pub fn unversioned(_: &dyn Storage) -> Result<Vec<Op>> {
+    Ok(Vec::new())
+}
+
+/// Upgrade the records to the current version of the schema, refusing records of a newer one
+///
+/// A database without records is new and gets the current version, one with records but no
+/// version predates versions and is at 0. Each migration is applied in one batch with its
+/// version, so a node killed while upgrading continues from the last finished step
+pub fn migrate(store: &mut dyn Storage, schema: &Schema) -> Result<u32> {
+    let latest = schema.migrations.len() as u32;
+    let stored = match store.get(SCHEMA_KEY)? {
+        Some(bytes) => {
+            let bytes: [u8; 4] = bytes
+                .as_slice()
+                .try_into()
+                .map_err(|_| anyhow!("invalid schema version of the {}", schema.name))?;
+            u32::from_be_bytes(bytes)
+        }
+        None if store.iter(b"").next().transpose()?.is_none() => {
+            store.batch(vec![schema_record(latest)])?;
+            return Ok(latest);
+        }
+        None => 0,
+    };
+    if stored > latest {
+        bail!(
+            "the {} are stored in schema version {} of a newer release, this one understands up \
+             to version {}",
+            schema.name,
+            stored,
+            latest
+        );
+    }
+    for (version, migration) in schema.migrations.iter().enumerate().skip(stored as usize) {
+        let mut batch = migration(store).with_context(|| {
+            format!(
+                "can not migrate the {} from version {}",
+                schema.name, version
+            )
+        })?;
+        batch.push(schema_record(version as u32 + 1));
+        store.batch(batch)?;
+        info!(
+            "Migrated the {} to schema version {}",
+            schema.name,
+            version + 1
+        );
+    }
+    Ok(latest)
+}
+
+fn schema_record(version: u32) -> Op {
+    Op::Put {
+        key: SCHEMA_KEY.to_vec(),
+        value: version.to_be_bytes().to_vec(),
+    }
+}
+
+/// Where records are kept
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum StorageBackend {
+    /// A sled database in a directory next to the node, kept across restarts
+    #[default]
+    #[serde(alias = "sled")]
+    #[value(alias = "sled")]
+    Disk,
+    /// Memory only, everything is gone when the node stops
+    Memory,
+    /// A RocksDB database in a directory next to the node, in builds with the `rocksdb` feature
+    Rocksdb,
+    /// A SQLite database next to the node with tables of the recipes and transactions to query,
+    /// in builds with the `sqlite` feature
+    Sqlite,
+}
+
+/// One change of a batch
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Op {
+    Put {
+        #[serde(with = "serde_bytes")]
+        key: Vec<u8>,
+        #[serde(with = "serde_bytes")]
+        value: Vec<u8>,
+    },
+    Delete {
+        #[serde(with = "serde_bytes")]
+        key: Vec<u8>,
+    },
+}
+
+/// A record with its key, as kept in backups
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Record {
+    #[serde(with = "serde_bytes")]
+    pub key: Vec<u8>,
+    #[serde(with = "serde_bytes")]
+    pub value: Vec<u8>,
+}
+
+/// Records read from a storage with their keys, a record that can not be read is an error
+pub type Entries<'a> = Box<dyn Iterator<Item = Result<(Vec<u8>, Vec<u8>)>> + 'a>;
+
+/// Key-value records of bytes, ordered by key
+pub trait Storage: fmt::Debug + Send {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>>;
+
+    /// Records whose key starts with the prefix, ordered by key
+    fn iter<'a>(&'a self, prefix: &'a [u8]) -> Entries<'a>;
+
+    /// Store the changes of the batch together, none of them when writing fails
+    ///
+    /// Reads see the changes once it returns. A storage written to disk in the background, as
+    /// the sled databases of the node are, has only queued them by then: a node killed before
+    /// they were written loses the batch whole, and a write that failed is returned by `flush`.
+    /// Wait for `flush` where a batch has to be on disk
+    fn batch(&mut self, batch: Vec<Op>) -> Result<()>;
+
+    fn put(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.batch(vec![Op::Put { key, value }])
+    }
+
+    /// Check that what is kept matches the records, nothing to check for memory
+    fn verify(&self) -> Pending<()> {
+        Pending::ready(Ok(()))
+    }
+
+    /// Answered once every batch so far is written
+    fn flush(&self) -> Pending<()> {
+        Pending::ready(Ok(()))
+    }
+
+    /// Rewrite what is kept without the records later batches superseded, answers the bytes
+    /// reclaimed, memory keeps nothing superseded
+    fn compact(&mut self) -> Pending<u64> {
+        Pending::ready(Ok(0))
+    }
+}
+
+/// Answer of a storage to work that may finish after the call, on a thread of the storage
+///
+/// `wait` blocks the thread until the answer is there, the event loop awaits `finish` instead
+#[must_use]
+#[derive(Debug)]
+pub struct Pending<T> {
+    answer: mpsc::Receiver<Result<T>>,
+}
+
+impl<T> Pending<T> {
+    /// Work that finished already
+    pub fn ready(result: Result<T>) -> Pending<T> {
+        let (reply, answer) = mpsc::channel();
+        let _ = reply.send(result);
+        Pending { answer }
+    }
+
+    pub fn wait(self) -> Result<T> {
+        self.answer
+            .recv()
+            .map_err(|_| anyhow!("the storage stopped before it answered"))?
+    }
+}
+
+impl<T: Send + 'static> Pending<T> {
+    /// Work done on a thread of its own
+    fn spawn(work: impl FnOnce() -> Result<T> + Send + 'static) -> Pending<T> {
+        let (reply, answer) = mpsc::channel();
+        let spawned = thread::Builder::new()
+            .name("storage-worker".to_owned())
+            .spawn(move || {
+                let _ = reply.send(work());
+            });
+        match spawned {
+            Ok(_) => Pending { answer },
+            Err(e) => Pending::ready(Err(e.into())),
+        }
+    }
+
+    /// Wait on a blocking thread of the runtime, so the task awaiting it does not hold up the
+    /// others
+    pub async fn finish(self) -> Result<T> {
+        tokio::task::spawn_blocking(move || self.wait()).await?
+    }
+}
+
+/// Copy of every record, taken from the records in memory rather than the files being written
+pub fn snapshot(store: &dyn Storage) -> Result<Vec<Record>> {
+    store
+        .iter(b"")
+        .map(|entry| entry.map(|(key, value)| Record { key, value }))
+        .collect()
+}
+
+/// A batch replacing every record of the store with the records
+pub fn replacement(store: &dyn Storage, records: Vec<Record>) -> Result<Vec<Op>> {
+    let mut batch = store
+        .iter(b"")
+        .map(|entry| entry.map(|(key, _)| Op::Delete { key }))
+        .collect::<Result<Vec<Op>>>()?;
+    batch.extend(
+        records
+            .into_iter()
+            .map(|Record { key, value }| Op::Put { key, value }),
+    );
+    Ok(batch)
+}
+
+/// Storage of the backend, named like the path with the extension of the backend, the disk one
+/// takes over the records of the log file earlier releases kept at the path
+pub fn open(backend: StorageBackend, path: impl AsRef<Path>) -> Result<Box<dyn Storage>> {
+    Ok(match backend {
+        StorageBackend::Disk => {
+            let mut store = SledStore::open(path.as_ref())?;
+            import_log(&mut store, path.as_ref())?;
+            Box::new(store)
+        }
+        StorageBackend::Memory => Box::new(MemoryStore::default()),
+        #[cfg(feature = "rocksdb")]
+        StorageBackend::Rocksdb => Box::new(RocksStore::open(path.as_ref())?),
+        #[cfg(not(feature = "rocksdb"))]
+        StorageBackend::Rocksdb => {
+            bail!("this build has no RocksDB storage, it needs the rocksdb feature")
+        }
+        #[cfg(feature = "sqlite")]
+        StorageBackend::Sqlite => Box::new(SqliteStore::open(path.as_ref())?),
+        #[cfg(not(feature = "sqlite"))]
+        StorageBackend::Sqlite => {
+            bail!("this build has no SQLite storage, it needs the sqlite feature")
+        }
+    })
+}
+
+/// Records kept in memory only
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    records: BTreeMap<Vec<u8>, Vec<u8>>,
+}
+
+impl Storage for MemoryStore {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        Ok(self.records.get(key).cloned())
+    }
+
+    fn iter<'a>(&'a self, prefix: &'a [u8]) -> Entries<'a> {
+        scan(&self.records, prefix)
+    }
+
+    fn batch(&mut self, batch: Vec<Op>) -> Result<()> {
+        for op in batch {
+            apply(&mut self.records, op);
+        }
+        Ok(())
+    }
+}
+
+/// Records of a database by key
+type Records = BTreeMap<Vec<u8>, Vec<u8>>;
+
+/// How long opening a sled database waits for the lock on it, which the threads of sled release
+/// only after a database closed in the same process was dropped
+const SLED_LOCK_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Records kept in a sled database, which applies a batch atomically
+///
+/// sled keeps recently used pages cached and writes its log to disk on a thread of its own every
+/// half second, so a batch does not hold up the event loop driving the swarm
+#[derive(Debug)]
+struct SledStore {
+    db: sled::Db,
+}
+
+impl SledStore {
+    /// Open the database in the directory named like the path with a `.sled` extension
+    fn open(path: &Path) -> Result<SledStore> {
+        let path = path.with_extension("sled");
+        let started = Instant::now();
+        loop {
+            match sled::open(&path) {
+                Ok(db) => return Ok(SledStore { db }),
+                // 同一进程里刚关闭的数据库，sled 的后台线程退出后才放开文件锁
+                Err(sled::Error::Io(e))
+                    if e.to_string().contains("could not acquire lock")
+                        && started.elapsed() < SLED_LOCK_TIMEOUT =>
+                {
+                    thread::sleep(Duration::from_millis(10))
+                }
+                Err(e) => {
+                    return Err(e).with_context(|| format!("can not open {}", path.display()))
+                }
+            }
+        }
+    }
+}
+
+impl Storage for SledStore {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        Ok(self.db.get(key)?.map(|value| value.to_vec()))
+    }
+
+    fn iter<'a>(&'a self, prefix: &'a [u8]) -> Entries<'a> {
+        Box::new(self.db.scan_prefix(prefix).map(|entry| {
+            let (key, value) = entry?;
+            Ok((key.to_vec(), value.to_vec()))
+        }))
+    }
+
+    /// Applied at once and on disk after the next flush of sled
+    fn batch(&mut self, batch: Vec<Op>) -> Result<()> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+        let mut changes = sled::Batch::default();
+        for op in batch {
+            match op {
+                Op::Put { key, value } => changes.insert(key, value),
+                Op::Delete { key } => changes.remove(key),
+            }
+        }
+        self.db.apply_batch(changes)?;
+        Ok(())
+    }
+
+    /// Every record can be read back, sled checks each against its checksum, read on a thread
+    /// of its own
+    fn verify(&self) -> Pending<()> {
+        let db = self.db.clone();
+        Pending::spawn(move || {
+            for entry in db.iter() {
+                entry?;
+            }
+            Ok(())
+        })
+    }
+
+    /// Write the log of sled to disk on a thread of its own
+    fn flush(&self) -> Pending<()> {
+        let db = self.db.clone();
+        Pending::spawn(move || {
+            db.flush()?;
+            Ok(())
+        })
+    }
+}
+
+/// Column families of a RocksDB database by the key prefix of their records, the records of no
+/// other prefix are in `ROCKSDB_STATE`
+#[cfg(feature = "rocksdb")]
+const ROCKSDB_FAMILIES: [(&str, &[u8]); 3] = [
+    ("blocks", b"block/"),
+    ("recipes", RECIPE_PREFIX),
+    ("indexes", b"blob/"),
+];
+
+/// Column family of the chain tip, the schema versions and whatever else is no block, recipe or
+/// index
+#[cfg(feature = "rocksdb")]
+const ROCKSDB_STATE: &str = "state";
+
+/// Bytes of memtables a RocksDB database may fill before they are flushed
+#[cfg(feature = "rocksdb")]
+const ROCKSDB_MEMTABLE_BUDGET: usize = 256 << 20;
+
+/// Records kept in a RocksDB database, in column families by what they hold
+///
+/// Tuned for the many small writes of syncing blocks: large memtables, compaction by level on
+/// threads of its own and syncing of the files in steps as they grow. Batches are written with
+/// the write-ahead log of RocksDB synced, so they are stored once `batch` returns
+#[cfg(feature = "rocksdb")]
+struct RocksStore {
+    path: PathBuf,
+    db: Arc<rocksdb::DB>,
+}
+
+#[cfg(feature = "rocksdb")]
+impl fmt::Debug for RocksStore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RocksStore")
+            .field("path", &self.path)
+            .finish()
+    }
+}
+
+#[cfg(feature = "rocksdb")]
+impl RocksStore {
+    /// Open the database in the directory named like the path with a `.rocksdb` extension
+    fn open(path: &Path) -> Result<RocksStore> {
+        let path = path.with_extension("rocksdb");
+        let mut options = rocksdb::Options::default();
+        options.create_if_missing(true);
+        options.create_missing_column_families(true);
+        let threads = thread::available_parallelism().map_or(2, |n| n.get());
+        options.increase_parallelism(threads as i32);
+        options.optimize_level_style_compaction(ROCKSDB_MEMTABLE_BUDGET);
+        options.set_max_write_buffer_number(4);
+        options.set_bytes_per_sync(1 << 20);
+        let families = RocksStore::families().map(|name| {
+            let mut family = rocksdb::Options::default();
+            family.optimize_level_style_compaction(ROCKSDB_MEMTABLE_BUDGET / 4);
+            if name == "blocks" {
+                // 区块只追加不修改，压缩换磁盘
+                family.set_compression_type(rocksdb::DBCompressionType::Lz4);
+            }
+            rocksdb::ColumnFamilyDescriptor::new(name, family)
+        });
+        let db = rocksdb::DB::open_cf_descriptors(&options, &path, families)
+            .with_context(|| format!("can not open {}", path.display()))?;
+        Ok(RocksStore {
+            path,
+            db: Arc::new(db),
+        })
+    }
+
+    /// The same database, for a thread that checks or compacts it
+    fn share(&self) -> RocksStore {
+        RocksStore {
+            path: self.path.clone(),
+            db: Arc::clone(&self.db),
+        }
+    }
+
+    fn families() -> impl Iterator<Item = &'static str> {
+        ROCKSDB_FAMILIES
+            .iter()
+            .map(|(name, _)| *name)
+            .chain(Some(ROCKSDB_STATE))
+    }
+
+    /// The column family the record of the key is in
+    fn family_of(key: &[u8]) -> &'static str {
+        ROCKSDB_FAMILIES
+            .iter()
+            .find(|(_, prefix)| key.starts_with(prefix))
+            .map_or(ROCKSDB_STATE, |(name, _)| *name)
+    }
+
+    /// The column families that may hold records whose key starts with the prefix
+    fn families_with(prefix: &[u8]) -> Vec<&'static str> {
+        let mut families: Vec<&'static str> = ROCKSDB_FAMILIES
+            .iter()
+            .filter(|(_, family)| family.starts_with(prefix) || prefix.starts_with(family))
+            .map(|(name, _)| *name)
+            .collect();
+        if !ROCKSDB_FAMILIES
+            .iter()
+            .any(|(_, family)| prefix.starts_with(family))
+        {
+            families.push(ROCKSDB_STATE);
+        }
+        families
+    }
+
+    fn handle(&self, name: &str) -> Result<&rocksdb::ColumnFamily> {
+        self.db
+            .cf_handle(name)
+            .ok_or_else(|| anyhow!("{} has no column family {}", self.path.display(), name))
+    }
+
+    /// Bytes of the table files of every column family
+    fn size(&self) -> Result<u64> {
+        let mut size = 0;
+        for name in RocksStore::families() {
+            size += self
+                .db
+                .property_int_value_cf(self.handle(name)?, "rocksdb.total-sst-files-size")?
+                .unwrap_or_default();
+        }
+        Ok(size)
+    }
+}
+
+#[cfg(feature = "rocksdb")]
+impl Storage for RocksStore {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let family = self.handle(RocksStore::family_of(key))?;
+        Ok(self.db.get_cf(family, key)?)
+    }
+
+    fn iter<'a>(&'a self, prefix: &'a [u8]) -> Entries<'a> {
+        let mut scans = Vec::new();
+        for name in RocksStore::families_with(prefix) {
+            let family = match self.handle(name) {
+                Ok(family) => family,
+                Err(e) => return Box::new(std::iter::once(Err(e))),
+            };
+            let mode = rocksdb::IteratorMode::From(prefix, rocksdb::Direction::Forward);
+            scans.push(
+                self.db
+                    .iterator_cf(family, mode)
+                    .map(|entry| -> Result<(Vec<u8>, Vec<u8>)> {
+                        let (key, value) = entry?;
+                        Ok((key.into_vec(), value.into_vec()))
+                    })
+                    .take_while(move |entry| match entry {
+                        Ok((key, _)) => key.starts_with(prefix),
+                        Err(_) => true,
+                    }),
+            );
+        }
+        if scans.len() == 1 {
+            return Box::new(scans.remove(0));
+        }
+        // 前缀跨越多个列族时合并成按键排序
+        match scans.into_iter().flatten().collect::<Result<Vec<_>>>() {
+            Ok(mut entries) => {
+                entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+                Box::new(entries.into_iter().map(Ok))
+            }
+            Err(e) => Box::new(std::iter::once(Err(e))),
+        }
+    }
+
+    /// Applied as one write batch across the column families
+    fn batch(&mut self, batch: Vec<Op>) -> Result<()> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+        let mut changes = rocksdb::WriteBatch::default();
+        for op in batch {
+            match op {
+                Op::Put { key, value } => {
+                    changes.put_cf(self.handle(RocksStore::family_of(&key))?, &key, &value)
+                }
+                Op::Delete { key } => {
+                    changes.delete_cf(self.handle(RocksStore::family_of(&key))?, &key)
+                }
+            }
+        }
+        let mut options = rocksdb::WriteOptions::default();
+        options.set_sync(true);
+        self.db.write_opt(changes, &options)?;
+        Ok(())
+    }
+
+    /// Every record can be read back, RocksDB checks each block of the tables against its
+    /// checksum, read on a thread of its own
+    fn verify(&self) -> Pending<()> {
+        let store = self.share();
+        Pending::spawn(move || {
+            for name in RocksStore::families() {
+                for entry in store
+                    .db
+                    .iterator_cf(store.handle(name)?, rocksdb::IteratorMode::Start)
+                {
+                    entry?;
+                }
+            }
+            Ok(())
+        })
+    }
+
+    /// Compacted on a thread of its own, batches go on meanwhile
+    fn compact(&mut self) -> Pending<u64> {
+        let store = self.share();
+        Pending::spawn(move || {
+            let before = store.size()?;
+            for name in RocksStore::families() {
+                store
+                    .db
+                    .compact_range_cf(store.handle(name)?, None::<&[u8]>, None::<&[u8]>);
+            }
+            Ok(before.saturating_sub(store.size()?))
+        })
+    }
+}
+
+/// Tables of a SQLite database, the records and what operators query
+#[cfg(feature = "sqlite")]
+const SQLITE_SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS records (
+        key BLOB PRIMARY KEY,
+        value BLOB NOT NULL
+    ) WITHOUT ROWID;
+    CREATE TABLE IF NOT EXISTS recipes (
+        id INTEGER PRIMARY KEY,
+        name TEXT NOT NULL,
+        ingredients TEXT NOT NULL,
+        instructions TEXT NOT NULL,
+        shared INTEGER NOT NULL,
+        topics TEXT NOT NULL,
+        image TEXT,
+        deleted INTEGER
+    );
+    CREATE TABLE IF NOT EXISTS transactions (
+        txid TEXT PRIMARY KEY,
+        height INTEGER NOT NULL,
+        block TEXT NOT NULL,
+        position INTEGER NOT NULL,
+        kind TEXT NOT NULL,
+        sender TEXT NOT NULL,
+        receiver TEXT NOT NULL,
+        amount INTEGER NOT NULL,
+        fee INTEGER NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS transactions_by_height ON transactions (height);
+    CREATE INDEX IF NOT EXISTS transactions_by_sender ON transactions (sender);
+    CREATE INDEX IF NOT EXISTS transactions_by_receiver ON transactions (receiver);
+";
+
+/// Records kept in a SQLite database, so operators can query the data of their node with SQL
+///
+/// The `records` table holds the records as any other backend does. The `recipes` and
+/// `transactions` tables are rewritten from the recipe and block records in the transaction of
+/// the batch that changes them, so they always match the records and are only read by people
+#[cfg(feature = "sqlite")]
+#[derive(Debug)]
+struct SqliteStore {
+    path: PathBuf,
+    db: rusqlite::Connection,
+}
+
+/// How long a connection waits for the database that another one writes, e.g. while it is
+/// vacuumed
+#[cfg(feature = "sqlite")]
+const SQLITE_BUSY_TIMEOUT: Duration = Duration::from_secs(60);
+
+#[cfg(feature = "sqlite")]
+impl SqliteStore {
+    /// Open the database in the file named like the path with a `.sqlite` extension
+    fn open(path: &Path) -> Result<SqliteStore> {
+        let path = path.with_extension("sqlite");
+        let db = SqliteStore::connect(&path)?;
+        db.execute_batch(SQLITE_SCHEMA)?;
+        Ok(SqliteStore { path, db })
+    }
+
+    fn connect(path: &Path) -> Result<rusqlite::Connection> {
+        let db = rusqlite::Connection::open(path)
+            .with_context(|| format!("can not open {}", path.display()))?;
+        // 提交时同步写前日志，batch 返回时已经落盘
+        db.pragma_update(None, "journal_mode", "WAL")?;
+        db.pragma_update(None, "synchronous", "FULL")?;
+        db.busy_timeout(SQLITE_BUSY_TIMEOUT)?;
+        Ok(db)
+    }
+
+    /// Bytes of the pages of the database
+    fn size(db: &rusqlite::Connection) -> Result<u64> {
+        let pages: i64 = db.query_row("PRAGMA page_count", [], |row| row.get(0))?;
+        let page: i64 = db.query_row("PRAGMA page_size", [], |row| row.get(0))?;
+        Ok((pages * page) as u64)
+    }
+}
+
+/// Bring the tables of recipes and transactions in step with the record of the key, its value
+/// none when it is deleted
+#[cfg(feature = "sqlite")]
+fn index_record(db: &rusqlite::Transaction, key: &[u8], value: Option<&[u8]>) -> Result<()> {
+    use crate::blockchain::{Block, BLOCK_PREFIX};
+    use rusqlite::params;
+
+    // 超出 i64 的金额按最大值记录
+    let integer = |n: u64| i64::try_from(n).unwrap_or(i64::MAX);
+    if let Some(id) = key.strip_prefix(RECIPE_PREFIX) {
+        let id: [u8; 8] = id
+            .try_into()
+            .map_err(|_| anyhow!("invalid recipe key {}", hex::encode(key)))?;
+        let id = integer(u64::from_be_bytes(id));
+        db.execute("DELETE FROM recipes WHERE id = ?1", [id])?;
+        if let Some(value) = value {
+            let recipe = decode_recipe(value)?;
+            db.execute(
+                "INSERT INTO recipes \
+                 (id, name, ingredients, instructions, shared, topics, image, deleted) \
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+                params![
+                    id,
+                    recipe.name,
+                    recipe.ingredients,
+                    recipe.instructions,
+                    recipe.shared,
+                    recipe.topics.join(","),
+                    recipe.image.map(|image| image.to_string()),
+                    recipe.deleted.map(integer),
+                ],
+            )?;
+        }
+    } else if let Some(height) = key.strip_prefix(BLOCK_PREFIX) {
+        let height: [u8; 8] = height
+            .try_into()
+            .map_err(|_| anyhow!("invalid block key {}", hex::encode(key)))?;
+        let height = integer(u64::from_be_bytes(height));
+        db.execute("DELETE FROM transactions WHERE height = ?1", [height])?;
+        if let Some(value) = value {
+            let block: Block =
+                ciborium::from_reader(value).map_err(|e| anyhow!("invalid block record: {}", e))?;
+            for (position, tx) in block.transactions.iter().enumerate() {
+                db.execute(
+                    "INSERT OR REPLACE INTO transactions \
+                     (txid, height, block, position, kind, sender, receiver, amount, fee) \
+                     VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+                    params![
+                        tx.id().to_string(),
+                        height,
+                        block.hash.to_string(),
+                        position as i64,
+                        tx.kind.to_string(),
+                        tx.from.to_string(),
+                        tx.to.to_string(),
+                        integer(tx.amount),
+                        integer(tx.fee),
+                    ],
+                )?;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(feature = "sqlite")]
+impl Storage for SqliteStore {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        use rusqlite::OptionalExtension;
+
+        Ok(self
+            .db
+            .query_row("SELECT value FROM records WHERE key = ?1", [key], |row| {
+                row.get(0)
+            })
+            .optional()?)
+    }
+
+    fn iter<'a>(&'a self, prefix: &'a [u8]) -> Entries<'a> {
+        let scan = || -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+            let mut query = self
+                .db
+                .prepare_cached("SELECT key, value FROM records WHERE key >= ?1 ORDER BY key")?;
+            let mut rows = query.query([prefix])?;
+            let mut entries = Vec::new();
+            while let Some(row) = rows.next()? {
+                let key: Vec<u8> = row.get(0)?;
+                if !key.starts_with(prefix) {
+                    break;
+                }
+                entries.push((key, row.get(1)?));
+            }
+            Ok(entries)
+        };
+        match scan() {
+            Ok(entries) => Box::new(entries.into_iter().map(Ok)),
+            Err(e) => Box::new(std::iter::once(Err(e))),
+        }
+    }
+
+    /// Applied in one SQLite transaction with the rows of the tables it changes
+    fn batch(&mut self, batch: Vec<Op>) -> Result<()> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+        let db = self.db.transaction()?;
+        for op in batch {
+            match op {
+                Op::Put { key, value } => {
+                    db.execute(
+                        "INSERT OR REPLACE INTO records (key, value) VALUES (?1, ?2)",
+                        [&key, &value],
+                    )?;
+                    index_record(&db, &key, Some(&value))?;
+                }
+                Op::Delete { key } => {
+                    db.execute("DELETE FROM records WHERE key = ?1", [&key])?;
+                    index_record(&db, &key, None)?;
+                }
+            }
+        }
+        db.commit()?;
+        Ok(())
+    }
+
+    /// Checked by a connection of its own on a thread of its own
+    fn verify(&self) -> Pending<()> {
+        let path = self.path.clone();
+        Pending::spawn(move || {
+            let db = SqliteStore::connect(&path)?;
+            let result: String = db.query_row("PRAGMA integrity_check", [], |row| row.get(0))?;
+            if result != "ok" {
+                bail!("the SQLite database is damaged: {}", result);
+            }
+            Ok(())
+        })
+    }
+
+    /// Vacuumed by a connection of its own on a thread of its own, batches wait for it up to
+    /// `SQLITE_BUSY_TIMEOUT`
+    fn compact(&mut self) -> Pending<u64> {
+        let path = self.path.clone();
+        Pending::spawn(move || {
+            let db = SqliteStore::connect(&path)?;
+            let before = SqliteStore::size(&db)?;
+            db.execute_batch("VACUUM")?;
+            Ok(before.saturating_sub(SqliteStore::size(&db)?))
+        })
+    }
+}
+
+// 写入线程持锁时不会 panic，被毒化的锁里的数据仍然完整
+pub fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// How long writes held the caller with one way of writing
+#[derive(Debug)]
+pub struct StorageBenchmark {
+    pub name: &'static str,
+    /// Time the caller spent in writes, the event loop is stuck for as long
+    pub blocked: Duration,
+    /// Longest a single write held the caller
+    pub longest: Duration,
+    /// Time until every batch was on disk
+    pub written: Duration,
+}
+
+/// Write `batches` batches of one 1 KiB record to a scratch sled database, once flushed by sled
+/// in the background and once flushed after every batch
+pub fn benchmark(batches: usize) -> Result<Vec<StorageBenchmark>> {
+    let mut results = Vec::new();
+    for (name, flushed) in [("background flush", false), ("flush per batch", true)] {
+        let path = env::temp_dir().join(format!(
+            "ant-chain-bench-{}-{}.db",
+            process::id(),
+            results.len()
+        ));
+        let mut store = SledStore::open(&path)?;
+        let started = Instant::now();
+        let mut longest = Duration::ZERO;
+        for i in 0..batches {
+            let began = Instant::now();
+            store.put(format!("bench/{:08}", i).into_bytes(), vec![0xab; 1024])?;
+            if flushed {
+                store.flush().wait()?;
+            }
+            longest = longest.max(began.elapsed());
+        }
+        let blocked = started.elapsed();
+        store.flush().wait()?;
+        let written = started.elapsed();
+        drop(store);
+        let _ = fs::remove_dir_all(path.with_extension("sled"));
+        results.push(StorageBenchmark {
+            name,
+            blocked,
+            longest,
+            written,
+        });
+    }
+    Ok(results)
+}
+
+fn scan<'a>(records: &'a Records, prefix: &'a [u8]) -> Entries<'a> {
+    Box::new(
+        records
+            .range(prefix.to_vec()..)
+            .take_while(move |(key, _)| key.starts_with(prefix))
+            .map(|(key, value)| Ok((key.clone(), value.clone()))),
+    )
+}
+
+fn apply(records: &mut Records, op: Op) {
+    match op {
+        Op::Put { key, value } => records.insert(key, value),
+        Op::Delete { key } => records.remove(&key),
+    };
+}
+
+fn compaction_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".compact");
+    PathBuf::from(name)
+}
+
+/// Sync the directory holding the file, so a file created or renamed in it is found after a
+/// crash
+fn sync_dir(path: &Path) -> Result<()> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    File::open(dir)
+        .and_then(|dir| dir.sync_all())
+        .with_context(|| format!("can not sync {}", dir.display()))
+}
+
+/// Replace the file with the bytes so a crash leaves either the old content or the new one
+///
+/// The bytes go to a file next to it that is synced, renamed over the file and the directory
+/// synced after, on a blocking thread that finishes the write even when the caller stops waiting
+pub async fn replace_file(path: impl Into<PathBuf>, bytes: Vec<u8>) -> Result<()> {
+    replace(path.into(), bytes, false).await
+}
+
+/// Replace the file like `replace_file`, readable and writable by its owner only
+pub async fn replace_private_file(path: impl Into<PathBuf>, bytes: Vec<u8>) -> Result<()> {
+    replace(path.into(), bytes, true).await
+}
+
+async fn replace(path: PathBuf, bytes: Vec<u8>, private: bool) -> Result<()> {
+    static REPLACING: Mutex<()> = Mutex::new(());
+
+    tokio::task::spawn_blocking(move || {
+        // 同一个文件的两次写入共用一个临时文件，依次进行
+        let _replacing = lock(&REPLACING);
+        let mut staged = path.as_os_str().to_owned();
+        staged.push(".tmp");
+        let staged = PathBuf::from(staged);
+        let mut options = OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        if private {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        #[cfg(not(unix))]
+        let _ = private;
+        // 残留的临时文件可能带着别的权限，重新创建
+        match fs::remove_file(&staged) {
+            Err(e) if e.kind() != ErrorKind::NotFound => {
+                return Err(e).with_context(|| format!("can not write {}", path.display()))
+            }
+            _ => {}
+        }
+        options
+            .open(&staged)
+            .and_then(|mut file| {
+                file.write_all(&bytes)?;
+                file.sync_all()
+            })
+            .and_then(|()| fs::rename(&staged, &path))
+            .with_context(|| format!("can not write {}", path.display()))?;
+        sync_dir(&path)
+    })
+    .await?
+}
+
+/// Take over the records of the log file an earlier release kept at the path, with the batches
+/// of the write-ahead log next to it applied on top, then remove both
+///
+/// The records and the mark that they were imported go in one batch and are flushed before the
+/// files are removed, so a crash leaves either the log to import again or the records imported
+fn import_log(store: &mut dyn Storage, path: &Path) -> Result<()> {
+    let logs = [path.to_path_buf(), wal_path(path)];
+    if store.get(IMPORTED_LOG_KEY)?.is_none() {
+        let mut records = Records::new();
+        let mut found = false;
+        for log in &logs {
+            let content = match fs::read(log) {
+                Ok(content) => content,
+                Err(e) if e.kind() == ErrorKind::NotFound => continue,
+                Err(e) => return Err(e).with_context(|| format!("can not read {}", log.display())),
+            };
+            found = true;
+            let mut offset = 0;
+            while let Some((batch, len)) = read_frame(&content[offset..]) {
+                for op in batch {
+                    apply(&mut records, op);
+                }
+                offset += len;
+            }
+            if offset < content.len() {
+                warn!(
+                    "dropping {} bytes of an unfinished write at the end of {}",
+                    content.len() - offset,
+                    log.display()
+                );
+            }
+        }
+        if !found {
+            return Ok(());
+        }
+        let imported = records.len();
+        let mut batch: Vec<Op> = records
+            .into_iter()
+            .map(|(key, value)| Op::Put { key, value })
+            .collect();
+        batch.push(Op::Put {
+            key: IMPORTED_LOG_KEY.to_vec(),
+            value: Vec::new(),
+        });
+        store.batch(batch)?;
+        store.flush().wait()?;
+        info!("Imported {} records from {}", imported, path.display());
+    }
+    for file in logs.iter().cloned().chain([compaction_path(path)]) {
+        match fs::remove_file(&file) {
+            Err(e) if e.kind() != ErrorKind::NotFound => {
+                return Err(e).with_context(|| format!("can not remove {}", file.display()))
+            }
+            _ => {}
+        }
+    }
+    sync_dir(path)
+}
+
+fn wal_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".wal");
+    PathBuf::from(name)
+}
+
+/// The batch at the start of the bytes and the length of its frame, none when the frame is
+/// incomplete or does not match its checksum
+fn read_frame(bytes: &[u8]) -> Option<(Vec<Op>, usize)> {
+    let header = bytes.get(..FRAME_HEADER_LEN)?;
+    let len = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize;
+    let payload = bytes.get(FRAME_HEADER_LEN..FRAME_HEADER_LEN + len)?;
+    if Sha256::digest(payload)[..4] != header[4..] {
+        return None;
+    }
+    let batch = ciborium::from_reader(payload).ok()?;
+    Some((batch, FRAME_HEADER_LEN + len))
+}
+
+/// Recipes of the node, one record per recipe keyed by its id
+///
+/// Recently read recipes are kept decoded, so answering every `ls r all` does not decode each
+/// record again
+#[derive(Debug)]
+pub struct RecipeStore {
+    db: Mutex<Box<dyn Storage>>,
+    /// Decoded recipes by their record key, only changed while the database is locked
+    cache: Mutex<LruCache<Vec<u8>, Recipe>>,
+}
+
+/// Records of a store and how its cache did so far
+#[derive(Debug)]
+pub struct StoreStats {
+    pub records: usize,
+    pub cache: CacheStats,
+}
+
+/// Open the recipes in the backend, importing the recipes of the older JSON file into a new
+/// database
+pub fn open_recipes(backend: StorageBackend) -> Result<()> {
+    let mut db = open(backend, RECIPES_DB_PATH)?;
+    migrate(db.as_mut(), &RECIPE_SCHEMA)?;
+    if db.iter(RECIPE_PREFIX).next().transpose()?.is_none() {
+        let imported = import_legacy(db.as_mut())?;
+        if imported > 0 {
+            info!("Imported {} recipes from {}", imported, STORAGE_FILE_PATH);
+        }
+    }
+    RECIPES
+        .set(RecipeStore {
+            db: Mutex::new(db),
+            cache: Mutex::new(LruCache::new(RECIPE_CACHE_CAPACITY)),
+        })
+        .map_err(|_| anyhow!("recipes are already open"))
+}
+
+/// The recipes opened by `open_recipes`
+pub fn recipes() -> &'static RecipeStore {
+    RECIPES.get().expect("recipes opened at startup")
+}
+
+fn import_legacy(db: &mut dyn Storage) -> Result<usize> {
+    let content = match fs::read(STORAGE_FILE_PATH) {
+        Ok(content) => content,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e).with_context(|| format!("can not read {}", STORAGE_FILE_PATH)),
+    };
+    let recipes: Vec<Recipe> = serde_json::from_slice(&content)
+        .with_context(|| format!("invalid recipes {}", STORAGE_FILE_PATH))?;
+    let batch = recipes
+        .iter()
+        .map(recipe_put)
+        .collect::<Result<Vec<Op>>>()?;
+    db.batch(batch)?;
+    Ok(recipes.len())
+}
+
+fn recipe_key(id: usize) -> Vec<u8> {
+    [RECIPE_PREFIX, &(id as u64).to_be_bytes()[..]].concat()
+}
+
+/// The id after the highest one given out so far
+fn next_id(db: &dyn Storage) -> Result<usize> {
+    let stored = match db.get(NEXT_ID_KEY)? {
+        Some(bytes) => {
+            let bytes: [u8; 8] = bytes
+                .as_slice()
+                .try_into()
+                .map_err(|_| anyhow!("invalid next recipe id"))?;
+            u64::from_be_bytes(bytes) as usize
+        }
+        None => 0,
+    };
+    // 导入的旧菜谱没有记下一个 id
+    let after_highest = match db.iter(RECIPE_PREFIX).last().transpose()? {
+        Some((_, value)) => decode_recipe(&value)?.id + 1,
+        None => 0,
+    };
+    Ok(stored.max(after_highest))
+}
+
+fn next_id_put(id: usize) -> Op {
+    Op::Put {
+        key: NEXT_ID_KEY.to_vec(),
+        value: (id as u64).to_be_bytes().to_vec(),
+    }
+}
+
+/// Record the id after the highest recipe, until then the ids of the last recipes expired are
+/// given out again
+fn record_next_id(db: &dyn Storage) -> Result<Vec<Op>> {
+    Ok(vec![next_id_put(next_id(db)?)])
+}
+
+fn recipe_put(recipe: &Recipe) -> Result<Op> {
+    let mut value = Vec::new();
+    ciborium::into_writer(recipe, &mut value)?;
+    Ok(Op::Put {
+        key: recipe_key(recipe.id),
+        value,
+    })
+}
+
+fn decode_recipe(value: &[u8]) -> Result<Recipe> {
+    ciborium::from_reader(value).map_err(|e| anyhow!("invalid recipe record: {}", e))
+}
+
+impl RecipeStore {
+    fn db(&self) -> MutexGuard<'_, Box<dyn Storage>> {
+        lock(&self.db)
+    }
+
+    pub fn flush(&self) -> Pending<()> {
+        self.db().flush()
+    }
+
+    /// All recipes but the deleted ones, ordered by id
+    pub fn list(&self) -> Result<Vec<Recipe>> {
+        Ok(self
+            .all()?
+            .into_iter()
+            .filter(|recipe| recipe.deleted.is_none())
+            .collect())
+    }
+
+    /// All recipes with the tombstones of the deleted ones
+    fn all(&self) -> Result<Vec<Recipe>> {
+        let db = self.db();
+        let mut cache = lock(&self.cache);
+        let entries = db.iter(RECIPE_PREFIX).collect::<Result<Vec<_>>>()?;
+        // 比缓存大的全量扫描只读缓存，否则每次扫描都把缓存里的菜谱全部换掉
+        let bypass = entries.len() > cache.capacity();
+        entries
+            .into_iter()
+            .map(|(key, value)| {
+                let cached = if bypass {
+                    cache.peek(&key)
+                } else {
+                    cache.get(&key)
+                };
+                match cached {
+                    Some(recipe) => Ok(recipe),
+                    None => {
+                        let recipe = decode_recipe(&value)?;
+                        if !bypass {
+                            cache.insert(key, recipe.clone());
+                        }
+                        Ok(recipe)
+                    }
+                }
+            })
+            .collect()
+    }
+
+    /// Images the recipes point at, deleted ones keep theirs until they expire
+    pub fn images(&self) -> Result<Vec<Hash>> {
+        Ok(self.all()?.into_iter().filter_map(|r| r.image).collect())
+    }
+
+    pub fn stats(&self) -> Result<StoreStats> {
+        let records = self
+            .db()
+            .iter(RECIPE_PREFIX)
+            .collect::<Result<Vec<_>>>()?
+            .len();
+        Ok(StoreStats {
+            records,
+            cache: lock(&self.cache).stats(),
+        })
+    }
+
+    /// The recipe with the id unless it is deleted
+    fn live(db: &dyn Storage, id: usize) -> Result<Option<Recipe>> {
+        match db
+            .get(&recipe_key(id))?
+            .as_deref()
+            .map(decode_recipe)
+            .transpose()?
+        {
+            Some(recipe) if recipe.deleted.is_none() => Ok(Some(recipe)),
+            _ => Ok(None),
+        }
+    }
+
+    /// Write the recipe and keep it decoded
+    fn store(&self, db: &mut dyn Storage, recipe: Recipe) -> Result<Recipe> {
+        db.batch(vec![recipe_put(&recipe)?])?;
+        lock(&self.cache).insert(recipe_key(recipe.id), recipe.clone());
+        Ok(recipe)
+    }
+
+    /// Store a new unshared recipe under the id after the highest one
+    pub fn create(&self, name: &str, ingredients: &str, instructions: &str) -> Result<Recipe> {
+        let mut db = self.db();
+        let id = next_id(db.as_ref())?;
+        let recipe = Recipe {
+            id,
+            name: name.to_owned(),
+            ingredients: ingredients.to_owned(),
+            instructions: instructions.to_owned(),
+            shared: false,
+            topics: Vec::new(),
+            image: None,
+            deleted: None,
+        };
+        db.batch(vec![recipe_put(&recipe)?, next_id_put(id + 1)])?;
+        lock(&self.cache).insert(recipe_key(id), recipe.clone());
+        Ok(recipe)
+    }
+
+    /// Check the storage and that every record holds a recipe under its own id, returns how many
+    /// recipes there are
+    pub fn verify(&self) -> Result<usize> {
+        // 不持锁等待文件校验
+        let stored = self.db().verify();
+        stored.wait()?;
+        let db = self.db();
+        let mut count = 0;
+        for entry in db.iter(RECIPE_PREFIX) {
+            let (key, value) = entry?;
+            let recipe = decode_recipe(&value)?;
+            if key != recipe_key(recipe.id) {
+                bail!("record {} holds recipe {}", hex::encode(key), recipe.id);
+            }
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    pub fn snapshot(&self) -> Result<Vec<Record>> {
+        snapshot(self.db().as_ref())
+    }
+
+    /// Replace every recipe with the records of a backup in one batch, upgraded to the current
+    /// schema first, returns how many recipes there are now
+    pub fn restore(&self, records: Vec<Record>) -> Result<usize> {
+        let mut staged = MemoryStore::default();
+        staged.batch(replacement(&staged, records)?)?;
+        migrate(&mut staged, &RECIPE_SCHEMA)?;
+        let count = staged.iter(RECIPE_PREFIX).count();
+        let mut db = self.db();
+        let batch = replacement(db.as_ref(), snapshot(&staged)?)?;
+        db.batch(batch)?;
+        lock(&self.cache).clear();
+        Ok(count)
+    }
+
+    /// Point the recipe at an image in the blob store, returns the recipe as it was, none when
+    /// there is no recipe with the id
+    pub fn set_image(&self, id: usize, image: Option<Hash>) -> Result<Option<Recipe>> {
+        let mut db = self.db();
+        let before = match RecipeStore::live(db.as_ref(), id)? {
+            Some(recipe) => recipe,
+            None => return Ok(None),
+        };
+        let recipe = Recipe {
+            image,
+            ..before.clone()
+        };
+        self.store(db.as_mut(), recipe)?;
+        Ok(Some(before))
+    }
+
+    /// Share the recipe on the topics, false when there is no recipe with the id
+    pub fn publish(&self, id: usize, topics: Vec<String>) -> Result<bool> {
+        let mut db = self.db();
+        let mut recipe = match RecipeStore::live(db.as_ref(), id)? {
+            Some(recipe) => recipe,
+            None => return Ok(false),
+        };
+        recipe.shared = true;
+        recipe.topics = topics;
+        self.store(db.as_mut(), recipe)?;
+        Ok(true)
+    }
+
+    /// Replace the recipe with a tombstone, which `expire` removes once the retention window
+    /// passed, false when there is no recipe with the id
+    pub fn delete(&self, id: usize) -> Result<bool> {
+        let mut db = self.db();
+        let mut recipe = match RecipeStore::live(db.as_ref(), id)? {
+            Some(recipe) => recipe,
+            None => return Ok(false),
+        };
+        recipe.deleted = Some(now());
+        self.store(db.as_mut(), recipe)?;
+        Ok(true)
+    }
+
+    /// Bring back a deleted recipe that did not expire yet, false when there is no such recipe
+    pub fn undelete(&self, id: usize) -> Result<bool> {
+        let mut db = self.db();
+        let mut recipe = match db
+            .get(&recipe_key(id))?
+            .as_deref()
+            .map(decode_recipe)
+            .transpose()?
+        {
+            Some(recipe) if recipe.deleted.is_some() => recipe,
+            _ => return Ok(false),
+        };
+        recipe.deleted = None;
+        self.store(db.as_mut(), recipe)?;
+        Ok(true)
+    }
+
+    /// Remove the recipes deleted longer than the retention ago in one batch, returns them so
+    /// their images can be released
+    pub fn expire(&self, retention: Duration) -> Result<Vec<Recipe>> {
+        let cutoff = now().saturating_sub(retention.as_secs());
+        let expired: Vec<Recipe> = self
+            .all()?
+            .into_iter()
+            .filter(|recipe| recipe.deleted.is_some_and(|deleted| deleted <= cutoff))
+            .collect();
+        let mut db = self.db();
+        let batch = expired
+            .iter()
+            .map(|recipe| Op::Delete {
+                key: recipe_key(recipe.id),
+            })
+            .collect();
+        db.batch(batch)?;
+        let mut cache = lock(&self.cache);
+        for recipe in expired.iter() {
+            cache.remove(&recipe_key(recipe.id));
+        }
+        Ok(expired)
+    }
+
+    pub fn compact(&self) -> Pending<u64> {
+        self.db().compact()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn put(key: &str, value: &str) -> Op {
+        Op::Put {
+            key: key.as_bytes().to_vec(),
+            value: value.as_bytes().to_vec(),
+        }
+    }
+
+    fn records(store: &dyn Storage) -> Vec<(Vec<u8>, Vec<u8>)> {
+        store.iter(b"").collect::<Result<_>>().unwrap()
+    }
+
+    /// Length, first 4 bytes of the SHA-256 of the payload and the CBOR encoded batch, as the log
+    /// files of earlier releases hold them
+    fn encode_frame(batch: &[Op]) -> Vec<u8> {
+        let mut payload = Vec::new();
+        ciborium::into_writer(batch, &mut payload).unwrap();
+        let mut frame = Vec::new();
+        frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+        frame.extend_from_slice(&Sha256::digest(&payload)[..4]);
+        frame.extend_from_slice(&payload);
+        frame
+    }
+
+    #[test]
+    fn the_log_of_an_earlier_release_is_imported_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.db");
+        let mut log = encode_frame(&[put("a", "1"), put("b", "2")]);
+        log.extend(encode_frame(&[Op::Delete { key: b"a".to_vec() }]));
+        let torn = encode_frame(&[put("c", "3")]);
+        log.extend_from_slice(&torn[..torn.len() / 2]);
+        fs::write(&path, log).unwrap();
+        fs::write(wal_path(&path), encode_frame(&[put("d", "4")])).unwrap();
+        fs::write(compaction_path(&path), b"half a log").unwrap();
+
+        let mut store = open(StorageBackend::Disk, &path).unwrap();
+        assert!(!path.exists() && !wal_path(&path).exists());
+        assert!(!compaction_path(&path).exists());
+        assert_eq!(store.get(b"b").unwrap(), Some(b"2".to_vec()));
+        assert_eq!(store.get(b"d").unwrap(), Some(b"4".to_vec()));
+        assert_eq!(store.iter(b"a").count() + store.iter(b"c").count(), 0);
+        store.put(b"b".to_vec(), b"5".to_vec()).unwrap();
+        store.flush().wait().unwrap();
+        drop(store);
+
+        // 导入后删除文件前崩溃，留下的旧日志不会再覆盖数据库
+        fs::write(&path, encode_frame(&[put("b", "2")])).unwrap();
+        let store = open(StorageBackend::Disk, &path).unwrap();
+        assert!(!path.exists());
+        assert_eq!(store.get(b"b").unwrap(), Some(b"5".to_vec()));
+    }
+
+    #[test]
+    fn the_id_of_an_expired_recipe_is_not_given_out_again() {
+        let recipes = RecipeStore {
+            db: Mutex::new(Box::<MemoryStore>::default()),
+            cache: Mutex::new(LruCache::new(RECIPE_CACHE_CAPACITY)),
+        };
+        recipes.create("Soup", "water", "boil").unwrap();
+        let last = recipes.create("Tea", "water", "steep").unwrap();
+        assert!(recipes.delete(last.id).unwrap());
+        assert_eq!(recipes.expire(Duration::ZERO).unwrap().len(), 1);
+
+        let next = recipes.create("Bread", "flour", "bake").unwrap();
+        assert_eq!(next.id, last.id + 1);
+    }
+
+    #[test]
+    fn a_scan_larger_than_the_cache_leaves_it_as_it_was() {
+        let recipes = RecipeStore {
+            db: Mutex::new(Box::<MemoryStore>::default()),
+            cache: Mutex::new(LruCache::new(2)),
+        };
+        for name in ["Soup", "Tea", "Bread"] {
+            recipes.create(name, "water", "boil").unwrap();
+        }
+        let cached = lock(&recipes.cache).stats();
+        assert_eq!(recipes.list().unwrap().len(), 3);
+
+        let scanned = lock(&recipes.cache).stats();
+        assert_eq!(scanned.len, cached.len);
+        assert_eq!((scanned.hits, scanned.misses), (2, 1));
+    }
+
+    #[test]
+    fn migrating_records_the_next_recipe_id() {
+        let mut db = MemoryStore::default();
+        db.batch(vec![
+            schema_record(1),
+            recipe_put(&Recipe {
+                id: 4,
+                name: "Soup".to_owned(),
+                ingredients: "water".to_owned(),
+                instructions: "boil".to_owned(),
+                shared: false,
+                topics: Vec::new(),
+                image: None,
+                deleted: None,
+            })
+            .unwrap(),
+        ])
+        .unwrap();
+
+        assert_eq!(migrate(&mut db, &RECIPE_SCHEMA).unwrap(), 2);
+        db.batch(vec![Op::Delete { key: recipe_key(4) }]).unwrap();
+        assert_eq!(next_id(&db).unwrap(), 5);
+    }
+
+    #[test]
+    fn sled_store_keeps_batches_across_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.db");
+        let mut store = SledStore::open(&path).unwrap();
+        store.batch(vec![put("a", "1"), put("b", "2")]).unwrap();
+        store
+            .batch(vec![Op::Delete { key: b"a".to_vec() }])
+            .unwrap();
+        store.flush().wait().unwrap();
+        drop(store);
+
+        let store = SledStore::open(&path).unwrap();
+        assert_eq!(records(&store), vec![(b"b".to_vec(), b"2".to_vec())]);
+        store.verify().wait().unwrap();
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn sqlite_store_keeps_the_tables_in_step() {
+        use crate::blockchain::Block;
+        use crate::transaction::{Address, Transaction};
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.db");
+        let mut store = SqliteStore::open(&path).unwrap();
+        let recipe = Recipe {
+            id: 3,
+            name: "Soup".to_owned(),
+            ingredients: "water".to_owned(),
+            instructions: "boil".to_owned(),
+            shared: false,
+            topics: Vec::new(),
+            image: None,
+            deleted: None,
+        };
+        let tx = Transaction::new(Address([1; 32]), Address([2; 32]), 5, 0);
+        let block = Block::new(1, Hash::default(), 0, String::new(), vec![tx.clone()]);
+        let mut encoded = Vec::new();
+        ciborium::into_writer(&block, &mut encoded).unwrap();
+        store
+            .batch(vec![
+                recipe_put(&recipe).unwrap(),
+                Op::Put {
+                    key: crate::blockchain::BLOCK_PREFIX
+                        .iter()
+                        .chain(&1u64.to_be_bytes())
+                        .copied()
+                        .collect(),
+                    value: encoded,
+                },
+            ])
+            .unwrap();
+        drop(store);
+
+        let mut store = SqliteStore::open(&path).unwrap();
+        store.verify().wait().unwrap();
+        let name: String = store
+            .db
+            .query_row("SELECT name FROM recipes WHERE id = 3", [], |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert_eq!(name, "Soup");
+        let (txid, amount): (String, i64) = store
+            .db
+            .query_row(
+                "SELECT txid, amount FROM transactions WHERE height = 1",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .unwrap();
+        assert_eq!((txid, amount), (tx.id().to_string(), 5));
+        assert_eq!(records(&store).len(), 2);
+
+        store
+            .batch(vec![Op::Delete { key: recipe_key(3) }])
+            .unwrap();
+        let recipes: i64 = store
+            .db
+            .query_row("SELECT COUNT(*) FROM recipes", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(recipes, 0);
+        assert_eq!(store.iter(RECIPE_PREFIX).count(), 0);
+    }
+
+    #[cfg(feature = "rocksdb")]
+    #[test]
+    fn rocks_store_reads_across_column_families() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.db");
+        let mut store = RocksStore::open(&path).unwrap();
+        store
+            .batch(vec![
+                put("recipe/1", "soup"),
+                put("block/1", "first"),
+                put("chain/tip", "first"),
+                put("blob/1", "image"),
+                put("block/2", "second"),
+            ])
+            .unwrap();
+        store
+            .batch(vec![Op::Delete {
+                key: b"block/1".to_vec(),
+            }])
+            .unwrap();
+        drop(store);
+
+        let mut store = RocksStore::open(&path).unwrap();
+        let keys: Vec<Vec<u8>> = records(&store).into_iter().map(|(key, _)| key).collect();
+        assert_eq!(
+            keys,
+            vec![
+                b"blob/1".to_vec(),
+                b"block/2".to_vec(),
+                b"chain/tip".to_vec(),
+                b"recipe/1".to_vec(),
+            ]
+        );
+        let blocks: Vec<_> = store.iter(b"block/").collect::<Result<_>>().unwrap();
+        assert_eq!(blocks, vec![(b"block/2".to_vec(), b"second".to_vec())]);
+        assert_eq!(store.get(b"chain/tip").unwrap(), Some(b"first".to_vec()));
+        store.verify().wait().unwrap();
+        store.compact().wait().unwrap();
+    }
+
+    #[tokio::test]
+    async fn a_replaced_file_has_the_new_content_and_no_staged_copy() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("scores.json");
+        fs::write(&path, b"old").unwrap();
+        replace_file(&path, b"new".to_vec()).await.unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"new");
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn a_private_file_is_only_readable_by_its_owner() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("wallet.json");
+        // 上次写入留下的临时文件
+        fs::write(dir.path().join("wallet.json.tmp"), b"stale").unwrap();
+        replace_private_file(&path, b"keys".to_vec()).await.unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"keys");
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+}
@@ -0,0 +1,614 @@
+use std::cmp;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+
+use libp2p::request_response::{self, RequestId};
+use libp2p::{PeerId, Swarm};
+use log::debug;
+use serde::{Deserialize, Serialize};
+
+use crate::accounts::AccountProof;
+use crate::behaviour::RecipeBehaviour;
+use crate::blockchain::{Block, BlockHeader, Chain, Hash};
+use crate::consts::{
+    MAX_REORG_DEPTH, SYNC_BATCH_SIZE, SYNC_DOWNLOAD_WINDOW, SYNC_HEADERS_BATCH_SIZE,
+};
+use crate::mempool::ShortId;
+use crate::merkle::MerkleProof;
+use crate::transaction::{Address, Transaction};
+use crate::validation::ValidationError;
+
+pub type SyncEvent = request_response::Event<SyncRequest, SyncResponse>;
+
+/// Where the chain of a node stands, exchanged with every new peer
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChainStatus {
+    /// Peers with another genesis hash belong to another network
+    pub genesis: Hash,
+    pub height: u64,
+    pub tip: Hash,
+    /// Work of the whole chain, the chain with the most work is the one to follow
+    pub work: u128,
+}
+
+impl ChainStatus {
+    pub fn of(chain: &Chain) -> ChainStatus {
+        ChainStatus {
+            genesis: chain.genesis_hash(),
+            height: chain.height(),
+            tip: chain.tip().hash,
+            work: chain.work(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum SyncRequest {
+    /// Our status, answered with the status of the peer
+    Status(ChainStatus),
+    /// Headers after the first block of the locator the peer has, at most
+    /// `SYNC_HEADERS_BATCH_SIZE` of them
+    Headers { locator: Vec<Hash>, count: u32 },
+    /// Blocks from the height on, the peer sends at most `SYNC_BATCH_SIZE` of them
+    Blocks { from: u64, count: u32 },
+    /// Short ids of our pending transactions, answered with the pending transactions of the peer
+    /// that are not among them
+    Mempool(Vec<ShortId>),
+    /// Merkle proof of the confirmed transaction, asked by light nodes
+    Proof(Hash),
+    /// Payload of the block with the hash, asked by light nodes
+    Data(Hash),
+    /// State proof of the account at the tip, asked by light nodes
+    Account(Address),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum SyncResponse {
+    Status(ChainStatus),
+    /// Consecutive headers, empty when the peer has none after the locator
+    Headers(Vec<BlockHeader>),
+    /// Consecutive blocks, empty when the peer does not have the requested height
+    Blocks(Vec<Block>),
+    /// Pending transactions, oldest first
+    Transactions(Vec<Transaction>),
+    /// None when no block of the chain contains the transaction
+    Proof(Option<MerkleProof>),
+    /// None when the block is not on the chain
+    Data(Option<String>),
+    /// None when the ledger of the peer has no accounts
+    Account(Option<AccountProof>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fetch {
+    Headers,
+    Blocks,
+}
+
+impl fmt::Display for Fetch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Fetch::Headers => f.write_str("headers"),
+            Fetch::Blocks => f.write_str("blocks"),
+        }
+    }
+}
+
+/// Range of headers or blocks being downloaded
+#[derive(Debug, Clone)]
+pub struct Download {
+    pub peer: PeerId,
+    pub fetch: Fetch,
+    pub from: u64,
+    pub count: u32,
+}
+
+/// Catches up with the chain of the most work among our peers, headers first
+///
+/// The headers of the best peer are followed first, which is quick and settles which chain to
+/// download, then the blocks under them are requested in batches from every peer that has them
+/// at once. The headers may fork off below our tip, their blocks then replace ours once they add
+/// up to more work
+#[derive(Debug, Default)]
+pub struct Syncer {
+    /// Latest known status of every peer of our network
+    peers: HashMap<PeerId, ChainStatus>,
+    /// Checked headers following a block of our chain in order, their blocks are yet to be
+    /// connected
+    headers: Vec<BlockHeader>,
+    /// Downloaded blocks waiting for the blocks below them, with the peer they came from
+    bodies: BTreeMap<u64, (PeerId, Block)>,
+    downloads: HashMap<RequestId, Download>,
+    /// Peers of another network, dropped whenever they connect until we restart
+    foreign: HashSet<PeerId>,
+    /// Blocks applied from downloads since startup
+    pub applied: u64,
+}
+
+impl Syncer {
+    /// Tell the peer where our chain stands, it answers with its own status
+    pub fn send_status(
+        &mut self,
+        swarm: &mut Swarm<RecipeBehaviour>,
+        peer_id: PeerId,
+        status: ChainStatus,
+    ) {
+        debug!("[Sync] sending status to {}", peer_id);
+        swarm
+            .behaviour_mut()
+            .sync
+            .send_request(&peer_id, SyncRequest::Status(status));
+    }
+
+    /// Compare mempools with a new peer, it sends the pending transactions we do not have
+    pub fn send_mempool(
+        &mut self,
+        swarm: &mut Swarm<RecipeBehaviour>,
+        peer_id: PeerId,
+        short_ids: Vec<ShortId>,
+    ) {
+        debug!(
+            "[Sync] sending {} mempool short ids to {}",
+            short_ids.len(),
+            peer_id
+        );
+        swarm
+            .behaviour_mut()
+            .sync
+            .send_request(&peer_id, SyncRequest::Mempool(short_ids));
+    }
+
+    pub fn on_status(&mut self, peer_id: PeerId, status: ChainStatus) {
+        self.peers.insert(peer_id, status);
+    }
+
+    /// Forget the peer, downloads from it are given up
+    pub fn remove(&mut self, peer_id: &PeerId) {
+        self.peers.remove(peer_id);
+        self.downloads
+            .retain(|_, download| &download.peer != peer_id);
+    }
+
+    /// Forget a peer of another network and refuse it from now on
+    pub fn reject(&mut self, peer_id: PeerId) {
+        self.remove(&peer_id);
+        self.foreign.insert(peer_id);
+    }
+
+    pub fn is_foreign(&self, peer_id: &PeerId) -> bool {
+        self.foreign.contains(peer_id)
+    }
+
+    /// Peer with the chain of the most work
+    pub fn best(&self) -> Option<(&PeerId, &ChainStatus)> {
+        self.peers.iter().max_by_key(|(_, status)| status.work)
+    }
+
+    pub fn peers(&self) -> impl Iterator<Item = (&PeerId, &ChainStatus)> {
+        self.peers.iter()
+    }
+
+    pub fn downloads(&self) -> impl Iterator<Item = &Download> {
+        self.downloads.values()
+    }
+
+    /// Checked headers following a block of our chain
+    pub fn headers(&self) -> &[BlockHeader] {
+        &self.headers
+    }
+
+    /// Height of the last checkpoint among the known headers, the headers up to it are part of
+    /// the trusted history
+    pub fn checkpointed(&self, chain: &Chain) -> Option<u64> {
+        self.headers
+            .iter()
+            .rev()
+            .find(|header| chain.is_checkpoint(header.index))
+            .map(|header| header.index)
+    }
+
+    /// Downloaded blocks waiting to be connected
+    pub fn pending_blocks(&self) -> usize {
+        self.bodies.len()
+    }
+
+    /// Height of the block of our chain the known headers follow
+    fn fork(&self, chain: &Chain) -> u64 {
+        self.headers
+            .first()
+            .map_or(chain.height(), |header| header.index - 1)
+    }
+
+    /// Request more headers while the best peer has more work than we know of, and the blocks
+    /// under the known headers from every idle peer that has them
+    pub fn request_next(&mut self, swarm: &mut Swarm<RecipeBehaviour>, chain: &Chain) {
+        self.align(chain);
+        let fork = self.fork(chain);
+        let known = fork + self.headers.len() as u64;
+        let work =
+            chain.work_until(fork) + self.headers.iter().map(BlockHeader::work).sum::<u128>();
+        let mut locator: Vec<Hash> = self
+            .headers
+            .last()
+            .map(BlockHeader::hash)
+            .into_iter()
+            .collect();
+        locator.extend(chain.locator());
+        self.request_headers(swarm, locator, known, work);
+        // 只下载窗口内的区块，前面的区块没到齐之前后面的区块都要留在内存里
+        let end = cmp::min(known, fork + SYNC_DOWNLOAD_WINDOW);
+        let mut from = fork + 1;
+        while from <= end {
+            if self.is_covered(from) {
+                from += 1;
+                continue;
+            }
+            let busy: HashSet<PeerId> = self
+                .downloads
+                .values()
+                .filter(|d| d.fetch == Fetch::Blocks)
+                .map(|d| d.peer)
+                .collect();
+            let (peer_id, last) = match self
+                .peers
+                .iter()
+                .find(|(peer_id, status)| status.height >= from && !busy.contains(peer_id))
+            {
+                Some((peer_id, status)) => (*peer_id, cmp::min(end, status.height)),
+                None => break,
+            };
+            let mut count = 0;
+            while count < SYNC_BATCH_SIZE
+                && from + (count as u64) <= last
+                && !self.is_covered(from + count as u64)
+            {
+                count += 1;
+            }
+            let request = SyncRequest::Blocks { from, count };
+            self.send(swarm, peer_id, request, Fetch::Blocks, from, count);
+            from += count as u64;
+        }
+    }
+
+    /// Request the headers after the locator from the best peer when it has more work than the
+    /// `work` of the `known` headers, unless headers are being downloaded already
+    pub fn request_headers(
+        &mut self,
+        swarm: &mut Swarm<RecipeBehaviour>,
+        locator: Vec<Hash>,
+        known: u64,
+        work: u128,
+    ) {
+        if self.downloads.values().any(|d| d.fetch == Fetch::Headers) {
+            return;
+        }
+        if let Some((peer_id, status)) = self.best() {
+            if status.work > work {
+                let peer_id = *peer_id;
+                let count = SYNC_HEADERS_BATCH_SIZE;
+                let request = SyncRequest::Headers { locator, count };
+                self.send(swarm, peer_id, request, Fetch::Headers, known + 1, count);
+            }
+        }
+    }
+
+    fn send(
+        &mut self,
+        swarm: &mut Swarm<RecipeBehaviour>,
+        peer_id: PeerId,
+        request: SyncRequest,
+        fetch: Fetch,
+        from: u64,
+        count: u32,
+    ) {
+        debug!(
+            "[Sync] requesting {} {}..{} from {}",
+            fetch,
+            from,
+            from + count as u64,
+            peer_id
+        );
+        let request_id = swarm.behaviour_mut().sync.send_request(&peer_id, request);
+        self.downloads.insert(
+            request_id,
+            Download {
+                peer: peer_id,
+                fetch,
+                from,
+                count,
+            },
+        );
+    }
+
+    /// Whether the block at the height is downloaded or being downloaded
+    fn is_covered(&self, index: u64) -> bool {
+        self.bodies.contains_key(&index)
+            || self.downloads.values().any(|d| {
+                d.fetch == Fetch::Blocks && d.from <= index && index < d.from + d.count as u64
+            })
+    }
+
+    /// Drop the headers and blocks the chain got past, all of them when the chain no longer has
+    /// the block they follow
+    fn align(&mut self, chain: &Chain) {
+        let passed = self
+            .headers
+            .iter()
+            .take_while(|header| {
+                chain.block(header.index).map(|block| &block.header) == Some(header)
+            })
+            .count();
+        self.headers.drain(..passed);
+        let first = match self.headers.first() {
+            Some(first) => first,
+            None => return self.reset(),
+        };
+        let fork = first.index - 1;
+        if chain.block(fork).map(|block| block.hash) != Some(first.prev_hash)
+            || chain.height() - fork > MAX_REORG_DEPTH
+            || fork < chain.finalized_height()
+        {
+            return self.reset();
+        }
+        self.bodies = self.bodies.split_off(&first.index);
+    }
+
+    /// Forget the headers and blocks downloaded so far, e.g. when one of the blocks is invalid
+    pub fn reset(&mut self) {
+        self.headers.clear();
+        self.bodies.clear();
+    }
+
+    /// The download the response or failure belongs to, which is then finished
+    pub fn take_download(&mut self, request_id: &RequestId) -> Option<Download> {
+        self.downloads.remove(request_id)
+    }
+
+    /// Follow the downloaded headers as far as they are valid, returns how many were added
+    ///
+    /// Headers that do not continue the known ones start a new branch, which must fork off our
+    /// chain no deeper than a reorganization may go
+    pub fn add_headers(
+        &mut self,
+        chain: &Chain,
+        download: &Download,
+        headers: Vec<BlockHeader>,
+    ) -> Result<usize, ValidationError> {
+        self.align(chain);
+        let first = match headers.first() {
+            Some(first) => first,
+            None => return Ok(0),
+        };
+        if self.headers.last().map(BlockHeader::hash) != Some(first.prev_hash) {
+            let fork = first
+                .index
+                .checked_sub(1)
+                .and_then(|index| chain.block(index));
+            if fork.map(|block| block.hash) != Some(first.prev_hash) {
+                return Err(ValidationError::UnknownParent {
+                    prev_hash: first.prev_hash,
+                    tip: chain.tip().hash,
+                });
+            }
+            if first.index <= chain.finalized_height() {
+                return Err(ValidationError::RevertsFinalized {
+                    fork: first.index - 1,
+                    finalized: chain.finalized_height(),
+                });
+            }
+            let depth = chain.height() + 1 - first.index;
+            if depth > MAX_REORG_DEPTH {
+                return Err(ValidationError::ReorgTooDeep {
+                    depth,
+                    max: MAX_REORG_DEPTH,
+                });
+            }
+            self.reset();
+        }
+        let mut added = 0;
+        for header in headers.into_iter().take(download.count as usize) {
+            chain.check_header(&self.headers, &header)?;
+            self.headers.push(header);
+            added += 1;
+        }
+        Ok(added)
+    }
+
+    /// Keep the downloaded blocks that belong under the known headers, false when the peer sent
+    /// a block of another chain
+    pub fn add_blocks(&mut self, chain: &Chain, download: &Download, blocks: Vec<Block>) -> bool {
+        self.align(chain);
+        let fork = self.fork(chain);
+        for block in blocks.into_iter().take(download.count as usize) {
+            let index = block.header.index;
+            // 已经接上的区块，或者区块头已被丢弃
+            let header = match index
+                .checked_sub(fork + 1)
+                .and_then(|offset| self.headers.get(offset as usize))
+            {
+                Some(header) => header,
+                None => continue,
+            };
+            if header != &block.header {
+                return false;
+            }
+            self.bodies.insert(index, (download.peer, block));
+        }
+        true
+    }
+
+    /// Downloaded blocks that can be connected now, with the peers they came from
+    ///
+    /// Blocks on top of our tip are returned as soon as they arrive, the blocks of a branch
+    /// forking off below the tip only once they have more work than the blocks they replace
+    pub fn take_ready(&mut self, chain: &Chain) -> Vec<(PeerId, Block)> {
+        self.align(chain);
+        let fork = self.fork(chain);
+        let ready = (fork + 1..)
+            .take_while(|index| self.bodies.contains_key(index))
+            .count() as u64;
+        if fork < chain.height() {
+            let work: u128 = self
+                .headers
+                .iter()
+                .take(ready as usize)
+                .map(BlockHeader::work)
+                .sum();
+            if work <= chain.work() - chain.work_until(fork) {
+                return Vec::new();
+            }
+        }
+        (fork + 1..fork + 1 + ready)
+            .filter_map(|index| self.bodies.remove(&index))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::genesis::Genesis;
+
+    /// A chain without proof of work with the blocks carrying the data on top of its genesis block
+    fn mined(data: &str, blocks: usize) -> Chain {
+        let mut genesis = Genesis::default();
+        genesis.params.initial_difficulty = 0;
+        genesis.params.min_difficulty = 0;
+        genesis.params.max_difficulty = 0;
+        let mut chain = Chain::from_genesis(&genesis);
+        for _ in 0..blocks {
+            let block = chain.next_block(data.to_owned(), Vec::new());
+            chain.try_add_block(block).unwrap();
+        }
+        chain
+    }
+
+    fn download(peer: PeerId, fetch: Fetch, from: u64, count: u32) -> Download {
+        Download {
+            peer,
+            fetch,
+            from,
+            count,
+        }
+    }
+
+    fn status(height: u64, work: u128) -> ChainStatus {
+        ChainStatus {
+            genesis: Hash::default(),
+            height,
+            tip: Hash::digest(&height.to_be_bytes()),
+            work,
+        }
+    }
+
+    #[test]
+    fn the_peer_with_the_most_work_is_followed_and_foreign_peers_are_refused() {
+        let (light, heavy, foreign) = (PeerId::random(), PeerId::random(), PeerId::random());
+        let mut syncer = Syncer::default();
+        assert!(syncer.best().is_none());
+        // 更高的链不一定有更多的工作量
+        syncer.on_status(light, status(10, 10));
+        syncer.on_status(heavy, status(5, 20));
+        syncer.on_status(foreign, status(50, 50));
+        syncer.reject(foreign);
+        assert_eq!(syncer.best().map(|(peer, _)| *peer), Some(heavy));
+        assert!(syncer.is_foreign(&foreign));
+        assert!(!syncer.is_foreign(&heavy));
+
+        syncer.remove(&heavy);
+        assert_eq!(syncer.best().map(|(peer, _)| *peer), Some(light));
+        assert_eq!(syncer.peers().count(), 1);
+
+        let chain = Chain::from_genesis(&Genesis::default());
+        let ours = ChainStatus::of(&chain);
+        assert_eq!(ours.height, 0);
+        assert_eq!(ours.tip, chain.genesis_hash());
+        assert_eq!(ours.work, chain.work());
+    }
+
+    #[test]
+    fn blocks_are_connected_in_the_order_of_the_headers_they_were_checked_against() {
+        let source = mined("", 4);
+        let chain = mined("", 0);
+        let peer = PeerId::random();
+        let mut syncer = Syncer::default();
+        let headers: Vec<BlockHeader> = source.iter().skip(1).map(|b| b.header.clone()).collect();
+        let added = syncer
+            .add_headers(&chain, &download(peer, Fetch::Headers, 1, 4), headers)
+            .unwrap();
+        assert_eq!(added, 4);
+        assert_eq!(syncer.headers().len(), 4);
+
+        // 后面的区块先到，要等前面的区块
+        let blocks: Vec<Block> = source.iter().skip(3).cloned().collect();
+        assert!(syncer.add_blocks(&chain, &download(peer, Fetch::Blocks, 3, 2), blocks));
+        assert_eq!(syncer.pending_blocks(), 2);
+        assert!(syncer.take_ready(&chain).is_empty());
+        let blocks: Vec<Block> = source.iter().skip(1).take(2).cloned().collect();
+        assert!(syncer.add_blocks(&chain, &download(peer, Fetch::Blocks, 1, 2), blocks));
+        let ready: Vec<Hash> = syncer
+            .take_ready(&chain)
+            .into_iter()
+            .map(|(from, block)| {
+                assert_eq!(from, peer);
+                block.hash
+            })
+            .collect();
+        let expected: Vec<Hash> = source.iter().skip(1).map(|block| block.hash).collect();
+        assert_eq!(ready, expected);
+        assert_eq!(syncer.pending_blocks(), 0);
+    }
+
+    #[test]
+    fn headers_must_follow_our_chain_and_blocks_their_headers() {
+        let source = mined("", 3);
+        let chain = mined("", 0);
+        let peer = PeerId::random();
+        let mut syncer = Syncer::default();
+        let detached: Vec<BlockHeader> = source.iter().skip(2).map(|b| b.header.clone()).collect();
+        assert!(matches!(
+            syncer.add_headers(&chain, &download(peer, Fetch::Headers, 2, 2), detached),
+            Err(ValidationError::UnknownParent { .. })
+        ));
+        assert!(syncer.headers().is_empty());
+
+        let headers: Vec<BlockHeader> = source.iter().skip(1).map(|b| b.header.clone()).collect();
+        syncer
+            .add_headers(&chain, &download(peer, Fetch::Headers, 1, 3), headers)
+            .unwrap();
+        let mut forged = source.block(1).unwrap().clone();
+        forged.data = "forged".to_owned();
+        forged.header.nonce += 1;
+        assert!(!syncer.add_blocks(&chain, &download(peer, Fetch::Blocks, 1, 1), vec![forged]));
+        assert_eq!(syncer.pending_blocks(), 0);
+    }
+
+    #[test]
+    fn a_branch_is_connected_once_it_has_more_work_than_the_blocks_it_replaces() {
+        let mut chain = mined("", 2);
+        let branch = mined("other", 3);
+        let peer = PeerId::random();
+        let mut syncer = Syncer::default();
+        let headers: Vec<BlockHeader> = branch.iter().skip(1).map(|b| b.header.clone()).collect();
+        syncer
+            .add_headers(&chain, &download(peer, Fetch::Headers, 1, 3), headers)
+            .unwrap();
+
+        let blocks: Vec<Block> = branch.iter().skip(1).take(2).cloned().collect();
+        assert!(syncer.add_blocks(&chain, &download(peer, Fetch::Blocks, 1, 2), blocks));
+        // 分叉上的两个区块和被替换的两个区块工作量一样
+        assert!(syncer.take_ready(&chain).is_empty());
+        let blocks = vec![branch.block(3).unwrap().clone()];
+        assert!(syncer.add_blocks(&chain, &download(peer, Fetch::Blocks, 3, 1), blocks));
+        let ready: Vec<Block> = syncer
+            .take_ready(&chain)
+            .into_iter()
+            .map(|(_, block)| block)
+            .collect();
+        assert_eq!(ready.len(), 3);
+        chain.reorganize(0, ready).unwrap();
+        assert_eq!(chain.tip().hash, branch.tip().hash);
+        assert_eq!(syncer.take_ready(&chain).len(), 0);
+        assert!(syncer.headers().is_empty());
+    }
+}
@@ -0,0 +1,275 @@
+use std::fmt;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use libp2p::swarm::SwarmEvent;
+use libp2p::{gossipsub, Multiaddr, PeerId};
+use log::{error, info};
+use serde::Serialize;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::mpsc;
+
+use crate::behaviour::RecipeBehaviourEvent;
+use crate::blockchain::Hash;
+
+/// Typed summary of a swarm event
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum TelemetryRecord {
+    ConnectionOpened {
+        peer_id: PeerId,
+        address: Multiaddr,
+        /// Whether we dialed the peer
+        outbound: bool,
+        /// Connections to the peer including this one
+        connections: u32,
+    },
+    ConnectionClosed {
+        peer_id: PeerId,
+        address: Multiaddr,
+        /// Why the connection closed, none when it was closed gracefully
+        cause: Option<String>,
+        /// Connections to the peer still open
+        connections: u32,
+    },
+    DialFailed {
+        peer_id: Option<PeerId>,
+        error: String,
+    },
+    IncomingConnectionFailed {
+        address: Multiaddr,
+        error: String,
+    },
+    ListenAddrAdded {
+        address: Multiaddr,
+    },
+    ListenAddrExpired {
+        address: Multiaddr,
+    },
+    MessageReceived {
+        /// Peer that relayed the message to us
+        peer_id: PeerId,
+        topic: String,
+        bytes: usize,
+    },
+    /// Any other event of one of the behaviours
+    Behaviour {
+        name: &'static str,
+    },
+    /// Swarm events without details worth recording
+    Other {
+        kind: &'static str,
+    },
+    /// The chain switched to a branch with more work
+    Reorg {
+        /// Height of the last block both branches share
+        fork: u64,
+        /// Blocks taken off the chain
+        depth: u64,
+        old_tip: Hash,
+        new_tip: Hash,
+    },
+}
+
+impl TelemetryRecord {
+    pub fn from_swarm_event<E: fmt::Display>(event: &SwarmEvent<RecipeBehaviourEvent, E>) -> Self {
+        match event {
+            SwarmEvent::Behaviour(RecipeBehaviourEvent::Gossipsub(gossipsub::Event::Message {
+                propagation_source,
+                message,
+                ..
+            })) => TelemetryRecord::MessageReceived {
+                peer_id: *propagation_source,
+                topic: message.topic.to_string(),
+                bytes: message.data.len(),
+            },
+            SwarmEvent::Behaviour(event) => TelemetryRecord::Behaviour {
+                name: behaviour_name(event),
+            },
+            SwarmEvent::ConnectionEstablished {
+                peer_id,
+                endpoint,
+                num_established,
+                ..
+            } => TelemetryRecord::ConnectionOpened {
+                peer_id: *peer_id,
+                address: endpoint.get_remote_address().clone(),
+                outbound: endpoint.is_dialer(),
+                connections: num_established.get(),
+            },
+            SwarmEvent::ConnectionClosed {
+                peer_id,
+                endpoint,
+                num_established,
+                cause,
+                ..
+            } => TelemetryRecord::ConnectionClosed {
+                peer_id: *peer_id,
+                address: endpoint.get_remote_address().clone(),
+                cause: cause.as_ref().map(|e| e.to_string()),
+                connections: *num_established,
+            },
+            SwarmEvent::OutgoingConnectionError { peer_id, error, .. } => {
+                TelemetryRecord::DialFailed {
+                    peer_id: *peer_id,
+                    error: error.to_string(),
+                }
+            }
+            SwarmEvent::IncomingConnectionError {
+                send_back_addr,
+                error,
+                ..
+            } => TelemetryRecord::IncomingConnectionFailed {
+                address: send_back_addr.clone(),
+                error: error.to_string(),
+            },
+            SwarmEvent::NewListenAddr { address, .. } => TelemetryRecord::ListenAddrAdded {
+                address: address.clone(),
+            },
+            SwarmEvent::ExpiredListenAddr { address, .. } => TelemetryRecord::ListenAddrExpired {
+                address: address.clone(),
+            },
+            SwarmEvent::IncomingConnection { .. } => TelemetryRecord::Other {
+                kind: "incoming_connection",
+            },
+            SwarmEvent::ListenerClosed { .. } => TelemetryRecord::Other {
+                kind: "listener_closed",
+            },
+            SwarmEvent::ListenerError { .. } => TelemetryRecord::Other {
+                kind: "listener_error",
+            },
+            SwarmEvent::Dialing { .. } => TelemetryRecord::Other { kind: "dialing" },
+        }
+    }
+}
+
+fn behaviour_name(event: &RecipeBehaviourEvent) -> &'static str {
+    match event {
+        RecipeBehaviourEvent::Gossipsub(_) => "gossipsub",
+        RecipeBehaviourEvent::Mdns(_) => "mdns",
+        RecipeBehaviourEvent::Kad(_) => "kad",
+        RecipeBehaviourEvent::RequestResponse(_) => "request_response",
+        RecipeBehaviourEvent::Sync(_) => "sync",
+        RecipeBehaviourEvent::RelayClient(_) => "relay_client",
+        RecipeBehaviourEvent::RelayServer(_) => "relay_server",
+        RecipeBehaviourEvent::Dcutr(_) => "dcutr",
+        RecipeBehaviourEvent::Autonat(_) => "autonat",
+        RecipeBehaviourEvent::Identify(_) => "identify",
+        RecipeBehaviourEvent::Ping(_) => "ping",
+        RecipeBehaviourEvent::Rendezvous(_) => "rendezvous",
+        RecipeBehaviourEvent::RendezvousServer(_) => "rendezvous_server",
+        RecipeBehaviourEvent::Upnp(_) => "upnp",
+    }
+}
+
+/// Totals of the recorded events
+#[derive(Debug, Default)]
+pub struct TelemetryCounters {
+    pub connections_opened: u64,
+    pub connections_closed: u64,
+    pub dial_failures: u64,
+    pub incoming_failures: u64,
+    pub messages_received: u64,
+    pub behaviour_events: u64,
+    pub reorgs: u64,
+    /// Most blocks a reorganization took off the chain
+    pub deepest_reorg: u64,
+}
+
+/// Record as written to the event sink, stamped with the unix time in milliseconds
+#[derive(Debug, Clone, Serialize)]
+pub struct TelemetryEvent {
+    pub at: u128,
+    #[serde(flatten)]
+    pub record: TelemetryRecord,
+}
+
+/// Turns swarm and chain events into records that feed the counters, the log and an optional
+/// sink
+#[derive(Debug, Default)]
+pub struct Telemetry {
+    pub counters: TelemetryCounters,
+    sink: Option<mpsc::UnboundedSender<TelemetryEvent>>,
+}
+
+impl Telemetry {
+    /// Receive every record from now on, replacing an earlier subscriber
+    pub fn subscribe(&mut self) -> mpsc::UnboundedReceiver<TelemetryEvent> {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        self.sink = Some(sender);
+        receiver
+    }
+
+    pub fn record<E: fmt::Display>(&mut self, event: &SwarmEvent<RecipeBehaviourEvent, E>) {
+        let record = TelemetryRecord::from_swarm_event(event);
+        let counters = &mut self.counters;
+        match &record {
+            TelemetryRecord::ConnectionOpened { .. } => counters.connections_opened += 1,
+            TelemetryRecord::ConnectionClosed { .. } => counters.connections_closed += 1,
+            TelemetryRecord::DialFailed { .. } => counters.dial_failures += 1,
+            TelemetryRecord::IncomingConnectionFailed { .. } => counters.incoming_failures += 1,
+            TelemetryRecord::MessageReceived { .. } => counters.messages_received += 1,
+            TelemetryRecord::Behaviour { .. } => counters.behaviour_events += 1,
+            TelemetryRecord::ListenAddrAdded { .. }
+            | TelemetryRecord::ListenAddrExpired { .. }
+            | TelemetryRecord::Other { .. }
+            | TelemetryRecord::Reorg { .. } => {}
+        }
+        info!("Swarm event: {:?}", record);
+        self.emit(record);
+    }
+
+    pub fn record_reorg(&mut self, fork: u64, depth: u64, old_tip: Hash, new_tip: Hash) {
+        self.counters.reorgs += 1;
+        self.counters.deepest_reorg = self.counters.deepest_reorg.max(depth);
+        self.emit(TelemetryRecord::Reorg {
+            fork,
+            depth,
+            old_tip,
+            new_tip,
+        });
+    }
+
+    fn emit(&mut self, record: TelemetryRecord) {
+        if let Some(sink) = &self.sink {
+            let at = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_millis())
+                .unwrap_or_default();
+            // 订阅方已退出时不再发送
+            if sink.send(TelemetryEvent { at, record }).is_err() {
+                self.sink = None;
+            }
+        }
+    }
+}
+
+/// Append every record as a JSON line to the file until the telemetry is dropped
+pub async fn write_to_file(path: PathBuf, mut records: mpsc::UnboundedReceiver<TelemetryEvent>) {
+    let mut file = match tokio::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .await
+    {
+        Ok(file) => file,
+        Err(e) => {
+            error!("can not open telemetry file {}: {}", path.display(), e);
+            return;
+        }
+    };
+    while let Some(event) = records.recv().await {
+        let mut line = match serde_json::to_vec(&event) {
+            Ok(line) => line,
+            Err(e) => {
+                error!("error encoding telemetry record: {}", e);
+                continue;
+            }
+        };
+        line.push(b'\n');
+        if let Err(e) = file.write_all(&line).await {
+            error!("error writing telemetry file {}: {}", path.display(), e);
+            return;
+        }
+    }
+}
@@ -0,0 +1,591 @@
+use std::fmt;
+use std::str::FromStr;
+
+use anyhow::{bail, Result};
+use libp2p::identity::{ed25519, Keypair, PublicKey, SigningError};
+use once_cell::sync::OnceCell;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::blockchain::Hash;
+use crate::consts::ADDRESS_PREFIX;
+use crate::multisig::MultisigSpend;
+use crate::slashing::Evidence;
+use crate::wallet;
+
+/// Genesis block of the chain, signed with every transaction so it is not valid on another chain
+static GENESIS: OnceCell<Hash> = OnceCell::new();
+
+/// Set at startup from the genesis file, before any transaction is signed or checked
+pub fn set_genesis(genesis: Hash) {
+    let _ = GENESIS.set(genesis);
+}
+
+/// Account identifier, the ed25519 public key of its owner
+#[derive(Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Address(pub [u8; 32]);
+
+impl Address {
+    /// Address of the key, none for key types other than ed25519
+    pub fn of(key: &PublicKey) -> Option<Address> {
+        let key = key.clone().try_into_ed25519().ok()?;
+        Some(Address(key.to_bytes()))
+    }
+
+    /// Address a contract deployed by the creator with the nonce lives at
+    pub fn of_contract(creator: &Address, nonce: u64) -> Address {
+        let mut seed = Vec::with_capacity(40);
+        seed.extend_from_slice(&creator.0);
+        seed.extend_from_slice(&nonce.to_be_bytes());
+        Address(Hash::digest(&seed).0)
+    }
+
+    /// Catches typos when an address is copied by hand
+    fn checksum(&self) -> [u8; 4] {
+        let digest = Hash::digest(&self.0);
+        [digest.0[0], digest.0[1], digest.0[2], digest.0[3]]
+    }
+}
+
+/// Shown in bech32 with the prefix of the network, e.g. `ant1...` on mainnet and `tant1...` on
+/// testnet
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&wallet::encode_address(&self.0))
+    }
+}
+
+impl fmt::Debug for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Address({})", self)
+    }
+}
+
+impl Address {
+    /// The checksummed hex form with the old prefix, none for other strings
+    fn parse_prefixed_hex(s: &str) -> Option<Result<Address>> {
+        let checked = s
+            .strip_prefix(ADDRESS_PREFIX)
+            .filter(|rest| rest.len() == 72)?;
+        let mut bytes = [0u8; 32];
+        let mut checksum = [0u8; 4];
+        let decoded = hex::decode_to_slice(&checked[..64], &mut bytes)
+            .and_then(|_| hex::decode_to_slice(&checked[64..], &mut checksum));
+        Some(match decoded {
+            Err(e) => Err(e.into()),
+            Ok(()) if Address(bytes).checksum() != checksum => Err(anyhow::anyhow!(
+                "address checksum does not match, check for typos"
+            )),
+            Ok(()) => Ok(Address(bytes)),
+        })
+    }
+}
+
+/// Parses addresses typed by users, which must carry a checksum: the bech32 form of our network
+/// or the older checksummed hex form
+impl FromStr for Address {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(address) = Address::parse_prefixed_hex(s) {
+            return address;
+        }
+        if s.len() == 64 && s.bytes().all(|c| c.is_ascii_hexdigit()) {
+            bail!(
+                "plain hex has no checksum, give the address in its {}1... form",
+                wallet::network().hrp()
+            );
+        }
+        let (network, key) = wallet::decode_address(s)?;
+        if network != wallet::network() {
+            bail!(
+                "address is for {:?}, this node is on {:?}",
+                network,
+                wallet::network()
+            );
+        }
+        Ok(Address(key))
+    }
+}
+
+// 链上数据与创世文件中保存不带校验和的十六进制
+impl Serialize for Address {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&hex::encode(self.0))
+    }
+}
+
+/// Files may hold plain hex besides the checksummed forms, and are read before the network is
+/// known, so an address of either network is accepted
+impl<'de> Deserialize<'de> for Address {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        let mut bytes = [0u8; 32];
+        if hex::decode_to_slice(&s, &mut bytes).is_ok() {
+            return Ok(Address(bytes));
+        }
+        Address::parse_prefixed_hex(&s)
+            .unwrap_or_else(|| wallet::decode_address(&s).map(|(_, key)| Address(key)))
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+/// Reference to an output of an earlier transaction
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct OutPoint {
+    pub tx: Hash,
+    pub index: u32,
+}
+
+impl fmt::Display for OutPoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.tx, self.index)
+    }
+}
+
+/// Coins owned by an address until an input spends them
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Output {
+    pub address: Address,
+    pub amount: u64,
+}
+
+/// When a transaction becomes valid, it can not be confirmed in an earlier block
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LockTime {
+    /// Height of the first block that may confirm the transaction
+    Height(u64),
+    /// Unix time in seconds the block confirming the transaction must be stamped with at least
+    Time(u64),
+}
+
+impl LockTime {
+    /// Whether a block at the height stamped with the time may confirm the transaction
+    pub fn is_reached(&self, height: u64, time: u64) -> bool {
+        match *self {
+            LockTime::Height(locked) => height >= locked,
+            LockTime::Time(locked) => time >= locked,
+        }
+    }
+}
+
+impl fmt::Display for LockTime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LockTime::Height(height) => write!(f, "height {}", height),
+            LockTime::Time(time) => write!(f, "time {}", time),
+        }
+    }
+}
+
+/// A height, or a unix time in seconds after `@` like `date -d @<seconds>` takes it
+impl FromStr for LockTime {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_prefix('@') {
+            Some(time) => Ok(LockTime::Time(time.parse()?)),
+            None => Ok(LockTime::Height(s.parse()?)),
+        }
+    }
+}
+
+/// What a transaction does with its amount
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TxKind {
+    /// Pay the amount to the receiver
+    #[default]
+    Transfer,
+    /// Lock the amount as stake of the sender, which makes it a validator
+    Bond,
+    /// Return the amount of the stake of the sender to its balance
+    Unbond,
+    /// Report the receiver for signing two blocks of a slot, part of its stake is burned
+    Slash,
+    /// Install the code of a contract at the receiver, the address derived from the sender and
+    /// the nonce
+    Deploy,
+    /// Run a method of the receiving contract
+    Call,
+}
+
+impl TxKind {
+    fn is_transfer(&self) -> bool {
+        *self == TxKind::Transfer
+    }
+}
+
+impl fmt::Display for TxKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TxKind::Transfer => write!(f, "transfer"),
+            TxKind::Bond => write!(f, "bond"),
+            TxKind::Unbond => write!(f, "unbond"),
+            TxKind::Slash => write!(f, "slash"),
+            TxKind::Deploy => write!(f, "deploy"),
+            TxKind::Call => write!(f, "call"),
+        }
+    }
+}
+
+/// What a contract transaction hands to the VM
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ContractOp {
+    /// WASM module, binary or text, exporting its memory and the methods it can be called with
+    Deploy {
+        #[serde(with = "serde_bytes")]
+        code: Vec<u8>,
+    },
+    Call {
+        contract: Address,
+        method: String,
+        #[serde(with = "serde_bytes")]
+        args: Vec<u8>,
+        /// Most gas the call may burn, the fee must pay for all of it
+        gas_limit: u64,
+    },
+}
+
+/// Transfer of coins between two addresses
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Transaction {
+    pub from: Address,
+    pub to: Address,
+    pub amount: u64,
+    /// Position of the transaction among those of the sender, makes identical transfers distinct
+    pub nonce: u64,
+    /// Outputs of the sender spent by the transaction, always spent as a whole
+    #[serde(default)]
+    pub inputs: Vec<OutPoint>,
+    /// What is left of the inputs after the payment, returned to the sender
+    #[serde(default)]
+    pub change: u64,
+    /// Paid to the miner of the block confirming the transaction
+    #[serde(default)]
+    pub fee: u64,
+    /// Staking transactions are sent to the sender itself
+    #[serde(default, skip_serializing_if = "TxKind::is_transfer")]
+    pub kind: TxKind,
+    /// The equivocation a slashing transaction reports
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub evidence: Option<Box<Evidence>>,
+    /// The code a deployment installs or the method a call runs
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub contract: Option<Box<ContractOp>>,
+    /// Policy and collected signatures of a spend from a shared address, which has no key to
+    /// fill `signature` with
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub multisig: Option<Box<MultisigSpend>>,
+    /// Earliest block that may confirm the transaction, e.g. for escrows and vesting
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub locktime: Option<LockTime>,
+    #[serde(with = "serde_bytes")]
+    pub signature: Vec<u8>,
+}
+
+impl Transaction {
+    /// An unsigned transaction
+    pub fn new(from: Address, to: Address, amount: u64, nonce: u64) -> Transaction {
+        Transaction {
+            from,
+            to,
+            amount,
+            nonce,
+            inputs: Vec::new(),
+            change: 0,
+            fee: 0,
+            kind: TxKind::Transfer,
+            evidence: None,
+            contract: None,
+            multisig: None,
+            locktime: None,
+            signature: Vec::new(),
+        }
+    }
+
+    /// Unsigned transaction creating the block subsidy and the fees of the block at the height for
+    /// its miner, the height as nonce keeps coinbases of different blocks apart
+    pub fn coinbase(to: Address, amount: u64, height: u64) -> Transaction {
+        Transaction::new(Address::default(), to, amount, height)
+    }
+
+    /// Whether the transaction creates coins instead of spending them, coinbases are sent from the
+    /// zero address
+    pub fn is_coinbase(&self) -> bool {
+        self.from == Address::default()
+    }
+
+    /// Unsigned transaction bonding or unbonding the amount of the stake of the sender
+    pub fn staking(kind: TxKind, from: Address, amount: u64, nonce: u64) -> Transaction {
+        Transaction {
+            kind,
+            ..Transaction::new(from, from, amount, nonce)
+        }
+    }
+
+    /// Unsigned transaction reporting the offender of the evidence, the reporter only pays the fee
+    pub fn slash(from: Address, evidence: Evidence, nonce: u64) -> Transaction {
+        Transaction {
+            kind: TxKind::Slash,
+            to: evidence.offender().unwrap_or_default(),
+            evidence: Some(Box::new(evidence)),
+            ..Transaction::new(from, Address::default(), 0, nonce)
+        }
+    }
+
+    /// Unsigned transaction deploying the code at the contract address of the sender and nonce
+    pub fn deploy(from: Address, code: Vec<u8>, nonce: u64) -> Transaction {
+        Transaction {
+            kind: TxKind::Deploy,
+            contract: Some(Box::new(ContractOp::Deploy { code })),
+            ..Transaction::new(from, Address::of_contract(&from, nonce), 0, nonce)
+        }
+    }
+
+    /// Unsigned transaction calling the method of the contract with the arguments and at most
+    /// `gas_limit` gas
+    pub fn call(
+        from: Address,
+        contract: Address,
+        method: String,
+        args: Vec<u8>,
+        gas_limit: u64,
+        nonce: u64,
+    ) -> Transaction {
+        Transaction {
+            kind: TxKind::Call,
+            contract: Some(Box::new(ContractOp::Call {
+                contract,
+                method,
+                args,
+                gas_limit,
+            })),
+            ..Transaction::new(from, contract, 0, nonce)
+        }
+    }
+
+    /// Gas the transaction may burn, zero for transactions that call no contract
+    pub fn gas_limit(&self) -> u64 {
+        match self.contract.as_deref() {
+            Some(ContractOp::Call { gas_limit, .. }) => *gas_limit,
+            _ => 0,
+        }
+    }
+
+    /// What the sender pays for the transaction, the payment and the fee
+    pub fn cost(&self) -> u64 {
+        self.amount.saturating_add(self.fee)
+    }
+
+    /// What leaves the balance of the sender, the amount of an unbonding comes from its stake
+    pub fn debit(&self) -> u64 {
+        match self.kind {
+            TxKind::Unbond | TxKind::Slash => self.fee,
+            TxKind::Transfer | TxKind::Bond | TxKind::Deploy | TxKind::Call => self.cost(),
+        }
+    }
+
+    /// Encoded size in bytes, measured like the size of blocks
+    pub fn size(&self) -> usize {
+        serde_json::to_vec(self).map_or(usize::MAX, |json| json.len())
+    }
+
+    /// Fee per 1000 bytes, miners prefer transactions paying more for the space they take
+    pub fn fee_rate(&self) -> u64 {
+        (self.fee as u128 * 1000 / self.size().max(1) as u128) as u64
+    }
+
+    /// Outputs created by the transaction, the payment first and the change if there is any, a
+    /// bonded amount, a report and contract transactions create no output
+    pub fn outputs(&self) -> Vec<Output> {
+        let mut outputs = Vec::new();
+        if matches!(self.kind, TxKind::Transfer | TxKind::Unbond) {
+            outputs.push(Output {
+                address: self.to,
+                amount: self.amount,
+            });
+        }
+        if self.change > 0 {
+            outputs.push(Output {
+                address: self.from,
+                amount: self.change,
+            });
+        }
+        outputs
+    }
+
+    /// The fields covered by the signature, in a fixed order
+    ///
+    /// The multisig part is left out, the policy is covered by `from` being its address and the
+    /// signatures sign these bytes. They start with the genesis hash, a transaction signed for a
+    /// testnet or a fork with the same keys can not be replayed here. Lists and strings are
+    /// preceded by their length, code and arguments are taken by their digest, and every optional
+    /// field starts with a tag of whether it is there, so no two transactions share their bytes
+    pub fn signing_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(128 + 36 * self.inputs.len());
+        bytes.extend_from_slice(&GENESIS.get().copied().unwrap_or_default().0);
+        bytes.extend_from_slice(&self.from.0);
+        bytes.extend_from_slice(&self.to.0);
+        bytes.extend_from_slice(&self.amount.to_be_bytes());
+        bytes.extend_from_slice(&self.nonce.to_be_bytes());
+        bytes.extend_from_slice(&(self.inputs.len() as u32).to_be_bytes());
+        for input in self.inputs.iter() {
+            bytes.extend_from_slice(&input.tx.0);
+            bytes.extend_from_slice(&input.index.to_be_bytes());
+        }
+        bytes.extend_from_slice(&self.change.to_be_bytes());
+        bytes.extend_from_slice(&self.fee.to_be_bytes());
+        bytes.push(match self.kind {
+            TxKind::Transfer => 0,
+            TxKind::Bond => 1,
+            TxKind::Unbond => 2,
+            TxKind::Slash => 3,
+            TxKind::Deploy => 4,
+            TxKind::Call => 5,
+        });
+        match self.evidence.as_ref() {
+            Some(evidence) => {
+                bytes.push(1);
+                bytes.extend_from_slice(&evidence.slot.to_be_bytes());
+                bytes.extend_from_slice(&evidence.first.hash().0);
+                bytes.extend_from_slice(&evidence.second.hash().0);
+            }
+            None => bytes.push(0),
+        }
+        match self.contract.as_deref() {
+            Some(ContractOp::Deploy { code }) => {
+                bytes.push(1);
+                bytes.extend_from_slice(&Hash::digest(code).0);
+            }
+            Some(ContractOp::Call {
+                contract,
+                method,
+                args,
+                gas_limit,
+            }) => {
+                bytes.push(2);
+                bytes.extend_from_slice(&contract.0);
+                bytes.extend_from_slice(&(method.len() as u32).to_be_bytes());
+                bytes.extend_from_slice(method.as_bytes());
+                bytes.extend_from_slice(&Hash::digest(args).0);
+                bytes.extend_from_slice(&gas_limit.to_be_bytes());
+            }
+            None => bytes.push(0),
+        }
+        match self.locktime {
+            Some(LockTime::Height(height)) => {
+                bytes.push(1);
+                bytes.extend_from_slice(&height.to_be_bytes());
+            }
+            Some(LockTime::Time(time)) => {
+                bytes.push(2);
+                bytes.extend_from_slice(&time.to_be_bytes());
+            }
+            None => bytes.push(0),
+        }
+        bytes
+    }
+
+    /// Sign with the key of the sender, which must be the key `from` was derived from
+    pub fn sign(&mut self, keys: &Keypair) -> Result<(), SigningError> {
+        self.signature = keys.sign(&self.signing_bytes())?;
+        Ok(())
+    }
+
+    /// Add the signature of a signer of the shared address the transaction spends from
+    pub fn cosign(&mut self, keys: &Keypair) -> Result<Address> {
+        let message = self.signing_bytes();
+        match self.multisig.as_deref_mut() {
+            Some(spend) => spend.sign(keys, &message),
+            None => bail!("transaction {} is no multisig spend", self.id()),
+        }
+    }
+
+    /// Whether the signature was made by the key of the sender, or enough signers of the policy
+    /// of a shared sender signed
+    pub fn verify(&self) -> bool {
+        if let Some(spend) = self.multisig.as_deref() {
+            return spend.verify(&self.from, &self.signing_bytes());
+        }
+        match ed25519::PublicKey::try_from_bytes(&self.from.0) {
+            Ok(key) => key.verify(&self.signing_bytes(), &self.signature),
+            Err(_) => false,
+        }
+    }
+
+    /// Transaction id, the signature is left out so it can not be changed by re-signing
+    pub fn id(&self) -> Hash {
+        Hash::digest(&self.signing_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn transfer() -> Transaction {
+        Transaction::new(Address([1; 32]), Address([2; 32]), 5, 0)
+    }
+
+    #[test]
+    fn transactions_that_differ_in_a_field_sign_different_bytes() {
+        let input = OutPoint {
+            tx: Hash::digest(b"funding"),
+            index: 0,
+        };
+        let with_inputs = |inputs: Vec<OutPoint>| Transaction {
+            inputs,
+            ..transfer()
+        };
+        let call = |method: &str, args: &[u8]| Transaction {
+            kind: TxKind::Call,
+            contract: Some(Box::new(ContractOp::Call {
+                contract: Address([3; 32]),
+                method: method.to_owned(),
+                args: args.to_vec(),
+                gas_limit: 1_000,
+            })),
+            ..transfer()
+        };
+        let variants = vec![
+            transfer(),
+            with_inputs(vec![input]),
+            with_inputs(vec![input, input]),
+            Transaction::staking(TxKind::Bond, Address([1; 32]), 5, 0),
+            Transaction {
+                to: Address([1; 32]),
+                ..transfer()
+            },
+            Transaction {
+                locktime: Some(LockTime::Height(1)),
+                ..transfer()
+            },
+            Transaction {
+                locktime: Some(LockTime::Time(1)),
+                ..transfer()
+            },
+            Transaction {
+                kind: TxKind::Deploy,
+                contract: Some(Box::new(ContractOp::Deploy { code: Vec::new() })),
+                ..transfer()
+            },
+            call("ab", b""),
+            call("a", b"b"),
+        ];
+        for (i, first) in variants.iter().enumerate() {
+            for second in &variants[i + 1..] {
+                assert_ne!(first.signing_bytes(), second.signing_bytes());
+            }
+        }
+    }
+
+    #[test]
+    fn every_optional_field_is_tagged_when_it_is_absent() {
+        let bytes = transfer().signing_bytes();
+        // 输入个数在前，类型、证据、合约和锁定时间各占一个标签
+        assert_eq!(bytes.len(), 32 * 3 + 8 * 2 + 4 + 8 * 2 + 4);
+        assert_eq!(&bytes[bytes.len() - 4..], &[0, 0, 0, 0]);
+    }
+}
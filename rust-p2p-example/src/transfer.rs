@@ -0,0 +1,161 @@
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::{bail, ensure, Result};
+use libp2p::PeerId;
+use log::debug;
+use once_cell::sync::Lazy;
+use sha2::{Digest, Sha256};
+
+use crate::consts::{TRANSFER_CHUNK_SIZE, TRANSFER_MAX_CHUNKS, TRANSFER_TIMEOUT};
+
+/// First byte of every chunk, distinct from the codec bytes of unchunked payloads
+const CHUNK_MARKER: u8 = 0x02;
+
+/// Marker, transfer id, chunk index, chunk count and the SHA-256 of the whole payload
+const HEADER_LEN: usize = 1 + 8 + 4 + 4 + 32;
+
+/// Transfer ids only need to be unique per sender, seeding from the clock avoids reusing the ids
+/// of a previous run
+static NEXT_TRANSFER_ID: Lazy<AtomicU64> = Lazy::new(|| {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    AtomicU64::new(now.as_nanos() as u64)
+});
+
+/// Whether a gossip payload is a chunk of a larger transfer
+pub fn is_chunk(data: &[u8]) -> bool {
+    data.first() == Some(&CHUNK_MARKER)
+}
+
+/// Split a payload that does not fit into a single gossip message into sequenced chunks
+///
+/// Small payloads are returned as they are
+pub fn split(payload: Vec<u8>) -> Result<Vec<Vec<u8>>> {
+    if payload.len() <= TRANSFER_CHUNK_SIZE {
+        return Ok(vec![payload]);
+    }
+    let total = (payload.len() + TRANSFER_CHUNK_SIZE - 1) / TRANSFER_CHUNK_SIZE;
+    ensure!(
+        total <= TRANSFER_MAX_CHUNKS,
+        "payload of {} bytes exceeds the transfer limit",
+        payload.len()
+    );
+    let id = NEXT_TRANSFER_ID.fetch_add(1, Ordering::Relaxed);
+    let digest = Sha256::digest(&payload);
+    let chunks = payload
+        .chunks(TRANSFER_CHUNK_SIZE)
+        .enumerate()
+        .map(|(index, data)| {
+            let mut chunk = Vec::with_capacity(HEADER_LEN + data.len());
+            chunk.push(CHUNK_MARKER);
+            chunk.extend_from_slice(&id.to_be_bytes());
+            chunk.extend_from_slice(&(index as u32).to_be_bytes());
+            chunk.extend_from_slice(&(total as u32).to_be_bytes());
+            chunk.extend_from_slice(&digest);
+            chunk.extend_from_slice(data);
+            chunk
+        })
+        .collect();
+    Ok(chunks)
+}
+
+/// A transfer of which only some chunks arrived so far
+#[derive(Debug)]
+struct PartialTransfer {
+    digest: [u8; 32],
+    chunks: Vec<Option<Vec<u8>>>,
+    received: usize,
+    started: Instant,
+}
+
+/// Collects chunks per sender until a payload is complete
+#[derive(Debug, Default)]
+pub struct Reassembler {
+    transfers: HashMap<(PeerId, u64), PartialTransfer>,
+}
+
+impl Reassembler {
+    /// Store a chunk, returning the whole payload once its last chunk arrived and the checksum
+    /// matches
+    ///
+    /// Transfers announcing more chunks than a payload of `max_size` bytes needs are rejected
+    /// before anything is buffered
+    pub fn accept(
+        &mut self,
+        source: PeerId,
+        chunk: &[u8],
+        max_size: usize,
+    ) -> Result<Option<Vec<u8>>> {
+        self.expire();
+        ensure!(chunk.len() > HEADER_LEN, "truncated chunk");
+        let id = u64::from_be_bytes(chunk[1..9].try_into()?);
+        let index = u32::from_be_bytes(chunk[9..13].try_into()?) as usize;
+        let total = u32::from_be_bytes(chunk[13..17].try_into()?) as usize;
+        let digest: [u8; 32] = chunk[17..HEADER_LEN].try_into()?;
+        ensure!(
+            total > 1 && total <= TRANSFER_MAX_CHUNKS && index < total,
+            "chunk {} of {} out of range",
+            index,
+            total
+        );
+        ensure!(
+            (total - 1) * TRANSFER_CHUNK_SIZE < max_size,
+            "transfer of {} chunks exceeds the maximum message size of {} bytes",
+            total,
+            max_size
+        );
+
+        let transfer = self
+            .transfers
+            .entry((source, id))
+            .or_insert_with(|| PartialTransfer {
+                digest,
+                chunks: vec![None; total],
+                received: 0,
+                started: Instant::now(),
+            });
+        if transfer.digest != digest || transfer.chunks.len() != total {
+            bail!("chunk {} does not belong to transfer {}", index, id);
+        }
+        if transfer.chunks[index].is_none() {
+            transfer.chunks[index] = Some(chunk[HEADER_LEN..].to_vec());
+            transfer.received += 1;
+        }
+        if transfer.received < total {
+            return Ok(None);
+        }
+
+        let transfer = self
+            .transfers
+            .remove(&(source, id))
+            .expect("transfer is there");
+        let payload: Vec<u8> = transfer.chunks.into_iter().flatten().flatten().collect();
+        ensure!(
+            Sha256::digest(&payload)[..] == transfer.digest[..],
+            "checksum mismatch of transfer {}",
+            id
+        );
+        Ok(Some(payload))
+    }
+
+    /// Drop transfers whose chunks stopped arriving
+    fn expire(&mut self) {
+        self.transfers.retain(|(source, id), transfer| {
+            let alive = transfer.started.elapsed() < TRANSFER_TIMEOUT;
+            if !alive {
+                debug!(
+                    "transfer {} from {} timed out with {} of {} chunks",
+                    id,
+                    source,
+                    transfer.received,
+                    transfer.chunks.len()
+                );
+            }
+            alive
+        });
+    }
+}
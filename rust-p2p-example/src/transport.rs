@@ -0,0 +1,111 @@
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+
+use anyhow::{anyhow, Context, Result};
+use either::Either;
+use libp2p::core::muxing::StreamMuxerBox;
+use libp2p::core::transport::{Boxed, OptionalTransport};
+use libp2p::core::upgrade::Version;
+use libp2p::futures::{AsyncRead, AsyncWrite};
+use libp2p::identity::Keypair;
+use libp2p::pnet::{PnetConfig, PreSharedKey};
+use libp2p::{noise, quic, tcp, tls, websocket, yamux, PeerId, Transport};
+
+use crate::config::CONFIG;
+use crate::security::{SecurityProtocol, SelectSecurityUpgrade};
+
+/// A fully upgraded transport that can be plugged into the swarm builder
+pub type BoxedTransport = Boxed<(PeerId, StreamMuxerBox)>;
+
+/// A transport that is only present when enabled in the config
+pub type NodeTransport = OptionalTransport<BoxedTransport>;
+
+/// Read a private network key in the `/key/swarm/psk/1.0.0/` format used by go-ipfs
+pub fn load_swarm_key(path: &Path) -> Result<PreSharedKey> {
+    let content = fs::read_to_string(path)
+        .with_context(|| format!("can not read swarm key {}", path.display()))?;
+    content
+        .parse()
+        .map_err(|e| anyhow!("invalid swarm key {}: {}", path.display(), e))
+}
+
+/// TCP secured with noise and/or TLS and multiplexed with yamux, wrapped in a private network when
+/// a swarm key is given so peers without that key are rejected before any libp2p handshake
+pub fn tcp_transport(
+    key: &Keypair,
+    psk: Option<PreSharedKey>,
+) -> Result<BoxedTransport, Box<dyn Error + Send + Sync>> {
+    let tcp = tcp::tokio::Transport::new(tcp::Config::default());
+    let base = match psk {
+        Some(psk) => {
+            Either::Left(tcp.and_then(move |socket, _| PnetConfig::new(psk).handshake(socket)))
+        }
+        None => Either::Right(tcp),
+    };
+    secure(base, key)
+}
+
+/// QUIC transport, only built when enabled
+///
+/// QUIC brings its own encryption and can not be wrapped in a private network
+pub fn quic_transport(key: &Keypair, enabled: bool) -> NodeTransport {
+    if !enabled {
+        return OptionalTransport::none();
+    }
+    let transport = quic::tokio::Transport::new(quic::Config::new(key))
+        .map(|(peer_id, conn), _| (peer_id, StreamMuxerBox::new(conn)))
+        .boxed();
+    OptionalTransport::some(transport)
+}
+
+/// WebSocket over TCP, secured and multiplexed like TCP so js-libp2p peers can join
+pub fn websocket_transport(
+    key: &Keypair,
+    psk: Option<PreSharedKey>,
+) -> Result<NodeTransport, Box<dyn Error + Send + Sync>> {
+    if !CONFIG.websocket {
+        return Ok(OptionalTransport::none());
+    }
+    let ws = websocket::WsConfig::new(tcp::tokio::Transport::new(tcp::Config::default()));
+    let base = match psk {
+        Some(psk) => {
+            Either::Left(ws.and_then(move |socket, _| PnetConfig::new(psk).handshake(socket)))
+        }
+        None => Either::Right(ws),
+    };
+    Ok(OptionalTransport::some(secure(base, key)?))
+}
+
+/// Secure the raw connections with the configured handshakes and multiplex them with yamux
+fn secure<T>(base: T, key: &Keypair) -> Result<BoxedTransport, Box<dyn Error + Send + Sync>>
+where
+    T: Transport + Send + Unpin + 'static,
+    T::Output: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    T::Dial: Send + 'static,
+    T::ListenerUpgrade: Send + 'static,
+    T::Error: Send + Sync + 'static,
+{
+    let builder = base.upgrade(Version::V1Lazy);
+    let transport = match CONFIG.security {
+        SecurityProtocol::Noise => builder
+            .authenticate(noise::Config::new(key)?)
+            .multiplex(yamux::Config::default())
+            .map(|(peer_id, conn), _| (peer_id, StreamMuxerBox::new(conn)))
+            .boxed(),
+        SecurityProtocol::Tls => builder
+            .authenticate(tls::Config::new(key)?)
+            .multiplex(yamux::Config::default())
+            .map(|(peer_id, conn), _| (peer_id, StreamMuxerBox::new(conn)))
+            .boxed(),
+        SecurityProtocol::Both => builder
+            .authenticate(SelectSecurityUpgrade::new(
+                noise::Config::new(key)?,
+                tls::Config::new(key)?,
+            ))
+            .multiplex(yamux::Config::default())
+            .map(|(peer_id, conn), _| (peer_id, StreamMuxerBox::new(conn)))
+            .boxed(),
+    };
+    Ok(transport)
+}
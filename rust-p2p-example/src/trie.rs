@@ -0,0 +1,576 @@
+use std::error::Error;
+use std::fmt;
+use std::marker::PhantomData;
+
+use once_cell::unsync::OnceCell;
+use serde::de::{DeserializeOwned, SeqAccess, Visitor};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::blockchain::Hash;
+
+/// Prefixes of the node encodings, keep one kind of node from passing as another
+const LEAF: u8 = 0;
+const EXTENSION: u8 = 1;
+const BRANCH: u8 = 2;
+
+/// Canonical bytes of a key or value, the CBOR encoding
+fn encode<T: Serialize>(value: &T) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    ciborium::into_writer(value, &mut bytes).expect("state entries encode");
+    bytes
+}
+
+/// Nibbles of the hash of the encoded key, every path is 64 nibbles long
+fn path_of<K: Serialize>(key: &K) -> Vec<u8> {
+    Hash::digest(&encode(key))
+        .0
+        .iter()
+        .flat_map(|byte| [byte >> 4, byte & 0x0f])
+        .collect()
+}
+
+fn common_prefix(a: &[u8], b: &[u8]) -> usize {
+    a.iter().zip(b).take_while(|(a, b)| a == b).count()
+}
+
+/// A node as it is hashed, proofs are made of them
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProofNode {
+    /// The rest of the path of an entry and its encoded value
+    Leaf {
+        path: Vec<u8>,
+        #[serde(with = "serde_bytes")]
+        value: Vec<u8>,
+    },
+    /// Nibbles shared by every entry below the child
+    Extension { path: Vec<u8>, child: Hash },
+    /// Hashes of the children by the next nibble, the default hash where there is none
+    Branch { children: Vec<Hash> },
+}
+
+impl ProofNode {
+    pub fn hash(&self) -> Hash {
+        let mut bytes = Vec::new();
+        match self {
+            ProofNode::Leaf { path, value } => {
+                bytes.push(LEAF);
+                bytes.push(path.len() as u8);
+                bytes.extend_from_slice(path);
+                bytes.extend_from_slice(value);
+            }
+            ProofNode::Extension { path, child } => {
+                bytes.push(EXTENSION);
+                bytes.push(path.len() as u8);
+                bytes.extend_from_slice(path);
+                bytes.extend_from_slice(&child.0);
+            }
+            ProofNode::Branch { children } => {
+                bytes.push(BRANCH);
+                for child in children.iter() {
+                    bytes.extend_from_slice(&child.0);
+                }
+            }
+        }
+        Hash::digest(&bytes)
+    }
+}
+
+/// The proof does not lead from the root to the key
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InvalidProof;
+
+impl fmt::Display for InvalidProof {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "state proof does not match the state root")
+    }
+}
+
+impl Error for InvalidProof {}
+
+/// The value the proof shows the key has in the trie with the root, none when the proof shows
+/// that the key is not set
+pub fn verify_proof<K: Serialize, V: DeserializeOwned>(
+    root: &Hash,
+    key: &K,
+    proof: &[ProofNode],
+) -> Result<Option<V>, InvalidProof> {
+    if proof.is_empty() {
+        return match *root == Hash::default() {
+            true => Ok(None),
+            false => Err(InvalidProof),
+        };
+    }
+    let path = path_of(key);
+    let mut rest = path.as_slice();
+    let mut expected = *root;
+    for node in proof.iter() {
+        if node.hash() != expected {
+            return Err(InvalidProof);
+        }
+        match node {
+            ProofNode::Leaf { path, value } => {
+                if path.as_slice() != rest {
+                    return Ok(None);
+                }
+                return ciborium::from_reader(value.as_slice())
+                    .map(Some)
+                    .map_err(|_| InvalidProof);
+            }
+            ProofNode::Extension { path, child } => match rest.strip_prefix(path.as_slice()) {
+                Some(below) => {
+                    rest = below;
+                    expected = *child;
+                }
+                None => return Ok(None),
+            },
+            ProofNode::Branch { children } => {
+                let slot = *rest.first().ok_or(InvalidProof)? as usize;
+                let child = *children.get(slot).ok_or(InvalidProof)?;
+                if child == Hash::default() {
+                    return Ok(None);
+                }
+                rest = &rest[1..];
+                expected = child;
+            }
+        }
+    }
+    // 证明在到达叶子之前就结束了
+    Err(InvalidProof)
+}
+
+/// Root committing to the roots of the parts of a state, in their order
+pub fn combine(roots: &[Hash]) -> Hash {
+    let bytes: Vec<u8> = roots.iter().flat_map(|root| root.0).collect();
+    Hash::digest(&bytes)
+}
+
+#[derive(Clone)]
+struct Node<K, V> {
+    kind: Kind<K, V>,
+    /// Computed when the root is asked for, nodes are replaced rather than changed
+    hash: OnceCell<Hash>,
+}
+
+#[derive(Clone)]
+enum Kind<K, V> {
+    Leaf {
+        path: Vec<u8>,
+        key: K,
+        value: V,
+    },
+    /// The child is always a branch
+    Extension {
+        path: Vec<u8>,
+        child: Box<Node<K, V>>,
+    },
+    /// Sixteen children, at least two of them set
+    Branch {
+        children: Vec<Option<Box<Node<K, V>>>>,
+    },
+}
+
+impl<K: Serialize, V: Serialize> Node<K, V> {
+    fn new(kind: Kind<K, V>) -> Box<Node<K, V>> {
+        Box::new(Node {
+            kind,
+            hash: OnceCell::new(),
+        })
+    }
+
+    fn leaf(path: Vec<u8>, key: K, value: V) -> Box<Node<K, V>> {
+        Node::new(Kind::Leaf { path, key, value })
+    }
+
+    fn branch(children: Vec<Option<Box<Node<K, V>>>>) -> Box<Node<K, V>> {
+        Node::new(Kind::Branch { children })
+    }
+
+    /// The node below `path`, the node itself when the path is empty
+    fn extend(path: &[u8], node: Box<Node<K, V>>) -> Box<Node<K, V>> {
+        if path.is_empty() {
+            return node;
+        }
+        if let Kind::Branch { .. } = node.kind {
+            return Node::new(Kind::Extension {
+                path: path.to_vec(),
+                child: node,
+            });
+        }
+        match node.kind {
+            Kind::Leaf {
+                path: below,
+                key,
+                value,
+            } => Node::leaf([path, &below].concat(), key, value),
+            Kind::Extension { path: below, child } => Node::new(Kind::Extension {
+                path: [path, &below].concat(),
+                child,
+            }),
+            Kind::Branch { .. } => unreachable!("branches are handled above"),
+        }
+    }
+
+    fn hash(&self) -> Hash {
+        *self.hash.get_or_init(|| self.proof_node().hash())
+    }
+
+    fn proof_node(&self) -> ProofNode {
+        match &self.kind {
+            Kind::Leaf { path, value, .. } => ProofNode::Leaf {
+                path: path.clone(),
+                value: encode(value),
+            },
+            Kind::Extension { path, child } => ProofNode::Extension {
+                path: path.clone(),
+                child: child.hash(),
+            },
+            Kind::Branch { children } => ProofNode::Branch {
+                children: children
+                    .iter()
+                    .map(|child| child.as_ref().map_or(Hash::default(), |child| child.hash()))
+                    .collect(),
+            },
+        }
+    }
+}
+
+fn empty_children<K, V>() -> Vec<Option<Box<Node<K, V>>>> {
+    (0..16).map(|_| None).collect()
+}
+
+fn insert<K: Serialize, V: Serialize>(
+    node: Option<Box<Node<K, V>>>,
+    path: &[u8],
+    key: K,
+    value: V,
+) -> (Box<Node<K, V>>, Option<V>) {
+    let node = match node {
+        Some(node) => node,
+        None => return (Node::leaf(path.to_vec(), key, value), None),
+    };
+    match node.kind {
+        Kind::Leaf {
+            path: leaf_path,
+            key: leaf_key,
+            value: leaf_value,
+        } => {
+            if leaf_path == path {
+                return (Node::leaf(leaf_path, key, value), Some(leaf_value));
+            }
+            // 所有路径等长，不同的路径必然在某一位分叉
+            let common = common_prefix(&leaf_path, path);
+            let mut children = empty_children();
+            children[leaf_path[common] as usize] = Some(Node::leaf(
+                leaf_path[common + 1..].to_vec(),
+                leaf_key,
+                leaf_value,
+            ));
+            children[path[common] as usize] =
+                Some(Node::leaf(path[common + 1..].to_vec(), key, value));
+            (Node::extend(&path[..common], Node::branch(children)), None)
+        }
+        Kind::Extension {
+            path: extension_path,
+            child,
+        } => {
+            let common = common_prefix(&extension_path, path);
+            if common == extension_path.len() {
+                let (child, old) = insert(Some(child), &path[common..], key, value);
+                return (Node::extend(&extension_path, child), old);
+            }
+            let mut children = empty_children();
+            children[extension_path[common] as usize] =
+                Some(Node::extend(&extension_path[common + 1..], child));
+            children[path[common] as usize] =
+                Some(Node::leaf(path[common + 1..].to_vec(), key, value));
+            (Node::extend(&path[..common], Node::branch(children)), None)
+        }
+        Kind::Branch { mut children } => {
+            let slot = path[0] as usize;
+            let (child, old) = insert(children[slot].take(), &path[1..], key, value);
+            children[slot] = Some(child);
+            (Node::branch(children), old)
+        }
+    }
+}
+
+fn remove<K: Serialize, V: Serialize>(
+    node: Box<Node<K, V>>,
+    path: &[u8],
+) -> (Option<Box<Node<K, V>>>, Option<V>) {
+    // 键不存在时原样返回，保留算好的哈希
+    let absent = match &node.kind {
+        Kind::Leaf {
+            path: leaf_path, ..
+        } => leaf_path.as_slice() != path,
+        Kind::Extension {
+            path: extension_path,
+            ..
+        } => !path.starts_with(extension_path),
+        Kind::Branch { children } => children[path[0] as usize].is_none(),
+    };
+    if absent {
+        return (Some(node), None);
+    }
+    match node.kind {
+        Kind::Leaf { value, .. } => (None, Some(value)),
+        Kind::Extension {
+            path: extension_path,
+            child,
+        } => {
+            let (child, old) = remove(child, &path[extension_path.len()..]);
+            (child.map(|child| Node::extend(&extension_path, child)), old)
+        }
+        Kind::Branch { mut children } => {
+            let slot = path[0] as usize;
+            let (child, old) = remove(children[slot].take().expect("slot is set"), &path[1..]);
+            children[slot] = child;
+            // 只剩一个子节点的分支并入它的子节点
+            let mut set = children
+                .iter()
+                .enumerate()
+                .filter(|(_, child)| child.is_some())
+                .map(|(slot, _)| slot);
+            let node = match (set.next(), set.next()) {
+                (Some(only), None) => {
+                    let child = children[only].take().expect("slot is set");
+                    Node::extend(&[only as u8], child)
+                }
+                _ => Node::branch(children),
+            };
+            (Some(node), old)
+        }
+    }
+}
+
+/// Merkle-Patricia trie mapping keys to values, the root commits to every entry
+///
+/// Paths are the nibbles of the hash of the encoded key, so every path has the same length and
+/// the trie stays shallow whatever the keys are
+#[derive(Clone)]
+pub struct StateTrie<K, V> {
+    root: Option<Box<Node<K, V>>>,
+    len: usize,
+}
+
+impl<K, V> Default for StateTrie<K, V> {
+    fn default() -> Self {
+        StateTrie { root: None, len: 0 }
+    }
+}
+
+impl<K: Serialize, V: Serialize> StateTrie<K, V> {
+    pub fn new() -> StateTrie<K, V> {
+        StateTrie::default()
+    }
+
+    /// Root hash of the entries, the default hash when there are none
+    pub fn root(&self) -> Hash {
+        self.root
+            .as_ref()
+            .map_or(Hash::default(), |root| root.hash())
+    }
+
+    pub fn get(&self, key: &K) -> Option<&V> {
+        let path = path_of(key);
+        let mut rest = path.as_slice();
+        let mut node = self.root.as_deref()?;
+        loop {
+            match &node.kind {
+                Kind::Leaf { path, value, .. } => {
+                    return (path.as_slice() == rest).then_some(value)
+                }
+                Kind::Extension { path, child } => {
+                    rest = rest.strip_prefix(path.as_slice())?;
+                    node = child;
+                }
+                Kind::Branch { children } => {
+                    node = children[*rest.first()? as usize].as_deref()?;
+                    rest = &rest[1..];
+                }
+            }
+        }
+    }
+
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.get(key).is_some()
+    }
+
+    /// Set the value of the key, returns the value it replaced
+    pub fn insert(&mut self, key: K, value: V) -> Option<V> {
+        let path = path_of(&key);
+        let (root, old) = insert(self.root.take(), &path, key, value);
+        self.root = Some(root);
+        if old.is_none() {
+            self.len += 1;
+        }
+        old
+    }
+
+    pub fn remove(&mut self, key: &K) -> Option<V> {
+        let root = self.root.take()?;
+        let (root, old) = remove(root, &path_of(key));
+        self.root = root;
+        if old.is_some() {
+            self.len -= 1;
+        }
+        old
+    }
+
+    /// Entries in the order of their paths
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &V)> {
+        let mut entries = Vec::with_capacity(self.len);
+        let mut stack: Vec<&Node<K, V>> = self.root.as_deref().into_iter().collect();
+        while let Some(node) = stack.pop() {
+            match &node.kind {
+                Kind::Leaf { key, value, .. } => entries.push((key, value)),
+                Kind::Extension { child, .. } => stack.push(child),
+                Kind::Branch { children } => {
+                    stack.extend(children.iter().rev().filter_map(|child| child.as_deref()))
+                }
+            }
+        }
+        entries.into_iter()
+    }
+
+    /// Nodes from the root down to the key, or to where the key would be when it is not set
+    pub fn prove(&self, key: &K) -> Vec<ProofNode> {
+        let path = path_of(key);
+        let mut rest = path.as_slice();
+        let mut proof = Vec::new();
+        let mut next = self.root.as_deref();
+        while let Some(node) = next {
+            proof.push(node.proof_node());
+            next = match &node.kind {
+                Kind::Leaf { .. } => None,
+                Kind::Extension { path, child } => {
+                    rest.strip_prefix(path.as_slice()).map(|below| {
+                        rest = below;
+                        child.as_ref()
+                    })
+                }
+                Kind::Branch { children } => match rest.split_first() {
+                    Some((slot, below)) => {
+                        rest = below;
+                        children[*slot as usize].as_deref()
+                    }
+                    None => None,
+                },
+            };
+        }
+        proof
+    }
+}
+
+impl<K: Serialize + fmt::Debug, V: Serialize + fmt::Debug> fmt::Debug for StateTrie<K, V> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_map().entries(self.iter()).finish()
+    }
+}
+
+/// Tries with the same root hold the same entries
+impl<K: Serialize, V: Serialize> PartialEq for StateTrie<K, V> {
+    fn eq(&self, other: &Self) -> bool {
+        self.root() == other.root()
+    }
+}
+
+impl<K: Serialize, V: Serialize> Eq for StateTrie<K, V> {}
+
+// 快照中保存为键值对列表，读入时重建
+impl<K: Serialize, V: Serialize> Serialize for StateTrie<K, V> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(self.iter())
+    }
+}
+
+impl<'de, K: Serialize + Deserialize<'de>, V: Serialize + Deserialize<'de>> Deserialize<'de>
+    for StateTrie<K, V>
+{
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct Entries<K, V>(PhantomData<(K, V)>);
+
+        impl<'de, K: Serialize + Deserialize<'de>, V: Serialize + Deserialize<'de>> Visitor<'de>
+            for Entries<K, V>
+        {
+            type Value = StateTrie<K, V>;
+
+            fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                write!(f, "a list of state entries")
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+                let mut trie = StateTrie::new();
+                while let Some((key, value)) = seq.next_element::<(K, V)>()? {
+                    trie.insert(key, value);
+                }
+                Ok(trie)
+            }
+        }
+
+        deserializer.deserialize_seq(Entries(PhantomData))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn trie(keys: impl Iterator<Item = u64>) -> StateTrie<u64, u64> {
+        let mut trie = StateTrie::new();
+        for key in keys {
+            trie.insert(key, key * 10);
+        }
+        trie
+    }
+
+    #[test]
+    fn the_root_depends_on_the_entries_only() {
+        let forwards = trie(0..50);
+        let backwards = trie((0..50).rev());
+        assert_eq!(forwards.root(), backwards.root());
+        assert_eq!(forwards.iter().count(), 50);
+        assert_eq!(forwards.get(&7), Some(&70));
+
+        let mut removed = trie(0..51);
+        assert_ne!(removed.root(), forwards.root());
+        assert_eq!(removed.remove(&50), Some(500));
+        assert_eq!(removed.root(), forwards.root());
+        for key in 0..50 {
+            removed.remove(&key);
+        }
+        assert_eq!(removed.root(), Hash::default());
+    }
+
+    #[test]
+    fn proofs_show_the_value_or_its_absence_against_the_root() {
+        let trie = trie(0..50);
+        let root = trie.root();
+        for key in 0..50 {
+            assert_eq!(
+                verify_proof(&root, &key, &trie.prove(&key)),
+                Ok(Some(key * 10))
+            );
+        }
+        assert_eq!(
+            verify_proof::<u64, u64>(&root, &99, &trie.prove(&99)),
+            Ok(None)
+        );
+
+        let mut forged = trie.prove(&7);
+        if let Some(ProofNode::Leaf { value, .. }) = forged.last_mut() {
+            value.clear();
+            ciborium::into_writer(&71u64, &mut *value).unwrap();
+        }
+        assert_eq!(
+            verify_proof::<u64, u64>(&root, &7, &forged),
+            Err(InvalidProof)
+        );
+        assert_eq!(
+            verify_proof::<u64, u64>(&root, &7, &forged[..forged.len() - 1]),
+            Err(InvalidProof)
+        );
+        assert_eq!(verify_proof::<u64, u64>(&root, &7, &[]), Err(InvalidProof));
+    }
+}
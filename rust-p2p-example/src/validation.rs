@@ -0,0 +1,660 @@
+use std::collections::HashSet;
+use std::error::Error;
+use std::fmt;
+
+use crate::blockchain::{merkle_root, Block, BlockHeader, ChainParams, Hash};
+use crate::consensus::{Consensus, Validator};
+use crate::consts::MAX_FUTURE_BLOCK_TIME;
+use crate::ledger::LedgerError;
+use crate::transaction::{Address, LockTime, OutPoint, Transaction, TxKind};
+
+/// Consensus rule a block breaks
+#[derive(Debug, PartialEq, Eq)]
+pub enum ValidationError {
+    /// The block is not at the height after the tip
+    NotNextHeight {
+        expected: u64,
+        actual: u64,
+    },
+    /// The block builds on another block than the tip
+    UnknownParent {
+        prev_hash: Hash,
+        tip: Hash,
+    },
+    /// The hash of the block is not the hash of its header
+    HashMismatch {
+        claimed: Hash,
+        computed: Hash,
+    },
+    /// The header claims another difficulty than the retarget schedule demands
+    DifficultyMismatch {
+        expected: u32,
+        actual: u32,
+    },
+    /// The hash does not have the leading zero bits the difficulty demands
+    InsufficientWork {
+        bits: u32,
+        difficulty: u32,
+    },
+    /// A proof-of-stake header without a proposer
+    MissingProposer,
+    /// The proof-of-stake block is not in a later slot than its parent
+    SlotNotAfterParent {
+        slot: u64,
+        parent: u64,
+    },
+    /// Another validator is entitled to propose the block of the slot
+    WrongProposer {
+        slot: u64,
+        expected: Address,
+        actual: Address,
+    },
+    InvalidProposerSignature,
+    /// Nobody has stake, so no block can be proposed
+    NoValidators,
+    /// The block is not stamped after the median timestamp of the blocks before it
+    TimestampNotAfterMedian {
+        timestamp: u64,
+        median: u64,
+    },
+    /// The block claims to be from further in the future than clocks may drift apart
+    TimestampInFuture {
+        timestamp: u64,
+        now: u64,
+    },
+    TooManyTransactions {
+        count: usize,
+        max: usize,
+    },
+    /// Encoded size of the block in bytes
+    TooLarge {
+        size: usize,
+        max: usize,
+    },
+    /// The gas limits of the calls of the block add up to more than a block may use
+    TooMuchGas {
+        gas: u64,
+        max: u64,
+    },
+    DataHashMismatch,
+    MerkleRootMismatch {
+        header: Hash,
+        computed: Hash,
+    },
+    /// The state after the transactions of the block is not the state the header commits to
+    StateRootMismatch {
+        header: Hash,
+        computed: Hash,
+    },
+    /// The receipts of the transactions of the block are not the ones the header commits to
+    ReceiptsRootMismatch {
+        header: Hash,
+        computed: Hash,
+    },
+    DuplicateTransaction(Hash),
+    /// The transaction is locked until a later block
+    LockedTransaction {
+        txid: Hash,
+        locktime: LockTime,
+    },
+    InvalidSignature(Hash),
+    /// A coinbase that is not the first transaction of the block
+    MisplacedCoinbase(Hash),
+    /// A coinbase with inputs, change, a fee or a nonce other than the height of the block
+    InvalidCoinbase(Hash),
+    /// The coinbase pays more than the subsidy and the fees of the block
+    CoinbaseTooLarge {
+        amount: u64,
+        max: u64,
+    },
+    /// A report without evidence of an equivocation, or evidence on another transaction
+    InvalidEvidence(Hash),
+    /// Two transactions of the block spend the same output
+    DoubleSpend(OutPoint),
+    /// A transaction does not apply on top of the ledger of the parent
+    Ledger(LedgerError),
+    /// The branch forks off further below the tip than a reorganization may go
+    ReorgTooDeep {
+        depth: u64,
+        max: u64,
+    },
+    /// The branch does not have more work than the blocks it would replace
+    LessWork {
+        work: u128,
+        replaced: u128,
+    },
+    /// The block is not the one the operator trusts at its height
+    CheckpointMismatch {
+        height: u64,
+        expected: Hash,
+        actual: Hash,
+    },
+    /// The branch forks off below the last final block
+    RevertsFinalized {
+        fork: u64,
+        finalized: u64,
+    },
+}
+
+impl ValidationError {
+    /// Whether the block is invalid no matter what chain the receiver has, a block that merely
+    /// does not fit our tip or clock is no reason to distrust the sender
+    pub fn is_peer_fault(&self) -> bool {
+        !matches!(
+            self,
+            ValidationError::NotNextHeight { .. }
+                | ValidationError::UnknownParent { .. }
+                | ValidationError::TimestampInFuture { .. }
+                | ValidationError::ReorgTooDeep { .. }
+                | ValidationError::LessWork { .. }
+                | ValidationError::CheckpointMismatch { .. }
+                | ValidationError::RevertsFinalized { .. }
+        )
+    }
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ValidationError::NotNextHeight { expected, actual } => {
+                write!(f, "height {} where {} was expected", actual, expected)
+            }
+            ValidationError::UnknownParent { prev_hash, tip } => {
+                write!(f, "parent {} is not the tip {}", prev_hash, tip)
+            }
+            ValidationError::HashMismatch { claimed, computed } => write!(
+                f,
+                "hash {} does not match the header hash {}",
+                claimed, computed
+            ),
+            ValidationError::DifficultyMismatch { expected, actual } => {
+                write!(f, "difficulty {} where {} was expected", actual, expected)
+            }
+            ValidationError::InsufficientWork { bits, difficulty } => write!(
+                f,
+                "hash has {} leading zero bits, the difficulty demands {}",
+                bits, difficulty
+            ),
+            ValidationError::MissingProposer => write!(f, "header names no proposer"),
+            ValidationError::SlotNotAfterParent { slot, parent } => write!(
+                f,
+                "slot {} is not after the slot {} of the parent",
+                slot, parent
+            ),
+            ValidationError::WrongProposer {
+                slot,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "slot {} belongs to {}, not to {}",
+                slot, expected, actual
+            ),
+            ValidationError::InvalidProposerSignature => {
+                write!(f, "header is not signed by its proposer")
+            }
+            ValidationError::NoValidators => write!(f, "no validator has stake"),
+            ValidationError::TimestampNotAfterMedian { timestamp, median } => write!(
+                f,
+                "timestamp {} is not after the median timestamp {} of the previous blocks",
+                timestamp, median
+            ),
+            ValidationError::TimestampInFuture { timestamp, now } => write!(
+                f,
+                "timestamp {} is more than {}s ahead of {}",
+                timestamp, MAX_FUTURE_BLOCK_TIME, now
+            ),
+            ValidationError::TooManyTransactions { count, max } => {
+                write!(f, "{} transactions, at most {} are allowed", count, max)
+            }
+            ValidationError::TooLarge { size, max } => {
+                write!(f, "{} bytes, at most {} are allowed", size, max)
+            }
+            ValidationError::TooMuchGas { gas, max } => {
+                write!(f, "calls given {} gas, at most {} are allowed", gas, max)
+            }
+            ValidationError::DataHashMismatch => write!(f, "data does not match the data hash"),
+            ValidationError::MerkleRootMismatch { header, computed } => write!(
+                f,
+                "merkle root {} does not match the transactions with root {}",
+                header, computed
+            ),
+            ValidationError::StateRootMismatch { header, computed } => write!(
+                f,
+                "state root {} does not match the state after the block with root {}",
+                header, computed
+            ),
+            ValidationError::ReceiptsRootMismatch { header, computed } => write!(
+                f,
+                "receipts root {} does not match the receipts of the block with root {}",
+                header, computed
+            ),
+            ValidationError::DuplicateTransaction(id) => {
+                write!(f, "transaction {} is included twice", id)
+            }
+            ValidationError::LockedTransaction { txid, locktime } => {
+                write!(f, "transaction {} is locked until {}", txid, locktime)
+            }
+            ValidationError::InvalidSignature(id) => {
+                write!(f, "transaction {} has an invalid signature", id)
+            }
+            ValidationError::MisplacedCoinbase(id) => {
+                write!(f, "coinbase {} is not the first transaction", id)
+            }
+            ValidationError::InvalidCoinbase(id) => write!(
+                f,
+                "coinbase {} must only pay the miner at the height of the block",
+                id
+            ),
+            ValidationError::CoinbaseTooLarge { amount, max } => write!(
+                f,
+                "coinbase pays {}, the subsidy and the fees allow {}",
+                amount, max
+            ),
+            ValidationError::InvalidEvidence(id) => {
+                write!(
+                    f,
+                    "transaction {} carries invalid equivocation evidence",
+                    id
+                )
+            }
+            ValidationError::DoubleSpend(outpoint) => {
+                write!(f, "output {} is spent twice", outpoint)
+            }
+            ValidationError::Ledger(e) => write!(f, "invalid transaction: {}", e),
+            ValidationError::ReorgTooDeep { depth, max } => write!(
+                f,
+                "branch replaces {} blocks, at most {} may be replaced",
+                depth, max
+            ),
+            ValidationError::LessWork { work, replaced } => write!(
+                f,
+                "branch has work {}, not more than the work {} it replaces",
+                work, replaced
+            ),
+            ValidationError::CheckpointMismatch {
+                height,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "block {} at height {} does not match the checkpoint {}",
+                actual, height, expected
+            ),
+            ValidationError::RevertsFinalized { fork, finalized } => write!(
+                f,
+                "branch forks off after block {}, below the final block {}",
+                fork, finalized
+            ),
+        }
+    }
+}
+
+impl Error for ValidationError {}
+
+/// What a block must follow besides its own content, the seal and the coinbase depend on the
+/// chain it extends
+pub struct Rules<'a> {
+    pub consensus: &'a dyn Consensus,
+    /// Difficulty the retarget schedule demands at the height of the block
+    pub difficulty: u32,
+    /// Validators entitled to propose the block
+    pub validators: &'a [Validator],
+    /// What the coinbase may create besides the fees
+    pub subsidy: u64,
+    /// Limits of the network on the size of blocks
+    pub params: &'a ChainParams,
+    /// Median timestamp of the blocks before the block, see `MEDIAN_TIME_SPAN`
+    pub median_time_past: u64,
+    /// Time of our clock, the block may be ahead of it by `MAX_FUTURE_BLOCK_TIME` at most
+    pub now: u64,
+    /// Whether the block leads to a checkpoint, its seal and signatures are then not verified
+    pub trusted: bool,
+}
+
+/// Every rule the block must follow to extend `parent` that does not need the ledger
+pub fn check_block(block: &Block, parent: &Block, rules: &Rules) -> Result<(), ValidationError> {
+    let computed = block.header.hash();
+    if block.hash != computed {
+        return Err(ValidationError::HashMismatch {
+            claimed: block.hash,
+            computed,
+        });
+    }
+    check_header(&block.header, &parent.header, &parent.hash, rules)?;
+    check_body(block, rules)?;
+    check_coinbase(block, rules.subsidy)?;
+    if !rules.trusted {
+        for tx in block.transactions.iter() {
+            check_evidence(tx, rules.consensus)?;
+        }
+    }
+    Ok(())
+}
+
+/// Linkage, seal and timestamp of a header on top of the parent with the hash `parent_hash`, all
+/// a header can be checked for without its body
+pub fn check_header(
+    header: &BlockHeader,
+    parent: &BlockHeader,
+    parent_hash: &Hash,
+    rules: &Rules,
+) -> Result<(), ValidationError> {
+    if header.index != parent.index + 1 {
+        return Err(ValidationError::NotNextHeight {
+            expected: parent.index + 1,
+            actual: header.index,
+        });
+    }
+    if &header.prev_hash != parent_hash {
+        return Err(ValidationError::UnknownParent {
+            prev_hash: header.prev_hash,
+            tip: *parent_hash,
+        });
+    }
+    if !rules.trusted {
+        rules
+            .consensus
+            .check_seal(header, parent, rules.difficulty, rules.validators)?;
+    }
+    if header.timestamp <= rules.median_time_past {
+        return Err(ValidationError::TimestampNotAfterMedian {
+            timestamp: header.timestamp,
+            median: rules.median_time_past,
+        });
+    }
+    if header.timestamp > rules.now.saturating_add(MAX_FUTURE_BLOCK_TIME) {
+        return Err(ValidationError::TimestampInFuture {
+            timestamp: header.timestamp,
+            now: rules.now,
+        });
+    }
+    Ok(())
+}
+
+/// Size limits, the commitments of the header and the transactions on their own
+fn check_body(block: &Block, rules: &Rules) -> Result<(), ValidationError> {
+    let max = rules.params.max_block_transactions;
+    if block.transactions.len() > max {
+        return Err(ValidationError::TooManyTransactions {
+            count: block.transactions.len(),
+            max,
+        });
+    }
+    let size = block.size();
+    if size > rules.params.max_block_size {
+        return Err(ValidationError::TooLarge {
+            size,
+            max: rules.params.max_block_size,
+        });
+    }
+    let gas = block
+        .transactions
+        .iter()
+        .fold(0u64, |gas, tx| gas.saturating_add(tx.gas_limit()));
+    if gas > rules.params.max_block_gas {
+        return Err(ValidationError::TooMuchGas {
+            gas,
+            max: rules.params.max_block_gas,
+        });
+    }
+    if block.header.data_hash != Hash::digest(block.data.as_bytes()) {
+        return Err(ValidationError::DataHashMismatch);
+    }
+    let computed = merkle_root(&block.transactions);
+    if block.header.merkle_root != computed {
+        return Err(ValidationError::MerkleRootMismatch {
+            header: block.header.merkle_root,
+            computed,
+        });
+    }
+    let mut ids = HashSet::new();
+    let mut spent = HashSet::new();
+    for tx in block.transactions.iter() {
+        let id = tx.id();
+        if !ids.insert(id) {
+            return Err(ValidationError::DuplicateTransaction(id));
+        }
+        // coinbase 没有签名，由 check_coinbase 检查
+        if tx.is_coinbase() {
+            continue;
+        }
+        if let Some(locktime) = tx.locktime {
+            if !locktime.is_reached(block.header.index, block.header.timestamp) {
+                return Err(ValidationError::LockedTransaction { txid: id, locktime });
+            }
+        }
+        if !rules.trusted && !tx.verify() {
+            return Err(ValidationError::InvalidSignature(id));
+        }
+        if let Some(outpoint) = tx.inputs.iter().find(|outpoint| !spent.insert(**outpoint)) {
+            return Err(ValidationError::DoubleSpend(*outpoint));
+        }
+    }
+    Ok(())
+}
+
+/// A slashing transaction must prove that its receiver signed two blocks of a slot and move no
+/// coins, other transactions carry no evidence
+pub fn check_evidence(tx: &Transaction, consensus: &dyn Consensus) -> Result<(), ValidationError> {
+    let valid = match (tx.kind, tx.evidence.as_ref()) {
+        (TxKind::Slash, Some(evidence)) => {
+            tx.amount == 0 && evidence.offender() == Some(tx.to) && evidence.verify(consensus)
+        }
+        (TxKind::Slash, None) => false,
+        (_, evidence) => evidence.is_none(),
+    };
+    if !valid {
+        return Err(ValidationError::InvalidEvidence(tx.id()));
+    }
+    Ok(())
+}
+
+/// A block may start with a coinbase paying its miner at most the subsidy and the fees of the
+/// other transactions
+fn check_coinbase(block: &Block, subsidy: u64) -> Result<(), ValidationError> {
+    if let Some(tx) = block
+        .transactions
+        .iter()
+        .skip(1)
+        .find(|tx| tx.is_coinbase())
+    {
+        return Err(ValidationError::MisplacedCoinbase(tx.id()));
+    }
+    let coinbase = match block.transactions.first() {
+        Some(tx) if tx.is_coinbase() => tx,
+        _ => return Ok(()),
+    };
+    if !coinbase.inputs.is_empty()
+        || coinbase.kind != TxKind::Transfer
+        || coinbase.change != 0
+        || coinbase.fee != 0
+        || coinbase.locktime.is_some()
+        || coinbase.contract.is_some()
+        || coinbase.multisig.is_some()
+        || coinbase.evidence.is_some()
+        || coinbase.nonce != block.header.index
+    {
+        return Err(ValidationError::InvalidCoinbase(coinbase.id()));
+    }
+    let fees = block
+        .transactions
+        .iter()
+        .skip(1)
+        .fold(0u64, |sum, tx| sum.saturating_add(tx.fee));
+    let max = subsidy.saturating_add(fees);
+    if coinbase.amount > max {
+        return Err(ValidationError::CoinbaseTooLarge {
+            amount: coinbase.amount,
+            max,
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use libp2p::identity::Keypair;
+
+    use super::*;
+    use crate::blockchain::Chain;
+    use crate::genesis::{Allocation, Genesis};
+    use crate::ledger::{LedgerError, LedgerKind};
+    use crate::multisig::{MultisigSpend, Policy};
+    use crate::slashing::Evidence;
+    use crate::transaction::ContractOp;
+
+    /// A chain without proof of work whose genesis block gives the key 100 coins
+    fn chain(kind: LedgerKind, keys: &Keypair) -> Chain {
+        let mut genesis = Genesis::default();
+        genesis.params.initial_difficulty = 0;
+        genesis.params.min_difficulty = 0;
+        genesis.params.max_difficulty = 0;
+        genesis.params.ledger = kind;
+        genesis.allocations = vec![Allocation {
+            address: Address::of(&keys.public()).unwrap(),
+            amount: 100,
+        }];
+        Chain::from_genesis(&genesis)
+    }
+
+    /// A payment of the amount signed by the key, funded from what it has at the tip
+    fn pay(chain: &Chain, keys: &Keypair, amount: u64) -> Transaction {
+        let from = Address::of(&keys.public()).unwrap();
+        let nonce = chain.ledger().nonce(&from);
+        let mut tx = Transaction::new(from, Address([9; 32]), amount, nonce);
+        chain.ledger().fund(&mut tx, &|_| false).unwrap();
+        tx.sign(keys).unwrap();
+        tx
+    }
+
+    fn rehashed(mut block: Block) -> Block {
+        block.hash = block.compute_hash();
+        block
+    }
+
+    #[test]
+    fn a_block_must_commit_to_its_transactions() {
+        let keys = Keypair::generate_ed25519();
+        let mut chain = chain(LedgerKind::Utxo, &keys);
+        let block = chain.next_block(String::new(), vec![pay(&chain, &keys, 10)]);
+
+        let mut forged = block.clone();
+        forged.header.merkle_root = Hash::default();
+        assert!(matches!(
+            chain.try_add_block(rehashed(forged)),
+            Err(ValidationError::MerkleRootMismatch { .. })
+        ));
+        let mut forged = block.clone();
+        forged.hash = Hash::default();
+        assert!(matches!(
+            chain.try_add_block(forged),
+            Err(ValidationError::HashMismatch { .. })
+        ));
+        chain.try_add_block(block).unwrap();
+        assert_eq!(chain.ledger().balance(&Address([9; 32])), 10);
+    }
+
+    #[test]
+    fn a_transaction_must_be_signed_by_its_sender() {
+        let keys = Keypair::generate_ed25519();
+        let mut chain = chain(LedgerKind::Utxo, &keys);
+        let mut tx = pay(&chain, &keys, 10);
+        tx.to = Address([8; 32]);
+        let block = chain.next_block(String::new(), vec![tx.clone()]);
+        assert!(matches!(
+            chain.try_add_block(block),
+            Err(ValidationError::InvalidSignature(id)) if id == tx.id()
+        ));
+    }
+
+    #[test]
+    fn an_output_is_spent_once_in_a_block() {
+        let keys = Keypair::generate_ed25519();
+        let mut chain = chain(LedgerKind::Utxo, &keys);
+        let first = pay(&chain, &keys, 10);
+        let second = pay(&chain, &keys, 20);
+        let block = chain.next_block(String::new(), vec![first.clone(), second]);
+        assert!(matches!(
+            chain.try_add_block(block),
+            Err(ValidationError::DoubleSpend(outpoint)) if outpoint == first.inputs[0]
+        ));
+    }
+
+    #[test]
+    fn only_the_first_transaction_may_be_a_coinbase() {
+        let keys = Keypair::generate_ed25519();
+        let mut chain = chain(LedgerKind::Utxo, &keys);
+        let coinbase = Transaction::coinbase(Address([9; 32]), chain.next_subsidy(), 1);
+        let block = chain.next_block(
+            String::new(),
+            vec![pay(&chain, &keys, 10), coinbase.clone()],
+        );
+        assert!(matches!(
+            chain.try_add_block(block),
+            Err(ValidationError::MisplacedCoinbase(id)) if id == coinbase.id()
+        ));
+        let too_much = Transaction::coinbase(Address([9; 32]), chain.next_subsidy() + 1, 1);
+        let block = chain.next_block(String::new(), vec![too_much]);
+        assert!(matches!(
+            chain.try_add_block(block),
+            Err(ValidationError::CoinbaseTooLarge { .. })
+        ));
+    }
+
+    #[test]
+    fn a_coinbase_carries_nothing_but_its_reward() {
+        let keys = Keypair::generate_ed25519();
+        let mut chain = chain(LedgerKind::Utxo, &keys);
+        let coinbase = Transaction::coinbase(Address([9; 32]), chain.next_subsidy(), 1);
+        let header = chain.tip().header.clone();
+        let policy = Policy::new(1, vec![Address::of(&keys.public()).unwrap()]).unwrap();
+        let forged = vec![
+            Transaction {
+                contract: Some(Box::new(ContractOp::Deploy { code: vec![0] })),
+                ..coinbase.clone()
+            },
+            Transaction {
+                multisig: Some(Box::new(MultisigSpend::new(policy))),
+                ..coinbase.clone()
+            },
+            Transaction {
+                evidence: Some(Box::new(Evidence {
+                    slot: 0,
+                    first: header.clone(),
+                    second: header,
+                })),
+                ..coinbase.clone()
+            },
+        ];
+        for tx in forged {
+            let block = chain.next_block(String::new(), vec![tx.clone()]);
+            assert!(matches!(
+                chain.try_add_block(block),
+                Err(ValidationError::InvalidCoinbase(id)) if id == tx.id()
+            ));
+        }
+        let block = chain.next_block(String::new(), vec![coinbase]);
+        chain.try_add_block(block).unwrap();
+    }
+
+    #[test]
+    fn a_confirmed_transaction_is_not_confirmed_again() {
+        for kind in [LedgerKind::Utxo, LedgerKind::Account] {
+            let keys = Keypair::generate_ed25519();
+            let mut chain = chain(kind, &keys);
+            let tx = pay(&chain, &keys, 10);
+            let block = chain.next_block(String::new(), vec![tx.clone()]);
+            chain.try_add_block(block).unwrap();
+            let replay = chain.next_block(String::new(), vec![tx]);
+            assert!(matches!(
+                chain.try_add_block(replay),
+                Err(ValidationError::Ledger(LedgerError::NonceMismatch {
+                    expected: 1,
+                    actual: 0
+                }))
+            ));
+            assert_eq!(chain.ledger().balance(&Address([9; 32])), 10);
+        }
+    }
+}
@@ -0,0 +1,324 @@
+use std::error::Error;
+use std::fmt;
+
+use anyhow::{anyhow, bail};
+use once_cell::sync::Lazy;
+use wasmtime::{
+    Caller, Config, Engine, Extern, Linker, Module, Store, StoreLimits, StoreLimitsBuilder, Trap,
+};
+
+use crate::consts::VM_MAX_MEMORY;
+use crate::transaction::Address;
+use crate::trie::StateTrie;
+
+/// Key-value storage of a contract, keys and values are bytes the contract chooses
+pub type Storage = StateTrie<Vec<u8>, Vec<u8>>;
+
+/// Module the host functions are imported from
+const HOST_MODULE: &str = "env";
+
+/// Functions a contract may import from the host
+///
+/// - `input_len() -> i32` and `input_read(ptr)` hand the arguments of the call to the contract,
+///   `caller(ptr)` writes the 32 bytes of the address of the caller
+/// - `storage_read(key_ptr, key_len, value_ptr, value_cap) -> i32` copies up to `value_cap`
+///   bytes of the value and returns its length, -1 when the key is not set
+/// - `storage_write(key_ptr, key_len, value_ptr, value_len)` and `storage_remove(key_ptr,
+///   key_len)` change the storage of the contract
+/// - `emit(ptr, len)` emits an event and `output(ptr, len)` sets what the call returns
+const HOST_FUNCTIONS: [&str; 8] = [
+    "input_len",
+    "input_read",
+    "caller",
+    "storage_read",
+    "storage_write",
+    "storage_remove",
+    "emit",
+    "output",
+];
+
+/// One engine for every call, configured so all nodes get the same result: floats are
+/// canonicalized, threads are off and every instruction costs fuel
+static ENGINE: Lazy<Engine> = Lazy::new(|| {
+    let mut config = Config::new();
+    config
+        .consume_fuel(true)
+        .cranelift_nan_canonicalization(true)
+        .wasm_threads(false);
+    Engine::new(&config).expect("valid engine configuration")
+});
+
+/// Why a contract could not be run
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VmError {
+    /// The code is no WASM module, imports more than the host functions or exports no memory
+    InvalidCode(String),
+    /// The contract exports no function by that name taking and returning nothing
+    NoSuchMethod(String),
+    /// The call burned all the gas it was given
+    OutOfGas { limit: u64 },
+    /// The contract trapped or misused a host function
+    Trap(String),
+}
+
+impl fmt::Display for VmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VmError::InvalidCode(reason) => write!(f, "invalid contract code: {}", reason),
+            VmError::NoSuchMethod(method) => write!(f, "contract has no method {}", method),
+            VmError::OutOfGas { limit } => write!(f, "contract ran out of {} gas", limit),
+            VmError::Trap(reason) => write!(f, "contract trapped: {}", reason),
+        }
+    }
+}
+
+impl Error for VmError {}
+
+/// What a call did, the storage only changes when the call succeeds
+#[derive(Debug, Clone, Default)]
+pub struct Execution {
+    /// Storage of the contract after the call
+    pub storage: Storage,
+    /// Payloads passed to `emit`, in order
+    pub events: Vec<Vec<u8>>,
+    /// Payload passed to `output` last
+    pub output: Vec<u8>,
+    pub gas_used: u64,
+}
+
+/// A call that failed and the gas it burned before, all of its limit when it ran out
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Failure {
+    pub error: VmError,
+    pub gas_used: u64,
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} after burning {} gas", self.error, self.gas_used)
+    }
+}
+
+impl From<VmError> for Failure {
+    /// Failures before the contract runs burn no gas
+    fn from(error: VmError) -> Failure {
+        Failure { error, gas_used: 0 }
+    }
+}
+
+/// What the host functions work on during a call
+struct Host {
+    input: Vec<u8>,
+    caller: Address,
+    storage: Storage,
+    events: Vec<Vec<u8>>,
+    output: Vec<u8>,
+    limits: StoreLimits,
+}
+
+/// Whether the code can be deployed, checked before its deployment is accepted
+pub fn check_code(code: &[u8]) -> Result<(), VmError> {
+    compile(code).map(|_| ())
+}
+
+/// Compile the code and check that it only imports host functions and exports its memory
+fn compile(code: &[u8]) -> Result<Module, VmError> {
+    let module =
+        Module::new(&ENGINE, code).map_err(|e| VmError::InvalidCode(e.root_cause().to_string()))?;
+    if let Some(import) = module
+        .imports()
+        .find(|import| import.module() != HOST_MODULE || !HOST_FUNCTIONS.contains(&import.name()))
+    {
+        return Err(VmError::InvalidCode(format!(
+            "unknown import {}.{}",
+            import.module(),
+            import.name()
+        )));
+    }
+    if module
+        .get_export("memory")
+        .and_then(|ty| ty.memory().cloned())
+        .is_none()
+    {
+        return Err(VmError::InvalidCode("no exported memory".to_owned()));
+    }
+    Ok(module)
+}
+
+/// Run the exported function `method` of the contract with `args` on a copy of its storage, with
+/// at most `gas_limit` gas and `VM_MAX_MEMORY` bytes of memory
+pub fn call(
+    code: &[u8],
+    storage: &Storage,
+    method: &str,
+    args: &[u8],
+    caller: &Address,
+    gas_limit: u64,
+) -> Result<Execution, Failure> {
+    let module = compile(code)?;
+    let host = Host {
+        input: args.to_vec(),
+        caller: *caller,
+        storage: storage.clone(),
+        events: Vec::new(),
+        output: Vec::new(),
+        limits: StoreLimitsBuilder::new()
+            .memory_size(VM_MAX_MEMORY)
+            .instances(1)
+            .build(),
+    };
+    let mut store = Store::new(&ENGINE, host);
+    store.limiter(|host| &mut host.limits);
+    store
+        .set_fuel(gas_limit)
+        .map_err(|e| VmError::Trap(e.to_string()))?;
+    // 启动函数也消耗 gas，失败时同样按已消耗的计费
+    let result = linker()
+        .instantiate(&mut store, &module)
+        .map_err(|e| trap(e, gas_limit, VmError::InvalidCode))
+        .and_then(|instance| {
+            instance
+                .get_typed_func::<(), ()>(&mut store, method)
+                .map_err(|_| VmError::NoSuchMethod(method.to_owned()))
+        })
+        .and_then(|function| {
+            function
+                .call(&mut store, ())
+                .map_err(|e| trap(e, gas_limit, VmError::Trap))
+        });
+    let gas_used = gas_limit - store.get_fuel().unwrap_or_default();
+    if let Err(error) = result {
+        return Err(Failure { error, gas_used });
+    }
+    let host = store.into_data();
+    Ok(Execution {
+        storage: host.storage,
+        events: host.events,
+        output: host.output,
+        gas_used,
+    })
+}
+
+/// Running out of gas is told apart from other errors, which are reported by their root cause
+fn trap(e: anyhow::Error, limit: u64, other: fn(String) -> VmError) -> VmError {
+    match e.downcast_ref::<Trap>() {
+        Some(Trap::OutOfFuel) => VmError::OutOfGas { limit },
+        _ => other(e.root_cause().to_string()),
+    }
+}
+
+/// Bytes of the memory of the contract, out of bounds ranges trap
+fn read(caller: &mut Caller<'_, Host>, ptr: i32, len: i32) -> anyhow::Result<Vec<u8>> {
+    let memory = match caller.get_export("memory") {
+        Some(Extern::Memory(memory)) => memory,
+        _ => bail!("contract exports no memory"),
+    };
+    let start = ptr as u32 as usize;
+    let end = start.saturating_add(len as u32 as usize);
+    memory
+        .data(&caller)
+        .get(start..end)
+        .map(<[u8]>::to_vec)
+        .ok_or_else(|| anyhow!("memory access out of bounds"))
+}
+
+fn write(caller: &mut Caller<'_, Host>, ptr: i32, bytes: &[u8]) -> anyhow::Result<()> {
+    let memory = match caller.get_export("memory") {
+        Some(Extern::Memory(memory)) => memory,
+        _ => bail!("contract exports no memory"),
+    };
+    memory
+        .write(caller, ptr as u32 as usize, bytes)
+        .map_err(|_| anyhow!("memory access out of bounds"))
+}
+
+fn linker() -> Linker<Host> {
+    let mut linker = Linker::new(&ENGINE);
+    // 函数名与签名都是固定的，注册不会失败
+    linker
+        .func_wrap(HOST_MODULE, "input_len", |caller: Caller<'_, Host>| {
+            caller.data().input.len() as i32
+        })
+        .expect("host function input_len");
+    linker
+        .func_wrap(
+            HOST_MODULE,
+            "input_read",
+            |mut caller: Caller<'_, Host>, ptr: i32| {
+                let input = caller.data().input.clone();
+                write(&mut caller, ptr, &input)
+            },
+        )
+        .expect("host function input_read");
+    linker
+        .func_wrap(
+            HOST_MODULE,
+            "caller",
+            |mut caller: Caller<'_, Host>, ptr: i32| {
+                let address = caller.data().caller;
+                write(&mut caller, ptr, &address.0)
+            },
+        )
+        .expect("host function caller");
+    linker
+        .func_wrap(
+            HOST_MODULE,
+            "storage_read",
+            |mut caller: Caller<'_, Host>, key_ptr: i32, key_len: i32, ptr: i32, cap: i32| {
+                let key = read(&mut caller, key_ptr, key_len)?;
+                let value = match caller.data().storage.get(&key) {
+                    Some(value) => value.clone(),
+                    None => return Ok(-1),
+                };
+                let copied = value.len().min(cap.max(0) as usize);
+                write(&mut caller, ptr, &value[..copied])?;
+                Ok(value.len() as i32)
+            },
+        )
+        .expect("host function storage_read");
+    linker
+        .func_wrap(
+            HOST_MODULE,
+            "storage_write",
+            |mut caller: Caller<'_, Host>, key_ptr: i32, key_len: i32, ptr: i32, len: i32| {
+                let key = read(&mut caller, key_ptr, key_len)?;
+                let value = read(&mut caller, ptr, len)?;
+                caller.data_mut().storage.insert(key, value);
+                Ok(())
+            },
+        )
+        .expect("host function storage_write");
+    linker
+        .func_wrap(
+            HOST_MODULE,
+            "storage_remove",
+            |mut caller: Caller<'_, Host>, key_ptr: i32, key_len: i32| {
+                let key = read(&mut caller, key_ptr, key_len)?;
+                caller.data_mut().storage.remove(&key);
+                Ok(())
+            },
+        )
+        .expect("host function storage_remove");
+    linker
+        .func_wrap(
+            HOST_MODULE,
+            "emit",
+            |mut caller: Caller<'_, Host>, ptr: i32, len: i32| {
+                let event = read(&mut caller, ptr, len)?;
+                caller.data_mut().events.push(event);
+                Ok(())
+            },
+        )
+        .expect("host function emit");
+    linker
+        .func_wrap(
+            HOST_MODULE,
+            "output",
+            |mut caller: Caller<'_, Host>, ptr: i32, len: i32| {
+                caller.data_mut().output = read(&mut caller, ptr, len)?;
+                Ok(())
+            },
+        )
+        .expect("host function output");
+    linker
+}
@@ -0,0 +1,560 @@
+use std::convert::TryInto;
+use std::fs;
+use std::future::Future;
+use std::io::ErrorKind;
+
+use anyhow::{anyhow, bail, Context, Result};
+use bip39::Mnemonic;
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use libp2p::identity::{ed25519, Keypair};
+use once_cell::sync::OnceCell;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+use crate::config::CONFIG;
+use crate::consts::{
+    MAINNET_ADDRESS_HRP, TESTNET_ADDRESS_HRP, WALLET_FILE_PATH, WALLET_KDF_ROUNDS,
+};
+use crate::hd::DerivationPath;
+use crate::multisig::Policy;
+use crate::storage;
+use crate::transaction::Address;
+
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+
+/// Characters of the bech32 alphabet by their 5-bit value
+const BECH32_CHARSET: &[u8; 32] = b"qpzry9x8gf2tvdw0s3jn54khce6mua7l";
+const BECH32_CHECKSUM_LEN: usize = 6;
+
+/// Network addresses are shown for, set once the genesis file is loaded
+static NETWORK: OnceCell<Network> = OnceCell::new();
+
+/// Which kind of network a chain is, addresses of one are refused by nodes of the other
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Network {
+    #[default]
+    Mainnet,
+    Testnet,
+}
+
+impl Network {
+    pub fn is_mainnet(&self) -> bool {
+        *self == Network::Mainnet
+    }
+
+    /// Human readable part of the bech32 form of its addresses
+    pub fn hrp(&self) -> &'static str {
+        match self {
+            Network::Mainnet => MAINNET_ADDRESS_HRP,
+            Network::Testnet => TESTNET_ADDRESS_HRP,
+        }
+    }
+}
+
+/// Show and accept addresses of the network from now on, only the first call counts
+pub fn set_network(network: Network) {
+    let _ = NETWORK.set(network);
+}
+
+/// The network of the chain, mainnet until the genesis file says otherwise
+pub fn network() -> Network {
+    NETWORK.get().copied().unwrap_or_default()
+}
+
+/// Bech32 form of the key with the human readable part of our network, e.g. `ant1...`
+pub fn encode_address(key: &[u8; 32]) -> String {
+    let hrp = network().hrp();
+    let mut data = convert_bits(key, 8, 5, true).expect("bytes regroup to 5 bits");
+    let checksum = bech32_checksum(hrp, &data);
+    data.extend_from_slice(&checksum);
+    let mut encoded = String::with_capacity(hrp.len() + 1 + data.len());
+    encoded.push_str(hrp);
+    encoded.push('1');
+    encoded.extend(
+        data.iter()
+            .map(|&value| BECH32_CHARSET[value as usize] as char),
+    );
+    encoded
+}
+
+/// Key and network of a bech32 address, the checksum catches any typo of up to four characters
+pub fn decode_address(s: &str) -> Result<(Network, [u8; 32])> {
+    if s.chars().any(char::is_uppercase) && s.chars().any(char::is_lowercase) {
+        bail!("address mixes upper and lower case");
+    }
+    let s = s.to_lowercase();
+    let (hrp, data) = match s.rsplit_once('1') {
+        Some(parts) => parts,
+        None => bail!("address has no bech32 separator"),
+    };
+    let network = [Network::Mainnet, Network::Testnet]
+        .iter()
+        .copied()
+        .find(|network| network.hrp() == hrp)
+        .ok_or_else(|| {
+            anyhow!(
+                "address prefix {} is not {} or {}",
+                hrp,
+                MAINNET_ADDRESS_HRP,
+                TESTNET_ADDRESS_HRP
+            )
+        })?;
+    let values = data
+        .bytes()
+        .map(|c| {
+            BECH32_CHARSET
+                .iter()
+                .position(|&known| known == c)
+                .map(|value| value as u8)
+        })
+        .collect::<Option<Vec<u8>>>()
+        .ok_or_else(|| anyhow!("address has characters outside the bech32 alphabet"))?;
+    if values.len() < BECH32_CHECKSUM_LEN {
+        bail!("address is too short");
+    }
+    if polymod(&[&hrp_expand(hrp)[..], &values[..]].concat()) != 1 {
+        bail!("address checksum does not match, check for typos");
+    }
+    let payload = &values[..values.len() - BECH32_CHECKSUM_LEN];
+    let bytes =
+        convert_bits(payload, 5, 8, false).ok_or_else(|| anyhow!("address has invalid padding"))?;
+    let key: [u8; 32] = bytes
+        .try_into()
+        .map_err(|_| anyhow!("address does not hold 32 bytes"))?;
+    Ok((network, key))
+}
+
+/// BCH checksum of BIP173 over the expanded human readable part and the data
+fn polymod(values: &[u8]) -> u32 {
+    const GENERATOR: [u32; 5] = [0x3b6a57b2, 0x26508e6d, 0x1ea119fa, 0x3d4233dd, 0x2a1462b3];
+    let mut checksum = 1u32;
+    for value in values.iter() {
+        let top = checksum >> 25;
+        checksum = (checksum & 0x1ffffff) << 5 ^ *value as u32;
+        for (bit, generator) in GENERATOR.iter().enumerate() {
+            if (top >> bit) & 1 == 1 {
+                checksum ^= generator;
+            }
+        }
+    }
+    checksum
+}
+
+fn hrp_expand(hrp: &str) -> Vec<u8> {
+    let mut expanded: Vec<u8> = hrp.bytes().map(|c| c >> 5).collect();
+    expanded.push(0);
+    expanded.extend(hrp.bytes().map(|c| c & 31));
+    expanded
+}
+
+fn bech32_checksum(hrp: &str, data: &[u8]) -> [u8; BECH32_CHECKSUM_LEN] {
+    let values = [&hrp_expand(hrp)[..], data, &[0; BECH32_CHECKSUM_LEN]].concat();
+    let checksum = polymod(&values) ^ 1;
+    let mut groups = [0u8; BECH32_CHECKSUM_LEN];
+    for (i, group) in groups.iter_mut().enumerate() {
+        *group = (checksum >> (5 * (5 - i))) as u8 & 31;
+    }
+    groups
+}
+
+/// Regroup bits, e.g. bytes into the 5-bit values bech32 encodes, none when the padding left
+/// over is not zero or, without `pad`, longer than a group
+fn convert_bits(data: &[u8], from: u32, to: u32, pad: bool) -> Option<Vec<u8>> {
+    let mut acc = 0u32;
+    let mut bits = 0u32;
+    let mut converted = Vec::with_capacity(data.len() * from as usize / to as usize + 1);
+    let max = (1u32 << to) - 1;
+    for value in data.iter() {
+        acc = (acc << from) | *value as u32;
+        bits += from;
+        while bits >= to {
+            bits -= to;
+            converted.push(((acc >> bits) & max) as u8);
+        }
+    }
+    if pad {
+        if bits > 0 {
+            converted.push(((acc << (to - bits)) & max) as u8);
+        }
+    } else if bits >= from || (acc << (to - bits)) & max != 0 {
+        return None;
+    }
+    Some(converted)
+}
+
+/// Secret encrypted with a key derived from the passphrase
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Sealed {
+    #[serde(with = "hex")]
+    salt: Vec<u8>,
+    #[serde(with = "hex")]
+    nonce: Vec<u8>,
+    #[serde(with = "hex")]
+    ciphertext: Vec<u8>,
+}
+
+impl Sealed {
+    fn seal(secret: &[u8], passphrase: &str) -> Result<Sealed> {
+        let mut salt = vec![0u8; SALT_LEN];
+        let mut nonce = vec![0u8; NONCE_LEN];
+        rand::thread_rng().fill_bytes(&mut salt);
+        rand::thread_rng().fill_bytes(&mut nonce);
+        let ciphertext = cipher(passphrase, &salt)
+            .encrypt(Nonce::from_slice(&nonce), secret)
+            .map_err(|_| anyhow!("can not encrypt secret"))?;
+        Ok(Sealed {
+            salt,
+            nonce,
+            ciphertext,
+        })
+    }
+
+    fn open(&self, passphrase: &str) -> Result<Vec<u8>> {
+        if self.nonce.len() != NONCE_LEN {
+            bail!("malformed nonce");
+        }
+        cipher(passphrase, &self.salt)
+            .decrypt(Nonce::from_slice(&self.nonce), self.ciphertext.as_ref())
+            .map_err(|_| anyhow!("wrong passphrase"))
+    }
+}
+
+/// Secret key of an address
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct EncryptedKey {
+    address: Address,
+    /// Where the key was derived from the seed, none for keys generated on their own
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    path: Option<String>,
+    #[serde(flatten)]
+    secret: Sealed,
+}
+
+impl EncryptedKey {
+    fn seal(
+        keys: &ed25519::Keypair,
+        path: Option<String>,
+        passphrase: &str,
+    ) -> Result<EncryptedKey> {
+        Ok(EncryptedKey {
+            address: Address(keys.public().to_bytes()),
+            path,
+            secret: Sealed::seal(keys.secret().as_ref(), passphrase)?,
+        })
+    }
+
+    fn open(&self, passphrase: &str) -> Result<Keypair> {
+        let mut secret = self
+            .secret
+            .open(passphrase)
+            .with_context(|| format!("can not decrypt key of {}", self.address))?;
+        let keys = ed25519::Keypair::from(ed25519::SecretKey::try_from_bytes(&mut secret)?);
+        if Address(keys.public().to_bytes()) != self.address {
+            bail!("key of {} does not match its address", self.address);
+        }
+        Ok(keys.into())
+    }
+}
+
+/// BIP39 seed the wallet keys are derived from
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct HdSeed {
+    /// Path of the account, the keys of the wallet are its children
+    path: String,
+    #[serde(flatten)]
+    seed: Sealed,
+}
+
+fn cipher(passphrase: &str, salt: &[u8]) -> ChaCha20Poly1305 {
+    let mut key = [0u8; 32];
+    pbkdf2::pbkdf2_hmac::<Sha256>(passphrase.as_bytes(), salt, WALLET_KDF_ROUNDS, &mut key);
+    ChaCha20Poly1305::new(Key::from_slice(&key))
+}
+
+/// The child key of the account path at the index
+fn derive(
+    account: &DerivationPath,
+    seed: &[u8],
+    index: u32,
+) -> Result<(DerivationPath, ed25519::Keypair)> {
+    let path = account.child(index);
+    let mut secret = path.derive(seed);
+    let keys = ed25519::Keypair::from(ed25519::SecretKey::try_from_bytes(&mut secret)?);
+    Ok((path, keys))
+}
+
+/// The configured passphrase, wallet keys can not be created or used without one
+pub fn passphrase() -> Result<&'static str> {
+    match &CONFIG.wallet_passphrase {
+        Some(passphrase) => Ok(passphrase),
+        None => {
+            bail!("no wallet passphrase, pass --wallet-passphrase or set ANT_WALLET_PASSPHRASE")
+        }
+    }
+}
+
+/// Keys owned by the operator besides the node key, kept in an encrypted keystore
+///
+/// With a mnemonic new keys are derived from its seed, so the phrase alone backs up the wallet.
+/// Addresses are stored in the clear so they can be listed without the passphrase. Changes are
+/// made in memory, the caller saves the wallet after them
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Wallet {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    hd: Option<HdSeed>,
+    keys: Vec<EncryptedKey>,
+    /// Shared addresses the operator takes part in, policies hold no secrets
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    multisigs: Vec<Policy>,
+}
+
+impl Wallet {
+    pub fn load() -> Result<Wallet> {
+        let content = match fs::read(WALLET_FILE_PATH) {
+            Ok(content) => content,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Wallet::default()),
+            Err(e) => {
+                return Err(e).with_context(|| format!("can not read wallet {}", WALLET_FILE_PATH))
+            }
+        };
+        serde_json::from_slice(&content)
+            .with_context(|| format!("invalid wallet {}", WALLET_FILE_PATH))
+    }
+
+    /// Whether the keystore of a backup can take the place of this one, it is refused while this
+    /// one holds a key, a shared address or a mnemonic seed the backup lacks, which would be lost
+    pub fn check_replace(&self, backup: &Wallet) -> Result<()> {
+        let missing: Vec<String> = self
+            .keys
+            .iter()
+            .map(|key| key.address)
+            .filter(|address| !backup.keys.iter().any(|key| key.address == *address))
+            .chain(
+                self.multisigs
+                    .iter()
+                    .map(Policy::address)
+                    .filter(|address| backup.multisig(address).is_none()),
+            )
+            .map(|address| address.to_string())
+            .collect();
+        if !missing.is_empty() {
+            bail!(
+                "the wallet holds {} that the backup lacks",
+                missing.join(", ")
+            );
+        }
+        if self.hd.is_some() && backup.hd.is_none() {
+            bail!("the wallet has a mnemonic seed that the backup lacks");
+        }
+        Ok(())
+    }
+
+    /// Replace the keystore with that of a backup, see `check_replace`
+    pub fn replace(&mut self, backup: Wallet) -> Result<()> {
+        self.check_replace(&backup)?;
+        *self = backup;
+        Ok(())
+    }
+
+    /// Replace the keystore with the wallet as it is now, the returned future only writes it
+    pub fn save(&self) -> impl Future<Output = Result<()>> + Send + 'static {
+        let json = serde_json::to_vec_pretty(self);
+        async move { storage::replace_private_file(WALLET_FILE_PATH, json?).await }
+    }
+
+    /// Add a key and return its address, the next child of the seed when the wallet has a
+    /// mnemonic and a random one otherwise
+    pub fn generate(&mut self, passphrase: &str) -> Result<Address> {
+        let key = match &self.hd {
+            Some(hd) => {
+                let index = self.keys.iter().filter(|key| key.path.is_some()).count() as u32;
+                let seed = hd.seed.open(passphrase).context("can not decrypt seed")?;
+                let (path, keys) = derive(&hd.path.parse()?, &seed, index)?;
+                EncryptedKey::seal(&keys, Some(path.to_string()), passphrase)?
+            }
+            None => EncryptedKey::seal(&ed25519::Keypair::generate(), None, passphrase)?,
+        };
+        let address = key.address;
+        self.keys.push(key);
+        Ok(address)
+    }
+
+    /// Create a mnemonic of 12 or 24 words to derive keys from, returned so it can be written down
+    pub fn init_mnemonic(&mut self, words: usize, passphrase: &str) -> Result<Mnemonic> {
+        if words != 12 && words != 24 {
+            bail!("mnemonics have 12 or 24 words");
+        }
+        let mnemonic = Mnemonic::generate(words)?;
+        self.set_mnemonic(&mnemonic, passphrase)?;
+        Ok(mnemonic)
+    }
+
+    /// Derive keys from a written down mnemonic until `gap` addresses in a row are unused
+    /// according to `used`, returns the restored addresses
+    pub fn restore(
+        &mut self,
+        phrase: &str,
+        passphrase: &str,
+        gap: u32,
+        used: impl Fn(&Address) -> bool,
+    ) -> Result<Vec<Address>> {
+        let mnemonic = Mnemonic::parse(phrase).context("invalid mnemonic")?;
+        self.set_mnemonic(&mnemonic, passphrase)?;
+        let hd = self.hd.as_ref().expect("mnemonic was just set");
+        let path: DerivationPath = hd.path.parse()?;
+        let seed = hd.seed.open(passphrase).context("can not decrypt seed")?;
+        let mut derived = Vec::new();
+        // 至少恢复第一个地址，最后一个用过的地址之后连续 gap 个未使用时停止
+        let mut keep = 1;
+        while derived.len() < keep + gap as usize {
+            let (path, keys) = derive(&path, &seed, derived.len() as u32)?;
+            if used(&Address(keys.public().to_bytes())) {
+                keep = derived.len() + 1;
+            }
+            derived.push((path, keys));
+        }
+        // 只加密保留下来的密钥，派生本身很快
+        let mut restored = Vec::new();
+        for (path, keys) in derived.into_iter().take(keep) {
+            let key = EncryptedKey::seal(&keys, Some(path.to_string()), passphrase)?;
+            restored.push(key.address);
+            self.keys.push(key);
+        }
+        Ok(restored)
+    }
+
+    fn set_mnemonic(&mut self, mnemonic: &Mnemonic, passphrase: &str) -> Result<()> {
+        if self.hd.is_some() {
+            bail!("the wallet already has a mnemonic");
+        }
+        let path: DerivationPath = CONFIG.wallet_derivation_path.parse()?;
+        self.hd = Some(HdSeed {
+            path: path.to_string(),
+            seed: Sealed::seal(&mnemonic.to_seed(""), passphrase)?,
+        });
+        Ok(())
+    }
+
+    /// Addresses of the wallet in the order they were created, with the path of derived ones
+    pub fn addresses(&self) -> impl Iterator<Item = (&Address, Option<&str>)> {
+        self.keys
+            .iter()
+            .map(|key| (&key.address, key.path.as_deref()))
+    }
+
+    /// Remember the policy of a shared address and return the address
+    pub fn add_multisig(&mut self, policy: Policy) -> Result<Address> {
+        let address = policy.address();
+        if self.multisig(&address).is_none() {
+            self.multisigs.push(policy);
+        }
+        Ok(address)
+    }
+
+    pub fn multisig(&self, address: &Address) -> Option<&Policy> {
+        self.multisigs
+            .iter()
+            .find(|policy| policy.address() == *address)
+    }
+
+    /// Policies of the shared addresses in the order they were added
+    pub fn multisigs(&self) -> impl Iterator<Item = &Policy> {
+        self.multisigs.iter()
+    }
+
+    /// Decrypt the key of the address
+    pub fn keypair(&self, address: &Address, passphrase: &str) -> Result<Keypair> {
+        match self.keys.iter().find(|key| &key.address == address) {
+            Some(key) => key.open(passphrase),
+            None => bail!("{} is not an address of the wallet", address),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: [u8; 32] = [0x5a; 32];
+
+    /// Bech32 form of the key under another human readable part than the one of our network
+    fn encode_with(hrp: &str, key: &[u8; 32]) -> String {
+        let mut data = convert_bits(key, 8, 5, true).unwrap();
+        data.extend_from_slice(&bech32_checksum(hrp, &data));
+        let data: String = data
+            .iter()
+            .map(|&value| BECH32_CHARSET[value as usize] as char)
+            .collect();
+        format!("{}1{}", hrp, data)
+    }
+
+    #[test]
+    fn the_checksum_is_the_one_of_bip173() {
+        let values: Vec<u8> = "2uel5l"
+            .bytes()
+            .map(|c| BECH32_CHARSET.iter().position(|&known| known == c).unwrap() as u8)
+            .collect();
+        assert_eq!(polymod(&[&hrp_expand("a")[..], &values[..]].concat()), 1);
+    }
+
+    #[test]
+    fn an_address_decodes_to_its_key_and_network() {
+        let encoded = encode_address(&KEY);
+        assert!(encoded.starts_with("ant1"));
+        assert_eq!(encode_with(MAINNET_ADDRESS_HRP, &KEY), encoded);
+        assert_eq!(decode_address(&encoded).unwrap(), (Network::Mainnet, KEY));
+        assert_eq!(
+            decode_address(&encoded.to_uppercase()).unwrap(),
+            (Network::Mainnet, KEY)
+        );
+        assert_eq!(encoded.parse::<Address>().unwrap(), Address(KEY));
+
+        let testnet = encode_with(TESTNET_ADDRESS_HRP, &KEY);
+        assert_eq!(decode_address(&testnet).unwrap(), (Network::Testnet, KEY));
+        assert!(testnet.parse::<Address>().is_err());
+    }
+
+    #[test]
+    fn a_typo_in_an_address_is_caught() {
+        let encoded = encode_address(&KEY);
+        let separator = encoded.rfind('1').unwrap();
+        for position in separator + 1..encoded.len() {
+            let mut typo = encoded.clone().into_bytes();
+            typo[position] = if typo[position] == b'q' { b'p' } else { b'q' };
+            let typo = String::from_utf8(typo).unwrap();
+            assert!(decode_address(&typo).is_err(), "{} was accepted", typo);
+        }
+        let mixed = format!("A{}", &encoded[1..]);
+        assert!(decode_address(&mixed).is_err());
+        assert!(hex::encode(KEY).parse::<Address>().is_err());
+        assert!(decode_address(&encoded[..encoded.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn a_backup_lacking_a_key_of_the_wallet_does_not_replace_it() {
+        let mut backup = Wallet::default();
+        let first = backup.generate("pw").unwrap();
+        let mut wallet = Wallet {
+            keys: backup.keys.clone(),
+            ..Wallet::default()
+        };
+        let second = wallet.generate("pw").unwrap();
+        let error = wallet.replace(backup.clone()).unwrap_err();
+        assert!(error.to_string().contains(&second.to_string()));
+        assert_eq!(wallet.addresses().count(), 2);
+
+        let policy = Policy::new(1, vec![first, second]).unwrap();
+        wallet.keys.retain(|key| key.address == first);
+        wallet.add_multisig(policy.clone()).unwrap();
+        assert!(wallet.replace(backup.clone()).is_err());
+        backup.add_multisig(policy).unwrap();
+        backup.generate("pw").unwrap();
+        wallet.replace(backup).unwrap();
+        assert_eq!(wallet.addresses().count(), 2);
+        assert_eq!(wallet.multisigs().count(), 1);
+    }
+}
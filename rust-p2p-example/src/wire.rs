@@ -0,0 +1,90 @@
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Result};
+use clap::ValueEnum;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+/// Tag byte of a JSON encoded message
+const FORMAT_JSON: u8 = 0x00;
+
+/// Tag byte of a CBOR encoded message
+const FORMAT_CBOR: u8 = 0x01;
+
+/// Serialization format of gossip messages
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum WireFormat {
+    /// Human readable, understood by every version of the recipe node
+    Json,
+    /// Compact binary encoding, keeps working when fields are added
+    Cbor,
+}
+
+/// Serialize a message, prefixed with the tag of its format
+pub fn serialize<T: Serialize>(message: &T, format: WireFormat) -> Result<Vec<u8>> {
+    let mut out = Vec::new();
+    match format {
+        WireFormat::Json => {
+            out.push(FORMAT_JSON);
+            serde_json::to_writer(&mut out, message)?;
+        }
+        WireFormat::Cbor => {
+            out.push(FORMAT_CBOR);
+            ciborium::into_writer(message, &mut out)?;
+        }
+    }
+    Ok(out)
+}
+
+/// Deserialize a message in whatever format its tag announces
+///
+/// Untagged JSON of peers predating the format tag is accepted as well
+pub fn deserialize<T: DeserializeOwned>(data: &[u8]) -> Result<T> {
+    match data.first() {
+        Some(&FORMAT_JSON) => Ok(serde_json::from_slice(&data[1..])?),
+        Some(&FORMAT_CBOR) => Ok(ciborium::from_reader(&data[1..])?),
+        Some(b'{') => Ok(serde_json::from_slice(data)?),
+        Some(format) => bail!("unknown wire format {:#04x}", format),
+        None => bail!("empty message"),
+    }
+}
+
+/// Encoded size and average round trip time of one format
+#[derive(Debug)]
+pub struct FormatBenchmark {
+    pub format: WireFormat,
+    pub size: usize,
+    pub encode: Duration,
+    pub decode: Duration,
+}
+
+/// Measure how large and how fast each format is for the given message
+pub fn benchmark<T: Serialize + DeserializeOwned>(
+    message: &T,
+    iterations: u32,
+) -> Result<Vec<FormatBenchmark>> {
+    let mut results = Vec::new();
+    for format in [WireFormat::Json, WireFormat::Cbor] {
+        let started = Instant::now();
+        let mut encoded = Vec::new();
+        for _ in 0..iterations {
+            encoded = serialize(message, format)?;
+        }
+        let encode = started.elapsed() / iterations;
+
+        let started = Instant::now();
+        for _ in 0..iterations {
+            deserialize::<T>(&encoded)?;
+        }
+        let decode = started.elapsed() / iterations;
+
+        results.push(FormatBenchmark {
+            format,
+            size: encoded.len(),
+            encode,
+            decode,
+        });
+    }
+    Ok(results)
+}